@@ -0,0 +1,54 @@
+//! Prints what an image contains, then writes one partition to a file or stdout without
+//! unpacking the rest:
+//!
+//! ```text
+//! cargo run --example inspect_and_extract -- update.img            # list the partitions
+//! cargo run --example inspect_and_extract -- update.img boot boot.img
+//! cargo run --example inspect_and_extract -- update.img parameter - # to stdout
+//! ```
+
+use std::fs::File;
+use std::io::Write;
+use afptool_rs::{extract_partition, human_size, inspect_file, list_partitions, UnpackResult};
+use anyhow::{bail, Context, Result};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(image) = args.first() else {
+        bail!("usage: inspect_and_extract <image> [<partition> [<output file or ->]]");
+    };
+
+    // Reads and checks the headers only, nothing is written
+    match inspect_file(image)? {
+        UnpackResult::Rkfw(info) => {
+            eprintln!("RKFW {} for {} built {}", info.version, info.chip_family, info.built_at);
+            eprintln!("  BOOT {}, embedded update image {}", human_size(info.boot_size as u64), human_size(info.update_size as u64));
+        }
+        UnpackResult::Rkaf(info) => eprintln!("RKAF for {} by {}", info.model, info.manufacturer),
+    }
+
+    // Offsets are relative to the file, also for the partitions inside an RKFW image
+    let partitions = list_partitions(image)?;
+    for part in &partitions {
+        let note = if part.is_special() { " (not extracted)" } else { "" };
+        eprintln!("  {:<16} {:<32} {:>10}{}", part.name, part.path, human_size(part.part_byte_count as u64), note);
+    }
+
+    let Some(name) = args.get(1) else { return Ok(()) };
+    let part = partitions
+        .iter()
+        .find(|part| part.name == *name && !part.is_special())
+        .with_context(|| format!("{} has no partition named {}", image, name))?;
+    let mut input = File::open(image)?;
+    let written = match args.get(2).map(String::as_str) {
+        None | Some("-") => {
+            let mut stdout = std::io::stdout().lock();
+            let written = extract_partition(&mut input, part, &mut stdout)?;
+            stdout.flush()?;
+            written
+        }
+        Some(output) => extract_partition(&mut input, part, &mut File::create(output)?)?,
+    };
+    eprintln!("Extracted {} ({})", part.name, human_size(written));
+    Ok(())
+}
@@ -0,0 +1,35 @@
+#![no_main]
+
+use std::io::Cursor;
+use afptool_rs::{inspect_file, list_partitions, unpack_reader_with_options, UnpackOptions, RKAF_SIGNATURE, RKFW_SIGNATURE};
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the signature put in front of the rest, so both the RKFW and the RKAF
+// parser see most inputs instead of failing on the magic. Every call may return `Err`, a panic
+// is a bug.
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, rest)) = data.split_first() else { return };
+    let image = match selector % 3 {
+        0 => [RKFW_SIGNATURE, rest].concat(),
+        1 => [RKAF_SIGNATURE, rest].concat(),
+        _ => rest.to_vec(),
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.img");
+    std::fs::write(&path, &image).unwrap();
+    let path = path.to_str().unwrap();
+    let _ = inspect_file(path);
+    let _ = list_partitions(path);
+
+    let options = UnpackOptions {
+        recursive: true,
+        split_boot: true,
+        split_resource: true,
+        expand_sparse: true,
+        max_size: Some(64 << 20),
+        ..UnpackOptions::default()
+    };
+    let dst = dir.path().join("out");
+    let _ = unpack_reader_with_options(Cursor::new(image), dst.to_str().unwrap(), &options);
+});
@@ -0,0 +1,123 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::error::ApfError;
+
+// Rockchip loader container, as written by boot_merger
+const BOOT_HEADER_SIZE: usize = 0x66;
+const BOOT_ENTRY_SIZE: usize = 0x39;
+const BOOT_ENTRY_NAME_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum BootEntryKind {
+    /// DDR init stage loaded by the mask ROM (usually named 471 or DDR)
+    Code471,
+    /// USB plug stage loaded after 471 (usually named 472 or usbplug)
+    Code472,
+    /// Stages written to flash, e.g. FlashData and FlashBoot
+    Loader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BootEntry {
+    pub kind: BootEntryKind,
+    pub name: String,
+    /// Offset of the entry data inside the BOOT blob
+    pub offset: u32,
+    pub size: u32,
+    /// Delay in milliseconds the mask ROM waits after loading the entry
+    pub delay: u32,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn parse_entry(data: &[u8], offset: usize, kind: BootEntryKind) -> Result<BootEntry, ApfError> {
+    let entry = data.get(offset..offset + BOOT_ENTRY_SIZE).ok_or_else(|| {
+        ApfError::Truncated(format!("BOOT entry at offset {:#x} is out of bounds", offset))
+    })?;
+
+    // Names are NUL-terminated UTF-16LE
+    let name_units: Vec<u16> = entry[5..5 + BOOT_ENTRY_NAME_LEN * 2]
+        .chunks(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+
+    let boot_entry = BootEntry {
+        kind,
+        name: String::from_utf16_lossy(&name_units),
+        offset: read_u32(entry, 0x2d),
+        size: read_u32(entry, 0x31),
+        delay: read_u32(entry, 0x35),
+    };
+    if boot_entry.offset as u64 + boot_entry.size as u64 > data.len() as u64 {
+        return Err(ApfError::Truncated(format!(
+            "BOOT entry {} at offset {:#x} (size {}) is out of bounds (size {})",
+            boot_entry.name,
+            boot_entry.offset,
+            boot_entry.size,
+            data.len()
+        )));
+    }
+    Ok(boot_entry)
+}
+
+pub fn parse_boot(data: &[u8]) -> Result<Vec<BootEntry>, ApfError> {
+    if data.len() < BOOT_HEADER_SIZE {
+        return Err(ApfError::Truncated(format!(
+            "BOOT blob too small to contain a loader header ({} bytes, need {})",
+            data.len(),
+            BOOT_HEADER_SIZE
+        )));
+    }
+    if &data[0..4] != b"BOOT" && &data[0..4] != b"LDR " {
+        return Err(ApfError::BadMagic);
+    }
+
+    // (count, table offset, entry size) for the 471, 472 and loader tables
+    let tables = [
+        (BootEntryKind::Code471, data[0x19], read_u32(data, 0x1a), data[0x1e]),
+        (BootEntryKind::Code472, data[0x1f], read_u32(data, 0x20), data[0x24]),
+        (BootEntryKind::Loader, data[0x25], read_u32(data, 0x26), data[0x2a]),
+    ];
+
+    let mut entries = Vec::new();
+    for (kind, count, table_offset, entry_size) in tables {
+        let entry_size = std::cmp::max(entry_size as usize, BOOT_ENTRY_SIZE);
+        for i in 0..count as usize {
+            entries.push(parse_entry(data, table_offset as usize + i * entry_size, kind)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+// Fixed key used by Rockchip tools (rkdeveloptool, boot_merger) for loader stages
+const RC4_KEY: [u8; 16] = [124, 78, 3, 4, 85, 5, 9, 7, 45, 44, 123, 56, 23, 13, 23, 17];
+
+/// Encrypts or decrypts `data` in place with the Rockchip RC4 key; applying it twice restores the input
+pub fn rc4_rk(data: &mut [u8]) {
+    let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(RC4_KEY[i % RC4_KEY.len()]);
+        s.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    for byte in data.iter_mut() {
+        i = i.wrapping_add(1);
+        j = j.wrapping_add(s[i as usize]);
+        s.swap(i as usize, j as usize);
+        *byte ^= s[s[i as usize].wrapping_add(s[j as usize]) as usize];
+    }
+}
+
+// A zero rc4 flag in the loader header means the 471/472 stages are stored encrypted
+pub(crate) fn boot_is_rc4_encrypted(data: &[u8]) -> bool {
+    data.len() >= BOOT_HEADER_SIZE && data[0x2c] == 0
+}
@@ -0,0 +1,188 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::error::ApfError;
+
+pub const ANDROID_BOOT_MAGIC: &[u8] = b"ANDROID!";
+
+// Sizes of the boot_img_hdr_v0 .. v4 layouts from AOSP bootimg.h
+const HEADER_V0_SIZE: usize = 1632;
+const HEADER_V1_SIZE: usize = 1648;
+const HEADER_V2_SIZE: usize = 1660;
+const HEADER_V3_SIZE: usize = 1580;
+const HEADER_V4_SIZE: usize = 1584;
+// Versions 3 and 4 have no page_size field, everything is aligned to 4 KiB
+const HEADER_V3_PAGE_SIZE: u32 = 4096;
+const MAX_HEADER_VERSION: u32 = 4;
+const NAME_LEN: usize = 16;
+const CMDLINE_LEN: usize = 512;
+const EXTRA_CMDLINE_LEN: usize = 1024;
+const V3_CMDLINE_LEN: usize = 1536;
+
+/// Byte range of one part of an Android boot image, relative to the start of the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BootImageSection {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Header of an Android boot or recovery image (`ANDROID!`), see `parse_boot_image`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AndroidBootImage {
+    /// 0 to 4
+    pub header_version: u32,
+    /// Alignment of every section, from the header for versions 0-2 and 4096 for 3 and 4
+    pub page_size: u32,
+    /// Android version and security patch level packed as in the header
+    pub os_version: u32,
+    /// Product name, empty for versions 3 and 4
+    pub name: String,
+    /// Kernel command line, including `extra_cmdline` for versions 0-2
+    pub cmdline: String,
+    pub kernel: BootImageSection,
+    pub ramdisk: BootImageSection,
+    /// Second stage loader, versions 0-2 only
+    pub second: Option<BootImageSection>,
+    /// Recovery DTBO of A-only recovery images, versions 1 and 2
+    pub recovery_dtbo: Option<BootImageSection>,
+    /// Device tree blob, version 2
+    pub dtb: Option<BootImageSection>,
+    /// Boot signature, version 4
+    pub signature: Option<BootImageSection>,
+}
+
+impl AndroidBootImage {
+    /// The non-empty sections with the file names they are extracted under, in image order
+    pub fn sections(&self) -> Vec<(&'static str, BootImageSection)> {
+        [
+            ("kernel", Some(self.kernel)),
+            ("ramdisk", Some(self.ramdisk)),
+            ("second", self.second),
+            ("recovery_dtbo", self.recovery_dtbo),
+            ("dtb", self.dtb),
+            ("boot_signature", self.signature),
+        ]
+        .into_iter()
+        .filter_map(|(name, section)| section.filter(|section| section.size > 0).map(|section| (name, section)))
+        .collect()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn read_cstr(data: &[u8]) -> String {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).to_string()
+}
+
+// Lays the sections out one after another from the page after the header, each starting on a
+// page boundary
+fn layout_sections(data: &[u8], page_size: u32, header_size: usize, sizes: &[u32]) -> Result<Vec<BootImageSection>, ApfError> {
+    let align = |value: u64| value.div_ceil(page_size as u64) * page_size as u64;
+    let mut offset = align(header_size as u64);
+    let mut sections = Vec::new();
+    for &size in sizes {
+        if offset + size as u64 > data.len() as u64 {
+            return Err(ApfError::Truncated(format!(
+                "Boot image section at offset {:#x} (size {}) is out of bounds (size {})",
+                offset,
+                size,
+                data.len()
+            )));
+        }
+        sections.push(BootImageSection { offset: offset as u32, size });
+        offset = align(offset + size as u64);
+    }
+    Ok(sections)
+}
+
+/// Parses an Android boot image header of version 0 to 4 and locates its sections; every section
+/// must lie inside `data`
+pub fn parse_boot_image(data: &[u8]) -> Result<AndroidBootImage, ApfError> {
+    if data.len() < HEADER_V3_SIZE {
+        return Err(ApfError::Truncated(format!(
+            "Boot image too small to contain a header ({} bytes, need {})",
+            data.len(),
+            HEADER_V3_SIZE
+        )));
+    }
+    if !data.starts_with(ANDROID_BOOT_MAGIC) {
+        return Err(ApfError::BadMagic);
+    }
+
+    // header_version sits at 0x28 in every layout
+    let header_version = read_u32(data, 0x28);
+    if header_version > MAX_HEADER_VERSION {
+        return Err(ApfError::Invalid(format!("Unsupported boot image header version {}", header_version)));
+    }
+    let header_size = match header_version {
+        0 => HEADER_V0_SIZE,
+        1 => HEADER_V1_SIZE,
+        2 => HEADER_V2_SIZE,
+        3 => HEADER_V3_SIZE,
+        _ => HEADER_V4_SIZE,
+    };
+    if data.len() < header_size {
+        return Err(ApfError::Truncated(format!(
+            "Boot image too small for a version {} header ({} bytes, need {})",
+            header_version,
+            data.len(),
+            header_size
+        )));
+    }
+
+    if header_version >= 3 {
+        let mut sizes = vec![read_u32(data, 0x08), read_u32(data, 0x0c)];
+        if header_version == 4 {
+            sizes.push(read_u32(data, HEADER_V3_SIZE));
+        }
+        let sections = layout_sections(data, HEADER_V3_PAGE_SIZE, header_size, &sizes)?;
+        return Ok(AndroidBootImage {
+            header_version,
+            page_size: HEADER_V3_PAGE_SIZE,
+            os_version: read_u32(data, 0x10),
+            name: String::new(),
+            cmdline: read_cstr(&data[0x2c..0x2c + V3_CMDLINE_LEN]),
+            kernel: sections[0],
+            ramdisk: sections[1],
+            second: None,
+            recovery_dtbo: None,
+            dtb: None,
+            signature: sections.get(2).copied(),
+        });
+    }
+
+    let page_size = read_u32(data, 0x24);
+    if !page_size.is_power_of_two() || page_size < 2048 {
+        return Err(ApfError::Invalid(format!("Invalid boot image page size {}", page_size)));
+    }
+    let mut sizes = vec![read_u32(data, 0x08), read_u32(data, 0x10), read_u32(data, 0x18)];
+    if header_version >= 1 {
+        sizes.push(read_u32(data, HEADER_V0_SIZE));
+    }
+    if header_version >= 2 {
+        sizes.push(read_u32(data, HEADER_V1_SIZE));
+    }
+    let sections = layout_sections(data, page_size, header_size, &sizes)?;
+
+    let cmdline_offset = 0x40;
+    let extra_cmdline_offset = cmdline_offset + CMDLINE_LEN + 32;
+    let mut cmdline = read_cstr(&data[cmdline_offset..cmdline_offset + CMDLINE_LEN]);
+    cmdline.push_str(&read_cstr(&data[extra_cmdline_offset..extra_cmdline_offset + EXTRA_CMDLINE_LEN]));
+    Ok(AndroidBootImage {
+        header_version,
+        page_size,
+        os_version: read_u32(data, 0x2c),
+        name: read_cstr(&data[0x30..0x30 + NAME_LEN]),
+        cmdline,
+        kernel: sections[0],
+        ramdisk: sections[1],
+        second: Some(sections[2]),
+        recovery_dtbo: sections.get(3).copied(),
+        dtb: sections.get(4).copied(),
+        signature: None,
+    })
+}
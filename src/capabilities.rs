@@ -0,0 +1,41 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::chip::CHIP_FAMILIES;
+use crate::parallel::CAN_EXTRACT_IN_PARALLEL;
+
+/// What this build can read and check, printed by `version-info` for bug reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Capabilities {
+    /// Crate version, e.g. `1.1.1`
+    pub version: &'static str,
+    /// Image signatures `unpack_file` accepts
+    pub signatures: Vec<&'static str>,
+    /// Algorithms accepted by `UnpackOptions::checksums`
+    pub hash_algorithms: Vec<&'static str>,
+    /// Number of chip codes in `CHIP_FAMILIES`
+    pub chips: usize,
+    /// Optional functionality and whether this build has it; new entries are appended
+    pub features: Vec<(&'static str, bool)>,
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        signatures: vec!["RKFW", "RKAF"],
+        hash_algorithms: vec!["md5", "sha256"],
+        chips: CHIP_FAMILIES.len(),
+        features: vec![
+            ("cli", cfg!(feature = "cli")),
+            ("serde", cfg!(feature = "serde")),
+            ("crc-verification", true),
+            ("gzip", true),
+            ("zstd", true),
+            ("sparse-expansion", true),
+            ("parallel-extraction", CAN_EXTRACT_IN_PARALLEL),
+            ("android-boot-image", true),
+            ("fuse", cfg!(feature = "fuse")),
+            ("fit-image", true),
+        ],
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use crate::error::ApfError;
+use crate::unpack::{partition_output_path, PartitionInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(anyhow!("Unsupported hash algorithm: {}", s)),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(ctx) => ctx.consume(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(ctx) => format!("{:x}", ctx.finalize()),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+// Hashes everything `reader` yields, returns the lowercase hex digest and the number of bytes hashed
+pub(crate) fn hash_reader<R: Read>(reader: &mut R, algorithm: HashAlgorithm) -> Result<(String, u64)> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; 16 * 1024];
+    let mut total = 0u64;
+    loop {
+        let read_bytes = reader.read(&mut buffer)?;
+        if read_bytes == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read_bytes]);
+        total += read_bytes as u64;
+    }
+    Ok((hasher.finish(), total))
+}
+
+pub fn hash_partition<R: Read + Seek>(reader: &mut R, part: &PartitionInfo, algorithm: HashAlgorithm) -> Result<String, ApfError> {
+    reader.seek(std::io::SeekFrom::Start(part.part_offset as u64))?;
+    let (digest, hashed) = hash_reader(&mut reader.take(part.part_byte_count as u64), algorithm)?;
+    if hashed != part.part_byte_count as u64 {
+        return Err(ApfError::Truncated(format!("Insufficient length in container image file for {}", part.name)));
+    }
+    Ok(digest)
+}
+
+/// Checks the files under `dst_path` against a `sha256sum` or `md5sum` style list of
+/// `<digest>  <path>` lines, such as the `checksums.txt` an unpack writes; paths are relative to
+/// `dst_path` and the algorithm follows from the digest length. Returns every listed path with
+/// whether it matched, a missing file counts as a mismatch.
+pub fn verify_against_manifest(dst_path: &str, manifest_path: &str) -> Result<Vec<(String, bool)>, ApfError> {
+    let manifest = std::fs::read_to_string(manifest_path)?;
+    let mut results = Vec::new();
+    for (index, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || ApfError::Invalid(format!("{} line {}: expected `<digest>  <path>`, got {:?}", manifest_path, index + 1, line));
+        // The second separator character is `*` for files hashed in binary mode
+        let (digest, path) = line.split_once(' ').ok_or_else(invalid)?;
+        let path = path.strip_prefix([' ', '*']).ok_or_else(invalid)?;
+        let algorithm = match digest.len() {
+            32 => HashAlgorithm::Md5,
+            64 => HashAlgorithm::Sha256,
+            _ => return Err(invalid()),
+        };
+        if path.is_empty() || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let matches = match File::open(partition_output_path(dst_path, path)?) {
+            Ok(mut file) => hash_reader(&mut file, algorithm)?.0.eq_ignore_ascii_case(digest),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        results.push((path.to_string(), matches));
+    }
+    Ok(results)
+}
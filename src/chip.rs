@@ -0,0 +1,55 @@
+/// RKFW chip codes (header offset 0x15) and the chip family each one stands for, in the order
+/// `chips` lists them. A family may have several codes; `chip_name_to_code` packs the first.
+pub const CHIP_FAMILIES: &[(u8, &str)] = &[
+    (0x50, "RK29xx"),
+    (0x60, "RK30xx"),
+    (0x70, "RK31xx"),
+    (0x80, "RK32xx"),
+    (0x41, "RK3368"),
+    (0x36, "RK3326"),
+    (0x32, "RK3562"),
+    (0x38, "RK3566"),
+    (0x30, "PX30"),
+    (0x90, "RK3399"),
+    (0x91, "RK3399"),
+    (0xa0, "RK3588"),
+    (0xa1, "RK3588S"),
+    (0xa2, "RK3576"),
+    (0xa3, "RK3528"),
+];
+
+/// Every known (chip code, family) pair, the same table `unpack` and `pack_rkfw` use
+pub fn all_chips() -> &'static [(u8, &'static str)] {
+    CHIP_FAMILIES
+}
+
+/// Family of an RKFW chip code, e.g. `RK3326` for 0x36. Unknown codes give `None`, not a
+/// placeholder such as the `unknown` that `RkfwInfo::chip_family` reports
+pub fn chip_family(code: u8) -> Option<&'static str> {
+    CHIP_FAMILIES.iter().find(|&&(known, _)| known == code).map(|&(_, family)| family)
+}
+
+// The known family named earliest in `data`, ignoring case, e.g. in the loader names a BOOT
+// blob carries. Older series match on their prefix (`RK30` for `RK30xx`); when several names
+// start at the same place the longest wins, so `RK3588S` is not taken for `RK3588`.
+pub(crate) fn infer_chip_family(data: &[u8]) -> Option<&'static str> {
+    let mut best: Option<(usize, usize, &'static str)> = None;
+    for &(_, family) in CHIP_FAMILIES {
+        let needle = family.strip_suffix("xx").unwrap_or(family).as_bytes();
+        let Some(position) = data.windows(needle.len()).position(|window| window.eq_ignore_ascii_case(needle)) else {
+            continue;
+        };
+        if best.is_none_or(|(best_position, best_len, _)| position < best_position || (position == best_position && needle.len() > best_len)) {
+            best = Some((position, needle.len(), family));
+        }
+    }
+    best.map(|(_, _, family)| family)
+}
+
+// Case-insensitive; the `xx` of the older series may be left out, e.g. `RK29`
+pub(crate) fn chip_code(family: &str) -> Option<u8> {
+    CHIP_FAMILIES
+        .iter()
+        .find(|&&(_, name)| name.eq_ignore_ascii_case(family) || name.strip_suffix("xx").is_some_and(|series| series.eq_ignore_ascii_case(family)))
+        .map(|&(code, _)| code)
+}
@@ -0,0 +1,66 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use anyhow::Result;
+use log::info;
+use crate::unpack::{copy_range, format_range, human_size, ImageSource, PartitionInfo, Progress};
+
+/// Index of the partitions written in chunks by `UnpackOptions::split_size`
+pub const CHUNKS_FILE: &str = "chunks.json";
+
+/// One file of a split partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Chunk {
+    /// Relative to the destination, `<partition path>.part<N>`
+    pub path: String,
+    /// Where the chunk starts in the partition data
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A partition written as chunks instead of one file; concatenating `chunks` in order gives
+/// the partition data (e.g. with `MultiFileReader`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkedPartition {
+    pub name: String,
+    /// The path the whole partition would have been extracted to
+    pub path: String,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Contents of `chunks.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChunkIndex {
+    pub chunk_size: u64,
+    pub partitions: Vec<ChunkedPartition>,
+}
+
+// Copies the partition data into `<relative_path>.part0`, `.part1`, ... below `dst_path`, each
+// `chunk_size` bytes except the last
+pub(crate) fn extract_chunks<R: ImageSource>(
+    fp: &mut R,
+    part: &PartitionInfo,
+    dst_path: &str,
+    relative_path: &str,
+    chunk_size: u64,
+    buffer_size: usize,
+    progress: Progress<'_>,
+) -> Result<ChunkedPartition> {
+    let size = part.part_byte_count as u64;
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let len = chunk_size.min(size - offset);
+        let path = format!("{}.part{}", relative_path, chunks.len());
+        let mut output_path = std::path::PathBuf::from(dst_path);
+        output_path.extend(path.split('/'));
+        info!("{:17} {:26} (size: {})", format_range(part.part_offset as u64 + offset, len), output_path.display(), human_size(len));
+        copy_range(fp, part.part_offset as u64 + offset, len, &output_path.to_string_lossy(), buffer_size, progress)?;
+        chunks.push(Chunk { path, offset, size: len });
+        offset += len;
+    }
+    Ok(ChunkedPartition { name: part.name.clone(), path: relative_path.to_string(), size, chunks })
+}
@@ -0,0 +1,41 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use log::info;
+use crate::error::ApfError;
+use crate::unpack::human_size;
+
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+// Streams the decompressed data into `output` and returns its size; stops with an error once
+// the output would exceed `max_size`
+pub(crate) fn decompress<R: Read, W: Write>(input: R, compression: Compression, output: &mut W, max_size: Option<u64>) -> Result<u64, ApfError> {
+    let limit = max_size.map_or(u64::MAX, |max| max.saturating_add(1));
+    let written = match compression {
+        Compression::Gzip => std::io::copy(&mut flate2::read::MultiGzDecoder::new(input).take(limit), output),
+        Compression::Zstd => std::io::copy(&mut zstd::stream::read::Decoder::new(input)?.take(limit), output),
+    }
+    .map_err(|err| ApfError::Invalid(format!("Cannot decompress {:?} image: {}", compression, err)))?;
+    if let Some(max) = max_size.filter(|&max| written > max) {
+        return Err(ApfError::Invalid(format!(
+            "Decompressed {:?} image exceeds the maximum size of {} bytes",
+            compression, max
+        )));
+    }
+    Ok(written)
+}
+
+// Streams the decompressed image into an anonymous temp file, which is removed once dropped
+pub(crate) fn decompress_to_temp<R: Read>(input: R, compression: Compression, max_size: Option<u64>) -> Result<File, ApfError> {
+    let mut output = tempfile::tempfile()?;
+    let written = decompress(input, compression, &mut output, max_size)?;
+    info!("Decompressed {:?} image ({})", compression, human_size(written));
+    output.rewind()?;
+    Ok(output)
+}
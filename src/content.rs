@@ -0,0 +1,112 @@
+use crate::boot::rc4_rk;
+use crate::bootimg::ANDROID_BOOT_MAGIC;
+use crate::fit::is_fit;
+use crate::sparse::SPARSE_HEADER_MAGIC;
+
+/// Number of leading partition bytes `detect_fs` looks at, enough for every superblock it knows
+pub const FS_PROBE_LEN: usize = 4096;
+
+// ext2/3/4 superblock at 1024, magic at +0x38
+const EXT_SUPERBLOCK: usize = 0x400;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+// extents, 64bit, flex_bg
+const EXT4_INCOMPAT: u32 = 0x40 | 0x80 | 0x200;
+
+const F2FS_MAGIC: u32 = 0xf2f52010;
+const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+const CRAMFS_MAGIC: u32 = 0x28cd3d45;
+const FDT_MAGIC: u32 = 0xd00dfeed;
+// First word of the RC4-encrypted ID block the mask ROM loads the miniloader from
+const IDBLOCK_MAGIC: u32 = 0x0ff0aa55;
+
+fn u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn ext_type(data: &[u8]) -> Option<&'static str> {
+    if u16_le(data, EXT_SUPERBLOCK + 0x38)? != EXT_MAGIC {
+        return None;
+    }
+    let compat = u32_le(data, EXT_SUPERBLOCK + 0x5c)?;
+    let incompat = u32_le(data, EXT_SUPERBLOCK + 0x60)?;
+    Some(if incompat & EXT4_INCOMPAT != 0 {
+        "ext4"
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    })
+}
+
+/// Names the file system or image format starting at `first_bytes` from its magic, e.g. `ext4`,
+/// `squashfs` or `android-boot`; `None` when nothing is recognized. Give it the first
+/// `FS_PROBE_LEN` bytes of a partition, fewer only rule out the formats with a superblock further in
+pub fn detect_fs(first_bytes: &[u8]) -> Option<&'static str> {
+    let magic = first_bytes.get(..8).unwrap_or(first_bytes);
+    let magic32 = u32_le(first_bytes, 0);
+    if magic.starts_with(ANDROID_BOOT_MAGIC) {
+        return Some("android-boot");
+    }
+    if magic.starts_with(b"VNDRBOOT") {
+        return Some("android-vendor-boot");
+    }
+    if magic.starts_with(b"hsqs") || magic.starts_with(b"sqsh") {
+        return Some("squashfs");
+    }
+    if magic32 == Some(CRAMFS_MAGIC) || magic32 == Some(CRAMFS_MAGIC.swap_bytes()) {
+        return Some("cramfs");
+    }
+    if magic32 == Some(SPARSE_HEADER_MAGIC) {
+        return Some("android-sparse");
+    }
+    // Device trees and FIT images (U-Boot, trust) are big endian
+    if magic32 == Some(FDT_MAGIC.swap_bytes()) {
+        return Some(if is_fit(first_bytes) { "fit" } else { "fdt" });
+    }
+    // uboot.img and trust.img as written by the Rockchip loaderimage and trust_merger tools
+    if magic.starts_with(b"LOADER  ") {
+        return Some("rockchip-uboot");
+    }
+    if magic.starts_with(b"BL3X") || magic.starts_with(b"TOS     ") {
+        return Some("rockchip-trust");
+    }
+    if magic.starts_with(b"RKNS") {
+        return Some("rockchip-idblock-v2");
+    }
+    if let Some(mut word) = first_bytes.get(..4).map(|b| [b[0], b[1], b[2], b[3]]) {
+        rc4_rk(&mut word);
+        if u32::from_le_bytes(word) == IDBLOCK_MAGIC {
+            return Some("rockchip-idblock");
+        }
+    }
+    if magic.starts_with(b"RSCE") {
+        return Some("rockchip-resource");
+    }
+    if magic.starts_with(b"BOOT") || magic.starts_with(b"LDR ") {
+        return Some("rockchip-loader");
+    }
+    if magic.starts_with(b"PARM") {
+        return Some("rockchip-parameter");
+    }
+    if magic.starts_with(b"KRNL") {
+        return Some("rockchip-kernel");
+    }
+    if let Some(ext) = ext_type(first_bytes) {
+        return Some(ext);
+    }
+    if u32_le(first_bytes, EXT_SUPERBLOCK) == Some(F2FS_MAGIC) {
+        return Some("f2fs");
+    }
+    if u32_le(first_bytes, EXT_SUPERBLOCK) == Some(EROFS_MAGIC) {
+        return Some("erofs");
+    }
+    if first_bytes.get(510..512) == Some(&[0x55, 0xaa]) && (first_bytes.get(0x36..0x39) == Some(b"FAT") || first_bytes.get(0x52..0x55) == Some(b"FAT")) {
+        return Some("vfat");
+    }
+    None
+}
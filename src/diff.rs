@@ -0,0 +1,122 @@
+use std::fmt;
+use std::fs::File;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::checksum::{hash_partition, HashAlgorithm};
+use crate::encoding::TextEncoding;
+use crate::error::ApfError;
+use crate::unpack::{inspect_file, list_partitions, parse_rkafp, read_bytes_at, stream_len, PartitionInfo, UnpackResult};
+use crate::UpdateHeader;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum PartitionDiff {
+    /// A header field such as `model` or `manufacturer` differs
+    Header { field: String, a: String, b: String },
+    /// Same name and same SHA-256 in both images
+    Identical { name: String, size: u32 },
+    /// Same name, different contents
+    Changed { name: String, a_size: u32, b_size: u32, a_sha256: String, b_sha256: String },
+    /// Only present in the second image
+    Added { name: String, size: u32 },
+    /// Only present in the first image
+    Removed { name: String, size: u32 },
+}
+
+impl fmt::Display for PartitionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionDiff::Header { field, a, b } => write!(f, "header {}: {:?} -> {:?}", field, a, b),
+            PartitionDiff::Identical { name, size } => write!(f, "= {} ({} bytes)", name, size),
+            PartitionDiff::Changed { name, a_size, b_size, .. } => {
+                write!(f, "~ {} ({} -> {} bytes)", name, a_size, b_size)
+            }
+            PartitionDiff::Added { name, size } => write!(f, "+ {} ({} bytes)", name, size),
+            PartitionDiff::Removed { name, size } => write!(f, "- {} ({} bytes)", name, size),
+        }
+    }
+}
+
+// Header fields worth comparing; for RKFW these include the embedded RKAF header
+fn header_fields(file_path: &str) -> Result<Vec<(&'static str, String)>, ApfError> {
+    let mut fields = Vec::new();
+    let (base, size) = match inspect_file(file_path)? {
+        UnpackResult::Rkaf(info) => {
+            fields.push(("format", "rkaf".to_string()));
+            (0, info.filesize)
+        }
+        UnpackResult::Rkfw(info) => {
+            fields.push(("format", "rkfw".to_string()));
+            fields.push(("version", info.version.clone()));
+            fields.push(("chip_family", info.chip_family.clone()));
+            (info.update_offset as u64, info.update_size as u64)
+        }
+    };
+
+    let mut file = File::open(file_path)?;
+    let filesize = stream_len(&mut file)?;
+    let header_bytes = read_bytes_at(&mut file, base, std::mem::size_of::<UpdateHeader>())?;
+    let info = parse_rkafp(UpdateHeader::from_bytes(&header_bytes), std::cmp::min(size, filesize.saturating_sub(base)), TextEncoding::Auto);
+    fields.push(("manufacturer", info.manufacturer));
+    fields.push(("model", info.model));
+    Ok(fields)
+}
+
+fn extracted_partitions(file_path: &str) -> Result<Vec<PartitionInfo>, ApfError> {
+    Ok(list_partitions(file_path)?.into_iter().filter(|part| !part.is_special()).collect())
+}
+
+/// Compares two update images: header fields first, then every partition of `a` in table
+/// order, then partitions only `b` has. Partitions are matched by name and compared by SHA-256.
+pub fn diff_images(a: &str, b: &str) -> Result<Vec<PartitionDiff>, ApfError> {
+    let mut diffs = Vec::new();
+    let a_fields = header_fields(a)?;
+    let b_fields = header_fields(b)?;
+    for (field, a_value) in &a_fields {
+        let b_value = b_fields
+            .iter()
+            .find(|(name, _)| name == field)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+        if *a_value != b_value {
+            diffs.push(PartitionDiff::Header { field: field.to_string(), a: a_value.clone(), b: b_value });
+        }
+    }
+    for (field, b_value) in &b_fields {
+        if !a_fields.iter().any(|(name, _)| name == field) {
+            diffs.push(PartitionDiff::Header { field: field.to_string(), a: String::new(), b: b_value.clone() });
+        }
+    }
+
+    let a_parts = extracted_partitions(a)?;
+    let b_parts = extracted_partitions(b)?;
+    let mut a_file = File::open(a)?;
+    let mut b_file = File::open(b)?;
+    for a_part in &a_parts {
+        let Some(b_part) = b_parts.iter().find(|part| part.name == a_part.name) else {
+            diffs.push(PartitionDiff::Removed { name: a_part.name.clone(), size: a_part.part_byte_count });
+            continue;
+        };
+        let a_sha256 = hash_partition(&mut a_file, a_part, HashAlgorithm::Sha256)?;
+        let b_sha256 = hash_partition(&mut b_file, b_part, HashAlgorithm::Sha256)?;
+        if a_sha256 == b_sha256 {
+            diffs.push(PartitionDiff::Identical { name: a_part.name.clone(), size: a_part.part_byte_count });
+        } else {
+            diffs.push(PartitionDiff::Changed {
+                name: a_part.name.clone(),
+                a_size: a_part.part_byte_count,
+                b_size: b_part.part_byte_count,
+                a_sha256,
+                b_sha256,
+            });
+        }
+    }
+    for b_part in &b_parts {
+        if !a_parts.iter().any(|part| part.name == b_part.name) {
+            diffs.push(PartitionDiff::Added { name: b_part.name.clone(), size: b_part.part_byte_count });
+        }
+    }
+
+    Ok(diffs)
+}
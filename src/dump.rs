@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::mem::{offset_of, size_of};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::chip::chip_family;
+use crate::encoding::{decode_header_string, TextEncoding};
+use crate::error::ApfError;
+use crate::signature::{peek_signature, ImageKind};
+use crate::unpack::{read_bytes_at, stream_len, FirmwareCode, FLASH_SIZE_GROW, RKFW_HEADER_SIZE, RKFW_V6_LAYOUT};
+use crate::{UpdateHeader, UpdatePart, MAX_FULL_PATH_LEN, MAX_ID_LEN, MAX_MANUFACTURER_LEN, MAX_MODEL_LEN, MAX_NAME_LEN, MAX_PARTS, RESERVED_LEN};
+
+/// Bytes of the file shown as a hexdump by `dump_header`
+pub const DUMP_HEAD_LEN: usize = 256;
+
+/// One header field as stored in the file, see `dump_header`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HeaderField {
+    /// Offset from the start of the file
+    pub offset: u64,
+    /// e.g. `boot_offset`, `parts[2].part_offset` or `embedded.model` for the RKAF header inside
+    /// an RKFW image
+    pub name: String,
+    /// The stored bytes, fewer than the field length when the file ends inside it
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hex"))]
+    pub raw: Vec<u8>,
+    /// The interpretation the parser uses, without checking it
+    pub value: String,
+}
+
+/// Raw view of the headers of an image for debugging one that does not parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HeaderDump {
+    pub kind: ImageKind,
+    /// RKFW header fields, then those of the embedded RKAF header when its magic is found at
+    /// `update_offset`; RKAF header fields for RKAF images; none for other files
+    pub fields: Vec<HeaderField>,
+    /// The first `DUMP_HEAD_LEN` bytes of the file
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hex"))]
+    pub head: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+}
+
+fn le_u32(raw: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes[..raw.len().min(4)].copy_from_slice(&raw[..raw.len().min(4)]);
+    u32::from_le_bytes(bytes)
+}
+
+fn text(raw: &[u8]) -> String {
+    decode_header_string(raw, TextEncoding::Auto).unwrap_or_else(|| String::from_utf8_lossy(raw).trim_end_matches('\0').to_string())
+}
+
+fn number(raw: &[u8]) -> String {
+    let value = le_u32(raw);
+    format!("{:#x} ({})", value, value)
+}
+
+fn zeroed(raw: &[u8]) -> String {
+    if raw.iter().all(|&byte| byte == 0) { "all zero".to_string() } else { "not zero".to_string() }
+}
+
+// Collects fields out of `header`, a header read from `base` in the file that may be cut short
+struct Fields<'a> {
+    header: &'a [u8],
+    base: u64,
+    prefix: &'a str,
+    fields: Vec<HeaderField>,
+}
+
+impl Fields<'_> {
+    fn add(&mut self, offset: usize, len: usize, name: &str, decode: impl Fn(&[u8]) -> String) {
+        let raw = self.header.get(offset..).map(|rest| &rest[..rest.len().min(len)]).unwrap_or_default();
+        let value = if raw.len() < len { "(truncated)".to_string() } else { decode(raw) };
+        let name = format!("{}{}", self.prefix, name);
+        self.fields.push(HeaderField { offset: self.base + offset as u64, name, raw: raw.to_vec(), value });
+    }
+}
+
+// Field offsets follow `parse_rkfw` and `write_rkfw`, the legacy layout; `parse_rkfw` only
+// detects the `RKFW_V6_LAYOUT` one
+fn rkfw_fields(fields: &mut Fields) {
+    fields.add(0x00, 4, "magic", |raw| String::from_utf8_lossy(raw).to_string());
+    fields.add(0x04, 1, "header_size", |raw| format!("{:#x}", raw[0]));
+    fields.add(0x05, 1, "layout", |raw| match raw[0] {
+        RKFW_V6_LAYOUT => format!("{} (v6 layout, not supported)", raw[0]),
+        layout => format!("{} (legacy layout)", layout),
+    });
+    fields.add(0x06, 4, "version", |raw| format!("{}.{}.{}", raw[3], raw[2], u16::from_le_bytes([raw[0], raw[1]])));
+    fields.add(0x0a, 4, "code", |raw| format!("{:#010x} (merge version {})", le_u32(raw), FirmwareCode(le_u32(raw))));
+    fields.add(0x0e, 7, "date", |raw| {
+        let date = format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", u16::from_le_bytes([raw[0], raw[1]]), raw[2], raw[3], raw[4], raw[5], raw[6]);
+        let valid = chrono::NaiveDate::from_ymd_opt(u16::from_le_bytes([raw[0], raw[1]]) as i32, raw[2] as u32, raw[3] as u32).is_some()
+            && chrono::NaiveTime::from_hms_opt(raw[4] as u32, raw[5] as u32, raw[6] as u32).is_some();
+        if valid { date } else { format!("{} (invalid)", date) }
+    });
+    fields.add(0x15, 1, "chip_code", |raw| format!("{:#04x} ({})", raw[0], chip_family(raw[0]).unwrap_or("unknown")));
+    fields.add(0x16, 3, "chip_digits", |raw| raw.iter().rev().map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' }).collect());
+    fields.add(0x19, 4, "boot_offset", number);
+    fields.add(0x1d, 4, "boot_size", number);
+    fields.add(0x21, 4, "update_offset", number);
+    fields.add(0x25, 4, "update_size", number);
+    fields.add(0x29, RKFW_HEADER_SIZE - 0x29, "reserved", zeroed);
+}
+
+fn rkaf_fields(fields: &mut Fields) {
+    fields.add(offset_of!(UpdateHeader, magic), 4, "magic", |raw| String::from_utf8_lossy(raw).to_string());
+    fields.add(offset_of!(UpdateHeader, length), 4, "length", |raw| format!("{:#x} (RKCRC offset)", le_u32(raw)));
+    fields.add(offset_of!(UpdateHeader, model), MAX_MODEL_LEN, "model", text);
+    fields.add(offset_of!(UpdateHeader, id), MAX_ID_LEN, "id", text);
+    fields.add(offset_of!(UpdateHeader, manufacturer), MAX_MANUFACTURER_LEN, "manufacturer", text);
+    fields.add(offset_of!(UpdateHeader, unknown1), 4, "unknown1", |raw| format!("{:#010x}", le_u32(raw)));
+    fields.add(offset_of!(UpdateHeader, version), 4, "version", |raw| format!("{:#010x} ({})", le_u32(raw), FirmwareCode(le_u32(raw))));
+    let num_parts_offset = offset_of!(UpdateHeader, num_parts);
+    fields.add(num_parts_offset, 4, "num_parts", |raw| {
+        let num_parts = le_u32(raw);
+        if num_parts as usize > MAX_PARTS { format!("{} (more than {})", num_parts, MAX_PARTS) } else { num_parts.to_string() }
+    });
+    let num_parts = fields.header.get(num_parts_offset..num_parts_offset + 4).map_or(0, le_u32);
+    // A corrupt count still shows the table, up to the room the header has
+    for index in 0..(num_parts as usize).min(MAX_PARTS) {
+        let part = offset_of!(UpdateHeader, parts) + index * size_of::<UpdatePart>();
+        let name = |field: &str| format!("parts[{}].{}", index, field);
+        fields.add(part + offset_of!(UpdatePart, name), MAX_NAME_LEN, &name("name"), text);
+        fields.add(part + offset_of!(UpdatePart, full_path), MAX_FULL_PATH_LEN, &name("full_path"), text);
+        fields.add(part + offset_of!(UpdatePart, flash_size), 4, &name("flash_size"), |raw| match le_u32(raw) {
+            FLASH_SIZE_GROW => format!("{:#x} (grow)", FLASH_SIZE_GROW),
+            sectors => format!("{:#x} sectors", sectors),
+        });
+        fields.add(part + offset_of!(UpdatePart, part_offset), 4, &name("part_offset"), number);
+        fields.add(part + offset_of!(UpdatePart, flash_offset), 4, &name("flash_offset"), |raw| format!("{:#x} sectors", le_u32(raw)));
+        fields.add(part + offset_of!(UpdatePart, padded_size), 4, &name("padded_size"), number);
+        fields.add(part + offset_of!(UpdatePart, part_byte_count), 4, &name("part_byte_count"), number);
+    }
+    fields.add(offset_of!(UpdateHeader, reserved), RESERVED_LEN, "reserved", zeroed);
+}
+
+/// Reads the RKFW or RKAF header of `file_path` field by field, with offset, raw bytes and the
+/// value the parser would take, plus the first `DUMP_HEAD_LEN` bytes (`afptool-rs dump-header
+/// FILE`). Nothing is validated, so it also works on images that fail to unpack; fields past
+/// the end of the file are reported as truncated.
+pub fn dump_header(file_path: &str) -> Result<HeaderDump, ApfError> {
+    let mut file = File::open(file_path)?;
+    let filesize = stream_len(&mut file)?;
+    // Whatever fits in the file, the fields report what is missing
+    let read_header = |file: &mut File, offset: u64, len: usize| read_bytes_at(file, offset, len.min(filesize.saturating_sub(offset) as usize));
+    let head = read_header(&mut file, 0, DUMP_HEAD_LEN)?;
+    let kind = peek_signature(&head).unwrap_or(ImageKind::Unknown);
+    let mut fields = Vec::new();
+    match kind {
+        ImageKind::Rkfw => {
+            let header = read_header(&mut file, 0, RKFW_HEADER_SIZE)?;
+            let mut rkfw = Fields { header: &header, base: 0, prefix: "", fields: Vec::new() };
+            rkfw_fields(&mut rkfw);
+            fields = rkfw.fields;
+            let update_offset = header.get(0x21..0x25).map_or(0, le_u32) as u64;
+            if update_offset > 0 && update_offset < filesize {
+                let embedded = read_header(&mut file, update_offset, size_of::<UpdateHeader>())?;
+                if UpdateHeader::has_rkaf_magic(&embedded) {
+                    let mut rkaf = Fields { header: &embedded, base: update_offset, prefix: "embedded.", fields };
+                    rkaf_fields(&mut rkaf);
+                    fields = rkaf.fields;
+                }
+            }
+        }
+        ImageKind::Rkaf => {
+            let header = read_header(&mut file, 0, size_of::<UpdateHeader>())?;
+            let mut rkaf = Fields { header: &header, base: 0, prefix: "", fields: Vec::new() };
+            rkaf_fields(&mut rkaf);
+            fields = rkaf.fields;
+        }
+        _ => {}
+    }
+    Ok(HeaderDump { kind, fields, head })
+}
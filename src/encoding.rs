@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{GBK, UTF_16LE};
+
+/// Encoding of the manufacturer, model and partition name fields in an RKAF header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    /// UTF-16LE when every other byte is zero, otherwise UTF-8, then GBK, then lossy UTF-8
+    #[default]
+    Auto,
+    Utf8,
+    /// Used by some Chinese vendors; decoded as GB18030, a superset of GBK and GB2312
+    Gbk,
+    Utf16Le,
+}
+
+impl std::str::FromStr for TextEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(TextEncoding::Auto),
+            "utf8" | "utf-8" => Ok(TextEncoding::Utf8),
+            "gbk" | "gb2312" | "gb18030" => Ok(TextEncoding::Gbk),
+            "utf16" | "utf-16" | "utf16le" | "utf-16le" => Ok(TextEncoding::Utf16Le),
+            _ => Err(anyhow!("Unsupported text encoding: {} (expected auto, utf8, gbk or utf16le)", s)),
+        }
+    }
+}
+
+// ASCII text stored as UTF-16LE has a zero high byte after each of the first characters
+fn looks_like_utf16le(field: &[u8]) -> bool {
+    field.len() >= 4 && field[0] != 0 && field[1] == 0 && field[2] != 0 && field[3] == 0
+}
+
+fn until_nul(field: &[u8]) -> Option<&[u8]> {
+    field.iter().position(|&b| b == 0).map(|end| &field[..end])
+}
+
+fn until_nul_utf16(field: &[u8]) -> Option<&[u8]> {
+    field
+        .chunks_exact(2)
+        .position(|unit| unit == [0, 0])
+        .map(|units| &field[..units * 2])
+}
+
+/// Decodes a NUL-terminated header field, `None` when the field has no terminator
+pub fn decode_header_string(field: &[u8], encoding: TextEncoding) -> Option<String> {
+    let encoding = match encoding {
+        TextEncoding::Auto if looks_like_utf16le(field) => TextEncoding::Utf16Le,
+        encoding => encoding,
+    };
+    let text = match encoding {
+        TextEncoding::Utf16Le => {
+            return until_nul_utf16(field).map(|text| UTF_16LE.decode_without_bom_handling(text).0.into_owned());
+        }
+        _ => until_nul(field)?,
+    };
+
+    match encoding {
+        TextEncoding::Gbk => Some(GBK.decode_without_bom_handling(text).0.into_owned()),
+        TextEncoding::Auto => match std::str::from_utf8(text) {
+            Ok(text) => Some(text.to_string()),
+            Err(_) => Some(
+                GBK.decode_without_bom_handling_and_without_replacement(text)
+                    .map(|text| text.into_owned())
+                    .unwrap_or_else(|| String::from_utf8_lossy(text).into_owned()),
+            ),
+        },
+        _ => Some(String::from_utf8_lossy(text).into_owned()),
+    }
+}
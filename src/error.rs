@@ -0,0 +1,66 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ApfError {
+    #[error("Unknown signature: {0:?}")]
+    UnknownSignature([u8; 4]),
+
+    #[error("Invalid header magic id")]
+    BadMagic,
+
+    #[error("CRC mismatch: computed {actual:#010x}, stored {expected:#010x}")]
+    CrcMismatch { expected: u32, actual: u32 },
+
+    /// The MD5 digest at the end of an RKFW image, both as lowercase hex
+    #[error("MD5 mismatch: computed {actual}, stored {expected}")]
+    Md5Mismatch { expected: String, actual: String },
+
+    /// The input ends before a header or region it describes
+    #[error("{0}")]
+    Truncated(String),
+
+    #[error("Invalid date")]
+    InvalidDate,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Stopped because the caller set the cancel flag
+    #[error("Cancelled")]
+    Cancelled,
+
+    /// Malformed input or metadata that fits none of the other variants
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// `std::io::Error` cannot be cloned, the copy keeps its kind and message
+impl Clone for ApfError {
+    fn clone(&self) -> Self {
+        match self {
+            ApfError::UnknownSignature(signature) => ApfError::UnknownSignature(*signature),
+            ApfError::BadMagic => ApfError::BadMagic,
+            ApfError::CrcMismatch { expected, actual } => ApfError::CrcMismatch { expected: *expected, actual: *actual },
+            ApfError::Md5Mismatch { expected, actual } => ApfError::Md5Mismatch { expected: expected.clone(), actual: actual.clone() },
+            ApfError::Truncated(message) => ApfError::Truncated(message.clone()),
+            ApfError::InvalidDate => ApfError::InvalidDate,
+            ApfError::Io(err) => ApfError::Io(std::io::Error::new(err.kind(), err.to_string())),
+            ApfError::Cancelled => ApfError::Cancelled,
+            ApfError::Invalid(message) => ApfError::Invalid(message.clone()),
+        }
+    }
+}
+
+// Internals use anyhow; recover the typed error where one was raised
+impl From<anyhow::Error> for ApfError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<ApfError>() {
+            Ok(apf) => return apf,
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(io) => ApfError::Io(io),
+            Err(err) => ApfError::Invalid(format!("{:#}", err)),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::error::ApfError;
+
+// Flattened device tree layout, see the devicetree specification
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+// Deeper trees than this are not FIT images and likely corrupt
+const MAX_DEPTH: usize = 16;
+
+/// One image inside a FIT (Flattened Image Tree) container as written by U-Boot's mkimage, e.g.
+/// the U-Boot proper, ATF and OP-TEE parts of Rockchip `uboot` and `trust` partitions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FitComponent {
+    /// Node name under `/images`, e.g. `uboot`, `atf-1` or `optee`
+    pub name: String,
+    pub description: String,
+    /// `type` property, e.g. `standalone`, `firmware`, `kernel` or `flat_dt`
+    pub kind: String,
+    pub arch: String,
+    pub os: String,
+    pub compression: String,
+    pub load: Option<u64>,
+    pub entry: Option<u64>,
+    /// Offset of the image data from the start of the FIT; data appended with `mkimage -E`
+    /// (`data-offset`, counted from the end of the tree) is converted
+    pub offset: Option<u64>,
+    pub size: Option<u64>,
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset.checked_add(4)?).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn prop_string(value: &[u8]) -> String {
+    let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+    String::from_utf8_lossy(&value[..len]).to_string()
+}
+
+// `addr` cells are one or two big-endian u32
+fn prop_number(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => be_u32(value, 0).map(u64::from),
+        8 => Some(((be_u32(value, 0)? as u64) << 32) | be_u32(value, 4)? as u64),
+        _ => None,
+    }
+}
+
+fn truncated(what: &str, offset: usize) -> ApfError {
+    ApfError::Truncated(format!("FIT {} at offset {:#x} is out of bounds", what, offset))
+}
+
+type Visitor<'a> = dyn FnMut(&[String], &str, usize, &[u8]) -> bool + 'a;
+
+// Calls `visit(node names from the root, property name, value offset, value)` for every property
+// and `visit(path, "", _, &[])` when a node starts, until it returns false. Property names are
+// read from the strings block only when `strings` is set, so a tree cut short after its nodes
+// can still be walked.
+fn walk_tree(data: &[u8], strings: bool, visit: &mut Visitor) -> Result<(), ApfError> {
+    if be_u32(data, 0) != Some(FDT_MAGIC) {
+        return Err(ApfError::BadMagic);
+    }
+    if data.len() < FDT_HEADER_SIZE {
+        return Err(truncated("header", 0));
+    }
+    let struct_offset = be_u32(data, 8).unwrap_or_default() as usize;
+    let strings_offset = be_u32(data, 12).unwrap_or_default() as usize;
+
+    let mut path: Vec<String> = Vec::new();
+    let mut pos = struct_offset;
+    loop {
+        let token = be_u32(data, pos).ok_or_else(|| truncated("structure block", pos))?;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let rest = data.get(pos..).ok_or_else(|| truncated("node name", pos))?;
+                let len = rest.iter().position(|&b| b == 0).ok_or_else(|| truncated("node name", pos))?;
+                path.push(String::from_utf8_lossy(&rest[..len]).to_string());
+                if path.len() > MAX_DEPTH {
+                    return Err(ApfError::Invalid(format!("FIT tree nested deeper than {} nodes", MAX_DEPTH)));
+                }
+                pos = (pos + len + 1).next_multiple_of(4);
+                if !visit(&path, "", pos, &[]) {
+                    return Ok(());
+                }
+            }
+            FDT_END_NODE => {
+                if path.pop().is_none() {
+                    return Err(ApfError::Invalid(format!("Unbalanced FIT node end at offset {:#x}", pos - 4)));
+                }
+            }
+            FDT_PROP => {
+                let len = be_u32(data, pos).ok_or_else(|| truncated("property", pos))? as usize;
+                let name_offset = be_u32(data, pos + 4).ok_or_else(|| truncated("property", pos))? as usize;
+                let value_offset = pos + 8;
+                let value = data.get(value_offset..value_offset + len).ok_or_else(|| truncated("property value", value_offset))?;
+                pos = (value_offset + len).next_multiple_of(4);
+                let name = if strings {
+                    let start = strings_offset.checked_add(name_offset).ok_or_else(|| truncated("property name", strings_offset))?;
+                    prop_string(data.get(start..).ok_or_else(|| truncated("property name", start))?)
+                } else {
+                    String::new()
+                };
+                if !visit(&path, &name, value_offset, value) {
+                    return Ok(());
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return Ok(()),
+            _ => return Err(ApfError::Invalid(format!("Unknown FIT structure token {:#x} at offset {:#x}", token, pos - 4))),
+        }
+    }
+}
+
+// A device tree with an `/images` node; only the nodes before it have to be present
+pub(crate) fn is_fit(data: &[u8]) -> bool {
+    let mut found = false;
+    let _ = walk_tree(data, false, &mut |path, name, _, _| {
+        found = path.len() == 2 && name.is_empty() && path[1] == "images";
+        !found
+    });
+    found
+}
+
+/// Lists the images of a FIT container from its `/images` node; external data (`mkimage -E`)
+/// is located with `data-offset` or `data-position`. `data` has to hold at least the whole tree
+/// (the FDT `totalsize`), the image data itself is not needed
+pub fn parse_fit(data: &[u8]) -> Result<Vec<FitComponent>, ApfError> {
+    // External data starts after the tree, aligned to 4 bytes
+    let data_base = (be_u32(data, 4).unwrap_or_default() as u64).next_multiple_of(4);
+    let mut components: Vec<FitComponent> = Vec::new();
+    let mut has_images = false;
+    walk_tree(data, true, &mut |path, name, value_offset, value| {
+        if path.len() == 2 && path[1] == "images" {
+            has_images = true;
+        }
+        if path.len() != 3 || path[1] != "images" {
+            return true;
+        }
+        if name.is_empty() {
+            components.push(FitComponent { name: path[2].clone(), ..FitComponent::default() });
+            return true;
+        }
+        let Some(component) = components.last_mut() else { return true };
+        match name {
+            "description" => component.description = prop_string(value),
+            "type" => component.kind = prop_string(value),
+            "arch" => component.arch = prop_string(value),
+            "os" => component.os = prop_string(value),
+            "compression" => component.compression = prop_string(value),
+            "load" => component.load = prop_number(value),
+            "entry" => component.entry = prop_number(value),
+            "data" => {
+                // Inline data follows the property header inside the structure block
+                component.size = Some(value.len() as u64);
+                component.offset = Some(value_offset as u64);
+            }
+            "data-offset" => component.offset = prop_number(value).map(|offset| data_base + offset),
+            "data-position" => component.offset = prop_number(value),
+            "data-size" => component.size = prop_number(value),
+            _ => {}
+        }
+        true
+    })?;
+    if !has_images {
+        return Err(ApfError::Invalid("Device tree has no /images node, not a FIT image".to_string()));
+    }
+    Ok(components)
+}
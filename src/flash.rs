@@ -0,0 +1,33 @@
+use std::fmt::Write;
+use crate::kind::PartitionKind;
+use crate::unpack::PartitionInfo;
+
+/// rkdeveloptool script written next to the extracted partitions
+pub(crate) const FLASH_SCRIPT_FILE: &str = "flash.sh";
+
+fn shell_quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+// `rkdeveloptool wl <sector> <file>` for every extracted partition in flash order; offsets in the
+// partition table are already 512-byte sectors. The loader is downloaded with `ul` instead.
+pub(crate) fn flash_script(entries: &[(&PartitionInfo, &str)], sparse_expanded: bool) -> String {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|(part, _)| part.flash_offset);
+
+    let mut script = String::from("#!/bin/sh\n# Written by afptool-rs, run from this directory with the device in loader mode\nset -e\n");
+    for (part, path) in entries {
+        if part.kind == PartitionKind::Loader && part.name.eq_ignore_ascii_case("bootloader") {
+            let _ = writeln!(script, "rkdeveloptool ul {}", shell_quote(path));
+            continue;
+        }
+        let size = if part.is_grow() {
+            "grows to the end of flash".to_string()
+        } else {
+            format!("{:#x} sectors", part.flash_size)
+        };
+        let note = if part.sparse && !sparse_expanded { ", Android sparse image, unpack with --expand-sparse to flash raw" } else { "" };
+        let _ = writeln!(script, "rkdeveloptool wl {:#010x} {} # {}, {}{}", part.flash_offset, shell_quote(path), part.name, size, note);
+    }
+    script
+}
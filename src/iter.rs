@@ -0,0 +1,113 @@
+use std::io::{Read, Seek, SeekFrom, Take};
+use std::path::PathBuf;
+use crate::error::ApfError;
+use crate::signature::ImageKind;
+use crate::split::{split_parts, MultiFileReader};
+use crate::unpack::{container_entry, find_image, is_selected, nested_rkfw_error, read_rkafp, read_rkfw, stream_len, PartitionInfo, RegionReader, UnpackOptions};
+
+/// Partitions of an image one at a time, see `Unpacker::into_iter_partitions`. Each item opens
+/// the image again, so readers are independent of each other and of the iterator.
+pub struct PartitionIter {
+    paths: Vec<PathBuf>,
+    partitions: std::vec::IntoIter<PartitionInfo>,
+}
+
+/// The bytes of one partition, read straight from the image
+pub struct PartitionReader {
+    inner: Take<MultiFileReader>,
+}
+
+impl PartitionReader {
+    /// Bytes left to read
+    pub fn remaining(&self) -> u64 {
+        self.inner.limit()
+    }
+}
+
+impl Read for PartitionReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl PartitionIter {
+    // Runs the same checks as unpacking; `part_offset` of every partition is made relative to
+    // the start of the input, like `list_partitions` does for RKFW images
+    pub(crate) fn open(file_path: &str, options: &UnpackOptions) -> Result<Self, ApfError> {
+        let paths = split_parts(file_path).unwrap_or_else(|| vec![PathBuf::from(file_path)]);
+        let mut input = MultiFileReader::open(&paths)?;
+        let (kind, base) = find_image(&mut input, options)?;
+        let len = stream_len(&mut input)? - base;
+        let mut image = RegionReader::new(&mut input, base, len);
+
+        let mut partitions = Vec::new();
+        let embedded = if kind == ImageKind::Rkfw {
+            let info = read_rkfw(&mut image, options)?;
+            if info.nested.is_some() && options.recursive {
+                return Err(nested_rkfw_error(&info));
+            }
+            if info.has_boot {
+                partitions.push(container_entry("BOOT", info.boot_offset, info.boot_size));
+            }
+            if options.recursive {
+                Some((info.update_offset, info.update_size as u64))
+            } else {
+                partitions.push(container_entry("embedded-update.img", info.update_offset, info.update_size));
+                None
+            }
+        } else {
+            Some((0, len))
+        };
+        if let Some((offset, size)) = embedded {
+            let rkaf = read_rkafp(&mut RegionReader::new(&mut image, offset as u64, size), options)?;
+            partitions.extend(rkaf.info.partitions.into_iter().filter(|part| is_selected(part, options)).map(|mut part| {
+                part.part_offset = part.part_offset.saturating_add(offset);
+                part
+            }));
+        }
+        Ok(Self::new(paths, partitions, base))
+    }
+
+    fn new(paths: Vec<PathBuf>, mut partitions: Vec<PartitionInfo>, base: u64) -> Self {
+        for part in partitions.iter_mut() {
+            part.part_offset = part.part_offset.saturating_add(base as u32);
+        }
+        Self { paths, partitions: partitions.into_iter() }
+    }
+
+    // The input files and the partitions not yielded yet, for readers that need random access
+    #[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+    pub(crate) fn into_parts(self) -> (Vec<PathBuf>, Vec<PartitionInfo>) {
+        (self.paths, self.partitions.collect())
+    }
+
+    fn reader(&self, part: &PartitionInfo) -> Result<PartitionReader, ApfError> {
+        let mut input = MultiFileReader::open(&self.paths)?;
+        let end = part.part_offset as u64 + part.part_byte_count as u64;
+        if end > input.len() {
+            return Err(ApfError::Truncated(format!(
+                "{} ends at {:#x}, past the end of the image ({} bytes)",
+                part.name,
+                end,
+                input.len()
+            )));
+        }
+        input.seek(SeekFrom::Start(part.part_offset as u64))?;
+        Ok(PartitionReader { inner: input.take(part.part_byte_count as u64) })
+    }
+}
+
+impl Iterator for PartitionIter {
+    type Item = Result<(PartitionInfo, PartitionReader), ApfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let part = self.partitions.next()?;
+        Some(self.reader(&part).map(|reader| (part, reader)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.partitions.size_hint()
+    }
+}
+
+impl ExactSizeIterator for PartitionIter {}
@@ -0,0 +1,75 @@
+use std::fmt;
+use anyhow::{anyhow, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Role of a partition, guessed from its name by `PartitionKind::from_name`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum PartitionKind {
+    /// The `parameter` file declaring the flash layout
+    Parameter,
+    /// Boot loader stages: `loader`, `bootloader`, `idbloader`, `miniloader`, `uboot`, `trust`, `spl`, ...
+    Loader,
+    /// Android boot images: `boot`, `recovery`
+    Boot,
+    /// Kernel and device tree: `kernel`, `dtb`, `dtbo`, `resource`
+    Kernel,
+    /// Root file systems: `system`, `rootfs`, `vendor`, `oem`, `product`, `odm`
+    System,
+    /// `backup`, which may carry a second copy of the parameter or loader
+    Backup,
+    #[default]
+    Other,
+}
+
+impl PartitionKind {
+    /// Case-insensitive match on the partition name with any `_a`/`_b` slot suffix removed;
+    /// use `UnpackOptions::kind_overrides` for vendor-specific names
+    pub fn from_name(name: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        let base = name.strip_suffix("_a").or_else(|| name.strip_suffix("_b")).unwrap_or(&name);
+        match base {
+            "parameter" => PartitionKind::Parameter,
+            "loader" | "bootloader" | "idbloader" | "miniloader" | "uboot" | "u-boot" | "trust" | "spl" => PartitionKind::Loader,
+            "boot" | "recovery" => PartitionKind::Boot,
+            "kernel" | "dtb" | "dtbo" | "resource" => PartitionKind::Kernel,
+            "system" | "rootfs" | "vendor" | "oem" | "product" | "odm" => PartitionKind::System,
+            "backup" => PartitionKind::Backup,
+            _ => PartitionKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PartitionKind::Parameter => "parameter",
+            PartitionKind::Loader => "loader",
+            PartitionKind::Boot => "boot",
+            PartitionKind::Kernel => "kernel",
+            PartitionKind::System => "system",
+            PartitionKind::Backup => "backup",
+            PartitionKind::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for PartitionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "parameter" => Ok(PartitionKind::Parameter),
+            "loader" => Ok(PartitionKind::Loader),
+            "boot" => Ok(PartitionKind::Boot),
+            "kernel" => Ok(PartitionKind::Kernel),
+            "system" => Ok(PartitionKind::System),
+            "backup" => Ok(PartitionKind::Backup),
+            "other" => Ok(PartitionKind::Other),
+            _ => Err(anyhow!("Unsupported partition kind: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::parameter::ParameterPartition;
+use crate::unpack::{RkafInfo, FLASH_SIZE_GROW};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum LayoutWarning {
+    /// `first` extends `bytes` past the start of `second`
+    Overlap { first: String, second: String, bytes: u64 },
+    /// Unused space between the padded end of `after` and the start of `before`
+    Gap { after: String, before: String, bytes: u64 },
+    /// `name` ends at `end`, past the end of the image
+    OutOfBounds { name: String, end: u64, filesize: u64 },
+    /// The parameter file places `name` differently than the partition table; `field` is
+    /// `flash_offset` or `flash_size`, values are in sectors
+    ParameterMismatch { name: String, field: String, parameter: u32, header: u32 },
+    /// `name` starts at `offset`, which is not a multiple of the 512-byte sector size
+    Misaligned { name: String, offset: u32 },
+    /// `count` partitions are named `name`
+    DuplicateName { name: String, count: usize },
+    /// `count` partitions are stored under `path`, so they would be extracted to the same file
+    DuplicatePath { path: String, count: usize },
+    /// `padded_size` is smaller than `part_byte_count`, so the data runs into the space after it
+    PaddedSizeTooSmall { name: String, part_byte_count: u32, padded_size: u32 },
+    /// `padded_size` reserves a whole `alignment` or more beyond the data, so it is not
+    /// `part_byte_count` rounded up to the image alignment
+    UnroundedPaddedSize { name: String, part_byte_count: u32, padded_size: u32, alignment: u32 },
+}
+
+impl fmt::Display for LayoutWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutWarning::Overlap { first, second, bytes } => {
+                write!(f, "{} overlaps {} by {} bytes", first, second, bytes)
+            }
+            LayoutWarning::Gap { after, before, bytes } => {
+                write!(f, "{} unused bytes between {} and {}", bytes, after, before)
+            }
+            LayoutWarning::OutOfBounds { name, end, filesize } => {
+                write!(f, "{} ends at {:#x}, past the end of the file ({} bytes)", name, end, filesize)
+            }
+            LayoutWarning::ParameterMismatch { name, field, parameter, header } => {
+                write!(f, "{}: parameter has {} {:#x}, partition table has {:#x}", name, field, parameter, header)
+            }
+            LayoutWarning::Misaligned { name, offset } => {
+                write!(f, "{} starts at {:#x}, which is not sector aligned", name, offset)
+            }
+            LayoutWarning::DuplicateName { name, count } => {
+                write!(f, "{} partitions are named {}", count, name)
+            }
+            LayoutWarning::DuplicatePath { path, count } => {
+                write!(f, "{} partitions are stored as {}", count, path)
+            }
+            LayoutWarning::PaddedSizeTooSmall { name, part_byte_count, padded_size } => {
+                write!(f, "{} has {:#x} bytes of data but a padded size of only {:#x}", name, part_byte_count, padded_size)
+            }
+            LayoutWarning::UnroundedPaddedSize { name, part_byte_count, padded_size, alignment } => {
+                write!(f, "{} has a padded size of {:#x} for {:#x} bytes of data, not a rounding up to {:#x}", name, padded_size, part_byte_count, alignment)
+            }
+        }
+    }
+}
+
+const SECTOR_SIZE: u32 = 512;
+
+/// Largest power of two dividing every partition offset, i.e. the padding granularity the
+/// image was packed with; `None` when there is no partition at a non-zero offset
+pub fn detect_alignment(info: &RkafInfo) -> Option<u32> {
+    let combined = info.partitions.iter().fold(0u32, |acc, part| acc | part.part_offset);
+    if combined == 0 {
+        return None;
+    }
+    Some(1 << combined.trailing_zeros())
+}
+
+// Every value used more than once with its count, in order of first use
+fn duplicates<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for value in values {
+        match counts.iter_mut().find(|(seen, _)| seen == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value.to_string(), 1)),
+        }
+    }
+    counts.retain(|&(_, count)| count > 1);
+    counts
+}
+
+pub fn validate_layout(info: &RkafInfo, filesize: u64) -> Vec<LayoutWarning> {
+    let mut warnings = Vec::new();
+    for (name, count) in duplicates(info.partitions.iter().map(|part| part.name.as_str())) {
+        warnings.push(LayoutWarning::DuplicateName { name, count });
+    }
+    for (path, count) in duplicates(info.partitions.iter().map(|part| part.path.as_str())) {
+        warnings.push(LayoutWarning::DuplicatePath { path, count });
+    }
+
+    let mut parts: Vec<_> = info.partitions.iter().collect();
+    parts.sort_by_key(|part| part.part_offset);
+
+    let alignment = detect_alignment(info);
+    for part in &parts {
+        let end = part.part_offset as u64 + part.part_byte_count as u64;
+        if end > filesize {
+            warnings.push(LayoutWarning::OutOfBounds {
+                name: part.name.clone(),
+                end,
+                filesize,
+            });
+        }
+        if part.padded_size < part.part_byte_count {
+            warnings.push(LayoutWarning::PaddedSizeTooSmall {
+                name: part.name.clone(),
+                part_byte_count: part.part_byte_count,
+                padded_size: part.padded_size,
+            });
+        } else if let Some(alignment) = alignment {
+            // Empty partitions may still reserve one unit
+            let slack = part.padded_size - part.part_byte_count;
+            if slack >= alignment && !(part.part_byte_count == 0 && slack == alignment) {
+                warnings.push(LayoutWarning::UnroundedPaddedSize {
+                    name: part.name.clone(),
+                    part_byte_count: part.part_byte_count,
+                    padded_size: part.padded_size,
+                    alignment,
+                });
+            }
+        }
+    }
+
+    if alignment.is_some_and(|alignment| alignment < SECTOR_SIZE) {
+        for part in parts.iter().filter(|part| part.part_offset % SECTOR_SIZE != 0) {
+            warnings.push(LayoutWarning::Misaligned {
+                name: part.name.clone(),
+                offset: part.part_offset,
+            });
+        }
+    }
+
+    for pair in parts.windows(2) {
+        let (current, next) = (pair[0], pair[1]);
+        let data_end = current.part_offset as u64 + current.part_byte_count as u64;
+        let padded_end = current.part_offset as u64 + std::cmp::max(current.padded_size, current.part_byte_count) as u64;
+        let next_offset = next.part_offset as u64;
+
+        if data_end > next_offset {
+            warnings.push(LayoutWarning::Overlap {
+                first: current.name.clone(),
+                second: next.name.clone(),
+                bytes: data_end - next_offset,
+            });
+        } else if padded_end < next_offset {
+            warnings.push(LayoutWarning::Gap {
+                after: current.name.clone(),
+                before: next.name.clone(),
+                bytes: next_offset - padded_end,
+            });
+        }
+    }
+
+    warnings
+}
+
+// Partitions only the parameter declares (e.g. userdata) are not in the image and are skipped
+pub fn validate_parameter_layout(parameter: &[ParameterPartition], info: &RkafInfo) -> Vec<LayoutWarning> {
+    let mut warnings = Vec::new();
+    for declared in parameter {
+        let Some(part) = info.partitions.iter().find(|part| part.name == declared.name) else {
+            continue;
+        };
+        let mut compare = |field: &str, parameter: u32, header: u32| {
+            if parameter != header {
+                warnings.push(LayoutWarning::ParameterMismatch {
+                    name: declared.name.clone(),
+                    field: field.to_string(),
+                    parameter,
+                    header,
+                });
+            }
+        };
+        compare("flash_offset", declared.start_sector, part.flash_offset);
+        // `-` in the parameter file is the grow marker in the header
+        compare("flash_size", declared.sector_count.unwrap_or(FLASH_SIZE_GROW), part.flash_size);
+    }
+    warnings
+}
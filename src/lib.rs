@@ -1,9 +1,80 @@
 use std::mem;
+mod boot;
+mod bootimg;
+mod capabilities;
+mod checksum;
+mod chunks;
+mod chip;
+mod compression;
+mod content;
+mod crc;
+mod diff;
+mod dump;
+mod encoding;
+mod error;
+mod fit;
+mod flash;
+mod iter;
+mod kind;
+mod layout;
+mod manifest;
+mod metadata;
+#[cfg(feature = "fuse")]
+mod mount;
 mod pack;
+mod parallel;
+mod parameter;
+mod repack;
+mod replace;
+mod resource;
+mod signature;
+mod sparse;
+mod split;
+mod strings;
+mod tar;
 mod unpack;
+mod unpacker;
+mod verify;
 
-pub use pack::{pack_rkfw, pack_rkaf, chip_name_to_code, PackRkfwResult, PackRkafResult};
-pub use unpack::{unpack_file, UnpackResult, RkfwInfo, RkafInfo, PartitionInfo};
+pub use boot::{parse_boot, rc4_rk, BootEntry, BootEntryKind};
+pub use bootimg::{parse_boot_image, AndroidBootImage, BootImageSection, ANDROID_BOOT_MAGIC};
+pub use capabilities::{capabilities, Capabilities};
+pub use chunks::{Chunk, ChunkIndex, ChunkedPartition, CHUNKS_FILE};
+pub use checksum::{hash_partition, verify_against_manifest, HashAlgorithm};
+pub use chip::{all_chips, chip_family, CHIP_FAMILIES};
+pub use content::{detect_fs, FS_PROBE_LEN};
+pub use crc::{rkcrc, rkcrc_file, update_crc, CrcUpdate};
+pub use diff::{diff_images, PartitionDiff};
+pub use dump::{dump_header, HeaderDump, HeaderField, DUMP_HEAD_LEN};
+pub use encoding::{decode_header_string, TextEncoding};
+pub use error::ApfError;
+pub use fit::{parse_fit, FitComponent};
+pub use iter::{PartitionIter, PartitionReader};
+pub use kind::PartitionKind;
+pub use layout::{detect_alignment, validate_layout, validate_parameter_layout, LayoutWarning};
+pub use manifest::{planned_size, Manifest, MANIFEST_SCHEMA_VERSION};
+#[cfg(feature = "serde")]
+pub use manifest::read_manifest;
+pub use metadata::{MetadataFormat, PartitionSort};
+#[cfg(feature = "fuse")]
+pub use mount::mount_image;
+pub use pack::{make_firmware, pack_rkfw, repack_rkfw, pack_rkaf, pack_rkafp, pack_rkafp_with_alignment, pack_rkafp_with_options, check_metadata, chip_name_to_code, PackOptions, PackRkfwResult, PackRkafResult};
+pub use parameter::{generate_parameter, parse_parameter, ParameterPartition};
+pub use repack::{repack_dir, repack_dir_with_alignment, repack_dir_with_options, RepackResult};
+pub use replace::{replace_partition, ReplaceResult};
+pub use resource::{parse_resource, ResourceEntry};
+pub use signature::{find_signature, peek_signature, ImageKind, SIGNATURE_PEEK_LEN, SIGNATURE_SCAN_LEN};
+pub use sparse::{is_sparse, unsparse, SPARSE_HEADER_MAGIC};
+pub use split::{split_parts, MultiFileReader};
+pub use strings::find_strings;
+pub use tar::{unpack_reader_to_tar, unpack_to_tar, unpack_to_tar_with_options};
+pub use unpack::{carve_embedded_rkaf, extract_partition, unpack_file, unpack_file_with_options, unpack_file_with_progress, unpack_reader, unpack_reader_with_options, unpack_reader_with_progress, unpack_to_map, inspect_file, probe_file, human_size, OutputNaming, DEFAULT_BUFFER_SIZE, EMBEDDED_MAGIC_WINDOW, FLASH_SIZE_GROW, list_partitions, read_rkaf_header, verify_rkaf_crc, verify_rkaf_crc_with_progress, verify_rkafp_crc, verify_rkfw_md5, verify_rkfw_md5_reader, UnpackOptions, UnpackResult, UnpackStats, PartitionEvent, PartitionStatus, FirmwareCode, ReservedRegion, RkfwInfo, RkafInfo, PartitionInfo};
+#[cfg(feature = "serde")]
+pub use unpack::unpack_file_json;
+pub use unpacker::Unpacker;
+pub use verify::{default_verify_cache_path, verify_file, verify_reader, VerifyCheck, VerifyReport};
+#[cfg(feature = "serde")]
+pub use verify::verify_file_cached;
 
 pub const RKAFP_MAGIC: &str = "RKAF";
 pub const PARM_MAGIC: &str = "PARM";
@@ -13,35 +84,56 @@ const MAX_FULL_PATH_LEN: usize = 60;
 const MAX_MODEL_LEN: usize = 34;
 const MAX_ID_LEN: usize = 30;
 const MAX_MANUFACTURER_LEN: usize = 56;
+const RESERVED_LEN: usize = 116;
 pub const RKAF_SIGNATURE: &[u8] = b"RKAF";
 pub const RKFW_SIGNATURE: &[u8] = b"RKFW";
 pub const RKFP_SIGNATURE: &[u8] = b"RKFP";
 
+/// One entry of the RKAF partition table, as stored on disk
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 pub struct UpdatePart {
+    /// NUL-terminated partition name
     pub name: [u8; MAX_NAME_LEN],
+    /// NUL-terminated path of the partition file, `SELF` and `RESERVED` are special entries
     pub full_path: [u8; MAX_FULL_PATH_LEN],
+    /// Size of the partition on flash, in sectors
     pub flash_size: u32,
+    /// Byte offset of the partition data inside the update image, a 512-byte sector index in
+    /// some vendor images (see `RkafInfo::sector_offsets`)
     pub part_offset: u32,
+    /// Offset of the partition on flash, in sectors
     pub flash_offset: u32,
+    /// Space reserved for the data inside the update image, in bytes
     pub padded_size: u32,
+    /// Number of data bytes actually stored at `part_offset`
     pub part_byte_count: u32,
 }
 
+/// The 2048-byte header at the start of an RKAF update image
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 pub struct UpdateHeader {
+    /// Always `RKAF`
     pub magic: [u8; 4],
+    /// Offset of the trailing RKCRC, i.e. the image size minus 4
     pub length: u32,
+    /// NUL-terminated model name
     pub model: [u8; MAX_MODEL_LEN],
-    id: [u8; MAX_ID_LEN],
+    /// NUL-terminated machine id, taken from `MACHINE_ID` in parameter.txt
+    pub id: [u8; MAX_ID_LEN],
+    /// NUL-terminated manufacturer name
     pub manufacturer: [u8; MAX_MANUFACTURER_LEN],
+    /// Meaning unknown, preserved as-is on repack
     pub unknown1: u32,
+    /// Firmware version
     pub version: u32,
+    /// Number of valid entries in `parts`
     pub num_parts: u32,
     pub parts: [UpdatePart; MAX_PARTS],
-    reserved: [u8; 116],
+    /// Unused and normally zero, but some vendors keep build ids here; `pack_rkafp` restores
+    /// it from the `reserved:` line of `header-metadata.txt`
+    pub reserved: [u8; RESERVED_LEN],
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -51,8 +143,8 @@ pub struct ParamHeader {
     length: u32,
 }
 
-impl UpdateHeader {
-    pub fn default() -> Self {
+impl Default for UpdateHeader {
+    fn default() -> Self {
         Self {
             magic: [0u8; 4],
             length: 0,
@@ -63,21 +155,33 @@ impl UpdateHeader {
             version: 0,
             num_parts: 0,
             parts: [UpdatePart::default(); MAX_PARTS],
-            reserved: [0u8; 116],
+            reserved: [0u8; RESERVED_LEN],
         }
     }
+}
 
+impl UpdateHeader {
+    /// Panics if `bytes` is shorter than `size_of::<UpdateHeader>()`
     pub fn from_bytes(bytes: &[u8]) -> &UpdateHeader {
-        unsafe { mem::transmute(bytes.as_ptr()) }
+        assert!(bytes.len() >= mem::size_of::<UpdateHeader>(), "buffer too small for UpdateHeader");
+        unsafe { &*(bytes.as_ptr() as *const UpdateHeader) }
     }
 
     pub fn to_bytes(&self) -> &[u8] {
         unsafe { std::slice::from_raw_parts(self as *const _ as *const u8, mem::size_of::<UpdateHeader>()) }
     }
+
+    /// `bytes` starts with an `UpdateHeader` whose `magic` is `RKAFP_MAGIC`; only the magic field
+    /// has to be present. `magic` is the first four bytes of the header, so this accepts exactly
+    /// the data starting with the `RKAF` signature. Every place that accepts RKAF data, embedded
+    /// or not, checks this
+    pub fn has_rkaf_magic(bytes: &[u8]) -> bool {
+        bytes.starts_with(RKAFP_MAGIC.as_bytes())
+    }
 }
 
-impl UpdatePart {
-    pub fn default() -> Self {
+impl Default for UpdatePart {
+    fn default() -> Self {
         Self {
             name: [0u8; MAX_NAME_LEN],
             full_path: [0u8; MAX_FULL_PATH_LEN],
@@ -90,6 +194,8 @@ impl UpdatePart {
     }
 }
 
+/// Prints a `rkunpack:` prefixed message to stderr and exits when `is_fatal`; only built with the `cli` feature
+#[cfg(feature = "cli")]
 pub fn info_and_fatal(is_fatal: bool, message: String) {
     if is_fatal {
         eprint!("rkunpack: fatal: ");
@@ -102,6 +208,7 @@ pub fn info_and_fatal(is_fatal: bool, message: String) {
     }
 }
 
+#[cfg(feature = "cli")]
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
@@ -109,6 +216,7 @@ macro_rules! info {
     };
 }
 
+#[cfg(feature = "cli")]
 #[macro_export]
 macro_rules! fatal {
     ($($arg:tt)*) => {
@@ -116,6 +224,9 @@ macro_rules! fatal {
     };
 }
 
+/// # Safety
+///
+/// `T` must be a plain-old-data type without padding bytes.
 pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     core::slice::from_raw_parts(
         (p as *const T) as *const u8,
@@ -1,12 +1,74 @@
-use clap::{Parser, Subcommand};
-use afptool_rs::{unpack_file, pack_rkfw, pack_rkaf};
-use anyhow::Result;
+use clap::{ArgAction, Parser, Subcommand};
+use afptool_rs::{capabilities, make_firmware, check_metadata, dump_header, find_strings, Unpacker, human_size, planned_size, read_manifest, carve_embedded_rkaf, list_partitions, repack_dir_with_options, replace_partition, rkcrc_file, update_crc, unpack_to_tar_with_options, default_verify_cache_path, verify_file, verify_file_cached, pack_rkfw, all_chips, pack_rkaf, ApfError, HashAlgorithm, MetadataFormat, PackOptions, OutputNaming, PartitionSort, TextEncoding, UnpackOptions};
+use anyhow::{bail, Result};
+use std::io::{IsTerminal, Write};
+use std::process::ExitCode;
+use std::path::PathBuf;
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("invalid number: {}", value))
+}
+
+fn parse_alignment(value: &str) -> Result<u32, String> {
+    match u32::try_from(parse_number(value)?) {
+        Ok(alignment) if alignment.is_power_of_two() => Ok(alignment),
+        _ => Err(format!("alignment is not a power of two: {}", value)),
+    }
+}
+
+// `hexdump -C` style lines, offsets counted from `base`
+fn hexdump(data: &[u8], base: u64) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+            format!("{:08x}  {:<48} |{}|", base + row as u64 * 16, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+fn parse_mapping(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok((name.to_string(), PathBuf::from(path))),
+        _ => Err(format!("expected NAME=PATH, got {}", value)),
+    }
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {}", value)),
+    }
+}
+
+// Stable exit codes for scripts, see `exit_code`
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  any other error, including invalid arguments
+  2  unknown signature or bad header magic
+  3  CRC or MD5 mismatch
+  4  truncated image or region out of bounds
+  5  I/O error";
 
 #[derive(Parser)]
 #[command(name = "afptool-rs")]
 #[command(about = "A Rust tool for packing and unpacking RockChip firmware images")]
 #[command(version)]
+#[command(after_help = EXIT_CODES_HELP)]
 struct Args {
+    // Not global: pack-rkfw already uses -v for --version, so these go before the subcommand
+    #[arg(short, long, conflicts_with = "verbose", help = "Only print errors")]
+    quiet: bool,
+
+    #[arg(short, long, action = ArgAction::Count, help = "Print debug details such as header offsets, -vv also traces every copied chunk")]
+    verbose: u8,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -14,11 +76,208 @@ struct Args {
 #[derive(Subcommand)]
 enum Commands {
     Unpack {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format), or - to read it from stdin")]
+        input: String,
+
+        #[arg(help = "Directory where extracted files will be saved, or the archive to write with --tar")]
+        output: String,
+
+        #[arg(long, conflicts_with_all = ["split_boot", "split_resource", "split_boot_image", "capture_padding", "expand_sparse", "gunzip_partitions", "pad_to_flash_size", "resume"], help = "Write everything into a tar archive at OUTPUT instead of a directory")]
+        tar: bool,
+
+        #[arg(long, conflicts_with = "ignore_crc", help = "Fail when a CRC or RKFW MD5 check does not match; the default, kept for existing scripts")]
+        strict_crc: bool,
+
+        #[arg(long, help = "Only warn when a CRC or RKFW MD5 check does not match, for images edited by hand")]
+        ignore_crc: bool,
+
+        #[arg(long, help = "Fail instead of warning when partition padding contains non-zero bytes")]
+        strict_padding: bool,
+
+        #[arg(long, help = "Write non-zero partition padding to <partition>.padding")]
+        capture_padding: bool,
+
+        #[arg(long, help = "Also write each BOOT loader entry to BOOT.d/")]
+        split_boot: bool,
+
+        #[arg(long, conflicts_with_all = ["split_boot", "boot_only"], help = "Do not write BOOT when unpacking an RKFW image")]
+        no_extract_boot: bool,
+
+        #[arg(long, conflicts_with = "recursive", help = "Only write BOOT from an RKFW image, skipping the embedded update image")]
+        boot_only: bool,
+
+        #[arg(long, requires = "split_boot", help = "RC4-decrypt the 471/472 loader stages written by --split-boot")]
+        decrypt_loader: bool,
+
+        #[arg(long, help = "Also write each file of the resource partition (device trees, logos) to resource/")]
+        split_resource: bool,
+
+        #[arg(long, help = "Also write the kernel, ramdisk, second stage and DTB of Android boot images (boot, recovery) to <name>.d/")]
+        split_boot_image: bool,
+
+        #[arg(long, alias = "unsparse", help = "Expand Android sparse partitions into raw images")]
+        expand_sparse: bool,
+
+        #[arg(long, help = "Also write gzip-compressed partitions (e.g. kernel) decompressed next to them as <name>.raw")]
+        gunzip_partitions: bool,
+
+        #[arg(long, help = "Zero-fill every extracted partition to its flash_size (sectors x 512), except grow partitions")]
+        pad_to_flash_size: bool,
+
+        #[arg(long, value_name = "ALGORITHM", help = "Write checksums.txt with the md5 or sha256 of every extracted partition")]
+        checksums: Option<HashAlgorithm>,
+
+        #[arg(long, value_name = "BYTES", help = "Copy buffer size used while extracting (default 1048576)")]
+        buffer_size: Option<usize>,
+
+        #[arg(long, value_name = "NAMING", default_value = "by-path", help = "Name extracted partitions by-path (as stored), by-name, by-flash-offset or flat")]
+        naming: OutputNaming,
+
+        #[arg(long, conflicts_with = "naming", help = "Write every partition into the output directory under its file name (same as --naming flat)")]
+        flat: bool,
+
+        #[arg(long, value_name = "PATTERN", value_delimiter = ',', help = "Only extract partitions whose name or path matches (case-insensitive, * and ? wildcards, comma-separated or repeated)")]
+        only: Vec<String>,
+
+        #[arg(long = "map", value_name = "NAME=PATH", value_parser = parse_mapping, help = "Extract partition NAME to PATH in the output directory instead of its default path (repeatable)")]
+        path_map: Vec<(String, PathBuf)>,
+
+        #[arg(long, requires = "path_map", help = "Only extract partitions given with --map")]
+        mapped_only: bool,
+
+        #[arg(long, value_name = "BYTES", conflicts_with = "tar", help = "Write partitions larger than this as .part0, .part1, ... chunks of at most this size, listed in chunks.json")]
+        split_size: Option<u64>,
+
+        #[arg(long, value_name = "FORMAT", default_value = "legacy", help = "Partition metadata format: legacy, csv, tsv or json (JSON lines)")]
+        output_format: MetadataFormat,
+
+        #[arg(long, value_name = "ORDER", default_value = "header", help = "Order of the partition metadata rows: header, by-offset, by-size or by-name (only header repacks byte for byte)")]
+        sort: PartitionSort,
+
+        #[arg(long, help = "Do not decompress gzip or zstd compressed input")]
+        no_decompress: bool,
+
+        #[arg(long, help = "Search the first 64 KiB for an RKFW or RKAF signature when the input does not start with one, saving the skipped bytes to prefix.bin")]
+        scan: bool,
+
+        #[arg(long, help = "Skip partitions that were already extracted with the expected size")]
+        resume: bool,
+
+        #[arg(long, requires = "resume", help = "With --resume, also compare SHA-256 digests before skipping a partition")]
+        resume_verify: bool,
+
+        #[arg(long, help = "Extract every partition again, overriding --resume; also lets a partition overwrite an earlier one with the same path")]
+        force: bool,
+
+        #[arg(long, help = "Unpack the RKAF image inside an RKFW image into embedded-update/ instead of writing embedded-update.img")]
+        recursive: bool,
+
+        #[arg(long, value_name = "BYTES", help = "Refuse images, partitions and decompressed input larger than this")]
+        max_size: Option<u64>,
+
+        #[arg(long, help = "Fail on any warning about the image (layout, lengths, chip code, signatures, CRC, padding)")]
+        strict: bool,
+
+        #[arg(long, help = "Also write the raw RKAF or RKFW header to header.bin")]
+        save_header: bool,
+
+        #[arg(long, conflicts_with = "tar", help = "Log partitions that cannot be extracted and go on with the rest")]
+        continue_on_error: bool,
+
+        #[arg(long, conflicts_with = "tar", help = "Fail if OUTPUT already contains files, unless --force is given")]
+        require_empty_dir: bool,
+
+        #[arg(long, value_name = "FILE", conflicts_with = "tar", help = "Check the extracted files against a sha256sum or md5sum list and fail on a mismatch")]
+        verify_manifest: Option<String>,
+
+        #[arg(short = 'j', long, value_name = "N", default_value_t = 1, help = "Extract up to N partitions at once")]
+        jobs: usize,
+
+        #[arg(long, conflicts_with = "tar", help = "Extract partitions in the order they are stored in the image, reading it front to back")]
+        preserve_order: bool,
+
+        #[arg(long, visible_alias = "timestamp-from-image", help = "Set the modification time of the extracted files to the RKFW build date")]
+        preserve_mtime: bool,
+
+        #[arg(long, value_name = "SECONDS", requires = "preserve_mtime", allow_hyphen_values = true, help = "Modification time for RKAF images, which have no build date, in seconds since the Unix epoch")]
+        epoch: Option<i64>,
+
+        #[arg(long, value_name = "MODE", value_parser = parse_mode, help = "Octal permissions for the extracted files, e.g. 644 (Unix only)")]
+        file_mode: Option<u32>,
+
+        #[arg(long, value_name = "ENCODING", default_value = "auto", help = "Encoding of manufacturer, model and partition names: auto, utf8, gbk or utf16le")]
+        encoding: TextEncoding,
+
+        #[arg(long, help = "Print the unpack result as JSON")]
+        json: bool,
+
+        #[arg(long, conflicts_with_all = ["json", "tar"], help = "Print one JSON line per partition as soon as it is extracted, skipped or failed")]
+        json_stream: bool,
+    },
+
+    List {
         #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
         input: String,
 
-        #[arg(help = "Directory where extracted files will be saved")]
+        #[arg(long, value_name = "ORDER", default_value = "header", help = "Order of the listed partitions: header, by-offset, by-size or by-name")]
+        sort: PartitionSort,
+    },
+
+    Verify {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        input: String,
+
+        #[arg(long, value_name = "FILE", help = "Where to keep the reports of unchanged files, default $XDG_CACHE_HOME/afptool-rs/verify-cache.json")]
+        cache_file: Option<PathBuf>,
+
+        #[arg(long, conflicts_with = "cache_file", help = "Always verify the whole file and leave the cache alone")]
+        no_cache: bool,
+    },
+
+    Crc {
+        #[arg(help = "File to checksum")]
+        input: String,
+
+        #[arg(long, value_name = "OFFSET", default_value = "0", value_parser = parse_number, help = "First byte of the range (decimal or 0x hex)")]
+        start: u64,
+
+        #[arg(long, value_name = "BYTES", value_parser = parse_number, help = "Length of the range (decimal or 0x hex), default up to the end of the file")]
+        len: Option<u64>,
+    },
+
+    UpdateCrc {
+        #[arg(help = "RKFW or RKAF image to fix in place")]
+        input: String,
+    },
+
+    Replace {
+        #[arg(help = "RKFW or RKAF image to edit in place")]
+        input: String,
+
+        #[arg(help = "Name (or stored path) of the partition to replace, e.g. kernel")]
+        partition: String,
+
+        #[arg(help = "File with the new partition data, at most the partition's padded size")]
+        file: String,
+    },
+
+    Repack {
+        #[arg(help = "Directory written by unpack")]
+        input: String,
+
+        #[arg(help = "Output firmware image file path")]
         output: String,
+
+        #[arg(long, value_name = "N", value_parser = parse_alignment, help = "Lay the partitions out again at this alignment (a power of two, decimal or 0x hex); by default the recorded offsets are kept")]
+        align: Option<u32>,
+
+        #[arg(long, help = "Repack partition files whose size changed since unpacking, instead of refusing them")]
+        allow_resized: bool,
+    },
+
+    CheckMetadata {
+        #[arg(help = "Directory written by unpack, with header-metadata.txt and the partition metadata")]
+        input: String,
     },
 
     PackRkfw {
@@ -28,7 +287,7 @@ enum Commands {
         #[arg(help = "Output RKFW firmware image file path")]
         output: String,
 
-        #[arg(short, long, help = "Chip family (e.g., RK29XX, RK30XX, RK31XX, RK32XX, RK3368, RK3326, RK3562, RK3566, PX30)")]
+        #[arg(short, long, help = "Chip family as listed by the chips command (e.g., RK3326, RK3588)")]
         chip: String,
 
         #[arg(short, long, help = "Version in format: major.minor.build (e.g., 8.1.0)")]
@@ -41,6 +300,23 @@ enum Commands {
         code: String,
     },
 
+    MakeFirmware {
+        #[arg(help = "Output RKFW firmware image file path")]
+        output: String,
+
+        #[arg(short, long, help = "Loader (e.g. MiniLoaderAll.bin), stored as BOOT and as the bootloader partition")]
+        loader: String,
+
+        #[arg(short, long, help = "Parameter file whose mtdparts give the flash layout")]
+        parameter: String,
+
+        #[arg(short, long, help = "Chip family as listed by the chips command (e.g., RK3326, RK3588)")]
+        chip: String,
+
+        #[arg(long = "part", value_name = "NAME=FILE", value_parser = parse_mapping, help = "Partition declared in the parameter file and the file to store in it (repeatable)")]
+        partitions: Vec<(String, PathBuf)>,
+    },
+
     PackRkaf {
         #[arg(help = "Directory containing package-file and files to pack")]
         input: String,
@@ -54,21 +330,331 @@ enum Commands {
         #[arg(short = 'M', long, help = "Manufacturer name")]
         manufacturer: String,
     },
+
+    Carve {
+        #[arg(help = "Path to the RKFW firmware file")]
+        input: String,
+
+        #[arg(help = "Where to write the embedded update image")]
+        output: String,
+    },
+
+    #[cfg(feature = "fuse")]
+    Mount {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        input: String,
+
+        #[arg(help = "Empty directory to show the partitions in, read-only, until it is unmounted")]
+        mountpoint: String,
+
+        #[arg(long, help = "Show the partitions inside the embedded update image of an RKFW image instead of embedded-update.img")]
+        recursive: bool,
+
+        #[arg(long, value_name = "PATTERN", value_delimiter = ',', help = "Only show partitions whose name or path matches (case-insensitive, * and ? wildcards)")]
+        only: Vec<String>,
+    },
+
+    Strings {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format)")]
+        input: String,
+
+        #[arg(short = 'n', long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..), help = "Shortest run of printable characters to print")]
+        min_len: u64,
+
+        #[arg(long, alias = "partition", value_name = "PATTERN", value_delimiter = ',', help = "Only scan partitions whose name or path matches (case-insensitive, * and ? wildcards)")]
+        only: Vec<String>,
+
+        #[arg(short = 't', long, help = "Print the hex offset of each string inside its partition")]
+        offsets: bool,
+
+        #[arg(long, help = "Scan the partitions inside the embedded update image of an RKFW image instead of embedded-update.img")]
+        recursive: bool,
+    },
+
+    DumpHeader {
+        #[arg(help = "Path to the firmware file (RKFW or RKAF format), also one that fails to unpack")]
+        input: String,
+
+        #[arg(long, help = "Print the fields and the first bytes as JSON")]
+        json: bool,
+    },
+
+    Chips,
+
+    VersionInfo {
+        #[arg(long, help = "Print the capabilities as JSON")]
+        json: bool,
+    },
+}
+
+// -q and -v override RUST_LOG; without them RUST_LOG applies and defaults to info. Only
+// diagnostics go through the logger, listings and JSON are printed to stdout regardless.
+fn init_logger(quiet: bool, verbose: u8) {
+    let mut builder = match (quiet, verbose) {
+        (false, 0) => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")),
+        _ => {
+            let level = match (quiet, verbose) {
+                (true, _) => log::LevelFilter::Error,
+                (_, 1) => log::LevelFilter::Debug,
+                _ => log::LevelFilter::Trace,
+            };
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+    };
+    // The FUSE library logs every mount and reports `umount` by the user as an error
+    if verbose == 0 {
+        builder.filter_module("fuser", log::LevelFilter::Off);
+    }
+    builder
+        .format(|buf, record| match record.level() {
+            log::Level::Info => writeln!(buf, "{}", record.args()),
+            log::Level::Warn => writeln!(buf, "warning: {}", record.args()),
+            level => writeln!(buf, "{}: {}", level.as_str().to_lowercase(), record.args()),
+        })
+        .init();
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+// The first typed error in the chain picks the code listed in `EXIT_CODES_HELP`; a read that
+// hits the end of the input counts as truncated
+fn exit_code(err: &anyhow::Error) -> u8 {
+    let io_code = |err: &std::io::Error| if err.kind() == std::io::ErrorKind::UnexpectedEof { 4 } else { 5 };
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<ApfError>() {
+            return match err {
+                ApfError::UnknownSignature(_) | ApfError::BadMagic => 2,
+                ApfError::CrcMismatch { .. } | ApfError::Md5Mismatch { .. } => 3,
+                ApfError::Truncated(_) => 4,
+                ApfError::Io(err) => io_code(err),
+                _ => 1,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            return io_code(err);
+        }
+    }
+    1
+}
+
+fn main() -> ExitCode {
+    // clap would exit with 2 on a usage error, which is taken by unknown signatures here
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(err) => {
+            let _ = err.print();
+            return if err.use_stderr() { ExitCode::FAILURE } else { ExitCode::SUCCESS };
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    init_logger(args.quiet, args.verbose);
 
     match args.command {
-        Commands::Unpack { input, output } => {
-            unpack_file(&input, &output)?;
+        Commands::Unpack { input, output, tar, strict_crc, ignore_crc, strict_padding, capture_padding, split_boot, no_extract_boot, boot_only, decrypt_loader, split_resource, split_boot_image, expand_sparse, gunzip_partitions, pad_to_flash_size, checksums, buffer_size, naming, flat, only, path_map, mapped_only, split_size, output_format, sort, no_decompress, scan, resume, resume_verify, force, recursive, max_size, strict, save_header, continue_on_error, require_empty_dir, verify_manifest, jobs, preserve_order, preserve_mtime, epoch, file_mode, encoding, json, json_stream } => {
+            let naming = if flat { OutputNaming::Flat } else { naming };
+            let options = UnpackOptions { strict_crc, allow_crc_mismatch: ignore_crc, strict_padding, capture_padding, split_boot, no_extract_boot, boot_only, decrypt_loader, split_resource, split_boot_image, expand_sparse, gunzip_partitions, pad_to_flash_size, checksums, buffer_size, naming, only, path_map: path_map.into_iter().collect(), mapped_only, split_size, metadata_format: output_format, sort, no_decompress, scan, text_encoding: encoding, resume, resume_verify, force, recursive, max_size, strict, save_header, continue_on_error, require_empty_dir, verify_manifest, parallelism: jobs, preserve_order, preserve_mtime, epoch, file_mode, ..Default::default() };
+            // Only draw the in-place percentage when a person is watching
+            let progress = |done: u64, total: u64| {
+                let percent = (done * 100).checked_div(total).unwrap_or(100);
+                eprint!("\r{:3}%", percent);
+                if done == total {
+                    eprint!("\r    \r");
+                }
+            };
+            let show_progress = std::io::stderr().is_terminal();
+            let result = if tar {
+                unpack_to_tar_with_options(&input, &output, &options)?
+            } else {
+                let mut unpacker = Unpacker::with_options(options);
+                if show_progress {
+                    unpacker = unpacker.progress(progress);
+                }
+                if json_stream {
+                    // Lines go out as they happen, stdout is flushed at every newline
+                    unpacker = unpacker.on_partition(|event| match serde_json::to_string(event) {
+                        Ok(line) => println!("{}", line),
+                        Err(e) => log::error!("Cannot serialize the event for {}: {}", event.partition.name, e),
+                    });
+                }
+                unpacker.unpack(&input, &output)?
+            };
+            if json {
+                println!("{}", result.to_json()?);
+            }
+            let stats = result.stats();
+            match stats.throughput_mb_s() {
+                Some(throughput) => log::info!("Done in {:.1}s ({:.0} MB/s)", stats.duration_ms as f64 / 1000.0, throughput),
+                None => log::info!("Done in {:.1}s", stats.duration_ms as f64 / 1000.0),
+            }
+            if !result.errors().is_empty() {
+                bail!("{} files could not be extracted from {}", result.errors().len(), input);
+            }
+        }
+        Commands::List { input, sort } => {
+            let mut partitions = list_partitions(&input)?;
+            sort.apply(&mut partitions);
+            println!("{:<16} {:<32} {:>10} {:>10} {:<18}", "NAME", "PATH", "OFFSET", "SIZE", "TYPE");
+            for part in partitions {
+                let note = if part.is_special() {
+                    " (not extracted)"
+                } else if part.sparse {
+                    " (sparse)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{:<16} {:<32} {:#010x} {:>10} {:<18}{}",
+                    part.name, part.path, part.part_offset, part.part_byte_count, part.content_type.as_deref().unwrap_or("-"), note
+                );
+            }
+        }
+        Commands::Verify { input, cache_file, no_cache } => {
+            let cache_file = if no_cache { None } else { cache_file.or_else(default_verify_cache_path) };
+            let report = match cache_file {
+                Some(cache_file) => verify_file_cached(&input, &cache_file)?,
+                None => verify_file(&input)?,
+            };
+            for check in &report.checks {
+                println!("[{}] {}: {}", if check.passed { " ok " } else { "FAIL" }, check.name, check.details);
+            }
+            if !report.passed() {
+                bail!("{} failed verification", input);
+            }
+        }
+        Commands::Crc { input, start, len } => {
+            let crc = rkcrc_file(&input, start, len)?;
+            println!("{:#010x} {}", crc, crc);
+        }
+        Commands::UpdateCrc { input } => {
+            let update = update_crc(&input)?;
+            if update.previous == update.crc {
+                println!("CRC {:#010x} was already correct", update.crc);
+            } else {
+                println!("CRC {:#010x} -> {:#010x}", update.previous, update.crc);
+            }
+            if let Some(md5) = update.md5 {
+                println!("MD5 {}", md5);
+            }
+        }
+        Commands::Replace { input, partition, file } => {
+            let result = replace_partition(&input, &partition, &file)?;
+            println!("Replaced {}: {} -> {} bytes (padded size {})", result.name, result.previous_size, result.size, result.padded_size);
+            println!("CRC {:#010x} -> {:#010x}", result.crc.previous, result.crc.crc);
+            if let Some(md5) = result.crc.md5 {
+                println!("MD5 {}", md5);
+            }
+        }
+        Commands::Repack { input, output, align, allow_resized } => {
+            // manifest.json sits next to the RKAF partitions, in embedded-update/ after a recursive RKFW unpack
+            let manifest = ["manifest.json", "embedded-update/manifest.json"]
+                .iter()
+                .find_map(|path| std::fs::read_to_string(PathBuf::from(&input).join(path)).ok())
+                .and_then(|data| read_manifest(&data).ok());
+            if let Some(manifest) = manifest {
+                log::info!("The RKAF image will take {}", human_size(planned_size(&manifest)));
+            }
+            let result = repack_dir_with_options(&input, &output, &PackOptions { alignment: align, allow_resized })?;
+            println!("Repacked {} ({}), {} checks passed", output, if result.rkfw.is_some() { "RKFW" } else { "RKAF" }, result.report.checks.len());
+        }
+        Commands::CheckMetadata { input } => {
+            let warnings = check_metadata(&input)?;
+            for warning in &warnings {
+                println!("{}", warning);
+            }
+            if !warnings.is_empty() {
+                bail!("{} layout problems in the metadata of {}", warnings.len(), input);
+            }
+            println!("Metadata of {} is consistent", input);
         }
         Commands::PackRkfw{ input, output, chip, version, timestamp, code } => {
             pack_rkfw(&input, &output, &chip, &version, timestamp, &code)?;
         }
+        Commands::MakeFirmware { output, loader, parameter, chip, partitions } => {
+            let partitions: Vec<(String, String)> = partitions.into_iter().map(|(name, file)| (name, file.to_string_lossy().to_string())).collect();
+            make_firmware(&loader, &parameter, &partitions, &chip, &output)?;
+        }
         Commands::PackRkaf { input, output, model, manufacturer } => {
             pack_rkaf(&input, &output, &model, &manufacturer)?;
         }
+        Commands::Carve { input, output } => {
+            carve_embedded_rkaf(&input, &output)?;
+        }
+        #[cfg(feature = "fuse")]
+        Commands::Mount { input, mountpoint, recursive, only } => {
+            let options = UnpackOptions { recursive, only, ..Default::default() };
+            afptool_rs::mount_image(&input, &mountpoint, &options)?;
+        }
+        Commands::Strings { input, min_len, only, offsets, recursive } => {
+            let mut stdout = std::io::stdout().lock();
+            for item in Unpacker::with_options(UnpackOptions { recursive, only, ..Default::default() }).into_iter_partitions(&input)? {
+                let (part, reader) = item?;
+                let mut written = Ok(());
+                find_strings(reader, min_len as usize, |offset, text| {
+                    if written.is_ok() {
+                        written = if offsets {
+                            writeln!(stdout, "{}: {:#010x} {}", part.name, offset, text)
+                        } else {
+                            writeln!(stdout, "{}: {}", part.name, text)
+                        };
+                    }
+                })?;
+                written?;
+            }
+        }
+        Commands::DumpHeader { input, json } => {
+            let dump = dump_header(&input)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&dump)?);
+            } else {
+                if dump.fields.is_empty() {
+                    println!("{}: no RKFW or RKAF signature ({:?}), only the first bytes are shown", input, dump.kind);
+                } else {
+                    println!("{:<10}  {:<28} {:<51} VALUE", "OFFSET", "FIELD", "RAW");
+                }
+                for field in &dump.fields {
+                    // Long fields such as names and `reserved` show their first 16 bytes
+                    let mut raw: Vec<String> = field.raw.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+                    if field.raw.len() > 16 {
+                        raw.push("..".to_string());
+                    }
+                    println!("{:#010x}  {:<28} {:<51} {}", field.offset, field.name, raw.join(" "), field.value);
+                }
+                println!();
+                for line in hexdump(&dump.head, 0) {
+                    println!("{}", line);
+                }
+            }
+        }
+        Commands::Chips => {
+            println!("{:<6} FAMILY", "CODE");
+            for (code, family) in all_chips() {
+                println!("{:#04x}   {}", code, family);
+            }
+        }
+        Commands::VersionInfo { json } => {
+            let capabilities = capabilities();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&capabilities)?);
+            } else {
+                println!("afptool-rs {}", capabilities.version);
+                println!("signatures: {}", capabilities.signatures.join(", "));
+                println!("checksums:  {}", capabilities.hash_algorithms.join(", "));
+                println!("chips:      {} (run `chips` to list them)", capabilities.chips);
+                println!("features:");
+                for (feature, enabled) in &capabilities.features {
+                    println!("  {:<20} {}", feature, if *enabled { "yes" } else { "no" });
+                }
+            }
+        }
     }
 
     Ok(())
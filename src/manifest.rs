@@ -0,0 +1,59 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use crate::error::ApfError;
+use crate::unpack::PartitionInfo;
+use crate::UpdateHeader;
+
+/// Bumped whenever a field of [`Manifest`] changes meaning or is removed
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
+
+/// Everything needed to rebuild an RKAF image byte for byte, written to `manifest.json` on unpack
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Manifest {
+    pub schema_version: u32,
+    /// Version of the tool that wrote the manifest
+    pub tool_version: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub id: String,
+    pub version: u32,
+    pub unknown1: u32,
+    /// Header `length`, the offset of the trailing RKCRC
+    pub length: u32,
+    /// The header's `reserved` bytes as hex, empty when they are all zero
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reserved: String,
+    pub filesize: u64,
+    /// The whole partition table in on-disk order, including `SELF` and `RESERVED` entries
+    pub partitions: Vec<PartitionInfo>,
+}
+
+#[cfg(feature = "serde")]
+pub fn read_manifest(data: &str) -> Result<Manifest, ApfError> {
+    let manifest: Manifest = serde_json::from_str(data)
+        .map_err(|err| ApfError::Invalid(format!("Invalid manifest: {}", err)))?;
+    if manifest.schema_version != MANIFEST_SCHEMA_VERSION {
+        return Err(ApfError::Invalid(format!(
+            "Unsupported manifest schema version {} (expected {})",
+            manifest.schema_version, MANIFEST_SCHEMA_VERSION
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Size of the RKAF image `pack_rkafp` builds from the directory this manifest was written to,
+/// as long as no partition file outgrows its `padded_size`: the header, every partition slot and
+/// `RESERVED` region up to the end of the last one (or the recorded `length` if that is further)
+/// and the trailing RKCRC. Partitions sharing an offset are counted once.
+pub fn planned_size(manifest: &Manifest) -> u64 {
+    let slots_end = manifest.partitions
+        .iter()
+        .filter(|part| part.path != "SELF")
+        .map(|part| part.part_offset as u64 + part.padded_size as u64)
+        .max()
+        .unwrap_or(0);
+    let length = (std::mem::size_of::<UpdateHeader>() as u64).max(slots_end).max(manifest.length as u64);
+    length + 4
+}
@@ -0,0 +1,337 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::unpack::{PartitionInfo, FLASH_SIZE_GROW};
+
+/// Serialization of the partition table written next to the extracted partitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataFormat {
+    /// `partition-metadata.txt`: headerless comma-separated rows with hex values
+    #[default]
+    Legacy,
+    /// `partition-metadata.csv`: header row, decimal values
+    Csv,
+    /// `partition-metadata.tsv`: like `Csv`, separated by tabs
+    Tsv,
+    /// `partition-metadata.jsonl`: one JSON object per partition
+    Json,
+}
+
+impl MetadataFormat {
+    pub(crate) const ALL: [MetadataFormat; 4] = [MetadataFormat::Legacy, MetadataFormat::Csv, MetadataFormat::Tsv, MetadataFormat::Json];
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            MetadataFormat::Legacy => "partition-metadata.txt",
+            MetadataFormat::Csv => "partition-metadata.csv",
+            MetadataFormat::Tsv => "partition-metadata.tsv",
+            MetadataFormat::Json => "partition-metadata.jsonl",
+        }
+    }
+
+    fn separator(&self) -> char {
+        if *self == MetadataFormat::Tsv { '\t' } else { ',' }
+    }
+}
+
+impl std::str::FromStr for MetadataFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "legacy" => Ok(MetadataFormat::Legacy),
+            "csv" => Ok(MetadataFormat::Csv),
+            "tsv" => Ok(MetadataFormat::Tsv),
+            "json" | "jsonl" => Ok(MetadataFormat::Json),
+            _ => Err(anyhow!("Unsupported metadata format: {}", s)),
+        }
+    }
+}
+
+/// Order of the partitions in `list` output and the partition metadata file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartitionSort {
+    /// As stored in the image header; `pack_rkafp` writes header entries in metadata file order,
+    /// so this is the only order that repacks byte for byte
+    #[default]
+    Header,
+    /// By flash offset
+    ByOffset,
+    /// By `part_byte_count`, smallest first
+    BySize,
+    /// By partition name
+    ByName,
+}
+
+impl PartitionSort {
+    /// Sorts `parts` in place; the sort is stable, so equal keys keep their header order
+    pub fn apply(&self, parts: &mut [PartitionInfo]) {
+        match self {
+            PartitionSort::Header => {}
+            PartitionSort::ByOffset => parts.sort_by_key(|part| part.flash_offset),
+            PartitionSort::BySize => parts.sort_by_key(|part| part.part_byte_count),
+            PartitionSort::ByName => parts.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+    }
+}
+
+impl std::str::FromStr for PartitionSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "header" => Ok(PartitionSort::Header),
+            "by-offset" | "offset" => Ok(PartitionSort::ByOffset),
+            "by-size" | "size" => Ok(PartitionSort::BySize),
+            "by-name" | "name" => Ok(PartitionSort::ByName),
+            _ => Err(anyhow!("Unsupported partition sort: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct PartitionMetadata {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) flash_size: u32,
+    pub(crate) flash_offset: u32,
+    pub(crate) part_offset: u32,
+    pub(crate) padded_size: u32,
+    pub(crate) part_byte_count: u32,
+    // SELF and RESERVED entries carry no file, only their header entry is restored
+    pub(crate) skip_extract: bool,
+}
+
+impl From<&PartitionInfo> for PartitionMetadata {
+    fn from(part: &PartitionInfo) -> Self {
+        PartitionMetadata {
+            name: part.name.clone(),
+            path: part.path.clone(),
+            flash_size: part.flash_size,
+            flash_offset: part.flash_offset,
+            part_offset: part.part_offset,
+            padded_size: part.padded_size,
+            part_byte_count: part.part_byte_count,
+            skip_extract: part.is_special(),
+        }
+    }
+}
+
+// Trailing field marking SELF/RESERVED rows in the legacy format
+const SKIP_EXTRACT_MARKER: &str = "#skip-extract";
+
+const COLUMNS: [&str; 8] = [
+    "name", "path", "flash_size", "flash_offset", "part_offset", "padded_size", "part_byte_count", "skip_extract",
+];
+
+// Quotes a CSV field when it contains the separator, a quote or a line break
+fn quote_field(value: &str, separator: char) -> String {
+    if value.contains(separator) || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn split_fields(line: &str, separator: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_hex_u32(value: &str) -> Result<u32> {
+    let value = value.trim();
+    let hex_str = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex_str, 16)
+        .map_err(|_| anyhow!("Invalid hex value: {}", value))
+}
+
+// Written for `FLASH_SIZE_GROW` in the CSV/TSV `flash_size` column, accepted in every text format
+const GROW_MARKER: &str = "grow";
+
+fn parse_flash_size(value: &str, parse: fn(&str) -> Result<u32>) -> Result<u32> {
+    if value.trim().eq_ignore_ascii_case(GROW_MARKER) {
+        Ok(FLASH_SIZE_GROW)
+    } else {
+        parse(value)
+    }
+}
+
+fn format_flash_size(flash_size: u32) -> String {
+    if flash_size == FLASH_SIZE_GROW { GROW_MARKER.to_string() } else { flash_size.to_string() }
+}
+
+fn parse_decimal_u32(value: &str) -> Result<u32> {
+    value.trim().parse().map_err(|_| anyhow!("Invalid number: {}", value))
+}
+
+pub(crate) fn format_partition_metadata(parts: &[PartitionMetadata], format: MetadataFormat) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        MetadataFormat::Legacy => {
+            for part in parts {
+                out.push_str(&format!(
+                    "{},{},{:#010x},{:#010x},{:#010x},{:#010x},{:#010x}{}\n",
+                    part.name,
+                    part.path,
+                    part.flash_size,
+                    part.flash_offset,
+                    part.part_offset,
+                    part.padded_size,
+                    part.part_byte_count,
+                    if part.skip_extract { format!(",{}", SKIP_EXTRACT_MARKER) } else { String::new() }
+                ));
+            }
+        }
+        MetadataFormat::Csv | MetadataFormat::Tsv => {
+            let separator = format.separator();
+            out.push_str(&COLUMNS.join(&separator.to_string()));
+            out.push('\n');
+            for part in parts {
+                let fields = [
+                    quote_field(&part.name, separator),
+                    quote_field(&part.path, separator),
+                    format_flash_size(part.flash_size),
+                    part.flash_offset.to_string(),
+                    part.part_offset.to_string(),
+                    part.padded_size.to_string(),
+                    part.part_byte_count.to_string(),
+                    part.skip_extract.to_string(),
+                ];
+                out.push_str(&fields.join(&separator.to_string()));
+                out.push('\n');
+            }
+        }
+        #[cfg(feature = "serde")]
+        MetadataFormat::Json => {
+            for part in parts {
+                out.push_str(&serde_json::to_string(part)?);
+                out.push('\n');
+            }
+        }
+        #[cfg(not(feature = "serde"))]
+        MetadataFormat::Json => return Err(anyhow!("JSON partition metadata needs the serde feature")),
+    }
+    Ok(out)
+}
+
+pub(crate) fn parse_partition_metadata(content: &str, format: MetadataFormat) -> Result<Vec<PartitionMetadata>> {
+    let mut parts = Vec::new();
+    let mut lines = content.lines().map(str::trim_end).filter(|line| !line.trim().is_empty());
+    match format {
+        MetadataFormat::Legacy => {
+            // End of the last partition, where a name,path,size row from older versions is placed
+            let mut next_offset = LEGACY_FIRST_OFFSET;
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let part = parse_legacy_row(line, next_offset)
+                    .map_err(|err| anyhow!("Line {} of partition metadata: {}", number + 1, err))?;
+                next_offset = std::cmp::max(next_offset, part.part_offset.saturating_add(part.padded_size));
+                parts.push(part);
+            }
+        }
+        MetadataFormat::Csv | MetadataFormat::Tsv => {
+            let separator = format.separator();
+            let header = lines.next().map(|line| split_fields(line, separator)).unwrap_or_default();
+            let column = |name: &str| {
+                header
+                    .iter()
+                    .position(|column| column.trim() == name)
+                    .ok_or_else(|| anyhow!("Missing column {} in partition metadata", name))
+            };
+            let indices = COLUMNS.iter().map(|name| column(name)).collect::<Result<Vec<_>>>()?;
+            for line in lines {
+                let fields = split_fields(line, separator);
+                let field = |i: usize| -> Result<&str> {
+                    fields
+                        .get(indices[i])
+                        .map(|field| field.as_str())
+                        .ok_or_else(|| anyhow!("Missing {} in partition metadata row: {}", COLUMNS[i], line))
+                };
+                parts.push(PartitionMetadata {
+                    name: field(0)?.to_string(),
+                    path: field(1)?.to_string(),
+                    flash_size: parse_flash_size(field(2)?, parse_decimal_u32)?,
+                    flash_offset: parse_decimal_u32(field(3)?)?,
+                    part_offset: parse_decimal_u32(field(4)?)?,
+                    padded_size: parse_decimal_u32(field(5)?)?,
+                    part_byte_count: parse_decimal_u32(field(6)?)?,
+                    skip_extract: field(7)?.trim().parse().map_err(|_| anyhow!("Invalid skip_extract in row: {}", line))?,
+                });
+            }
+        }
+        #[cfg(feature = "serde")]
+        MetadataFormat::Json => {
+            for line in lines {
+                parts.push(serde_json::from_str(line).map_err(|err| anyhow!("Invalid partition metadata row: {}", err))?);
+            }
+        }
+        #[cfg(not(feature = "serde"))]
+        MetadataFormat::Json => return Err(anyhow!("JSON partition metadata needs the serde feature")),
+    }
+    Ok(parts)
+}
+
+// Older versions wrote only name, path and size; the data is then laid out like `pack_rkaf`
+// does, from the end of the 2048-byte header in 2048-byte aligned steps
+const LEGACY_ALIGNMENT: u32 = 2048;
+const LEGACY_FIRST_OFFSET: u32 = 2048;
+
+fn parse_legacy_row(line: &str, next_offset: u32) -> Result<PartitionMetadata> {
+    let fields: Vec<&str> = line.split(',').collect();
+    match fields.len() {
+        3 => {
+            let path = fields[1].to_string();
+            let size = parse_hex_u32(fields[2])?;
+            // The flash layout was never recorded for these, only the image layout is rebuilt
+            Ok(PartitionMetadata {
+                name: fields[0].to_string(),
+                skip_extract: path == "SELF" || path == "RESERVED",
+                path,
+                flash_size: 0,
+                flash_offset: 0,
+                part_offset: next_offset,
+                padded_size: size.div_ceil(LEGACY_ALIGNMENT).saturating_mul(LEGACY_ALIGNMENT),
+                part_byte_count: size,
+            })
+        }
+        7 | 8 => Ok(PartitionMetadata {
+            name: fields[0].to_string(),
+            path: fields[1].to_string(),
+            flash_size: parse_flash_size(fields[2], parse_hex_u32)?,
+            flash_offset: parse_hex_u32(fields[3])?,
+            part_offset: parse_hex_u32(fields[4])?,
+            padded_size: parse_hex_u32(fields[5])?,
+            part_byte_count: parse_hex_u32(fields[6])?,
+            skip_extract: fields.get(7).map(|marker| marker.trim()) == Some(SKIP_EXTRACT_MARKER),
+        }),
+        count => Err(anyhow!("expected 3 or 7 comma-separated fields, found {}: {}", count, line)),
+    }
+}
+
+/// The partition metadata file in `dir`, whichever format it was written in
+pub(crate) fn find_partition_metadata(dir: &str) -> Option<(String, MetadataFormat)> {
+    MetadataFormat::ALL
+        .iter()
+        .map(|format| (format!("{}/{}", dir, format.file_name()), *format))
+        .find(|(path, _)| Path::new(path).exists())
+}
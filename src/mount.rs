@@ -0,0 +1,156 @@
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime};
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+use log::{info, warn};
+use crate::error::ApfError;
+use crate::iter::PartitionIter;
+use crate::split::MultiFileReader;
+use crate::unpack::{PartitionInfo, UnpackOptions};
+
+const ROOT_INODE: u64 = 1;
+// The image does not change while it is mounted, let the kernel cache everything
+const TTL: Duration = Duration::from_secs(3600);
+
+// One file per partition under the mount point, read straight from the image
+struct ImageFs {
+    input: MultiFileReader,
+    /// (file name, partition), inode `index + 2`
+    files: Vec<(String, PartitionInfo)>,
+    mtime: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+// The file name of each stored path; a name that is already taken gets the partition index in front
+fn file_names(partitions: Vec<PartitionInfo>) -> Vec<(String, PartitionInfo)> {
+    let mut files: Vec<(String, PartitionInfo)> = Vec::new();
+    for (index, part) in partitions.into_iter().enumerate() {
+        let base = part.path.rsplit(['/', '\\']).next().filter(|name| !name.is_empty() && *name != "." && *name != "..").unwrap_or(&part.name);
+        let mut name = base.to_string();
+        if name.is_empty() || files.iter().any(|(taken, _)| *taken == name) {
+            name = format!("{}_{}", index, base);
+        }
+        files.push((name, part));
+    }
+    files
+}
+
+impl ImageFs {
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = if ino == ROOT_INODE {
+            (FileType::Directory, 0, 0o555, 2)
+        } else {
+            let (_, part) = self.files.get(ino.checked_sub(2)? as usize)?;
+            (FileType::RegularFile, part.part_byte_count as u64, 0o444, 1)
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    fn read_at(&mut self, part_offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.seek(SeekFrom::Start(part_offset))?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.input.read(&mut buf[filled..])? {
+                0 => break,
+                read_bytes => filled += read_bytes,
+            }
+        }
+        Ok(filled)
+    }
+}
+
+impl Filesystem for ImageFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = (parent == ROOT_INODE)
+            .then(|| self.files.iter().position(|(file_name, _)| OsStr::new(file_name) == name))
+            .flatten()
+            .and_then(|index| self.attr(index as u64 + 2));
+        match found {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let Some((_, part)) = ino.checked_sub(2).and_then(|index| self.files.get(index as usize)) else {
+            return reply.error(libc::ENOENT);
+        };
+        // Reads are clamped to the partition, never past it into the next one
+        let len = part.part_byte_count as u64;
+        let start = (offset.max(0) as u64).min(len);
+        let end = start.saturating_add(size as u64).min(len);
+        let part_offset = part.part_offset as u64 + start;
+        let mut buf = vec![0u8; (end - start) as usize];
+        match self.read_at(part_offset, &mut buf) {
+            Ok(read_bytes) => reply.data(&buf[..read_bytes]),
+            Err(e) => {
+                warn!("read of inode {} at {:#x} failed: {}", ino, part_offset, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INODE {
+            return reply.error(libc::ENOTDIR);
+        }
+        let entries = [(ROOT_INODE, FileType::Directory, "."), (ROOT_INODE, FileType::Directory, "..")]
+            .into_iter()
+            .chain(self.files.iter().enumerate().map(|(index, (name, _))| (index as u64 + 2, FileType::RegularFile, name.as_str())));
+        for (next, (entry_ino, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            // `next` is the offset the kernel passes back to continue after this entry
+            if reply.add(entry_ino, next as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mounts the partitions of `file_path` read-only at `mountpoint`, one file per partition named
+/// after the file name of its stored path, and serves reads from the partition's byte range of
+/// the image. Runs the checks `Unpacker::into_iter_partitions` does; with `options.recursive` an
+/// RKFW image shows `BOOT` and the embedded partitions instead of `embedded-update.img`, and
+/// `options.only` limits the files. Blocks until the file system is unmounted, e.g. with
+/// `fusermount -u`.
+pub fn mount_image(file_path: &str, mountpoint: &str, options: &UnpackOptions) -> Result<(), ApfError> {
+    let (paths, partitions) = PartitionIter::open(file_path, options)?.into_parts();
+    let metadata = std::fs::metadata(&paths[0])?;
+    let files = file_names(partitions.into_iter().filter(|part| !part.is_special()).collect());
+    let fs = ImageFs {
+        input: MultiFileReader::open(&paths)?,
+        files,
+        mtime: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    };
+    info!("Mounting {} partitions of {} at {}", fs.files.len(), file_path, mountpoint);
+    let mount_options = [MountOption::RO, MountOption::FSName(format!("afptool-rs:{}", file_path))];
+    fuser::mount2(fs, mountpoint, &mount_options)?;
+    Ok(())
+}
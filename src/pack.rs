@@ -2,14 +2,55 @@ use std::fs::File;
 use std::io::{Read, Write, BufRead, BufReader};
 use std::collections::HashMap;
 use anyhow::{anyhow, Result};
+use crate::error::ApfError;
 use chrono::{Datelike, Timelike};
-use crate::{UpdateHeader, UpdatePart, MAX_NAME_LEN, MAX_FULL_PATH_LEN, RKFW_SIGNATURE, RKAF_SIGNATURE};
+use log::info;
+use crate::chip::chip_code;
+use crate::crc::rkcrc32;
+use crate::metadata::{find_partition_metadata, parse_partition_metadata, PartitionMetadata};
+use crate::encoding::TextEncoding;
+use crate::layout::{validate_layout, LayoutWarning};
+use crate::parameter::parse_parameter;
+use crate::unpack::{human_size, parse_rkafp, ReservedRegion, RkfwInfo, BOOT_GAP_FILE, FLASH_SIZE_GROW, PRE_BOOT_FILE, SIGNATURE_FILE};
+use crate::{UpdateHeader, UpdatePart, MAX_NAME_LEN, RESERVED_LEN, MAX_FULL_PATH_LEN, MAX_PARTS, PARM_MAGIC, RKFW_SIGNATURE, RKAF_SIGNATURE};
 
 #[derive(Debug, Clone)]
-struct PartitionMetadata {
-    flash_size: u32,
-    flash_offset: u32,
-    padded_size: u32,
+struct HeaderMetadata {
+    manufacturer: String,
+    model: String,
+    id: String,
+    version: u32,
+    unknown1: u32,
+    length: u32,
+    /// `UpdateHeader::reserved`, zero unless `header-metadata.txt` has a `reserved:` line
+    reserved: [u8; RESERVED_LEN],
+}
+
+impl Default for HeaderMetadata {
+    fn default() -> Self {
+        Self {
+            manufacturer: String::new(),
+            model: String::new(),
+            id: String::new(),
+            version: 0,
+            unknown1: 0,
+            length: 0,
+            reserved: [0u8; RESERVED_LEN],
+        }
+    }
+}
+
+fn parse_reserved(value: &str) -> Result<[u8; RESERVED_LEN]> {
+    let value = value.trim();
+    if value.len() != RESERVED_LEN * 2 || !value.is_ascii() {
+        return Err(anyhow!("reserved must be {} hex digits, got {}", RESERVED_LEN * 2, value.len()));
+    }
+    let mut reserved = [0u8; RESERVED_LEN];
+    for (byte, digits) in reserved.iter_mut().zip(value.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| anyhow!("Invalid hex byte in reserved: {}", digits))?;
+    }
+    Ok(reserved)
 }
 
 #[derive(Debug, Clone)]
@@ -41,123 +82,63 @@ pub struct PackRkafResult {
     pub checksum: u32,
 }
 
-// RockChip CRC-32 table
-const RKCRC32_TABLE: [u32; 256] = [
-    0x00000000, 0x04c10db7, 0x09821b6e, 0x0d4316d9,
-    0x130436dc, 0x17c53b6b, 0x1a862db2, 0x1e472005,
-    0x26086db8, 0x22c9600f, 0x2f8a76d6, 0x2b4b7b61,
-    0x350c5b64, 0x31cd56d3, 0x3c8e400a, 0x384f4dbd,
-    0x4c10db70, 0x48d1d6c7, 0x4592c01e, 0x4153cda9,
-    0x5f14edac, 0x5bd5e01b, 0x5696f6c2, 0x5257fb75,
-    0x6a18b6c8, 0x6ed9bb7f, 0x639aada6, 0x675ba011,
-    0x791c8014, 0x7ddd8da3, 0x709e9b7a, 0x745f96cd,
-    0x9821b6e0, 0x9ce0bb57, 0x91a3ad8e, 0x9562a039,
-    0x8b25803c, 0x8fe48d8b, 0x82a79b52, 0x866696e5,
-    0xbe29db58, 0xbae8d6ef, 0xb7abc036, 0xb36acd81,
-    0xad2ded84, 0xa9ece033, 0xa4aff6ea, 0xa06efb5d,
-    0xd4316d90, 0xd0f06027, 0xddb376fe, 0xd9727b49,
-    0xc7355b4c, 0xc3f456fb, 0xceb74022, 0xca764d95,
-    0xf2390028, 0xf6f80d9f, 0xfbbb1b46, 0xff7a16f1,
-    0xe13d36f4, 0xe5fc3b43, 0xe8bf2d9a, 0xec7e202d,
-    0x34826077, 0x30436dc0, 0x3d007b19, 0x39c176ae,
-    0x278656ab, 0x23475b1c, 0x2e044dc5, 0x2ac54072,
-    0x128a0dcf, 0x164b0078, 0x1b0816a1, 0x1fc91b16,
-    0x018e3b13, 0x054f36a4, 0x080c207d, 0x0ccd2dca,
-    0x7892bb07, 0x7c53b6b0, 0x7110a069, 0x75d1adde,
-    0x6b968ddb, 0x6f57806c, 0x621496b5, 0x66d59b02,
-    0x5e9ad6bf, 0x5a5bdb08, 0x5718cdd1, 0x53d9c066,
-    0x4d9ee063, 0x495fedd4, 0x441cfb0d, 0x40ddf6ba,
-    0xaca3d697, 0xa862db20, 0xa521cdf9, 0xa1e0c04e,
-    0xbfa7e04b, 0xbb66edfc, 0xb625fb25, 0xb2e4f692,
-    0x8aabbb2f, 0x8e6ab698, 0x8329a041, 0x87e8adf6,
-    0x99af8df3, 0x9d6e8044, 0x902d969d, 0x94ec9b2a,
-    0xe0b30de7, 0xe4720050, 0xe9311689, 0xedf01b3e,
-    0xf3b73b3b, 0xf776368c, 0xfa352055, 0xfef42de2,
-    0xc6bb605f, 0xc27a6de8, 0xcf397b31, 0xcbf87686,
-    0xd5bf5683, 0xd17e5b34, 0xdc3d4ded, 0xd8fc405a,
-    0x6904c0ee, 0x6dc5cd59, 0x6086db80, 0x6447d637,
-    0x7a00f632, 0x7ec1fb85, 0x7382ed5c, 0x7743e0eb,
-    0x4f0cad56, 0x4bcda0e1, 0x468eb638, 0x424fbb8f,
-    0x5c089b8a, 0x58c9963d, 0x558a80e4, 0x514b8d53,
-    0x25141b9e, 0x21d51629, 0x2c9600f0, 0x28570d47,
-    0x36102d42, 0x32d120f5, 0x3f92362c, 0x3b533b9b,
-    0x031c7626, 0x07dd7b91, 0x0a9e6d48, 0x0e5f60ff,
-    0x101840fa, 0x14d94d4d, 0x199a5b94, 0x1d5b5623,
-    0xf125760e, 0xf5e47bb9, 0xf8a76d60, 0xfc6660d7,
-    0xe22140d2, 0xe6e04d65, 0xeba35bbc, 0xef62560b,
-    0xd72d1bb6, 0xd3ec1601, 0xdeaf00d8, 0xda6e0d6f,
-    0xc4292d6a, 0xc0e820dd, 0xcdab3604, 0xc96a3bb3,
-    0xbd35ad7e, 0xb9f4a0c9, 0xb4b7b610, 0xb076bba7,
-    0xae319ba2, 0xaaf09615, 0xa7b380cc, 0xa3728d7b,
-    0x9b3dc0c6, 0x9ffccd71, 0x92bfdba8, 0x967ed61f,
-    0x8839f61a, 0x8cf8fbad, 0x81bbed74, 0x857ae0c3,
-    0x5d86a099, 0x5947ad2e, 0x5404bbf7, 0x50c5b640,
-    0x4e829645, 0x4a439bf2, 0x47008d2b, 0x43c1809c,
-    0x7b8ecd21, 0x7f4fc096, 0x720cd64f, 0x76cddbf8,
-    0x688afbfd, 0x6c4bf64a, 0x6108e093, 0x65c9ed24,
-    0x11967be9, 0x1557765e, 0x18146087, 0x1cd56d30,
-    0x02924d35, 0x06534082, 0x0b10565b, 0x0fd15bec,
-    0x379e1651, 0x335f1be6, 0x3e1c0d3f, 0x3add0088,
-    0x249a208d, 0x205b2d3a, 0x2d183be3, 0x29d93654,
-    0xc5a71679, 0xc1661bce, 0xcc250d17, 0xc8e400a0,
-    0xd6a320a5, 0xd2622d12, 0xdf213bcb, 0xdbe0367c,
-    0xe3af7bc1, 0xe76e7676, 0xea2d60af, 0xeeec6d18,
-    0xf0ab4d1d, 0xf46a40aa, 0xf9295673, 0xfde85bc4,
-    0x89b7cd09, 0x8d76c0be, 0x8035d667, 0x84f4dbd0,
-    0x9ab3fbd5, 0x9e72f662, 0x9331e0bb, 0x97f0ed0c,
-    0xafbfa0b1, 0xab7ead06, 0xa63dbbdf, 0xa2fcb668,
-    0xbcbb966d, 0xb87a9bda, 0xb5398d03, 0xb1f880b4,
-];
-
-fn rkcrc32(mut crc: u32, data: &[u8]) -> u32 {
-    for &byte in data {
-        let index = ((crc >> 24) ^ (byte as u32)) as usize;
-        crc = (crc << 8) ^ RKCRC32_TABLE[index & 0xFF];
-    }
-    crc
+fn parse_hex_u32(value: &str) -> Result<u32> {
+    let value = value.trim();
+    let hex_str = value.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex_str, 16)
+        .map_err(|_| anyhow!("Invalid hex value: {}", value))
 }
 
-fn parse_partition_metadata(input_dir: &str) -> Result<HashMap<String, PartitionMetadata>> {
-    let metadata_path = format!("{}/partition-metadata.txt", input_dir);
-    let mut metadata_map = HashMap::new();
-
-    let file = match File::open(&metadata_path) {
-        Ok(f) => f,
-        Err(_) => return Ok(metadata_map),
+fn read_partition_metadata(input_dir: &str) -> Result<Vec<PartitionMetadata>> {
+    let Some((metadata_path, format)) = find_partition_metadata(input_dir) else {
+        return Ok(Vec::new());
     };
+    parse_partition_metadata(&std::fs::read_to_string(&metadata_path)?, format)
+}
+
+fn partition_metadata_by_name(input_dir: &str) -> Result<HashMap<String, PartitionMetadata>> {
+    Ok(read_partition_metadata(input_dir)?
+        .into_iter()
+        .map(|meta| (meta.name.clone(), meta))
+        .collect())
+}
+
+fn read_header_metadata(input_dir: &str) -> Result<HeaderMetadata> {
+    let metadata_path = format!("{}/header-metadata.txt", input_dir);
+    let file = File::open(&metadata_path)
+        .map_err(|_| anyhow!("Cannot find header-metadata.txt in {}", input_dir))?;
 
+    let mut metadata = HeaderMetadata::default();
     let reader = BufReader::new(file);
     for line in reader.lines() {
         let line = line?;
-        let line = line.trim();
-
-        if line.is_empty() {
+        // Values are not trimmed: the model and manufacturer usually carry a leading space
+        let Some((key, value)) = line.split_once(':') else {
             continue;
-        }
+        };
 
-        let parts: Vec<&str> = line.split(',').collect();
-        if parts.len() >= 7 {
-            let name = parts[0].to_string();
-            let flash_size = u32::from_str_radix(parts[2].trim_start_matches("0x"), 16)?;
-            let flash_offset = u32::from_str_radix(parts[3].trim_start_matches("0x"), 16)?;
-            let padded_size = u32::from_str_radix(parts[5].trim_start_matches("0x"), 16)?;
-
-            metadata_map.insert(name, PartitionMetadata {
-                flash_size,
-                flash_offset,
-                padded_size,
-            });
+        match key {
+            "manufacturer" => metadata.manufacturer = value.to_string(),
+            "model" => metadata.model = value.to_string(),
+            "id" => metadata.id = value.to_string(),
+            "version" => metadata.version = parse_hex_u32(value)?,
+            "unknown1" => metadata.unknown1 = parse_hex_u32(value)?,
+            "length" => metadata.length = parse_hex_u32(value)?,
+            "reserved" => metadata.reserved = parse_reserved(value)?,
+            _ => {}
         }
     }
 
-    Ok(metadata_map)
+    Ok(metadata)
 }
 
-pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str, timestamp: i64, code_hex: &str) -> Result<PackRkfwResult> {
-    let hex_str = code_hex.trim_start_matches("0x").trim_start_matches("0X");
-    let code_value = u32::from_str_radix(hex_str, 16)
-        .map_err(|_| anyhow!("Invalid hex value for code field: {}", hex_str))?;
+fn copy_c_string(dst: &mut [u8], value: &str) {
+    let bytes = value.as_bytes();
+    let len = bytes.len().min(dst.len() - 1);
+    dst[..len].copy_from_slice(&bytes[..len]);
+}
 
+fn parse_version(version: &str) -> Result<(u8, u8, u16)> {
     let version_parts: Vec<&str> = version.split('.').collect();
     if version_parts.len() != 3 {
         return Err(anyhow!("Version must be in format: major.minor.build (e.g., 8.1.0)"));
@@ -170,6 +151,14 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
     let build: u16 = version_parts[2].parse()
         .map_err(|_| anyhow!("Invalid build number"))?;
 
+    Ok((major, minor, build))
+}
+
+pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str, timestamp: i64, code_hex: &str) -> Result<PackRkfwResult, ApfError> {
+    let hex_str = code_hex.trim_start_matches("0x").trim_start_matches("0X");
+    let code_value = u32::from_str_radix(hex_str, 16)
+        .map_err(|_| anyhow!("Invalid hex value for code field: {}", hex_str))?;
+
     let chip_code = chip_name_to_code(chip)?;
 
     let boot_path = format!("{}/BOOT", input_dir);
@@ -185,14 +174,88 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
         .map_err(|_| anyhow!("Cannot find embedded-update.img file in {}", input_dir))?
         .read_to_end(&mut update_data)?;
 
-    if update_data.len() < 4 || &update_data[0..4] != b"RKAF" {
+    Ok(write_rkfw(&[], &boot_data, &[], &update_data, &[], output_file, chip, chip_code, version, timestamp, code_value)?)
+}
+
+pub fn repack_rkfw(boot_path: &str, update_path: &str, info: &RkfwInfo, dst: &str) -> Result<PackRkfwResult, ApfError> {
+    let mut boot_data = Vec::new();
+    // Images unpacked without a usable BOOT blob have no BOOT file to read back
+    if info.has_boot || std::path::Path::new(boot_path).exists() {
+        File::open(boot_path)
+            .map_err(|e| anyhow!("Cannot open {}: {}", boot_path, e))?
+            .read_to_end(&mut boot_data)?;
+    }
+
+    let mut update_data = Vec::new();
+    File::open(update_path)
+        .map_err(|e| anyhow!("Cannot open {}: {}", update_path, e))?
+        .read_to_end(&mut update_data)?;
+
+    // Reserved regions are saved next to BOOT, zero-filled ones are only recorded in `info`
+    let dir = std::path::Path::new(boot_path).parent().unwrap_or(std::path::Path::new("."));
+    let reserved_data = |region: Option<ReservedRegion>, file_name: &str| -> Result<Vec<u8>> {
+        match region {
+            None => Ok(Vec::new()),
+            Some(region) if region.zeroed => Ok(vec![0u8; region.size as usize]),
+            Some(region) => {
+                let path = dir.join(file_name);
+                let data = std::fs::read(&path).map_err(|e| anyhow!("Cannot open {}: {}", path.display(), e))?;
+                if data.len() != region.size as usize {
+                    return Err(ApfError::Invalid(format!(
+                        "{} has {} bytes, the reserved region needs {}",
+                        path.display(),
+                        data.len(),
+                        region.size
+                    )).into());
+                }
+                Ok(data)
+            }
+        }
+    };
+    let pre_boot = reserved_data(info.pre_boot, PRE_BOOT_FILE)?;
+    let boot_gap = reserved_data(info.boot_gap, BOOT_GAP_FILE)?;
+    // A signature no longer matches a modified image, but keeps the round trip byte-exact
+    let signature = reserved_data(info.signature, SIGNATURE_FILE)?;
+
+    Ok(write_rkfw(
+        &pre_boot,
+        &boot_data,
+        &boot_gap,
+        &update_data,
+        &signature,
+        dst,
+        &info.chip_family,
+        info.chip_code,
+        &info.version,
+        info.timestamp,
+        info.code,
+    )?)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_rkfw(
+    pre_boot: &[u8],
+    boot_data: &[u8],
+    boot_gap: &[u8],
+    update_data: &[u8],
+    signature: &[u8],
+    output_file: &str,
+    chip: &str,
+    chip_code: u8,
+    version: &str,
+    timestamp: i64,
+    code_value: u32,
+) -> Result<PackRkfwResult> {
+    let (major, minor, build) = parse_version(version)?;
+
+    if !UpdateHeader::has_rkaf_magic(update_data) {
         return Err(anyhow!("embedded-update.img must be a valid RKAF file"));
     }
 
     let header_size = 0x66;
-    let boot_offset = header_size;
+    let boot_offset = header_size + pre_boot.len() as u32;
     let boot_size = boot_data.len() as u32;
-    let update_offset = boot_offset + boot_size;
+    let update_offset = boot_offset + boot_size + boot_gap.len() as u32;
     let update_size = update_data.len() as u32;
 
     let mut header = vec![0u8; header_size as usize];
@@ -212,16 +275,22 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
     header[0x0c] = code_bytes[2];
     header[0x0d] = code_bytes[3];
 
-    let datetime = chrono::DateTime::from_timestamp(timestamp, 0)
-        .ok_or_else(|| anyhow!("Invalid timestamp"))?
-        .naive_utc();
-
-    let year = datetime.year() as u16;
-    let month = datetime.month() as u8;
-    let day = datetime.day() as u8;
-    let hour = datetime.hour() as u8;
-    let minute = datetime.minute() as u8;
-    let second = datetime.second() as u8;
+    // 0 is what unpacking reports for a zeroed date, which is written back as zeros
+    let (year, month, day, hour, minute, second) = if timestamp == 0 {
+        (0, 0, 0, 0, 0, 0)
+    } else {
+        let datetime = chrono::DateTime::from_timestamp(timestamp, 0)
+            .ok_or_else(|| anyhow!("Invalid timestamp"))?
+            .naive_utc();
+        (
+            datetime.year() as u16,
+            datetime.month() as u8,
+            datetime.day() as u8,
+            datetime.hour() as u8,
+            datetime.minute() as u8,
+            datetime.second() as u8,
+        )
+    };
 
     header[0x0e] = (year & 0xFF) as u8;
     header[0x0f] = ((year >> 8) & 0xFF) as u8;
@@ -255,8 +324,11 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
 
     let mut file_data = Vec::new();
     file_data.extend_from_slice(&header);
-    file_data.extend_from_slice(&boot_data);
-    file_data.extend_from_slice(&update_data);
+    file_data.extend_from_slice(pre_boot);
+    file_data.extend_from_slice(boot_data);
+    file_data.extend_from_slice(boot_gap);
+    file_data.extend_from_slice(update_data);
+    file_data.extend_from_slice(signature);
 
     let digest = md5::compute(&file_data);
     let md5_hex = format!("{:x}", digest);
@@ -269,15 +341,15 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
 
     let date_string = format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
 
-    println!("Successfully packed RKFW image:");
-    println!("  Output: {}", output_file);
-    println!("  Version: {}.{}.{}", major, minor, build);
-    println!("  Date: {}", date_string);
-    println!("  Chip: {} (code: 0x{:02x})", chip, chip_code);
-    println!("  BOOT size: {} bytes", boot_size);
-    println!("  Update image size: {} bytes", update_size);
-    println!("  MD5: {}", md5_hex);
-    println!("  Total size: {} bytes", total_size);
+    info!("Successfully packed RKFW image:");
+    info!("  Output: {}", output_file);
+    info!("  Version: {}.{}.{}", major, minor, build);
+    info!("  Date: {}", date_string);
+    info!("  Chip: {} (code: 0x{:02x})", chip, chip_code);
+    info!("  BOOT size: {}", human_size(boot_size as u64));
+    info!("  Update image size: {}", human_size(update_size as u64));
+    info!("  MD5: {}", md5_hex);
+    info!("  Total size: {}", human_size(total_size as u64));
 
     Ok(PackRkfwResult {
         output_file: output_file.to_string(),
@@ -297,19 +369,8 @@ pub fn pack_rkfw(input_dir: &str, output_file: &str, chip: &str, version: &str,
     })
 }
 
-pub fn chip_name_to_code(chip: &str) -> Result<u8> {
-    match chip.to_uppercase().as_str() {
-        "RK29XX" | "RK29" => Ok(0x50),
-        "RK30XX" | "RK30" => Ok(0x60),
-        "RK31XX" | "RK31" => Ok(0x70),
-        "RK32XX" | "RK32" => Ok(0x80),
-        "RK3368" => Ok(0x41),
-        "RK3326" => Ok(0x36),
-        "RK3562" => Ok(0x32),
-        "RK3566" => Ok(0x38),
-        "PX30" => Ok(0x30),
-        _ => Err(anyhow!("Unsupported chip family: {}", chip)),
-    }
+pub fn chip_name_to_code(chip: &str) -> Result<u8, ApfError> {
+    chip_code(chip).ok_or_else(|| ApfError::Invalid(format!("Unsupported chip family: {}", chip)))
 }
 
 fn put_u32_le(slice: &mut [u8], value: u32) {
@@ -320,7 +381,7 @@ fn put_u32_le(slice: &mut [u8], value: u32) {
     slice[3] = bytes[3];
 }
 
-pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer: &str) -> Result<PackRkafResult> {
+pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer: &str) -> Result<PackRkafResult, ApfError> {
     let package_file_path = format!("{}/package-file", input_dir);
     let package_file = File::open(&package_file_path)
         .map_err(|_| anyhow!("Cannot find package-file in {}", input_dir))?;
@@ -343,18 +404,16 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
     }
 
     if file_list.is_empty() {
-        return Err(anyhow!("No files found in package-file"));
+        return Err(ApfError::Invalid("No files found in package-file".to_string()));
     }
 
     let mut machine_id = String::new();
     if let Ok(param_file) = File::open(format!("{}/parameter.txt", input_dir)) {
         let reader = BufReader::new(param_file);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if line.starts_with("MACHINE_ID:") {
-                    machine_id = line.split(':').nth(1).unwrap_or("").trim().to_string();
-                    break;
-                }
+        for line in reader.lines().map_while(Result::ok) {
+            if line.starts_with("MACHINE_ID:") {
+                machine_id = line.split(':').nth(1).unwrap_or("").trim().to_string();
+                break;
             }
         }
     }
@@ -381,28 +440,20 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
     header.manufacturer[..len].copy_from_slice(&manufacturer_bytes[..len]);
 
     if !machine_id.is_empty() {
-        let id_bytes = format!(" {}", machine_id);
-        let id_bytes = id_bytes.as_bytes();
-        let len = id_bytes.len().min(30 - 1); // MAX_ID_LEN is 30
-        unsafe {
-            let header_ptr = &header as *const UpdateHeader as *const u8;
-            let id_offset = 42; // id field offset in UpdateHeader
-            let id_ptr = header_ptr.add(id_offset) as *mut u8;
-            std::ptr::copy_nonoverlapping(id_bytes.as_ptr(), id_ptr, len);
-        }
+        copy_c_string(&mut header.id, &format!(" {}", machine_id));
     }
 
     header.num_parts = file_list.len() as u32;
     header.version = 0x01000000; // Version
 
-    let partition_metadata = parse_partition_metadata(input_dir)?;
+    let partition_metadata = partition_metadata_by_name(input_dir)?;
     if partition_metadata.is_empty() {
-        return Err(anyhow!("Missing partition metadata"));
+        return Err(ApfError::Invalid("Missing partition metadata".to_string()));
     }
 
     let header_size = std::mem::size_of::<UpdateHeader>();
     let sector_size = 2048;
-    let mut current_offset = ((header_size + sector_size - 1) / sector_size) * sector_size;
+    let mut current_offset = header_size.div_ceil(sector_size) * sector_size;
 
     let mut file_data_map: HashMap<String, (Vec<u8>, u32, u32)> = HashMap::new();
     let mut file_data_list = Vec::new();
@@ -421,7 +472,7 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
                 .read_to_end(&mut file_data)?;
 
             let file_size = file_data.len() as u32;
-            let padded_size = ((file_size + sector_size as u32 - 1) / sector_size as u32) * sector_size as u32;
+            let padded_size = file_size.div_ceil(sector_size as u32) * sector_size as u32;
             let file_offset = current_offset as u32;
 
             file_data_map.insert(path.clone(), (file_data.clone(), file_offset, padded_size));
@@ -447,7 +498,7 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
             part.flash_offset = meta.flash_offset;
             part.padded_size = meta.padded_size;
         } else {
-            return Err(anyhow!("Missing partition metadata for {:}", name));
+            return Err(ApfError::Invalid(format!("Missing partition metadata for {:}", name)));
         }
 
         part.part_offset = file_offset;
@@ -486,12 +537,12 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
 
     let num_parts = header.num_parts;
 
-    println!("Successfully packed RKAF image:");
-    println!("  Output: {}", output_file);
-    println!("  Model: {}", model);
-    println!("  Manufacturer: {}", manufacturer);
-    println!("  Parts: {}", num_parts);
-    println!("  Total size: {} bytes", current_offset);
+    info!("Successfully packed RKAF image:");
+    info!("  Output: {}", output_file);
+    info!("  Model: {}", model);
+    info!("  Manufacturer: {}", manufacturer);
+    info!("  Parts: {}", num_parts);
+    info!("  Total size: {}", human_size(current_offset as u64));
 
     Ok(PackRkafResult {
         output_file: output_file.to_string(),
@@ -503,3 +554,357 @@ pub fn pack_rkaf(input_dir: &str, output_file: &str, model: &str, manufacturer:
         checksum,
     })
 }
+
+
+// The header `pack_rkafp` starts from: the header fields and the partition table as recorded,
+// before the sizes of the partition files are known
+fn metadata_header(header_metadata: &HeaderMetadata, partition_metadata: &[PartitionMetadata]) -> Result<UpdateHeader, ApfError> {
+    if partition_metadata.is_empty() {
+        return Err(ApfError::Invalid("Missing partition metadata".to_string()));
+    }
+    if partition_metadata.len() > MAX_PARTS {
+        return Err(ApfError::Invalid(format!(
+            "Too many partitions: {} (max {})",
+            partition_metadata.len(),
+            MAX_PARTS
+        )));
+    }
+
+    let mut header = UpdateHeader::default();
+    header.magic.copy_from_slice(RKAF_SIGNATURE);
+    copy_c_string(&mut header.model, &header_metadata.model);
+    copy_c_string(&mut header.manufacturer, &header_metadata.manufacturer);
+    copy_c_string(&mut header.id, &header_metadata.id);
+    header.version = header_metadata.version;
+    header.unknown1 = header_metadata.unknown1;
+    header.length = header_metadata.length;
+    header.reserved = header_metadata.reserved;
+    header.num_parts = partition_metadata.len() as u32;
+    for (part, meta) in header.parts.iter_mut().zip(partition_metadata) {
+        copy_c_string(&mut part.name, &meta.name);
+        copy_c_string(&mut part.full_path, &meta.path);
+        part.flash_size = meta.flash_size;
+        part.flash_offset = meta.flash_offset;
+        part.part_offset = meta.part_offset;
+        part.padded_size = meta.padded_size;
+        part.part_byte_count = meta.part_byte_count;
+    }
+    Ok(header)
+}
+
+/// Checks the partition metadata and `header-metadata.txt` of a directory written by
+/// `unpack_file`, e.g. after editing them by hand, with `validate_layout` against the recorded
+/// `length`, without opening any partition file (`afptool-rs check-metadata DIR`). Parse errors
+/// are returned as errors, layout problems as warnings.
+pub fn check_metadata(src_dir: &str) -> Result<Vec<LayoutWarning>, ApfError> {
+    let header_metadata = read_header_metadata(src_dir)?;
+    let header = metadata_header(&header_metadata, &read_partition_metadata(src_dir)?)?;
+    let info = parse_rkafp(&header, header_metadata.length as u64 + 4, TextEncoding::Auto);
+    Ok(validate_layout(&info, info.declared_length))
+}
+
+// Used for a new layout when no recorded offset tells the granularity, as `pack_rkaf` pads
+const FALLBACK_ALIGNMENT: u32 = 2048;
+
+// Granularity of the recorded partition offsets, as `detect_alignment` finds it for an image
+fn recorded_alignment(partition_metadata: &[PartitionMetadata]) -> u32 {
+    let combined = partition_metadata.iter().filter(|meta| !meta.skip_extract).fold(0u32, |acc, meta| acc | meta.part_offset);
+    if combined == 0 {
+        FALLBACK_ALIGNMENT
+    } else {
+        1 << combined.trailing_zeros()
+    }
+}
+
+// Moves the partitions next to each other after the header in their order in the image, each
+// slot starting on an `alignment` boundary and sized to its data rounded up (one unit for empty
+// partitions); `blobs` are keyed by their recorded offset and get the new one. Returns the end
+// of the last slot.
+fn lay_out_partitions(header: &mut UpdateHeader, partition_metadata: &[PartitionMetadata], blobs: &mut [(u32, Vec<u8>)], alignment: u32) -> Result<u32, ApfError> {
+    let align = |value: u64| value.div_ceil(alignment as u64) * alignment as u64;
+    blobs.sort_by_key(|(offset, _)| *offset);
+    let mut offset = align(std::mem::size_of::<UpdateHeader>() as u64);
+    for (recorded, data) in blobs.iter_mut() {
+        let slot = align(data.len().max(1) as u64);
+        if offset + slot > u32::MAX as u64 {
+            return Err(ApfError::Invalid(format!("Partitions aligned to {:#x} bytes do not fit in a 4 GiB image", alignment)));
+        }
+        for (part, meta) in header.parts.iter_mut().zip(partition_metadata) {
+            if !meta.skip_extract && meta.part_offset == *recorded {
+                part.part_offset = offset as u32;
+                part.padded_size = slot as u32;
+            }
+        }
+        *recorded = offset as u32;
+        offset += slot;
+    }
+    Ok(offset as u32)
+}
+
+/// How `pack_rkafp_with_options` rebuilds an image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackOptions {
+    /// Lay the partitions out again at this alignment, see `pack_rkafp_with_alignment`
+    pub alignment: Option<u32>,
+    /// Take `part_byte_count` from the partition files on disk instead of refusing files whose
+    /// size no longer matches the metadata, e.g. after editing a partition (`--allow-resized`)
+    pub allow_resized: bool,
+}
+
+/// Rebuilds an RKAF image from a directory written by `unpack_file`. Every partition file has to
+/// have the `part_byte_count` recorded in the metadata, so that a stale or mistyped file is not
+/// packed silently. This replaces the earlier behaviour of always taking `part_byte_count` from the
+/// files on disk, which is still available as `PackOptions::allow_resized` to repack edited
+/// partitions.
+pub fn pack_rkafp(src_dir: &str, dst_file: &str) -> Result<PackRkafResult, ApfError> {
+    pack_rkafp_with_options(src_dir, dst_file, &PackOptions::default())
+}
+
+/// Like `pack_rkafp`, but with `alignment` the partitions are laid out again: in their order in
+/// the image, each starting at the next multiple of `alignment` after the header and the previous
+/// one, with `part_byte_count` rounded up to it as `padded_size` (one unit for empty partitions)
+/// and the image ending after the last one. Without it the recorded offsets are kept unless a
+/// partition file outgrew its `padded_size`; then the partitions are laid out at the granularity
+/// of the recorded offsets (see `detect_alignment`). `alignment` has to be a power of two.
+pub fn pack_rkafp_with_alignment(src_dir: &str, dst_file: &str, alignment: Option<u32>) -> Result<PackRkafResult, ApfError> {
+    pack_rkafp_with_options(src_dir, dst_file, &PackOptions { alignment, ..Default::default() })
+}
+
+/// `pack_rkafp` with the alignment and the handling of resized partition files in `options`
+pub fn pack_rkafp_with_options(src_dir: &str, dst_file: &str, options: &PackOptions) -> Result<PackRkafResult, ApfError> {
+    let alignment = options.alignment;
+    if let Some(alignment) = alignment.filter(|alignment| !alignment.is_power_of_two()) {
+        return Err(ApfError::Invalid(format!("Alignment {} is not a power of two", alignment)));
+    }
+    let header_metadata = read_header_metadata(src_dir)?;
+    let partition_metadata = read_partition_metadata(src_dir)?;
+    let mut header = metadata_header(&header_metadata, &partition_metadata)?;
+
+    // Partitions sharing an offset reference the same data and are only written once
+    let mut blobs: Vec<(u32, Vec<u8>)> = Vec::new();
+
+    for (i, meta) in partition_metadata.iter().enumerate() {
+        if meta.skip_extract {
+            continue;
+        }
+
+        // Images built on Windows store `Image\boot.img`, which unpacks to `Image/boot.img`
+        let mut file_path = format!("{}/{}", src_dir, meta.path.replace('\\', "/"));
+        // A flat unpack (`OutputNaming::Flat`) stores the file under its base name
+        if !std::path::Path::new(&file_path).exists() {
+            let base_name = meta.path.rsplit(['/', '\\']).next().unwrap_or_default();
+            let flat_path = format!("{}/{}", src_dir, base_name);
+            if std::path::Path::new(&flat_path).is_file() {
+                file_path = flat_path;
+            }
+        }
+        let mut file_data = Vec::new();
+        File::open(&file_path)
+            .map_err(|e| anyhow!("Cannot open {}: {}", file_path, e))?
+            .read_to_end(&mut file_data)?;
+
+        let size = u32::try_from(file_data.len())
+            .map_err(|_| ApfError::Invalid(format!("{} is {} bytes, partitions are limited to 4 GiB", file_path, file_data.len())))?;
+        if size != meta.part_byte_count {
+            if !options.allow_resized {
+                return Err(ApfError::Invalid(format!(
+                    "{} is {} bytes but the metadata records {} for {}, allow resized partitions (--allow-resized) to repack it",
+                    file_path, size, meta.part_byte_count, meta.name
+                )));
+            }
+            info!("{}: size changed from {} to {} bytes", meta.path, meta.part_byte_count, size);
+        }
+
+        header.parts[i].part_byte_count = size;
+
+        // Non-zero padding captured on unpack only still lines up if the data kept its size
+        let padding_path = format!("{}.padding", file_path);
+        if size == meta.part_byte_count && std::path::Path::new(&padding_path).exists() {
+            file_data.extend_from_slice(&std::fs::read(&padding_path)?);
+        }
+
+        if !blobs.iter().any(|(offset, _)| *offset == meta.part_offset) {
+            blobs.push((meta.part_offset, file_data));
+        }
+    }
+
+    let grown = header.parts.iter().zip(&partition_metadata).any(|(part, meta)| !meta.skip_extract && part.part_byte_count > meta.padded_size);
+    let alignment = alignment.or_else(|| grown.then(|| recorded_alignment(&partition_metadata)));
+    let slots_end = match alignment {
+        Some(alignment) => {
+            info!("Laying out partitions at {:#x}-byte alignment", alignment);
+            Some(lay_out_partitions(&mut header, &partition_metadata, &mut blobs, alignment)?)
+        }
+        None => None,
+    };
+
+    blobs.sort_by_key(|(offset, _)| *offset);
+
+    let header_size = std::mem::size_of::<UpdateHeader>();
+    let mut end = header_size;
+    for (offset, data) in blobs.iter() {
+        if (*offset as usize) < end {
+            return Err(ApfError::Invalid(format!("Partition at offset {:#010x} overlaps previous data", offset)));
+        }
+        end = *offset as usize + data.len();
+    }
+
+    // A new layout ends with the last slot, the recorded length belongs to the old one
+    let length = match slots_end {
+        Some(slots_end) => slots_end as usize,
+        None => end.max(header_metadata.length as usize),
+    };
+    header.length = length as u32;
+
+    // SELF always describes the whole image, RESERVED keeps its recorded geometry
+    for part in header.parts.iter_mut().take(partition_metadata.len()) {
+        if part.full_path.starts_with(b"SELF\0") {
+            part.part_offset = 0;
+            part.padded_size = length as u32;
+            part.part_byte_count = length as u32;
+        }
+    }
+
+    let mut image = Vec::with_capacity(length + 4);
+    image.extend_from_slice(header.to_bytes());
+    for (offset, data) in blobs.iter() {
+        image.resize(*offset as usize, 0);
+        image.extend_from_slice(data);
+    }
+    image.resize(length, 0);
+
+    let checksum = rkcrc32(0, &image);
+    image.extend_from_slice(&checksum.to_le_bytes());
+
+    let mut out_file = File::create(dst_file)?;
+    out_file.write_all(&image)?;
+
+    let num_parts = header.num_parts;
+
+    info!("Successfully packed RKAF image:");
+    info!("  Output: {}", dst_file);
+    info!("  Model: {}", header_metadata.model);
+    info!("  Manufacturer: {}", header_metadata.manufacturer);
+    info!("  Parts: {}", num_parts);
+    info!("  Total size: {}", human_size(length as u64));
+
+    Ok(PackRkafResult {
+        output_file: dst_file.to_string(),
+        model: header_metadata.model,
+        manufacturer: header_metadata.manufacturer,
+        machine_id: if header_metadata.id.is_empty() { None } else { Some(header_metadata.id) },
+        num_parts,
+        total_size: length,
+        checksum,
+    })
+}
+
+// Merge version written to the RKFW `code` field of images built by `make_firmware`
+const MAKE_FIRMWARE_CODE: u32 = 0x01000000;
+
+// `KEY: value` line of a parameter file, e.g. `MACHINE_MODEL: RK3326`
+fn parameter_value(text: &str, key: &str) -> Option<String> {
+    text.lines()
+        .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix(':').map(|value| value.trim().to_string()))
+        .filter(|value| !value.is_empty())
+}
+
+// `FIRMWARE_VER` as major.minor.build, `1.0` meaning 1.0.0
+fn firmware_version(text: &str) -> Result<String> {
+    let Some(version) = parameter_value(text, "FIRMWARE_VER") else {
+        return Ok("1.0.0".to_string());
+    };
+    let version = if version.split('.').count() == 2 { format!("{}.0", version) } else { version };
+    parse_version(&version).map_err(|e| anyhow!("Invalid FIRMWARE_VER {} in the parameter file: {}", version, e))?;
+    Ok(version)
+}
+
+/// Builds a new RKFW image from scratch, like Rockchip's `afptool -pack` followed by
+/// `rkImageMaker`: `partitions` (name, file) are packed into an RKAF image after the parameter
+/// file and the loader, then the loader becomes BOOT of an RKFW image for `chip` written to
+/// `output`. Each partition is stored as `Image/<file name>` and gets its flash offset and size
+/// from the `mtdparts` of the parameter file, which has to declare it; the parameter is stored
+/// with a `PARM` header unless it already has one. The model, manufacturer and machine id come
+/// from `MACHINE_MODEL`, `MANUFACTURER` and `MACHINE_ID` (the model and manufacturer default to
+/// `chip`), the RKAF and RKFW versions from `FIRMWARE_VER` (default 1.0.0), and the build date is
+/// `SOURCE_DATE_EPOCH` when set, otherwise the current time.
+pub fn make_firmware(loader: &str, parameter: &str, partitions: &[(String, String)], chip: &str, output: &str) -> Result<PackRkfwResult, ApfError> {
+    let chip_code = chip_name_to_code(chip)?;
+    let read = |path: &str| std::fs::read(path).map_err(|e| ApfError::from(anyhow!("Cannot open {}: {}", path, e)));
+    let loader_data = read(loader)?;
+    let mut parameter_data = read(parameter)?;
+    let declared = parse_parameter(&parameter_data)?;
+    // A parameter that already has a PARM header has its text after the magic and length
+    let text = String::from_utf8_lossy(parameter_data.strip_prefix(PARM_MAGIC.as_bytes()).map_or(&parameter_data[..], |rest| rest.get(4..).unwrap_or_default())).to_string();
+    if !parameter_data.starts_with(PARM_MAGIC.as_bytes()) {
+        let text = std::mem::take(&mut parameter_data);
+        parameter_data.extend_from_slice(PARM_MAGIC.as_bytes());
+        parameter_data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        parameter_data.extend_from_slice(&text);
+        parameter_data.extend_from_slice(&rkcrc32(0, &text).to_le_bytes());
+    }
+    let version = firmware_version(&text)?;
+    let (major, minor, build) = parse_version(&version)?;
+
+    if partitions.len() + 2 > MAX_PARTS {
+        return Err(ApfError::Invalid(format!("Too many partitions: {} (max {} with parameter and loader)", partitions.len(), MAX_PARTS - 2)));
+    }
+    // (name, stored path, flash offset, flash size, data)
+    let mut entries = vec![
+        ("parameter".to_string(), "parameter.txt".to_string(), 0, 0, parameter_data),
+        ("bootloader".to_string(), "MiniLoaderAll.bin".to_string(), 0, 0, loader_data.clone()),
+    ];
+    for (name, file) in partitions {
+        let declared = declared
+            .iter()
+            .find(|declared| declared.name == *name)
+            .ok_or_else(|| ApfError::Invalid(format!("{} does not declare a partition named {}", parameter, name)))?;
+        let file_name = std::path::Path::new(file).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let path = format!("Image/{}", file_name);
+        if entries.iter().any(|(other, ..)| other == name) {
+            return Err(ApfError::Invalid(format!("Partition {} is given more than once", name)));
+        }
+        if let Some((other, ..)) = entries.iter().find(|(_, other_path, ..)| *other_path == path) {
+            return Err(ApfError::Invalid(format!("{} and {} would both be stored as {}", other, name, path)));
+        }
+        let flash_size = declared.sector_count.unwrap_or(FLASH_SIZE_GROW);
+        entries.push((name.clone(), path, declared.start_sector, flash_size, read(file)?));
+    }
+
+    let mut header = UpdateHeader::default();
+    header.magic.copy_from_slice(RKAF_SIGNATURE);
+    copy_c_string(&mut header.model, &parameter_value(&text, "MACHINE_MODEL").unwrap_or_else(|| chip.to_string()));
+    copy_c_string(&mut header.manufacturer, &parameter_value(&text, "MANUFACTURER").unwrap_or_else(|| chip.to_string()));
+    copy_c_string(&mut header.id, &parameter_value(&text, "MACHINE_ID").unwrap_or_default());
+    header.version = (major as u32) << 24 | (minor as u32) << 16 | build as u32;
+    header.num_parts = entries.len() as u32;
+
+    let align = |value: usize| value.div_ceil(FALLBACK_ALIGNMENT as usize) * FALLBACK_ALIGNMENT as usize;
+    let mut image = header.to_bytes().to_vec();
+    for (part, (name, path, flash_offset, flash_size, data)) in header.parts.iter_mut().zip(&entries) {
+        image.resize(align(image.len()), 0);
+        copy_c_string(&mut part.name, name);
+        copy_c_string(&mut part.full_path, path);
+        part.flash_offset = *flash_offset;
+        part.flash_size = *flash_size;
+        part.part_offset = image.len() as u32;
+        part.part_byte_count = data.len() as u32;
+        part.padded_size = align(data.len().max(1)) as u32;
+        image.extend_from_slice(data);
+    }
+    image.resize(align(image.len()), 0);
+    if image.len() > u32::MAX as usize {
+        return Err(ApfError::Invalid(format!("The partitions do not fit in a 4 GiB image ({} bytes)", image.len())));
+    }
+    header.length = image.len() as u32;
+    image[..std::mem::size_of::<UpdateHeader>()].copy_from_slice(header.to_bytes());
+    let checksum = rkcrc32(0, &image);
+    image.extend_from_slice(&checksum.to_le_bytes());
+    info!("Packed {} partitions into a {} RKAF image", entries.len(), human_size(image.len() as u64));
+
+    let timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse().map_err(|_| anyhow!("Invalid SOURCE_DATE_EPOCH: {}", epoch))?,
+        Err(_) => chrono::Utc::now().timestamp(),
+    };
+    Ok(write_rkfw(&[], &loader_data, &[], &image, &[], output, chip, chip_code, &version, timestamp, MAKE_FIRMWARE_CODE)?)
+}
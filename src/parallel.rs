@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use anyhow::Result;
+use std::time::Instant;
+use log::{debug, info};
+use crate::unpack::{copy_to_writer, format_range, format_throughput, human_size, is_cancelled, remove_if_cancelled, PartitionInfo, PartitionStatus, Progress};
+
+/// Positioned reads are only implemented for Unix and Windows, elsewhere partitions are always
+/// extracted one after another
+pub(crate) const CAN_EXTRACT_IN_PARALLEL: bool = cfg!(any(unix, windows));
+
+// A partition copied by `extract_parallel`, offsets are relative to the image
+pub(crate) struct CopyJob {
+    /// Index of the partition in `RkafInfo::partitions`
+    pub(crate) index: usize,
+    pub(crate) part: PartitionInfo,
+    pub(crate) path: String,
+}
+
+impl CopyJob {
+    fn offset(&self) -> u64 {
+        self.part.part_offset as u64
+    }
+
+    fn len(&self) -> u64 {
+        self.part.part_byte_count as u64
+    }
+}
+
+// Reads `file` from `base` on without moving its cursor, so several threads can share it
+struct PositionedReader<'a> {
+    file: &'a File,
+    base: u64,
+    pos: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_bytes = read_at(self.file, buf, self.base + self.pos)?;
+        self.pos += read_bytes as u64;
+        Ok(read_bytes)
+    }
+}
+
+impl Seek for PositionedReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.file.metadata()?.len().saturating_sub(self.base).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the image"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(_file: &File, _buf: &mut [u8], _offset: u64) -> std::io::Result<usize> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// Copies every job out of the image at `base` in `file` on up to `threads` threads. Each file
+// is logged and reported to `progress` as (len, len) and as finished once it is complete, from
+// the calling thread; the workers check its cancel flag. After the first failure no new jobs are started and
+// that error is returned, unless `keep_going` is set: then every job runs and the failures are
+// returned by position in `jobs`. Cancelling always stops.
+pub(crate) fn extract_parallel(
+    file: &File,
+    base: u64,
+    jobs: &[CopyJob],
+    threads: usize,
+    buffer_size: usize,
+    keep_going: bool,
+    progress: Progress<'_>,
+) -> Result<Vec<(usize, anyhow::Error)>> {
+    // Only the flag goes to the workers, the callback is not `Sync`
+    let cancel = progress.cancel;
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(jobs.len()) {
+            let sender = sender.clone();
+            let (next, failed) = (&next, &failed);
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(index) else { break };
+                    let mut reader = PositionedReader { file, base, pos: 0 };
+                    let started = Instant::now();
+                    let result = File::create(&job.path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|mut out| copy_to_writer(&mut reader, job.offset(), job.len(), &mut out, buffer_size, Progress { cancel, ..Progress::default() }))
+                        .map(|()| started.elapsed());
+                    let result = remove_if_cancelled(result, &job.path);
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut outcome = Ok(());
+        let mut failures = Vec::new();
+        for (index, result) in receiver {
+            let job = &jobs[index];
+            match result {
+                Ok(elapsed) => {
+                    info!("{:17} {:26} (size: {})", format_range(job.offset(), job.len()), job.path, human_size(job.len()));
+                    debug!("{} written in {:.1?} ({})", job.path, elapsed, format_throughput(job.len(), elapsed));
+                    progress.report(job.len(), job.len());
+                    progress.finished(&job.part, &job.path, PartitionStatus::Extracted, job.len());
+                }
+                Err(e) if keep_going && !is_cancelled(&e) => {
+                    progress.failed(&job.part, &job.path, &e);
+                    failures.push((index, e));
+                }
+                Err(e) if outcome.is_ok() => {
+                    progress.failed(&job.part, &job.path, &e);
+                    failed.store(true, Ordering::Relaxed);
+                    outcome = Err(e);
+                }
+                Err(_) => {}
+            }
+        }
+        failures.sort_by_key(|&(index, _)| index);
+        outcome.map(|()| failures)
+    })
+}
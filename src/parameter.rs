@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Result};
+use crate::error::ApfError;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::unpack::{PartitionInfo, RkafInfo, FLASH_SIZE_GROW};
+use crate::PARM_MAGIC;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ParameterPartition {
+    pub name: String,
+    /// Start of the partition in 512-byte sectors
+    pub start_sector: u32,
+    /// Size in 512-byte sectors, `None` for a partition that grows to the end of the flash
+    pub sector_count: Option<u32>,
+}
+
+fn parse_sector_value(value: &str) -> Result<u32> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex_str) => u32::from_str_radix(hex_str, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| anyhow!("Invalid sector value in mtdparts: {}", value))
+}
+
+// Parses a single `<size>@<offset>(<name>[:flags])` entry
+fn parse_partition_def(def: &str) -> Result<ParameterPartition> {
+    let (geometry, rest) = def
+        .split_once('(')
+        .ok_or_else(|| anyhow!("Missing partition name in mtdparts entry: {}", def))?;
+    let name = rest
+        .strip_suffix(')')
+        .ok_or_else(|| anyhow!("Unterminated partition name in mtdparts entry: {}", def))?;
+    let name = name.split(':').next().unwrap_or(name).to_string();
+
+    let (size, offset) = geometry
+        .split_once('@')
+        .ok_or_else(|| anyhow!("Missing partition offset in mtdparts entry: {}", def))?;
+    let sector_count = if size.trim() == "-" {
+        None
+    } else {
+        Some(parse_sector_value(size)?)
+    };
+
+    Ok(ParameterPartition {
+        name,
+        start_sector: parse_sector_value(offset)?,
+        sector_count,
+    })
+}
+
+pub fn parse_parameter(data: &[u8]) -> Result<Vec<ParameterPartition>, ApfError> {
+    // parameter files stored in an update image carry a PARM header: magic, length, text, CRC
+    let text = if data.starts_with(PARM_MAGIC.as_bytes()) {
+        if data.len() < 8 {
+            return Err(ApfError::Truncated("Truncated PARM header".to_string()));
+        }
+        let length = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        data.get(8..8 + length)
+            .ok_or_else(|| ApfError::Truncated(format!("PARM length ({}) exceeds parameter size ({})", length, data.len())))?
+    } else {
+        data
+    };
+    let text = String::from_utf8_lossy(text);
+
+    let cmdline = text
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("CMDLINE:"))
+        .ok_or_else(|| anyhow!("No CMDLINE found in parameter"))?;
+    let mtdparts = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("mtdparts=").or_else(|| arg.strip_prefix("blkdevparts=")))
+        .ok_or_else(|| anyhow!("No mtdparts found in parameter CMDLINE"))?;
+
+    let mut partitions = Vec::new();
+    for device in mtdparts.split(';') {
+        let (_, defs) = device
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Missing device id in mtdparts: {}", device))?;
+        for def in defs.split(',').filter(|def| !def.is_empty()) {
+            partitions.push(parse_partition_def(def)?);
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Builds a parameter file for an image without a `parameter` partition: `FIRMWARE_VER`,
+/// `MACHINE_MODEL` and `MANUFACTURER` from the header and a `CMDLINE: mtdparts=rk29xxnand:...`
+/// line with every partition that has a flash region (`flash_size` non-zero), in flash order,
+/// as `<sectors>@<start sector>(<name>)`; grow partitions get `-` as the size and the `grow`
+/// flag. `parse_parameter` reads the result back.
+pub fn generate_parameter(info: &RkafInfo) -> String {
+    let mut partitions: Vec<&PartitionInfo> = info.partitions.iter().filter(|part| part.flash_size != 0).collect();
+    partitions.sort_by_key(|part| part.flash_offset);
+    let mut defs: Vec<String> = Vec::new();
+    for part in partitions {
+        if part.flash_size == FLASH_SIZE_GROW {
+            defs.push(format!("-@{:#010x}({}:grow)", part.flash_offset, part.name));
+        } else {
+            defs.push(format!("{:#010x}@{:#010x}({})", part.flash_size, part.flash_offset, part.name));
+        }
+    }
+    format!(
+        "FIRMWARE_VER: {}\nMACHINE_MODEL: {}\nMANUFACTURER: {}\nCMDLINE: mtdparts=rk29xxnand:{}\n",
+        info.version,
+        info.model.trim(),
+        info.manufacturer.trim(),
+        defs.join(",")
+    )
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+use log::info;
+use crate::error::ApfError;
+use crate::metadata::find_partition_metadata;
+use crate::pack::{pack_rkafp_with_options, repack_rkfw, PackOptions, PackRkafResult, PackRkfwResult};
+use crate::unpack::{RkfwInfo, EMBEDDED_DIR, RKFW_INFO_FILE};
+use crate::verify::{verify_file, VerifyReport};
+
+#[derive(Debug, Clone)]
+pub struct RepackResult {
+    /// Set when the RKAF image was rebuilt from the partition metadata
+    pub rkaf: Option<PackRkafResult>,
+    /// Set when the output was wrapped in RKFW again
+    pub rkfw: Option<PackRkfwResult>,
+    /// Verification of the written image, every check passed
+    pub report: VerifyReport,
+}
+
+/// Rebuilds an image from a directory written by `unpack_file` and verifies the result.
+///
+/// A directory with `rkfw-info.json` came from an RKFW image and is wrapped in RKFW again; its
+/// embedded RKAF is rebuilt from the partition metadata (in any `MetadataFormat`) when that was
+/// unpacked into the same directory or into `embedded-update/` by a recursive unpack, otherwise
+/// `embedded-update.img` is used as is.
+pub fn repack_dir(src_dir: &str, dst_file: &str) -> Result<RepackResult, ApfError> {
+    repack_dir_with_alignment(src_dir, dst_file, None)
+}
+
+/// Like `repack_dir`, but an RKAF image rebuilt from the partition metadata is laid out at
+/// `alignment` as `pack_rkafp_with_alignment` does; `embedded-update.img` used as is keeps its layout
+pub fn repack_dir_with_alignment(src_dir: &str, dst_file: &str, alignment: Option<u32>) -> Result<RepackResult, ApfError> {
+    repack_dir_with_options(src_dir, dst_file, &PackOptions { alignment, ..Default::default() })
+}
+
+/// Like `repack_dir`, rebuilding the RKAF image with `pack_rkafp_with_options`
+pub fn repack_dir_with_options(src_dir: &str, dst_file: &str, options: &PackOptions) -> Result<RepackResult, ApfError> {
+    let dir = Path::new(src_dir);
+    let has_rkaf_metadata = find_partition_metadata(src_dir).is_some();
+    let rkfw_info_path = dir.join(RKFW_INFO_FILE);
+
+    let mut result = if rkfw_info_path.exists() {
+        let info = read_rkfw_info(&rkfw_info_path)?;
+        let boot_path = format!("{}/BOOT", src_dir);
+        let embedded_dir = format!("{}/{}", src_dir, EMBEDDED_DIR);
+        let rkaf_dir = if has_rkaf_metadata {
+            Some(src_dir.to_string())
+        } else {
+            find_partition_metadata(&embedded_dir).map(|_| embedded_dir)
+        };
+        if let Some(rkaf_dir) = rkaf_dir {
+            let update_path = format!("{}.rkaf.tmp", dst_file);
+            let rebuilt = pack_rkafp_with_options(&rkaf_dir, &update_path, options)
+                .and_then(|rkaf| Ok((rkaf, repack_rkfw(&boot_path, &update_path, &info, dst_file)?)));
+            std::fs::remove_file(&update_path).ok();
+            let (rkaf, rkfw) = rebuilt?;
+            RepackResult { rkaf: Some(rkaf), rkfw: Some(rkfw), report: VerifyReport::default() }
+        } else {
+            let update_path = format!("{}/embedded-update.img", src_dir);
+            let rkfw = repack_rkfw(&boot_path, &update_path, &info, dst_file)?;
+            RepackResult { rkaf: None, rkfw: Some(rkfw), report: VerifyReport::default() }
+        }
+    } else if has_rkaf_metadata {
+        let rkaf = pack_rkafp_with_options(src_dir, dst_file, options)?;
+        RepackResult { rkaf: Some(rkaf), rkfw: None, report: VerifyReport::default() }
+    } else {
+        return Err(ApfError::Invalid(format!(
+            "{} contains neither {} nor partition metadata",
+            src_dir, RKFW_INFO_FILE
+        )));
+    };
+
+    result.report = verify_file(dst_file)?;
+    if !result.report.passed() {
+        let failed: Vec<String> = result.report.checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| format!("{} ({})", check.name, check.details))
+            .collect();
+        return Err(ApfError::Invalid(format!("Repacked image failed verification: {}", failed.join(", "))));
+    }
+    info!("Verified {}: {} checks passed", dst_file, result.report.checks.len());
+    Ok(result)
+}
+
+#[cfg(feature = "serde")]
+fn read_rkfw_info(path: &Path) -> Result<RkfwInfo, ApfError> {
+    serde_json::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|err| ApfError::Invalid(format!("Invalid {}: {}", RKFW_INFO_FILE, err)))
+}
+
+#[cfg(not(feature = "serde"))]
+fn read_rkfw_info(_path: &Path) -> Result<RkfwInfo, ApfError> {
+    Err(ApfError::Invalid(format!("Reading {} needs the serde feature", RKFW_INFO_FILE)))
+}
@@ -0,0 +1,131 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use log::{info, warn};
+use crate::crc::{update_crc, CrcUpdate};
+use crate::encoding::{decode_header_string, TextEncoding};
+use crate::error::ApfError;
+use crate::signature::{read_signature, ImageKind};
+use crate::unpack::{check_num_parts, locate_embedded_image, offsets_in_sectors, parse_rkfw, read_bytes_at, stream_len, UnpackOptions, FLASH_SIZE_GROW, RKFW_HEADER_SIZE};
+use crate::{UpdateHeader, UpdatePart};
+
+const ZERO_CHUNK: usize = 1024 * 1024;
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaceResult {
+    /// Name of the replaced partition as stored in the header
+    pub name: String,
+    /// `part_byte_count` before and after the replacement
+    pub previous_size: u32,
+    pub size: u32,
+    /// Space available to the data, the partition's `padded_size`
+    pub padded_size: u32,
+    /// The rewritten RKCRC, and the MD5 for RKFW images
+    pub crc: CrcUpdate,
+}
+
+fn stored_path(part: &UpdatePart) -> String {
+    std::ffi::CStr::from_bytes_until_nul(&part.full_path).map(|path| path.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+// Start and size of the RKAF image inside the file: the whole file, or the embedded image of RKFW
+fn rkaf_region(file: &mut File, filesize: u64) -> Result<(u64, u64), ApfError> {
+    let (kind, signature) = read_signature(file)?;
+    match kind {
+        ImageKind::Rkaf => Ok((0, filesize)),
+        ImageKind::Rkfw => {
+            if filesize < RKFW_HEADER_SIZE as u64 {
+                return Err(ApfError::Truncated(format!("File too small to contain an RKFW header ({} bytes)", filesize)));
+            }
+            let mut info = parse_rkfw(&read_bytes_at(file, 0, RKFW_HEADER_SIZE)?, &UnpackOptions::default())?;
+            locate_embedded_image(file, &mut info, filesize)?;
+            Ok((info.update_offset as u64, info.update_size as u64))
+        }
+        _ => Err(ApfError::UnknownSignature(signature)),
+    }
+}
+
+/// Replaces the data of the partition called `name` (or stored under that path) in the RKAF image,
+/// or the embedded RKAF of an RKFW image, at `image_path` with the contents of `new_file`, without
+/// repacking: the data is written over the old one, the rest of the partition's `padded_size` is
+/// zero-filled, `part_byte_count` is updated in the header and the trailing RKCRC (and for RKFW
+/// the MD5) is rewritten with `update_crc`. A file larger than `padded_size` would overwrite the
+/// next partition and is rejected before anything is written.
+pub fn replace_partition(image_path: &str, name: &str, new_file: &str) -> Result<ReplaceResult, ApfError> {
+    let mut image = OpenOptions::new().read(true).write(true).open(image_path)?;
+    let filesize = stream_len(&mut image)?;
+    let (base, size) = rkaf_region(&mut image, filesize)?;
+    let header_size = std::mem::size_of::<UpdateHeader>();
+    if size < header_size as u64 + 4 || base + size > filesize {
+        return Err(ApfError::Truncated(format!("RKAF image at {:#x} (size {}) is too small or out of bounds", base, size)));
+    }
+    let buf = read_bytes_at(&mut image, base, header_size)?;
+    if !UpdateHeader::has_rkaf_magic(&buf) {
+        return Err(ApfError::BadMagic);
+    }
+    let mut header = *UpdateHeader::from_bytes(&buf);
+    check_num_parts(&header)?;
+
+    let matches: Vec<usize> = header.parts.iter()
+        .take(header.num_parts as usize)
+        .enumerate()
+        .filter(|(_, part)| decode_header_string(&part.name, TextEncoding::Auto).as_deref() == Some(name) || stored_path(part) == name)
+        .map(|(index, _)| index)
+        .collect();
+    let index = match matches[..] {
+        [index] => index,
+        [] => return Err(ApfError::Invalid(format!("{} has no partition named {}", image_path, name))),
+        _ => return Err(ApfError::Invalid(format!("{} partitions are named {}, replace needs a unique name", matches.len(), name))),
+    };
+    let part = header.parts[index];
+    let path = stored_path(&part);
+    if path == "SELF" || path == "RESERVED" {
+        return Err(ApfError::Invalid(format!("{} is a {} entry without partition data", name, path)));
+    }
+
+    let mut input = File::open(new_file)?;
+    let new_len = input.metadata()?.len();
+    let (padded_size, previous_size, flash_size) = (part.padded_size, part.part_byte_count, part.flash_size);
+    let part_offset = if offsets_in_sectors(&header, size) { part.part_offset as u64 * SECTOR_SIZE } else { part.part_offset as u64 };
+    if new_len > padded_size as u64 {
+        return Err(ApfError::Invalid(format!(
+            "{} ({} bytes) does not fit the {} bytes reserved for {}, unpack and repack the image instead",
+            new_file, new_len, padded_size, name
+        )));
+    }
+    // The CRC sits in the last 4 bytes, the slot has to end before it
+    if part_offset + padded_size as u64 > size - 4 {
+        return Err(ApfError::Truncated(format!(
+            "{} at {:#x} (padded size {}) runs past the end of the image",
+            name, part_offset, padded_size
+        )));
+    }
+    if flash_size != FLASH_SIZE_GROW && flash_size > 0 && new_len > flash_size as u64 * SECTOR_SIZE {
+        warn!("{} ({} bytes) is larger than the flash allocation of {} ({} sectors)", new_file, new_len, name, flash_size);
+    }
+
+    image.seek(SeekFrom::Start(base + part_offset))?;
+    std::io::copy(&mut (&mut input).take(new_len), &mut image)?;
+    let zeros = vec![0u8; ZERO_CHUNK];
+    let mut remaining = padded_size as u64 - new_len;
+    while remaining > 0 {
+        let chunk = remaining.min(ZERO_CHUNK as u64) as usize;
+        image.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    header.parts[index].part_byte_count = new_len as u32;
+    image.seek(SeekFrom::Start(base))?;
+    image.write_all(header.to_bytes())?;
+    image.flush()?;
+    drop(image);
+
+    info!("Replaced {} ({} -> {} bytes)", name, previous_size, new_len);
+    let crc = update_crc(image_path)?;
+    Ok(ReplaceResult {
+        name: decode_header_string(&part.name, TextEncoding::Auto).unwrap_or_default(),
+        previous_size,
+        size: new_len as u32,
+        padded_size,
+        crc,
+    })
+}
@@ -0,0 +1,84 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::error::ApfError;
+
+// Rockchip resource image (the `resource` partition), as written by resource_tool
+const RESOURCE_MAGIC: &[u8] = b"RSCE";
+const RESOURCE_ENTRY_MAGIC: &[u8] = b"ENTR";
+const RESOURCE_HEADER_SIZE: usize = 15;
+const RESOURCE_ENTRY_SIZE: usize = 268;
+const RESOURCE_ENTRY_NAME_LEN: usize = 256;
+const RESOURCE_BLOCK_SIZE: usize = 512;
+/// Index table layout understood by `parse_resource`
+const RESOURCE_INDEX_TBL_VERSION: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ResourceEntry {
+    /// File name inside the resource image, e.g. `rk3326-evb.dtb` or `logo.bmp`
+    pub name: String,
+    /// Offset of the file data inside the resource image
+    pub offset: u32,
+    pub size: u32,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+pub fn parse_resource(data: &[u8]) -> Result<Vec<ResourceEntry>, ApfError> {
+    if data.len() < RESOURCE_HEADER_SIZE {
+        return Err(ApfError::Truncated(format!(
+            "Resource image too small to contain a header ({} bytes, need {})",
+            data.len(),
+            RESOURCE_HEADER_SIZE
+        )));
+    }
+    if &data[0..4] != RESOURCE_MAGIC {
+        return Err(ApfError::BadMagic);
+    }
+
+    // Offsets and sizes in the header are counted in 512-byte blocks
+    let index_tbl_version = read_u16(data, 6);
+    if index_tbl_version != RESOURCE_INDEX_TBL_VERSION {
+        return Err(ApfError::Invalid(format!("Unsupported resource index table version {}", index_tbl_version)));
+    }
+    let tbl_offset = data[9] as usize * RESOURCE_BLOCK_SIZE;
+    let entry_size = std::cmp::max(data[10] as usize * RESOURCE_BLOCK_SIZE, RESOURCE_ENTRY_SIZE);
+    let entry_count = read_u32(data, 11) as usize;
+
+    let mut entries = Vec::new();
+    for i in 0..entry_count {
+        let offset = tbl_offset + i * entry_size;
+        let entry = data.get(offset..offset + RESOURCE_ENTRY_SIZE).ok_or_else(|| {
+            ApfError::Truncated(format!("Resource entry {} at offset {:#x} is out of bounds", i, offset))
+        })?;
+        if &entry[0..4] != RESOURCE_ENTRY_MAGIC {
+            return Err(ApfError::Invalid(format!("Resource entry {} at offset {:#x} has no ENTR tag", i, offset)));
+        }
+
+        let name_field = &entry[4..4 + RESOURCE_ENTRY_NAME_LEN];
+        let name_len = name_field.iter().position(|&b| b == 0).unwrap_or(name_field.len());
+        let resource = ResourceEntry {
+            name: String::from_utf8_lossy(&name_field[..name_len]).to_string(),
+            offset: read_u32(entry, 4 + RESOURCE_ENTRY_NAME_LEN).saturating_mul(RESOURCE_BLOCK_SIZE as u32),
+            size: read_u32(entry, 8 + RESOURCE_ENTRY_NAME_LEN),
+        };
+        if resource.offset as u64 + resource.size as u64 > data.len() as u64 {
+            return Err(ApfError::Truncated(format!(
+                "Resource {} at offset {:#x} (size {}) is out of bounds (size {})",
+                resource.name,
+                resource.offset,
+                resource.size,
+                data.len()
+            )));
+        }
+        entries.push(resource);
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,88 @@
+use std::io::{Read, Seek};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::compression::{GZIP_MAGIC, ZSTD_MAGIC};
+use crate::error::ApfError;
+use crate::sparse::SPARSE_HEADER_MAGIC;
+use crate::{RKAF_SIGNATURE, RKFW_SIGNATURE};
+
+/// Number of leading bytes `peek_signature` needs at most
+pub const SIGNATURE_PEEK_LEN: usize = 8;
+
+/// Number of leading bytes searched for an RKFW or RKAF signature with `UnpackOptions::scan`
+pub const SIGNATURE_SCAN_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ImageKind {
+    /// RKFW firmware wrapper around a BOOT blob and an RKAF image
+    Rkfw,
+    /// RKAF update image
+    Rkaf,
+    /// gzip compressed, `unpack_file` decompresses it first
+    Gzip,
+    /// zstd compressed, `unpack_file` decompresses it first
+    Zstd,
+    /// Android sparse image, usually a single partition rather than a whole update image
+    Sparse,
+    Unknown,
+}
+
+/// Classifies a file from its first `SIGNATURE_PEEK_LEN` bytes (fewer are fine), `None` when
+/// there are too few bytes to tell
+pub fn peek_signature(first_bytes: &[u8]) -> Option<ImageKind> {
+    if first_bytes.starts_with(&GZIP_MAGIC) {
+        return Some(ImageKind::Gzip);
+    }
+    let magic = first_bytes.get(..4)?;
+    let kind = if magic == RKFW_SIGNATURE {
+        ImageKind::Rkfw
+    } else if magic == RKAF_SIGNATURE {
+        ImageKind::Rkaf
+    } else if magic == ZSTD_MAGIC {
+        ImageKind::Zstd
+    } else if magic == SPARSE_HEADER_MAGIC.to_le_bytes() {
+        ImageKind::Sparse
+    } else {
+        ImageKind::Unknown
+    };
+    Some(kind)
+}
+
+/// First RKFW or RKAF signature in `buf` and its offset, e.g. after a vendor download header
+pub fn find_signature(buf: &[u8]) -> Option<(ImageKind, usize)> {
+    buf.windows(4).enumerate().find_map(|(offset, magic)| {
+        if magic == RKFW_SIGNATURE {
+            Some((ImageKind::Rkfw, offset))
+        } else if magic == RKAF_SIGNATURE {
+            Some((ImageKind::Rkaf, offset))
+        } else {
+            None
+        }
+    })
+}
+
+// Searches the first `SIGNATURE_SCAN_LEN` bytes of `fp` with `find_signature` and rewinds it
+pub(crate) fn scan_signature<R: Read + Seek>(fp: &mut R) -> Result<Option<(ImageKind, u64)>, ApfError> {
+    let mut buf = Vec::with_capacity(SIGNATURE_SCAN_LEN);
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    (&mut *fp).take(SIGNATURE_SCAN_LEN as u64).read_to_end(&mut buf)?;
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    Ok(find_signature(&buf).map(|(kind, offset)| (kind, offset as u64)))
+}
+
+// Peeks at the start of `fp` and rewinds it; also returns the first four bytes for error messages
+pub(crate) fn read_signature<R: Read + Seek>(fp: &mut R) -> Result<(ImageKind, [u8; 4]), ApfError> {
+    let mut first_bytes = Vec::with_capacity(SIGNATURE_PEEK_LEN);
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    (&mut *fp).take(SIGNATURE_PEEK_LEN as u64).read_to_end(&mut first_bytes)?;
+    fp.seek(std::io::SeekFrom::Start(0))?;
+
+    let kind = peek_signature(&first_bytes)
+        .ok_or_else(|| ApfError::Truncated(format!("File too small to contain a signature ({} bytes)", first_bytes.len())))?;
+    let mut signature = [0u8; 4];
+    let len = std::cmp::min(first_bytes.len(), 4);
+    signature[..len].copy_from_slice(&first_bytes[..len]);
+    Ok((kind, signature))
+}
@@ -0,0 +1,122 @@
+use std::io::{Read, Write};
+use crate::error::ApfError;
+
+// Android sparse image format, as produced by img2simg / make_ext4fs -s
+pub const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+pub(crate) const SPARSE_HEADER_SIZE: usize = 28;
+
+pub fn is_sparse(header: &[u8]) -> bool {
+    header.len() >= 4 && header[0..4] == SPARSE_HEADER_MAGIC.to_le_bytes()
+}
+
+// Size of the raw image described by a sparse header (block size × total blocks)
+pub(crate) fn sparse_expanded_size(header: &[u8]) -> Option<u64> {
+    if !is_sparse(header) || header.len() < SPARSE_HEADER_SIZE {
+        return None;
+    }
+    let block_size = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let total_blocks = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+    Some(block_size as u64 * total_blocks as u64)
+}
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+fn skip_bytes<R: Read>(input: &mut R, len: u64) -> Result<(), ApfError> {
+    let skipped = std::io::copy(&mut input.take(len), &mut std::io::sink())?;
+    if skipped != len {
+        return Err(ApfError::Truncated("Truncated sparse image".to_string()));
+    }
+    Ok(())
+}
+
+fn write_repeated<W: Write>(output: &mut W, pattern: [u8; 4], len: u64) -> Result<(), ApfError> {
+    let buffer: Vec<u8> = pattern.iter().copied().cycle().take(16 * 1024).collect();
+    let mut remaining = len;
+    while remaining > 0 {
+        let write_len = std::cmp::min(remaining, buffer.len() as u64) as usize;
+        output.write_all(&buffer[..write_len])?;
+        remaining -= write_len as u64;
+    }
+    Ok(())
+}
+
+// Expands an Android sparse image into the raw image it describes, returns the number of bytes written
+pub fn unsparse<R: Read, W: Write>(mut input: R, mut output: W) -> Result<u64, ApfError> {
+    let mut header = [0u8; SPARSE_HEADER_SIZE];
+    input.read_exact(&mut header)
+        .map_err(|_| ApfError::Truncated("File too small to contain a sparse header".to_string()))?;
+    if !is_sparse(&header) {
+        return Err(ApfError::BadMagic);
+    }
+
+    let file_header_size = u16::from_le_bytes([header[8], header[9]]) as usize;
+    let chunk_header_size = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let block_size = u32::from_le_bytes([header[12], header[13], header[14], header[15]]) as u64;
+    let total_blocks = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as u64;
+    let total_chunks = u32::from_le_bytes([header[20], header[21], header[22], header[23]]);
+    if file_header_size < SPARSE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+        return Err(ApfError::Invalid(format!("Invalid sparse header sizes ({}, {})", file_header_size, chunk_header_size)));
+    }
+    skip_bytes(&mut input, (file_header_size - SPARSE_HEADER_SIZE) as u64)?;
+
+    let mut written = 0u64;
+    for chunk in 0..total_chunks {
+        let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+        input.read_exact(&mut chunk_header)
+            .map_err(|_| ApfError::Truncated(format!("Truncated sparse image at chunk {}", chunk)))?;
+        skip_bytes(&mut input, (chunk_header_size - CHUNK_HEADER_SIZE) as u64)?;
+
+        let chunk_type = u16::from_le_bytes([chunk_header[0], chunk_header[1]]);
+        let chunk_blocks = u32::from_le_bytes([chunk_header[4], chunk_header[5], chunk_header[6], chunk_header[7]]) as u64;
+        let total_size = u32::from_le_bytes([chunk_header[8], chunk_header[9], chunk_header[10], chunk_header[11]]) as u64;
+        let data_size = total_size
+            .checked_sub(chunk_header_size as u64)
+            .ok_or_else(|| ApfError::Invalid(format!("Invalid size for sparse chunk {}", chunk)))?;
+        let out_size = chunk_blocks * block_size;
+
+        match chunk_type {
+            CHUNK_TYPE_RAW => {
+                if data_size != out_size {
+                    return Err(ApfError::Invalid(format!("Raw sparse chunk {} has {} bytes, expected {}", chunk, data_size, out_size)));
+                }
+                let copied = std::io::copy(&mut (&mut input).take(data_size), &mut output)?;
+                if copied != data_size {
+                    return Err(ApfError::Truncated(format!("Truncated sparse image at chunk {}", chunk)));
+                }
+            }
+            CHUNK_TYPE_FILL => {
+                if data_size != 4 {
+                    return Err(ApfError::Invalid(format!("Fill sparse chunk {} has {} bytes, expected 4", chunk, data_size)));
+                }
+                let mut pattern = [0u8; 4];
+                input.read_exact(&mut pattern)?;
+                write_repeated(&mut output, pattern, out_size)?;
+            }
+            CHUNK_TYPE_DONT_CARE => {
+                skip_bytes(&mut input, data_size)?;
+                write_repeated(&mut output, [0u8; 4], out_size)?;
+            }
+            CHUNK_TYPE_CRC32 => {
+                skip_bytes(&mut input, data_size)?;
+                continue;
+            }
+            _ => {
+                return Err(ApfError::Invalid(format!("Unknown sparse chunk type {:#06x}", chunk_type)));
+            }
+        }
+        written += out_size;
+    }
+
+    if written != block_size * total_blocks {
+        return Err(ApfError::Invalid(format!(
+            "Sparse image expanded to {} bytes, header says {}",
+            written,
+            block_size * total_blocks
+        )));
+    }
+    Ok(written)
+}
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::error::ApfError;
+
+/// Presents several files as one contiguous seekable stream, e.g. the parts of a firmware
+/// split into `update.img.001`, `update.img.002`, ...
+pub struct MultiFileReader {
+    files: Vec<File>,
+    /// Offset of each file in the combined stream
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+}
+
+impl MultiFileReader {
+    /// Opens `paths` in order; the combined stream is their concatenation
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, ApfError> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut starts = Vec::with_capacity(paths.len());
+        let mut len = 0u64;
+        for path in paths {
+            let file = File::open(path)?;
+            starts.push(len);
+            len += file.metadata()?.len();
+            files.push(file);
+        }
+        Ok(Self { files, starts, len, pos: 0 })
+    }
+
+    /// Opens the first part of a split image together with every following numbered part
+    pub fn open_split(first_part: &str) -> Result<Self, ApfError> {
+        let parts = split_parts(first_part)
+            .ok_or_else(|| ApfError::Invalid(format!("{} is not the first part of a split image (.001)", first_part)))?;
+        Self::open(&parts)
+    }
+
+    /// Total length of all parts
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Index of the part containing `pos`, skipping empty parts
+    fn part_at(&self, pos: u64) -> usize {
+        self.starts.partition_point(|&start| start <= pos).saturating_sub(1)
+    }
+}
+
+impl Read for MultiFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.part_at(self.pos);
+        let part_end = self.starts.get(index + 1).copied().unwrap_or(self.len);
+        let max = std::cmp::min(buf.len() as u64, part_end - self.pos) as usize;
+        let file = &mut self.files[index];
+        file.seek(SeekFrom::Start(self.pos - self.starts[index]))?;
+        let read_bytes = file.read(&mut buf[..max])?;
+        self.pos += read_bytes as u64;
+        Ok(read_bytes)
+    }
+}
+
+impl Seek for MultiFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the stream")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// For `<name>.001` returns it and every consecutive `<name>.002`, `<name>.003`, ... that exists;
+/// `None` for any other file name
+pub fn split_parts(first_part: &str) -> Option<Vec<PathBuf>> {
+    let (stem, number) = first_part.rsplit_once('.')?;
+    if number.len() < 3 || !number.bytes().all(|b| b.is_ascii_digit()) || number.parse::<u32>().ok()? != 1 {
+        return None;
+    }
+    let width = number.len();
+    let parts = (1..)
+        .map(|index| PathBuf::from(format!("{}.{:0width$}", stem, index, width = width)))
+        .take_while(|path| path.is_file())
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then_some(parts)
+}
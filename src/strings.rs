@@ -0,0 +1,96 @@
+use std::io::Read;
+use crate::error::ApfError;
+use crate::unpack::DEFAULT_BUFFER_SIZE;
+
+// Collects one run of printable text; multi-byte UTF-8 characters may be split across reads
+struct Run {
+    text: Vec<u8>,
+    chars: usize,
+    start: u64,
+    /// Bytes of a UTF-8 character read so far and its expected length
+    pending: Vec<u8>,
+    pending_len: usize,
+}
+
+impl Run {
+    fn push(&mut self, offset: u64, bytes: &[u8]) {
+        if self.text.is_empty() {
+            self.start = offset;
+        }
+        self.text.extend_from_slice(bytes);
+        self.chars += 1;
+    }
+
+    fn end<F: FnMut(u64, &str)>(&mut self, min_len: usize, found: &mut F) {
+        if self.chars >= min_len {
+            // Only printable ASCII and complete UTF-8 characters are collected
+            found(self.start, std::str::from_utf8(&self.text).unwrap_or_default());
+        }
+        self.text.clear();
+        self.chars = 0;
+        self.pending.clear();
+    }
+}
+
+fn is_printable_ascii(byte: u8) -> bool {
+    byte == b'\t' || (0x20..0x7f).contains(&byte)
+}
+
+// Length of the UTF-8 character starting with `byte`, for lead bytes of non-ASCII characters
+fn utf8_len(byte: u8) -> Option<usize> {
+    match byte {
+        0xc2..=0xdf => Some(2),
+        0xe0..=0xef => Some(3),
+        0xf0..=0xf4 => Some(4),
+        _ => None,
+    }
+}
+
+/// Streams `reader` like `strings(1)` and calls `found(offset, text)` for every run of at least
+/// `min_len` printable characters: ASCII from space to `~`, tabs, and non-control UTF-8
+/// characters. `offset` counts from the start of the reader. Returns the number of bytes read.
+pub fn find_strings<R: Read, F: FnMut(u64, &str)>(mut reader: R, min_len: usize, mut found: F) -> Result<u64, ApfError> {
+    let min_len = min_len.max(1);
+    let mut run = Run { text: Vec::new(), chars: 0, start: 0, pending: Vec::new(), pending_len: 0 };
+    let mut buffer = vec![0u8; DEFAULT_BUFFER_SIZE];
+    let mut offset = 0u64;
+    loop {
+        let read_bytes = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read_bytes) => read_bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for (index, &byte) in buffer[..read_bytes].iter().enumerate() {
+            let position = offset + index as u64;
+            if !run.pending.is_empty() {
+                if byte & 0xc0 == 0x80 {
+                    run.pending.push(byte);
+                    if run.pending.len() == run.pending_len {
+                        let pending = std::mem::take(&mut run.pending);
+                        let printable = std::str::from_utf8(&pending).ok().and_then(|text| text.chars().next()).is_some_and(|c| !c.is_control());
+                        if printable {
+                            run.push(position + 1 - pending.len() as u64, &pending);
+                        } else {
+                            run.end(min_len, &mut found);
+                        }
+                    }
+                    continue;
+                }
+                // A cut short character ends the run, the byte may start the next one
+                run.end(min_len, &mut found);
+            }
+            if is_printable_ascii(byte) {
+                run.push(position, &[byte]);
+            } else if let Some(len) = utf8_len(byte) {
+                run.pending.push(byte);
+                run.pending_len = len;
+            } else {
+                run.end(min_len, &mut found);
+            }
+        }
+        offset += read_bytes as u64;
+    }
+    run.end(min_len, &mut found);
+    Ok(offset)
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::time::Instant;
+use anyhow::Result;
+use log::info;
+use crate::checksum::hash_partition;
+use crate::error::ApfError;
+use crate::flash::{flash_script, FLASH_SCRIPT_FILE};
+use crate::signature::ImageKind;
+use crate::split::{split_parts, MultiFileReader};
+use crate::UpdateHeader;
+use crate::unpack::{
+    check_boot_selection, check_partition_count, copy_to_writer, decompress_input, find_image, human_size, is_selected, log_flash_usage, nested_rkfw_error, read_bytes_at, Progress, read_rkafp,
+    read_rkfw, spool_stdin, stream_len, RegionReader, RkafInfo, RkfwInfo, UnpackOptions, UnpackResult, UnpackStats, BOOT_GAP_FILE, EMBEDDED_DIR,
+    GENERATED_PARAMETER_FILE, HEADER_FILE, PREFIX_FILE, PRE_BOOT_FILE, RKFW_HEADER_SIZE, SIGNATURE_FILE, STDIN_PATH,
+};
+#[cfg(feature = "serde")]
+use crate::unpack::RKFW_INFO_FILE;
+
+const BLOCK_SIZE: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+/// Largest size the 11 octal digits of the ustar size field can hold
+const MAX_ENTRY_SIZE: u64 = 0o77777777777;
+/// Mode of every entry except `flash.sh` unless `UnpackOptions::file_mode` is set
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const SCRIPT_FILE_MODE: u32 = 0o755;
+
+// Writes a POSIX ustar archive of regular files. The size of every entry is known before its
+// data, so partitions are copied straight from the image without buffering them.
+struct TarWriter<W: Write> {
+    out: W,
+    mode: u32,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(out: W, mode: u32) -> Self {
+        Self { out, mode, mtime: 0 }
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.append_with_mode(name, data, self.mode)
+    }
+
+    fn append_with_mode(&mut self, name: &str, data: &[u8], mode: u32) -> Result<()> {
+        self.out.write_all(&entry_header(name, data.len() as u64, mode, self.mtime)?)?;
+        self.out.write_all(data)?;
+        self.end_entry(data.len() as u64)
+    }
+
+    // Streams `len` bytes at `offset` of `fp` into a new entry
+    fn append_range<R: Read + Seek>(&mut self, fp: &mut R, name: &str, offset: u64, len: u64, options: &UnpackOptions) -> Result<()> {
+        self.out.write_all(&entry_header(name, len, self.mode, self.mtime)?)?;
+        copy_to_writer(fp, offset, len, &mut self.out, options.buffer_size(), Progress::default())?;
+        self.end_entry(len)
+    }
+
+    // Entry data is padded with zeros to a whole block
+    fn end_entry(&mut self, size: u64) -> Result<()> {
+        let padding = (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+        self.out.write_all(&[0u8; BLOCK_SIZE][..padding])?;
+        Ok(())
+    }
+
+    // The archive ends with two zero blocks
+    fn finish(mut self) -> Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK_SIZE])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn entry_header(name: &str, size: u64, mode: u32, mtime: u64) -> Result<[u8; BLOCK_SIZE]> {
+    if size > MAX_ENTRY_SIZE {
+        return Err(ApfError::Invalid(format!("{} is too large for a tar entry ({} bytes)", name, size)).into());
+    }
+    let (prefix, name) = split_name(name)?;
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // Summed with the checksum field itself filled with spaces, stored as 6 digits, NUL, space
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+// Names longer than 100 bytes are split at a `/` into the 155-byte prefix field and the name field
+fn split_name(name: &str) -> Result<(&str, &str)> {
+    if name.len() <= NAME_LEN {
+        return Ok(("", name));
+    }
+    name.match_indices('/')
+        .map(|(index, _)| (&name[..index], &name[index + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= PREFIX_LEN && !rest.is_empty() && rest.len() <= NAME_LEN)
+        .ok_or_else(|| ApfError::Invalid(format!("Path is too long for a tar entry: {}", name)).into())
+}
+
+// Zero-padded octal digits filling all but the last byte of the field, which is NUL
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Like `unpack_file`, but writes the partitions and the files needed to repack them into one
+/// tar archive at `tar_path`; extracting it gives the directory `unpack_file` would have written
+pub fn unpack_to_tar(file_path: &str, tar_path: &str) -> Result<UnpackResult, ApfError> {
+    unpack_to_tar_with_options(file_path, tar_path, &UnpackOptions::default())
+}
+
+/// The checks and `only`, `naming`, `metadata_format`, `checksums`, `recursive`,
+/// `preserve_mtime`, `epoch`, `file_mode` and `save_header` behave as in
+/// `unpack_file_with_options`; `split_boot`, `split_resource`, `split_boot_image`, `capture_padding`,
+/// `expand_sparse`, `gunzip_partitions`, `pad_to_flash_size`, `resume`, `parallelism` and `preserve_order` are ignored. The archive is removed again when
+/// unpacking fails.
+pub fn unpack_to_tar_with_options(file_path: &str, tar_path: &str, options: &UnpackOptions) -> Result<UnpackResult, ApfError> {
+    let out = BufWriter::new(File::create(tar_path)?);
+    let result = match split_parts(file_path) {
+        _ if file_path == STDIN_PATH => spool_stdin(options).and_then(|input| tar_input(input, out, options)),
+        Some(parts) => MultiFileReader::open(&parts).and_then(|input| tar_input(input, out, options)),
+        None => File::open(file_path).map_err(ApfError::from).and_then(|input| tar_input(input, out, options)),
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(tar_path);
+    }
+    result
+}
+
+fn tar_input<R: Read + Seek, W: Write>(mut input: R, out: W, options: &UnpackOptions) -> Result<UnpackResult, ApfError> {
+    match decompress_input(&mut input, options)? {
+        Some(file) => unpack_reader_to_tar(file, out, options),
+        None => unpack_reader_to_tar(input, out, options),
+    }
+}
+
+/// Writes the tar archive of an RKAF or RKFW image read from `reader` to `out`
+pub fn unpack_reader_to_tar<R: Read + Seek, W: Write>(mut reader: R, out: W, options: &UnpackOptions) -> Result<UnpackResult, ApfError> {
+    let started = Instant::now();
+    let (kind, prefix_len) = find_image(&mut reader, options)?;
+    let mut tar = TarWriter::new(out, options.file_mode.unwrap_or(DEFAULT_FILE_MODE));
+    if options.preserve_mtime {
+        tar.mtime = options.epoch.unwrap_or_default().max(0) as u64;
+    }
+    let mut result = if prefix_len > 0 {
+        tar.append_range(&mut reader, PREFIX_FILE, 0, prefix_len, options)?;
+        let len = stream_len(&mut reader)? - prefix_len;
+        image_to_tar(&mut RegionReader::new(&mut reader, prefix_len, len), kind, &mut tar, options)?
+    } else {
+        image_to_tar(&mut reader, kind, &mut tar, options)?
+    };
+    tar.finish()?;
+    result.stats_mut().duration_ms = started.elapsed().as_millis() as u64;
+    let stats = result.stats();
+    info!("Archived {} partitions, {} total", stats.files_written, human_size(stats.bytes_written));
+    Ok(result)
+}
+
+fn image_to_tar<R: Read + Seek, W: Write>(fp: &mut R, kind: ImageKind, tar: &mut TarWriter<W>, options: &UnpackOptions) -> Result<UnpackResult> {
+    check_boot_selection(kind, options)?;
+    Ok(match kind {
+        ImageKind::Rkfw => UnpackResult::Rkfw(rkfw_to_tar(fp, tar, options)?),
+        _ => UnpackResult::Rkaf(rkaf_to_tar(fp, tar, "", options)?),
+    })
+}
+
+// Entries are named like the files `unpack_rkafp` writes, below `prefix`
+fn rkaf_to_tar<R: Read + Seek, W: Write>(fp: &mut R, tar: &mut TarWriter<W>, prefix: &str, options: &UnpackOptions) -> Result<RkafInfo> {
+    let image = read_rkafp(fp, options)?;
+    tar.append(&format!("{}header-metadata.txt", prefix), image.header_metadata().as_bytes())?;
+    if options.save_header {
+        tar.append(&format!("{}{}", prefix, HEADER_FILE), &read_bytes_at(fp, 0, std::mem::size_of::<UpdateHeader>())?)?;
+    }
+    if let Some(parameter) = image.generated_parameter() {
+        tar.append(&format!("{}{}", prefix, GENERATED_PARAMETER_FILE), parameter.as_bytes())?;
+    }
+    tar.append(&format!("{}{}", prefix, options.metadata_format.file_name()), image.partition_metadata(options)?.as_bytes())?;
+    #[cfg(feature = "serde")]
+    tar.append(&format!("{}manifest.json", prefix), serde_json::to_string_pretty(&image.manifest(options))?.as_bytes())?;
+
+    let mut checksums = Vec::new();
+    let mut flashed = Vec::new();
+    let mut stats = UnpackStats { skipped_special: image.info.reserved_parts.len() as u32, ..Default::default() };
+    for (part, relative_path) in image.info.partitions.iter().zip(&image.relative_paths) {
+        if !is_selected(part, options) {
+            info!("Skipping {} (not selected)", part.path);
+            stats.skipped_filtered += 1;
+            continue;
+        }
+        let len = part.part_byte_count as u64;
+        tar.append_range(fp, &format!("{}{}", prefix, relative_path), part.part_offset as u64, len, options)?;
+        stats.add_file(len);
+        flashed.push((part, relative_path.as_str()));
+        if let Some(algorithm) = options.checksums {
+            checksums.push(format!("{}  {}\n", hash_partition(fp, part, algorithm)?, relative_path));
+        }
+    }
+    check_partition_count(&stats, 0, image.header.num_parts, options)?;
+    log_flash_usage(&image.info);
+
+    let script = flash_script(&flashed, false);
+    tar.append_with_mode(&format!("{}{}", prefix, FLASH_SCRIPT_FILE), script.as_bytes(), SCRIPT_FILE_MODE)?;
+    if !checksums.is_empty() {
+        tar.append(&format!("{}checksums.txt", prefix), checksums.concat().as_bytes())?;
+    }
+
+    let mut info = image.info;
+    info.stats = stats;
+    Ok(info)
+}
+
+fn rkfw_to_tar<R: Read + Seek, W: Write>(fp: &mut R, tar: &mut TarWriter<W>, options: &UnpackOptions) -> Result<RkfwInfo> {
+    let mut info = read_rkfw(fp, options)?;
+    if options.preserve_mtime {
+        tar.mtime = info.timestamp.max(0) as u64;
+    }
+    if options.save_header {
+        tar.append(HEADER_FILE, &read_bytes_at(fp, 0, RKFW_HEADER_SIZE)?)?;
+    }
+    for (region, file_name) in [(info.pre_boot, PRE_BOOT_FILE), (info.boot_gap, BOOT_GAP_FILE), (info.signature, SIGNATURE_FILE)] {
+        if let Some(region) = region.filter(|region| !region.zeroed) {
+            tar.append_range(fp, file_name, region.offset as u64, region.size as u64, options)?;
+        }
+    }
+    if info.has_boot && !options.no_extract_boot {
+        tar.append_range(fp, "BOOT", info.boot_offset as u64, info.boot_size as u64, options)?;
+        info.stats.add_file(info.boot_size as u64);
+    }
+    let update_offset = info.update_offset as u64;
+    let update_size = info.update_size as u64;
+    if options.recursive && info.nested.is_some() && !options.boot_only {
+        return Err(nested_rkfw_error(&info).into());
+    }
+    if options.boot_only {
+        info!("Skipping embedded-update.img (boot only)");
+    } else if options.recursive {
+        let prefix = format!("{}/", EMBEDDED_DIR);
+        let embedded = rkaf_to_tar(&mut RegionReader::new(fp, update_offset, update_size), tar, &prefix, options)?;
+        info.stats.merge(&embedded.stats);
+        info.embedded = Some(Box::new(embedded));
+    } else {
+        tar.append_range(fp, "embedded-update.img", update_offset, update_size, options)?;
+        info.stats.add_file(update_size);
+    }
+    #[cfg(feature = "serde")]
+    tar.append(RKFW_INFO_FILE, serde_json::to_string_pretty(&info)?.as_bytes())?;
+    Ok(info)
+}
@@ -1,73 +1,832 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Seek, Write};
-use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use anyhow::{anyhow, Result};
-use chrono::NaiveDateTime;
-use crate::{RKAF_SIGNATURE, RKFW_SIGNATURE, UpdateHeader, RKAFP_MAGIC};
+use crate::error::ApfError;
+use chrono::{DateTime, NaiveDateTime, Utc};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use log::{debug, error, info, trace, warn};
+use crate::boot::{boot_is_rc4_encrypted, parse_boot, rc4_rk, BootEntryKind};
+use crate::bootimg::parse_boot_image;
+use crate::checksum::{hash_partition, hash_reader, verify_against_manifest, HashAlgorithm};
+use crate::chunks::extract_chunks;
+#[cfg(feature = "serde")]
+use crate::chunks::{ChunkIndex, CHUNKS_FILE};
+use crate::chip::{chip_family, infer_chip_family};
+use crate::compression::{decompress, decompress_to_temp, Compression};
+use crate::content::{detect_fs, FS_PROBE_LEN};
+use crate::crc::rkcrc32;
+use crate::encoding::{decode_header_string, TextEncoding};
+use crate::fit::parse_fit;
+use crate::flash::{flash_script, FLASH_SCRIPT_FILE};
+use crate::kind::PartitionKind;
+use crate::layout::{detect_alignment, validate_layout, validate_parameter_layout};
+use crate::parallel::{extract_parallel, CopyJob, CAN_EXTRACT_IN_PARALLEL};
+use crate::parameter::{generate_parameter, parse_parameter};
+use crate::resource::parse_resource;
+#[cfg(feature = "serde")]
+use crate::manifest::{Manifest, MANIFEST_SCHEMA_VERSION};
+use crate::metadata::{format_partition_metadata, MetadataFormat, PartitionMetadata, PartitionSort};
+use crate::split::{split_parts, MultiFileReader};
+use crate::signature::{read_signature, scan_signature, ImageKind};
+use crate::sparse::{is_sparse, sparse_expanded_size, unsparse, SPARSE_HEADER_SIZE};
+use crate::{RKFW_SIGNATURE, UpdateHeader, RKAFP_MAGIC, MAX_PARTS};
+
+// All offsets and sizes serialize as plain decimal integers
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RkfwInfo {
     pub version: String,
+    /// Raw merge version, see [`RkfwInfo::firmware_code`]
     pub code: u32,
+    /// 0 when the header date is zeroed or invalid, see `UnpackOptions::strict`
     pub timestamp: i64,
+    /// Build date from the header, the same instant as `timestamp`
+    pub built_at: DateTime<Utc>,
     pub chip_family: String,
+    /// `chip_family` was not found for `chip_code` but taken from a chip name inside BOOT
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub chip_family_inferred: bool,
     pub chip_code: u8,
     pub boot_offset: u32,
     pub boot_size: u32,
+    /// `false` when the image carries no usable BOOT blob (zero size or missing signature)
+    pub has_boot: bool,
+    /// Start and size of the embedded image, after skipping `update_skipped` bytes
     pub update_offset: u32,
     pub update_size: u32,
+    /// Bytes between the `update_offset` stored in the header and the embedded image's magic,
+    /// already added to `update_offset` and left out of `update_size`; see `EMBEDDED_MAGIC_WINDOW`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub update_skipped: u32,
+    /// Bytes between the header and BOOT, `None` when BOOT follows the header directly
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pre_boot: Option<ReservedRegion>,
+    /// Bytes between BOOT and the embedded update image, `None` when there are none
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub boot_gap: Option<ReservedRegion>,
+    /// Bytes between the embedded update image and the trailing MD5, where secure-boot builds
+    /// keep their RSA signature; `None` when the MD5 follows the image directly
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub signature: Option<ReservedRegion>,
+    /// `signature` is present and not zero-filled; checking it needs the signing key
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub signed: bool,
+    /// The trailing MD5 stored in the file and the one computed over everything before it, `None`
+    /// when the file does not end in 32 hex digits
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub md5_expected: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub md5_computed: Option<String>,
+    /// What `unpack_file` wrote, including the embedded image with `UnpackOptions::recursive`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stats: UnpackStats,
+    /// The embedded image when it is another RKFW image instead of an RKAF one, as some broken
+    /// builds wrap the firmware twice; its offsets are relative to `update_offset`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub nested: Option<Box<RkfwInfo>>,
+    /// The embedded RKAF image and its partitions, unpacked with `UnpackOptions::recursive`;
+    /// its stats are included in `stats` and its errors in `errors`
+    #[cfg_attr(feature = "serde", serde(skip_deserializing, skip_serializing_if = "Option::is_none"))]
+    pub embedded: Option<Box<RkafInfo>>,
+    /// Files that could not be extracted under `UnpackOptions::continue_on_error`, embedded
+    /// partitions prefixed with `embedded-update/`
+    #[cfg_attr(feature = "serde", serde(skip_deserializing, serialize_with = "serialize_errors", skip_serializing_if = "Vec::is_empty"))]
+    pub errors: Vec<(String, ApfError)>,
 }
 
-#[derive(Debug, Clone)]
+/// Counters for one unpack, zero for `inspect_file`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UnpackStats {
+    /// Partition files written, `BOOT` and `embedded-update.img` for RKFW
+    pub files_written: u32,
+    /// Partition bytes written, the expanded size for expanded sparse partitions
+    pub bytes_written: u64,
+    /// `SELF` and `RESERVED` entries, which are never extracted
+    pub skipped_special: u32,
+    /// Partitions left out by `UnpackOptions::only`
+    pub skipped_filtered: u32,
+    /// Partitions kept from an earlier run by `UnpackOptions::resume`
+    pub skipped_existing: u32,
+    /// Wall-clock time of the whole unpack in milliseconds, checks and metadata files included
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub duration_ms: u64,
+}
+
+impl UnpackStats {
+    pub(crate) fn add_file(&mut self, bytes: u64) {
+        self.files_written += 1;
+        self.bytes_written += bytes;
+    }
+
+    pub(crate) fn merge(&mut self, other: &UnpackStats) {
+        self.files_written += other.files_written;
+        self.bytes_written += other.bytes_written;
+        self.skipped_special += other.skipped_special;
+        self.skipped_filtered += other.skipped_filtered;
+        self.skipped_existing += other.skipped_existing;
+    }
+
+    /// `bytes_written` per second of `duration_ms` in MB (10^6 bytes), `None` when the unpack
+    /// took less than a millisecond
+    pub fn throughput_mb_s(&self) -> Option<f64> {
+        (self.duration_ms > 0).then(|| self.bytes_written as f64 / 1e6 / (self.duration_ms as f64 / 1000.0))
+    }
+}
+
+/// Reported to `Unpacker::on_partition` as soon as one partition is done, in the order they
+/// finish; RKFW images report `BOOT` and `embedded-update.img`, or `BOOT` and the embedded
+/// partitions with `UnpackOptions::recursive`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PartitionEvent {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub partition: PartitionInfo,
+    /// The file written, or the one that would have been for skipped partitions
+    pub output: String,
+    pub status: PartitionStatus,
+    /// Bytes written, the expanded size for expanded sparse partitions and 0 unless `Extracted`
+    pub written: u64,
+    /// Why a `Failed` partition could not be extracted
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum PartitionStatus {
+    Extracted,
+    /// Left out by `UnpackOptions::only` or `UnpackOptions::no_extract_boot`
+    NotSelected,
+    /// Kept from an earlier run by `UnpackOptions::resume`
+    AlreadyExtracted,
+    /// The unpack goes on only with `UnpackOptions::continue_on_error`
+    Failed,
+}
+
+/// A region of an RKFW image outside the header, BOOT and the embedded update image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReservedRegion {
+    pub offset: u32,
+    pub size: u32,
+    /// Every byte is zero; only regions with other contents are saved as `reserved-*.bin`
+    pub zeroed: bool,
+}
+
+/// The `code` field of an RKFW header (offset 0x0a).
+///
+/// Rockchip's image maker calls it the merge version and packs it like the firmware version:
+/// major in the top byte, minor in the next one and a 16-bit build number below, so
+/// `0x02000000` reads as 2.0.0. No dependency on the chip family is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct FirmwareCode(pub u32);
+
+impl FirmwareCode {
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    pub fn major(&self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn minor(&self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    pub fn build(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl std::fmt::Display for FirmwareCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major(), self.minor(), self.build())
+    }
+}
+
+impl RkfwInfo {
+    /// `code` split into its merge version fields
+    pub fn firmware_code(&self) -> FirmwareCode {
+        FirmwareCode(self.code)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PartitionInfo {
     pub name: String,
     pub path: String,
+    /// Size of the partition on flash in 512-byte sectors, `FLASH_SIZE_GROW` for a partition that
+    /// takes the rest of the flash; `flash_size_bytes` converts it
     pub flash_size: u32,
+    /// Start of the partition on flash in 512-byte sectors; `flash_offset_bytes` converts it
     pub flash_offset: u32,
+    /// Start of the partition data in bytes, from the start of the RKAF image it was read from
+    /// (for `list_partitions` and `PartitionIter`, from the start of the outer file)
     pub part_offset: u32,
+    /// Bytes reserved for the data in the image from `part_offset` on, `part_byte_count` rounded
+    /// up to the packer's alignment
     pub padded_size: u32,
+    /// Length of the partition data in bytes
     pub part_byte_count: u32,
+    /// Partition data is an Android sparse image and needs converting before raw flashing
+    pub sparse: bool,
+    /// Role guessed from the name, see `PartitionKind::from_name`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kind: PartitionKind,
+    /// File system or image format found at the start of the partition data, see `detect_fs`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub content_type: Option<String>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct RkafInfo {
     pub manufacturer: String,
     pub model: String,
+    /// Undecoded `manufacturer` header field including the NUL padding, for vendor-specific encodings
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub manufacturer_raw: Vec<u8>,
+    /// Undecoded `model` header field including the NUL padding
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub model_raw: Vec<u8>,
+    /// Header `version` field, the firmware version packed like `FirmwareCode`; afptool copies
+    /// it from `FIRMWARE_VER` in the parameter file
+    pub version: FirmwareCode,
     pub filesize: u64,
+    /// Image size given by the header, `update_header.length` plus the 4-byte RKCRC
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub declared_length: u64,
+    /// Bytes actually present, the same as `filesize`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub actual_length: u64,
+    /// `declared_length == actual_length`; a mismatch is a warning, or an error with `strict`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub length_matches: bool,
     pub partitions: Vec<PartitionInfo>,
+    /// `SELF` and `RESERVED` entries, kept for repacking but never extracted
+    pub reserved_parts: Vec<PartitionInfo>,
+    /// Granularity shared by all partition offsets, see `detect_alignment`
+    pub alignment: Option<u32>,
+    /// Bytes between the end of the last partition (its padded size included) and the trailing
+    /// RKCRC; a byte-exact repack has to reproduce them
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub trailing_bytes: u64,
+    /// The table stores `part_offset` as 512-byte sectors, see `offsets_in_sectors`; the offsets
+    /// in `partitions` and the saved metadata are converted to bytes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sector_offsets: bool,
+    /// What `unpack_file` wrote
+    pub stats: UnpackStats,
+    /// Partition paths that could not be extracted under `UnpackOptions::continue_on_error`
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_errors", skip_serializing_if = "Vec::is_empty"))]
+    pub errors: Vec<(String, ApfError)>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "format", rename_all = "lowercase"))]
 pub enum UnpackResult {
     Rkfw(RkfwInfo),
     Rkaf(RkafInfo),
 }
 
-pub fn unpack_file(file_path: &str, dst_path: &str) -> Result<UnpackResult> {
-    let mut file = File::open(file_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+impl UnpackResult {
+    /// `ImageKind::Rkfw` or `ImageKind::Rkaf`
+    pub fn kind(&self) -> ImageKind {
+        match self {
+            UnpackResult::Rkfw(_) => ImageKind::Rkfw,
+            UnpackResult::Rkaf(_) => ImageKind::Rkaf,
+        }
+    }
 
-    let signature = &buffer[0..4];
-    match signature {
-        RKAF_SIGNATURE => {
-            let info = unpack_rkafp(file_path, dst_path)?;
-            Ok(UnpackResult::Rkaf(info))
+    pub fn as_rkfw(&self) -> Option<&RkfwInfo> {
+        match self {
+            UnpackResult::Rkfw(info) => Some(info),
+            UnpackResult::Rkaf(_) => None,
         }
-        RKFW_SIGNATURE => {
-            let info = unpack_rkfw(&buffer, dst_path)?;
-            Ok(UnpackResult::Rkfw(info))
+    }
+
+    pub fn as_rkaf(&self) -> Option<&RkafInfo> {
+        match self {
+            UnpackResult::Rkaf(info) => Some(info),
+            UnpackResult::Rkfw(_) => None,
         }
-        _ => {
-            Err(anyhow!("Unknown signature: {:?}", signature))
+    }
+
+    pub fn stats(&self) -> &UnpackStats {
+        match self {
+            UnpackResult::Rkfw(info) => &info.stats,
+            UnpackResult::Rkaf(info) => &info.stats,
+        }
+    }
+
+    pub(crate) fn stats_mut(&mut self) -> &mut UnpackStats {
+        match self {
+            UnpackResult::Rkfw(info) => &mut info.stats,
+            UnpackResult::Rkaf(info) => &mut info.stats,
+        }
+    }
+
+    /// Pretty-printed JSON, tagged with `"format": "rkfw"` or `"rkaf"`
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String, ApfError> {
+        serde_json::to_string_pretty(self).map_err(|e| ApfError::Invalid(e.to_string()))
+    }
+
+    /// Failures skipped by `UnpackOptions::continue_on_error`, empty otherwise
+    pub fn errors(&self) -> &[(String, ApfError)] {
+        match self {
+            UnpackResult::Rkfw(info) => &info.errors,
+            UnpackResult::Rkaf(info) => &info.errors,
+        }
+    }
+}
+
+impl From<RkfwInfo> for UnpackResult {
+    fn from(info: RkfwInfo) -> Self {
+        UnpackResult::Rkfw(info)
+    }
+}
+
+impl From<RkafInfo> for UnpackResult {
+    fn from(info: RkafInfo) -> Self {
+        UnpackResult::Rkaf(info)
+    }
+}
+
+impl TryFrom<UnpackResult> for RkfwInfo {
+    type Error = ApfError;
+
+    fn try_from(result: UnpackResult) -> Result<Self, ApfError> {
+        match result {
+            UnpackResult::Rkfw(info) => Ok(info),
+            UnpackResult::Rkaf(_) => Err(ApfError::Invalid("expected an RKFW image, got RKAF".to_string())),
+        }
+    }
+}
+
+impl TryFrom<UnpackResult> for RkafInfo {
+    type Error = ApfError;
+
+    fn try_from(result: UnpackResult) -> Result<Self, ApfError> {
+        match result {
+            UnpackResult::Rkaf(info) => Ok(info),
+            UnpackResult::Rkfw(_) => Err(ApfError::Invalid("expected an RKAF image, got RKFW".to_string())),
+        }
+    }
+}
+
+// Errors serialize as their messages, `[["Image/boot.img", "..."]]`
+#[cfg(feature = "serde")]
+fn serialize_errors<S: serde::Serializer>(errors: &[(String, ApfError)], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(errors.iter().map(|(path, err)| (path, err.to_string())))
+}
+
+/// How extracted RKAF partitions are named inside the destination directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputNaming {
+    /// Use the path stored in the image, e.g. `Image/boot.img`; required for `pack_rkafp`
+    #[default]
+    ByPath,
+    /// `<name>.img`, e.g. `boot.img`
+    ByName,
+    /// `<index>_<name>_<flash offset>.img` numbered in flash order, e.g. `00_parameter_0x00000000.img`
+    ByFlashOffset,
+    /// The file name of the stored path without its directories, e.g. `boot.img`; fails when two
+    /// partitions share a file name
+    Flat,
+}
+
+impl std::str::FromStr for OutputNaming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "by-path" | "path" => Ok(OutputNaming::ByPath),
+            "by-name" | "name" => Ok(OutputNaming::ByName),
+            "by-flash-offset" | "flash-offset" => Ok(OutputNaming::ByFlashOffset),
+            "flat" => Ok(OutputNaming::Flat),
+            _ => Err(anyhow!("Unsupported output naming: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// Fail on a CRC or RKFW MD5 mismatch even when `allow_crc_mismatch` is set
+    pub strict_crc: bool,
+    /// Only warn about a CRC or RKFW MD5 mismatch instead of failing, to unpack images that were
+    /// edited by hand (`--ignore-crc`)
+    pub allow_crc_mismatch: bool,
+    /// Expand Android sparse partitions into raw images while extracting
+    pub expand_sparse: bool,
+    /// Extra chip code to family name mappings, checked before the built-in table
+    pub chip_overrides: HashMap<u8, String>,
+    /// Also write each BOOT loader entry (471, 472, FlashData, FlashBoot) to `BOOT.d/`
+    pub split_boot: bool,
+    /// RC4-decrypt the 471/472 loader stages written by `split_boot`
+    pub decrypt_loader: bool,
+    /// Also write each file of the `resource` partition (device trees, boot logos) to `resource/`
+    pub split_resource: bool,
+    /// Also write the kernel, ramdisk and other sections of every Android boot image partition
+    /// (`ANDROID!`, usually `boot` and `recovery`) to `<name>.d/`
+    pub split_boot_image: bool,
+    /// Also write the decompressed contents of every gzip-compressed partition (e.g. `kernel`)
+    /// next to it with a `.raw` extension, `Image/kernel.img` to `Image/kernel.raw`; the partition
+    /// file itself is left as stored
+    pub gunzip_partitions: bool,
+    /// Zero-fill every extracted RKAF partition up to its flash allocation (`flash_size` sectors),
+    /// except grow-to-end partitions
+    pub pad_to_flash_size: bool,
+    /// Hash every extracted partition and write the digests to `checksums.txt`
+    pub checksums: Option<HashAlgorithm>,
+    /// Fail instead of warning when the padding after a partition contains non-zero bytes
+    pub strict_padding: bool,
+    /// Write non-zero padding after a partition to `<partition path>.padding`
+    pub capture_padding: bool,
+    /// Size of the copy buffer used while extracting, `None` (or 0) means `DEFAULT_BUFFER_SIZE`
+    pub buffer_size: Option<usize>,
+    /// File names for extracted RKAF partitions, `partition-metadata.txt` always records the stored paths
+    pub naming: OutputNaming,
+    /// Only extract RKAF partitions whose name or path matches one of these case-insensitive
+    /// patterns (`*` and `?` wildcards), empty extracts everything
+    pub only: Vec<String>,
+    /// Format of the partition metadata file, `partition-metadata.txt` for `MetadataFormat::Legacy`
+    pub metadata_format: MetadataFormat,
+    /// Order of the rows in the partition metadata file; extraction keeps header order
+    pub sort: PartitionSort,
+    /// Do not transparently decompress gzip or zstd input in `unpack_file`
+    pub no_decompress: bool,
+    /// Leave `BOOT` out of an RKFW unpack; its offset and size are still checked and reported
+    pub no_extract_boot: bool,
+    /// Only extract `BOOT` from an RKFW image, not the embedded update image; fails for RKAF
+    /// images, which have no BOOT
+    pub boot_only: bool,
+    /// Encoding of the manufacturer, model and partition names in the RKAF header
+    pub text_encoding: TextEncoding,
+    /// Skip RKAF partitions whose output file already exists with the expected size, so an
+    /// interrupted unpack can continue; files of any other size are extracted again from scratch
+    pub resume: bool,
+    /// With `resume`, also compare the SHA-256 of an existing file with the partition data
+    /// before skipping it (expanded sparse images are only checked by size)
+    pub resume_verify: bool,
+    /// Extract every partition even when `resume` is set, and let a partition replace an earlier
+    /// one that would be extracted to the same path instead of failing
+    pub force: bool,
+    /// Unpack the RKAF image embedded in an RKFW image into `embedded-update/` instead of
+    /// writing it to `embedded-update.img`
+    pub recursive: bool,
+    /// Refuse inputs, declared image lengths and partitions larger than this many bytes before
+    /// anything is extracted; also caps decompressed and expanded sparse sizes. `None` is unlimited
+    pub max_size: Option<u64>,
+    /// Set the modification time of every file in the destination directory to the RKFW build
+    /// date (`RkfwInfo::timestamp`) so repeated unpacks produce identical trees; RKAF images
+    /// and RKFW images with a zeroed date get `epoch` instead, or are left alone without it
+    pub preserve_mtime: bool,
+    /// Seconds since the Unix epoch used by `preserve_mtime` for images without a build date,
+    /// e.g. the value of `SOURCE_DATE_EPOCH`
+    pub epoch: Option<i64>,
+    /// Unix permission bits for every file in the destination directory except `flash.sh`,
+    /// e.g. `0o644`; ignored on other platforms
+    pub file_mode: Option<u32>,
+    /// Turn every warning about the image (layout, lengths, chip code, signatures, CRC,
+    /// padding) into an error, for automated validation
+    pub strict: bool,
+    /// Partition kinds by partition name, replacing what `PartitionKind::from_name` guesses
+    pub kind_overrides: HashMap<String, PartitionKind>,
+    /// When the input does not start with a signature, search the first `SIGNATURE_SCAN_LEN`
+    /// bytes for one and unpack from there, saving the skipped bytes to `prefix.bin`. Offsets in
+    /// the result are relative to the signature
+    pub scan: bool,
+    /// Extract up to this many RKAF partitions at once when the input is a local file; 0 and 1
+    /// extract them one after another. Expanded sparse images and readers passed to
+    /// `unpack_reader` are always extracted sequentially
+    pub parallelism: usize,
+    /// Extract RKAF partitions in ascending `part_offset` order, the order they are stored in,
+    /// so the image is read front to back without seeking backwards; the metadata, manifest,
+    /// `flash.sh` and `checksums.txt` keep the partition table order
+    pub preserve_order: bool,
+    /// After extracting, check the output against this `sha256sum` style list and fail on any
+    /// mismatch, see `verify_against_manifest`. Only used when unpacking to a directory
+    pub verify_manifest: Option<String>,
+    /// Refuse to unpack into a directory that already has files in it, so two extractions are
+    /// never mixed; `force` and `resume` skip the check. Only used when unpacking to a directory
+    pub require_empty_dir: bool,
+    /// Log a file that cannot be extracted, record it in the result's `errors` and go on with
+    /// the next one instead of failing the unpack; checks that run before extraction still fail.
+    /// Only used when unpacking to a directory
+    pub continue_on_error: bool,
+    /// Also write the raw header to `header.bin`: the 2048-byte `UpdateHeader` of an RKAF image
+    /// or the 0x66-byte RKFW header, for archiving or re-signing apart from the partitions
+    pub save_header: bool,
+    /// Output paths by partition name, used instead of the path `naming` gives; relative to the
+    /// destination, with `/` or `\` separators, and like stored paths they may not leave it.
+    /// Missing parent directories are created, and two partitions with the same output path
+    /// fail unless `force` is set
+    pub path_map: HashMap<String, std::path::PathBuf>,
+    /// Only extract RKAF partitions that have an entry in `path_map`, on top of `only`
+    pub mapped_only: bool,
+    /// Write RKAF partitions larger than this many bytes as `<path>.part0`, `.part1`, ... of at
+    /// most this size instead of one file, described in `chunks.json` (`ChunkIndex`). Chunks
+    /// hold the stored bytes: expanded sparse partitions are not split, split ones are not
+    /// padded by `pad_to_flash_size`, and `resume` extracts them again. Only used when
+    /// unpacking to a directory
+    pub split_size: Option<u64>,
+}
+
+/// RKFW header fields saved next to `BOOT` and `embedded-update.img` when unpacking RKFW
+pub(crate) const RKFW_INFO_FILE: &str = "rkfw-info.json";
+
+/// Raw image header written with `UnpackOptions::save_header`
+pub(crate) const HEADER_FILE: &str = "header.bin";
+
+/// Written by an RKAF unpack when the image has no parameter partition, see `generate_parameter`
+pub(crate) const GENERATED_PARAMETER_FILE: &str = "parameter.generated";
+
+/// Bytes before the signature found by `UnpackOptions::scan`, e.g. a vendor download header
+pub(crate) const PREFIX_FILE: &str = "prefix.bin";
+
+/// Subdirectory the embedded RKAF image is unpacked into with `UnpackOptions::recursive`
+pub(crate) const EMBEDDED_DIR: &str = "embedded-update";
+
+/// Subdirectory the files of the `resource` partition are written to with `UnpackOptions::split_resource`
+pub(crate) const RESOURCE_DIR: &str = "resource";
+
+/// Copy buffer size used when `UnpackOptions::buffer_size` is not set
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+impl UnpackOptions {
+    pub(crate) fn buffer_size(&self) -> usize {
+        self.buffer_size.filter(|&size| size > 0).unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    fn check_size(&self, what: &str, size: u64) -> Result<()> {
+        match self.max_size {
+            Some(max) if size > max => Err(ApfError::Invalid(format!(
+                "{} is {} bytes, larger than the maximum size of {} bytes",
+                what, size, max
+            )).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn unpack_file(file_path: &str, dst_path: &str) -> Result<UnpackResult, ApfError> {
+    unpack_file_with_options(file_path, dst_path, &UnpackOptions::default())
+}
+
+pub fn unpack_file_with_options(file_path: &str, dst_path: &str, options: &UnpackOptions) -> Result<UnpackResult, ApfError> {
+    unpack_file_with_progress(file_path, dst_path, options, None, None)
+}
+
+// `progress` is called with (bytes done, total) for every chunk of each extracted file, ending with (total, total)
+// `cancel` is checked before each partition and chunk; once it is set the extraction stops, the
+// file being written is deleted and `ApfError::Cancelled` is returned, also with `continue_on_error`
+// A `<name>.001` path is read together with its following numbered parts
+pub fn unpack_file_with_progress(file_path: &str, dst_path: &str, options: &UnpackOptions, progress: Option<&dyn Fn(u64, u64)>, cancel: Option<&AtomicBool>) -> Result<UnpackResult, ApfError> {
+    unpack_path(file_path, dst_path, options, Progress { report: progress, cancel, finished: None })
+}
+
+pub(crate) fn unpack_path(file_path: &str, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<UnpackResult, ApfError> {
+    if file_path == STDIN_PATH {
+        return unpack_input(spool_stdin(options)?, dst_path, options, progress);
+    }
+    match split_parts(file_path) {
+        Some(parts) => {
+            info!("Reading {} as a split image of {} parts", file_path, parts.len());
+            unpack_input(MultiFileReader::open(&parts)?, dst_path, options, progress)
+        }
+        None => unpack_input(File::open(file_path)?, dst_path, options, progress),
+    }
+}
+
+fn unpack_input<R: ImageSource>(mut input: R, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<UnpackResult, ApfError> {
+    match decompress_input(&mut input, options)? {
+        Some(file) => unpack_source(file, dst_path, options, progress),
+        None => unpack_source(input, dst_path, options, progress),
+    }
+}
+
+/// Input path that reads the image from stdin
+pub(crate) const STDIN_PATH: &str = "-";
+
+// Both formats are read out of order (RKFW ends in an MD5 of the whole file, RKAF partitions can
+// be anywhere), so stdin is copied to an anonymous temporary file that is removed on close
+pub(crate) fn spool_stdin(options: &UnpackOptions) -> Result<File, ApfError> {
+    let mut spool = tempfile::tempfile()?;
+    let limit = options.max_size.map_or(u64::MAX, |max| max.saturating_add(1));
+    let written = std::io::copy(&mut std::io::stdin().lock().take(limit), &mut spool)?;
+    options.check_size("Input image", written)?;
+    info!("Read {} from stdin", human_size(written));
+    spool.rewind()?;
+    Ok(spool)
+}
+
+// A temporary file with the decompressed image for gzip or zstd input, `None` for anything else
+pub(crate) fn decompress_input<R: Read + Seek>(input: &mut R, options: &UnpackOptions) -> Result<Option<File>, ApfError> {
+    if options.no_decompress {
+        return Ok(None);
+    }
+    let compression = match read_signature(input)?.0 {
+        ImageKind::Gzip => Compression::Gzip,
+        ImageKind::Zstd => Compression::Zstd,
+        _ => return Ok(None),
+    };
+    decompress_to_temp(input, compression, options.max_size).map(Some)
+}
+
+#[cfg(feature = "serde")]
+pub fn unpack_file_json(file_path: &str, dst_path: &str) -> Result<String, ApfError> {
+    unpack_file(file_path, dst_path)?.to_json()
+}
+
+pub fn unpack_reader<R: Read + Seek>(reader: R, dst_path: &str) -> Result<UnpackResult, ApfError> {
+    unpack_reader_with_options(reader, dst_path, &UnpackOptions::default())
+}
+
+pub fn unpack_reader_with_options<R: Read + Seek>(reader: R, dst_path: &str, options: &UnpackOptions) -> Result<UnpackResult, ApfError> {
+    unpack_reader_with_progress(reader, dst_path, options, None, None)
+}
+
+/// Like `unpack_file_with_progress` for a reader
+pub fn unpack_reader_with_progress<R: Read + Seek>(reader: R, dst_path: &str, options: &UnpackOptions, progress: Option<&dyn Fn(u64, u64)>, cancel: Option<&AtomicBool>) -> Result<UnpackResult, ApfError> {
+    unpack_stream(reader, dst_path, options, Progress { report: progress, cancel, finished: None })
+}
+
+pub(crate) fn unpack_stream<R: Read + Seek>(reader: R, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<UnpackResult, ApfError> {
+    unpack_source(StreamSource(reader), dst_path, options, progress)
+}
+
+fn unpack_source<R: ImageSource>(mut reader: R, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<UnpackResult, ApfError> {
+    let started = Instant::now();
+    check_empty_dir(dst_path, options)?;
+    let (kind, prefix_len) = find_image(&mut reader, options)?;
+    let prefix_path = format!("{}/{}", dst_path, PREFIX_FILE);
+    if std::path::Path::new(&prefix_path).exists() {
+        std::fs::remove_file(&prefix_path)?;
+    }
+    let mut result = if prefix_len > 0 {
+        std::fs::create_dir_all(dst_path)?;
+        copy_range(&mut reader, 0, prefix_len, &prefix_path, options.buffer_size(), Progress::default())?;
+        info!("Saved the {} byte prefix to {}", prefix_len, prefix_path);
+        let len = stream_len(&mut reader)? - prefix_len;
+        unpack_image(&mut RegionReader::new(&mut reader, prefix_len, len), kind, dst_path, options, progress)?
+    } else {
+        unpack_image(&mut reader, kind, dst_path, options, progress)?
+    };
+    set_file_attributes(dst_path, options, result.as_rkfw().map(|info| info.timestamp).filter(|&timestamp| timestamp != 0))?;
+    if let Some(manifest_path) = &options.verify_manifest {
+        check_manifest(dst_path, manifest_path)?;
+    }
+    result.stats_mut().duration_ms = started.elapsed().as_millis() as u64;
+    let stats = result.stats();
+    info!("Extracted {} partitions, {} total", stats.files_written, human_size(stats.bytes_written));
+    if !result.errors().is_empty() {
+        warn!("{} files could not be extracted", result.errors().len());
+    }
+    if let UnpackResult::Rkaf(info) = &result {
+        if info.trailing_bytes > 0 {
+            info!("{} trailing bytes after the last partition", info.trailing_bytes);
         }
     }
+    Ok(result)
 }
 
-fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<RkfwInfo> {
-    let mut chip: Option<&str> = None;
+// See `UnpackOptions::verify_manifest`
+fn check_manifest(dst_path: &str, manifest_path: &str) -> Result<(), ApfError> {
+    let results = verify_against_manifest(dst_path, manifest_path)?;
+    let mismatched: Vec<&str> = results.iter().filter(|(_, matches)| !matches).map(|(path, _)| path.as_str()).collect();
+    for path in &mismatched {
+        warn!("{} does not match {}", path, manifest_path);
+    }
+    if !mismatched.is_empty() {
+        return Err(ApfError::Invalid(format!("{} of {} files do not match {}", mismatched.len(), results.len(), manifest_path)));
+    }
+    info!("All {} files match {}", results.len(), manifest_path);
+    Ok(())
+}
+
+// See `UnpackOptions::require_empty_dir`; a missing directory counts as empty
+fn check_empty_dir(dst_path: &str, options: &UnpackOptions) -> Result<(), ApfError> {
+    if !options.require_empty_dir || options.force || options.resume {
+        return Ok(());
+    }
+    let mut entries = match std::fs::read_dir(dst_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if entries.next().transpose()?.is_some() {
+        return Err(ApfError::Invalid(format!("{} is not empty, pass --force to unpack into it anyway", dst_path)));
+    }
+    Ok(())
+}
+
+// Kind of the image and its offset in `reader`, which is only non-zero with `UnpackOptions::scan`
+pub(crate) fn find_image<R: Read + Seek>(reader: &mut R, options: &UnpackOptions) -> Result<(ImageKind, u64), ApfError> {
+    let (kind, signature) = read_signature(reader)?;
+    match kind {
+        ImageKind::Rkaf | ImageKind::Rkfw => Ok((kind, 0)),
+        _ if options.scan => match scan_signature(reader)? {
+            Some((kind, offset)) => {
+                info!("Found {} signature at offset {:#x}", if kind == ImageKind::Rkfw { "RKFW" } else { "RKAF" }, offset);
+                Ok((kind, offset))
+            }
+            None => Err(ApfError::UnknownSignature(signature)),
+        },
+        _ => Err(ApfError::UnknownSignature(signature)),
+    }
+}
+
+fn unpack_image<R: ImageSource>(reader: &mut R, kind: ImageKind, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<UnpackResult> {
+    check_boot_selection(kind, options)?;
+    Ok(match kind {
+        ImageKind::Rkfw => UnpackResult::Rkfw(unpack_rkfw(reader, dst_path, options, progress)?),
+        _ => UnpackResult::Rkaf(unpack_rkafp(reader, dst_path, options, progress)?),
+    })
+}
+
+// `no_extract_boot` and `boot_only` only make sense apart and for RKFW images
+pub(crate) fn check_boot_selection(kind: ImageKind, options: &UnpackOptions) -> Result<()> {
+    if options.boot_only && options.no_extract_boot {
+        return Err(ApfError::Invalid("boot_only and no_extract_boot exclude each other".to_string()).into());
+    }
+    if options.boot_only && kind != ImageKind::Rkfw {
+        return Err(ApfError::Invalid("boot_only needs an RKFW image, RKAF images have no BOOT".to_string()).into());
+    }
+    Ok(())
+}
+
+// Post-extraction pass over the destination directory for `preserve_mtime` and `file_mode`
+fn set_file_attributes(dst_path: &str, options: &UnpackOptions, timestamp: Option<i64>) -> Result<()> {
+    let mtime = match (options.preserve_mtime, timestamp.or(options.epoch)) {
+        (true, Some(timestamp)) => {
+            let offset = std::time::Duration::from_secs(timestamp.unsigned_abs());
+            if timestamp >= 0 {
+                std::time::UNIX_EPOCH.checked_add(offset)
+            } else {
+                std::time::UNIX_EPOCH.checked_sub(offset)
+            }
+        }
+        (true, None) => {
+            info!("The image has no build date and no epoch was given, file times are left unchanged");
+            None
+        }
+        (false, _) => None,
+    };
+    if mtime.is_none() && options.file_mode.is_none() {
+        return Ok(());
+    }
+
+    let mut dirs = vec![std::path::PathBuf::from(dst_path)];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            // Before the mode, which may make the file read-only
+            if let Some(mtime) = mtime {
+                File::options().write(true).open(&path)?.set_modified(mtime)?;
+            }
+            #[cfg(unix)]
+            if let Some(mode) = options.file_mode.filter(|_| path.file_name() != Some(FLASH_SCRIPT_FILE.as_ref())) {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) const RKFW_HEADER_SIZE: usize = 0x66;
+
+/// Value of the RKFW byte at 0x05 in the headers of recent RK3566/RK3588 SDKs, which keep the
+/// BOOT and update ranges at other offsets than the 0x19..0x29 that `parse_rkfw` reads
+pub(crate) const RKFW_V6_LAYOUT: u8 = 6;
+
+pub(crate) fn parse_rkfw(buf: &[u8], options: &UnpackOptions) -> Result<RkfwInfo> {
+    if buf.len() < RKFW_HEADER_SIZE {
+        return Err(ApfError::Truncated(format!(
+            "File too small to contain an RKFW header ({} bytes, need {})",
+            buf.len(),
+            RKFW_HEADER_SIZE
+        )).into());
+    }
 
-    println!("RKFW signature detected");
+    info!("RKFW signature detected");
 
     let version_str = format!(
         "{}.{}.{}",
@@ -75,10 +834,10 @@ fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<RkfwInfo> {
         buf[8],
         ((buf[7] as u16) << 8) + buf[6] as u16
     );
-    println!("version: {}", version_str);
+    info!("version: {}", version_str);
 
     let code = u32::from_le_bytes([buf[0x0a], buf[0x0b], buf[0x0c], buf[0x0d]]);
-    println!("code field: 0x{:08x}", code);
+    info!("code field: 0x{:08x} (merge version {})", code, FirmwareCode(code));
 
     let year = ((buf[0x0f] as u16) << 8) | (buf[0x0e] as u16);
     let month = buf[0x10];
@@ -87,218 +846,2134 @@ fn unpack_rkfw(buf: &[u8], dst_path: &str) -> Result<RkfwInfo> {
     let minute = buf[0x13];
     let second = buf[0x14];
 
-    let date = chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
-        .ok_or_else(|| anyhow!("Invalid date"))?;
-    let time = chrono::NaiveTime::from_hms_opt(hour as u32, minute as u32, second as u32)
-        .ok_or_else(|| anyhow!("Invalid time"))?;
-    let dt = NaiveDateTime::new(date, time);
-    let unix_timestamp = dt.and_utc().timestamp();
+    // Zeroed dates (common in dev builds) end up here too, month and day 0 are out of range
+    let date = chrono::NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32);
+    let time = chrono::NaiveTime::from_hms_opt(hour as u32, minute as u32, second as u32);
+    let built_at = match date.zip(time) {
+        Some((date, time)) => NaiveDateTime::new(date, time).and_utc(),
+        None if options.strict => return Err(ApfError::InvalidDate.into()),
+        None => {
+            warn!(
+                "Invalid build date {}-{:02}-{:02} {:02}:{:02}:{:02}, using timestamp 0",
+                year, month, day, hour, minute, second
+            );
+            DateTime::UNIX_EPOCH
+        }
+    };
+    let unix_timestamp = built_at.timestamp();
 
-    println!(
+    info!(
         "date: {}-{:02}-{:02} {:02}:{:02}:{:02} (Unix timestamp: {})",
         year, month, day, hour, minute, second, unix_timestamp
     );
 
     let chip_code = buf[0x15];
-    match chip_code {
-        0x50 => chip = Some("RK29xx"),
-        0x60 => chip = Some("RK30xx"),
-        0x70 => chip = Some("RK31xx"),
-        0x80 => chip = Some("RK32xx"),
-        0x41 => chip = Some("RK3368"),
-        0x36 => chip = Some("RK3326"),
-        0x32 => chip = Some("RK3562"),
-        0x38 => chip = Some("RK3566"),
-        0x30 => chip = Some("PX30"),
-        _ => println!(
-            "You got a brand new chip ({:#x}), congratulations!!!",
+    let chip = options.chip_overrides.get(&chip_code).map(String::as_str).or_else(|| chip_family(chip_code));
+    if chip.is_none() {
+        warn_or_err(options, format!(
+            "You got a brand new chip ({:#x}), congratulations!!! Run `chips` to list the known ones",
             chip_code
-        ),
+        ))?;
     }
 
     let chip_name = chip.unwrap_or("unknown");
-    println!("family: {}", chip_name);
+    info!("family: {}", chip_name);
+
+    let layout = buf[0x05];
+    if layout == RKFW_V6_LAYOUT {
+        // No image to confirm where v6 moved the fields to, so they are read at the legacy offsets
+        warn_or_err(options, format!(
+            "RKFW header layout {} is not supported, the BOOT and update offsets may be misread",
+            layout
+        ))?;
+    }
 
     let boot_offset = get_u32_le(&buf[0x19..]);
     let boot_size: u32 = get_u32_le(&buf[0x1d..]);
 
-    // if &buf[boot_offset as usize..boot_offset as usize + 4] != b"BOOT" {
-    //     panic!("cannot find BOOT signature");
-    // }
-
-    println!(
-        "{:08x}-{:08x} {:26} (size: {})",
-        boot_offset,
-        boot_offset + boot_size - 1,
+    info!(
+        "{:17} {:26} (size: {})",
+        format_range(boot_offset as u64, boot_size as u64),
         "BOOT",
-        boot_size
+        human_size(boot_size as u64)
     );
-    std::fs::create_dir_all(dst_path)?;
-    write_file(
-        &Path::new(&format!("{}/BOOT", dst_path)),
-        &buf[boot_offset as usize..boot_offset as usize + (boot_size as usize)],
-    )?;
 
     let update_offset = get_u32_le(&buf[0x21..]);
     let update_size = get_u32_le(&buf[0x25..]);
+    debug!(
+        "RKFW header: layout {}, chip code {:#04x}, BOOT {:#x}+{:#x}, update {:#x}+{:#x}",
+        layout, chip_code, boot_offset, boot_size, update_offset, update_size
+    );
 
-    if &buf[update_offset as usize..update_offset as usize + 4] != b"RKAF" {
-        panic!("cannot find embedded RKAF update.img");
-    }
-
-    println!(
-        "{:08x}-{:08x} {:26} (size: {})",
-        update_offset,
-        update_offset + update_size - 1,
+    info!(
+        "{:17} {:26} (size: {})",
+        format_range(update_offset as u64, update_size as u64),
         "embedded-update.img",
-        update_size
+        human_size(update_size as u64)
     );
-    write_file(
-        &Path::new(&format!("{}/embedded-update.img", dst_path)),
-        &buf[update_offset as usize..update_offset as usize + update_size as usize],
-    )?;
 
     Ok(RkfwInfo {
         version: version_str,
         code,
         timestamp: unix_timestamp,
+        built_at,
         chip_family: chip_name.to_string(),
+        chip_family_inferred: false,
         chip_code,
         boot_offset,
         boot_size,
+        has_boot: boot_size != 0,
         update_offset,
         update_size,
+        update_skipped: 0,
+        pre_boot: None,
+        boot_gap: None,
+        signature: None,
+        signed: false,
+        md5_expected: None,
+        md5_computed: None,
+        stats: UnpackStats::default(),
+        nested: None,
+        embedded: None,
+        errors: Vec::new(),
     })
 }
 
-fn extract_file(fp: &mut File, offset: u64, len: u64, full_path: &str) -> Result<()> {
-    println!("{:08x}-{:08x} {}", offset, len, full_path);
-    let mut buffer = vec![0u8; 16 * 1024];
-    let mut fp_out = File::create(full_path)?;
+/// How far past the header's `update_offset` the embedded image's magic is looked for; some vendor
+/// images point a few bytes before it
+pub const EMBEDDED_MAGIC_WINDOW: u32 = 16;
 
-    fp.seek(std::io::SeekFrom::Start(offset))?;
+// Moves `update_offset` onto the first RKAF magic or RKFW signature within EMBEDDED_MAGIC_WINDOW
+// bytes of it and records the skipped bytes in `update_skipped`; `info` is left alone when the
+// magic is at `update_offset` or nowhere in the window. Returns the number of bytes skipped.
+pub(crate) fn locate_embedded_image<R: Read + Seek>(fp: &mut R, info: &mut RkfwInfo, filesize: u64) -> Result<u32> {
+    let start = info.update_offset as u64;
+    let window = (info.update_size as u64).min(EMBEDDED_MAGIC_WINDOW as u64 + 3).min(filesize.saturating_sub(start));
+    if window < 4 {
+        return Ok(0);
+    }
+    let buf = read_bytes_at(fp, start, window as usize)?;
+    let found = (0..=buf.len() - 4).find(|&skip| UpdateHeader::has_rkaf_magic(&buf[skip..]) || buf[skip..].starts_with(RKFW_SIGNATURE));
+    match found {
+        Some(skip) if skip > 0 => {
+            let skip = skip as u32;
+            info.update_offset += skip;
+            info.update_size -= skip;
+            info.update_skipped = skip;
+            Ok(skip)
+        }
+        _ => Ok(0),
+    }
+}
 
-    let mut remaining = len;
+// What the functions that only understand one RKFW level report for a double-wrapped image
+pub(crate) fn nested_rkfw_error(info: &RkfwInfo) -> ApfError {
+    ApfError::Invalid(format!(
+        "embedded-update.img at offset {:#x} is another RKFW image instead of RKAF, unpack it to a directory with --recursive",
+        info.update_offset
+    ))
+}
 
-    while remaining > 0 {
-        let read_len = std::cmp::min(remaining as usize, buffer.len());
-        let read_bytes = fp.read(&mut buffer[..read_len])?;
+pub(crate) const PRE_BOOT_FILE: &str = "reserved-pre-boot.bin";
+pub(crate) const BOOT_GAP_FILE: &str = "reserved-gap.bin";
+pub(crate) const SIGNATURE_FILE: &str = "signature.bin";
+/// The MD5 at the end of an RKFW image, as 32 hex characters
+pub(crate) const RKFW_MD5_LEN: u64 = 32;
 
-        if read_bytes != read_len {
-            return Err(anyhow!("Insufficient length in container image file"));
+// Fills in pre_boot, boot_gap and signature; regions that overlap or run past the file are left out
+fn find_reserved_regions<R: Read + Seek>(fp: &mut R, info: &mut RkfwInfo, filesize: u64) -> Result<()> {
+    let boot_end = info.boot_offset as u64 + info.boot_size as u64;
+    let mut region = |start: u64, end: u64| -> Result<Option<ReservedRegion>> {
+        if end <= start || end > filesize {
+            return Ok(None);
         }
+        Ok(Some(ReservedRegion {
+            offset: start as u32,
+            size: (end - start) as u32,
+            zeroed: range_is_zero(fp, start, end - start)?,
+        }))
+    };
+    info.pre_boot = region(RKFW_HEADER_SIZE as u64, info.boot_offset as u64)?;
+    info.boot_gap = region(boot_end, info.update_offset as u64)?;
+    let update_end = info.update_offset as u64 + info.update_size as u64;
+    info.signature = region(update_end, filesize.saturating_sub(RKFW_MD5_LEN))?;
+    info.signed = info.signature.is_some_and(|region| !region.zeroed);
+    Ok(())
+}
 
-        fp_out.write_all(&buffer[..read_len])?;
+// RK29xx-RK33xx loaders start with "BOOT", newer ones with "LDR "
+const BOOT_SIGNATURES: [&[u8]; 2] = [b"BOOT", b"LDR "];
 
-        remaining -= read_len as u64;
+// BOOT is already taken by the whole blob, so the entries go next to it
+fn split_boot(boot_data: &[u8], dst_path: &str, options: &UnpackOptions) -> Result<()> {
+    let entries = match parse_boot(boot_data) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return warn_or_err(options, format!("cannot split BOOT: {}", e));
+        }
+    };
+
+    let encrypted = boot_is_rc4_encrypted(boot_data);
+    let decrypt = options.decrypt_loader;
+    if decrypt && !encrypted {
+        info!("BOOT loader stages are not RC4 encrypted");
     }
 
+    let boot_dir = format!("{}/BOOT.d", dst_path);
+    std::fs::create_dir_all(&boot_dir)?;
+    let mut used_names: Vec<String> = Vec::new();
+    for entry in entries {
+        let file_name = unique_file_name(&entry.name, &format!("{:?}", entry.kind), &used_names);
+        let start = entry.offset as usize;
+        let path = format!("{}/{}", boot_dir, file_name);
+        info!("{:17} {:26} (size: {})", format_range(entry.offset as u64, entry.size as u64), path, human_size(entry.size as u64));
+        let mut data = boot_data[start..start + entry.size as usize].to_vec();
+        if decrypt && encrypted && entry.kind != BootEntryKind::Loader {
+            rc4_rk(&mut data);
+        }
+        std::fs::write(&path, &data)?;
+        used_names.push(file_name);
+    }
     Ok(())
 }
 
-fn unpack_rkafp(file_path: &str, dst_path: &str) -> Result<RkafInfo> {
-    use std::mem;
+// `name` with path separators replaced, so it stays inside the output directory
+fn unique_file_name(name: &str, fallback: &str, used_names: &[String]) -> String {
+    let mut file_name: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    if file_name.is_empty() || file_name == "." || file_name == ".." {
+        file_name = fallback.to_string();
+    }
+    if used_names.contains(&file_name) {
+        file_name = format!("{}.{}", file_name, used_names.len());
+    }
+    file_name
+}
 
-    let mut fp = File::open(file_path)?;
-    let mut buf = vec![0u8; mem::size_of::<UpdateHeader>()];
-    fp.read_exact(&mut buf)?;
-    let header = UpdateHeader::from_bytes(buf.as_mut());
-    let magic_str = std::str::from_utf8(&header.magic)?;
-    if magic_str != RKAFP_MAGIC {
-        return Err(anyhow!("Invalid header magic id"));
+// Writes the sections of an Android boot image to `<name>.d/`, e.g. `boot.d/kernel`
+fn split_boot_image(image_data: &[u8], name: &str, dst_path: &str, options: &UnpackOptions) -> Result<()> {
+    let image = match parse_boot_image(image_data) {
+        Ok(image) => image,
+        Err(e) => {
+            return warn_or_err(options, format!("cannot split boot image {}: {}", name, e));
+        }
+    };
+    info!("{} is an Android boot image (header version {}, page size {})", name, image.header_version, image.page_size);
+
+    let image_dir = format!("{}/{}.d", dst_path, unique_file_name(name, "bootimg", &[]));
+    std::fs::create_dir_all(&image_dir)?;
+    for (section_name, section) in image.sections() {
+        let start = section.offset as usize;
+        let path = format!("{}/{}", image_dir, section_name);
+        info!("{:17} {:26} (size: {})", format_range(section.offset as u64, section.size as u64), path, human_size(section.size as u64));
+        std::fs::write(&path, &image_data[start..start + section.size as usize])?;
+    }
+    if !image.cmdline.is_empty() {
+        std::fs::write(format!("{}/cmdline", image_dir), format!("{}\n", image.cmdline))?;
     }
+    Ok(())
+}
 
-    let filesize = fp.metadata()?.len();
-    println!("Filesize: {}", filesize);
-    if filesize - 4 != header.length as u64 {
-        eprintln!("update_header.length cannot be correct, cannot check CRC");
+// Writes the contents of a gzip-compressed partition next to its file, e.g. `Image/kernel.raw`;
+// partitions without the gzip magic are left alone
+fn gunzip_partition<R: Read + Seek>(fp: &mut R, part: &PartitionInfo, output_path: &std::path::Path, options: &UnpackOptions) -> Result<()> {
+    let mut region = RegionReader::new(fp, part.part_offset as u64, part.part_byte_count as u64);
+    if !matches!(read_signature(&mut region), Ok((ImageKind::Gzip, _))) {
+        return Ok(());
     }
-    std::fs::create_dir_all(format!("{}/Image", dst_path))?;
-    // 安全地从null-terminated字符串中提取文本
-    let manufacturer = std::ffi::CStr::from_bytes_until_nul(&header.manufacturer)
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|_| "unknown".to_string());
-    let model = std::ffi::CStr::from_bytes_until_nul(&header.model)
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|_| "unknown".to_string());
+    let raw_path = output_path.with_extension("raw");
+    let mut out = File::create(&raw_path)?;
+    match decompress(&mut region, Compression::Gzip, &mut out, options.max_size) {
+        Ok(written) => {
+            info!("{} is gzip compressed, wrote {} to {}", part.path, human_size(written), raw_path.display());
+            Ok(())
+        }
+        Err(e) => {
+            drop(out);
+            std::fs::remove_file(&raw_path)?;
+            warn_or_err(options, format!("cannot decompress {}: {}", part.path, e))
+        }
+    }
+}
 
-    println!("manufacturer: {}", manufacturer);
-    println!("model: {}", model);
+// Writes every file of a resource image (device trees, boot logos) to `resource/`
+fn split_resource(resource_data: &[u8], dst_path: &str, options: &UnpackOptions) -> Result<()> {
+    let entries = match parse_resource(resource_data) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return warn_or_err(options, format!("cannot split resource: {}", e));
+        }
+    };
 
-    // Save partition metadata for repacking
-    let metadata_path = format!("{}/partition-metadata.txt", dst_path);
-    let mut metadata_file = File::create(&metadata_path)?;
-    let mut partitions = Vec::new();
+    let resource_dir = format!("{}/{}", dst_path, RESOURCE_DIR);
+    std::fs::create_dir_all(&resource_dir)?;
+    let mut used_names: Vec<String> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let file_name = unique_file_name(&entry.name, &format!("resource{}", index), &used_names);
+        let start = entry.offset as usize;
+        let path = format!("{}/{}", resource_dir, file_name);
+        info!("{:17} {:26} (size: {})", format_range(entry.offset as u64, entry.size as u64), path, human_size(entry.size as u64));
+        std::fs::write(&path, &resource_data[start..start + entry.size as usize])?;
+        used_names.push(file_name);
+    }
+    Ok(())
+}
 
-    for i in 0..header.num_parts {
-        let part = &header.parts[i as usize];
-        // 安全地提取路径字符串
-        if let Ok(cstr_path) = std::ffi::CStr::from_bytes_until_nul(&part.full_path) {
-            let part_full_path = cstr_path.to_string_lossy();
-            if part_full_path == "SELF" || part_full_path == "RESERVED" {
-                continue;
-            }
+fn check_boot_signature<R: Read + Seek>(fp: &mut R, info: &mut RkfwInfo, filesize: u64, options: &UnpackOptions) -> Result<()> {
+    if !info.has_boot {
+        return warn_or_err(options, "RKFW image has no BOOT blob (boot_size is 0)".to_string());
+    }
+    let offset = info.boot_offset as u64;
+    let signature = if info.boot_size >= 4 && offset + 4 <= filesize {
+        read_bytes_at(fp, offset, 4)?
+    } else {
+        Vec::new()
+    };
+    if !BOOT_SIGNATURES.contains(&signature.as_slice()) {
+        warn_or_err(options, format!("cannot find BOOT signature at offset {:#x}, skipping BOOT", offset))?;
+        info.has_boot = false;
+    }
+    Ok(())
+}
 
-            let part_name = if let Ok(cstr_name) = std::ffi::CStr::from_bytes_until_nul(&part.name) {
-                cstr_name.to_string_lossy().to_string()
-            } else {
-                String::new()
-            };
-
-            let flash_size = part.flash_size;
-            let flash_offset = part.flash_offset;
-            let part_offset = part.part_offset;
-            let padded_size = part.padded_size;
-            let part_byte_count = part.part_byte_count;
-
-            writeln!(
-                metadata_file,
-                "{},{},{:#010x},{:#010x},{:#010x},{:#010x},{:#010x}",
-                part_name,
-                part_full_path,
-                flash_size,
-                flash_offset,
-                part_offset,
-                padded_size,
-                part_byte_count
-            )?;
-
-            partitions.push(PartitionInfo {
-                name: part_name.clone(),
-                path: part_full_path.to_string(),
-                flash_size,
-                flash_offset,
-                part_offset,
-                padded_size,
-                part_byte_count,
-            });
-
-            let part_full_path = format!("{}/{}", dst_path, part_full_path);
-            extract_file(
-                &mut fp,
-                part.part_offset as u64,
-                part.part_byte_count as u64,
-                &part_full_path,
-            )?;
-        }
-    }
-
-    println!("\nPartition metadata saved to: {}", metadata_path);
-
-    Ok(RkafInfo {
-        manufacturer,
-        model,
-        filesize,
-        partitions,
-    })
+// A chip code missing from the table often belongs to a SoC the loader still names, e.g. RK3588
+fn infer_chip_from_boot<R: Read + Seek>(fp: &mut R, info: &mut RkfwInfo, options: &UnpackOptions) -> Result<()> {
+    if !info.has_boot || options.chip_overrides.contains_key(&info.chip_code) || chip_family(info.chip_code).is_some() {
+        return Ok(());
+    }
+    let boot = read_bytes_at(fp, info.boot_offset as u64, info.boot_size as usize)?;
+    if let Some(family) = infer_chip_family(&boot) {
+        info!("family: {} (inferred from BOOT, chip code {:#x})", family, info.chip_code);
+        info.chip_family = family.to_string();
+        info.chip_family_inferred = true;
+    }
+    Ok(())
 }
 
-fn get_u32_le(slice: &[u8]) -> u32 {
-    u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]])
+// Parses and checks an RKFW image before anything is written
+pub(crate) fn read_rkfw<R: Read + Seek>(fp: &mut R, options: &UnpackOptions) -> Result<RkfwInfo> {
+    read_nested_rkfw(fp, options, 0)
 }
 
-fn write_file(path: &Path, buffer: &[u8]) -> Result<()> {
-    let mut file = File::create(path)?;
-    file.write_all(buffer)?;
-    Ok(())
+/// RKFW images wrapped in more RKFW images than this are refused
+pub(crate) const MAX_RKFW_NESTING: usize = 4;
+
+// `depth` counts the RKFW images around this one
+fn read_nested_rkfw<R: Read + Seek>(fp: &mut R, options: &UnpackOptions, depth: usize) -> Result<RkfwInfo> {
+    let filesize = stream_len(fp)?;
+    options.check_size("Input image", filesize)?;
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    let mut buf = Vec::new();
+    (&mut *fp).take(RKFW_HEADER_SIZE as u64).read_to_end(&mut buf)?;
+    let mut info = parse_rkfw(&buf, options)?;
+    options.check_size("BOOT", info.boot_size as u64)?;
+    options.check_size("embedded-update.img", info.update_size as u64)?;
+
+    check_region(info.boot_offset, info.boot_size, filesize, "BOOT")?;
+    check_boot_signature(fp, &mut info, filesize, options)?;
+    infer_chip_from_boot(fp, &mut info, options)?;
+    check_region(info.update_offset, info.update_size, filesize, "embedded-update.img")?;
+    let skipped = locate_embedded_image(fp, &mut info, filesize)?;
+    if skipped > 0 {
+        warn_or_err(options, format!(
+            "embedded-update.img starts {} bytes after the update offset {:#x} in the header, using {:#x}",
+            skipped,
+            info.update_offset - skipped,
+            info.update_offset
+        ))?;
+    }
+    // The skipped bytes become part of the gap after BOOT, which keeps them for repacking
+    find_reserved_regions(fp, &mut info, filesize)?;
+    if let Some(signature) = info.signature.filter(|_| info.signed) {
+        info!(
+            "Signed image: {} byte signature block at {:#x}-{:#x} before the MD5",
+            signature.size,
+            signature.offset,
+            region_end(signature.offset, signature.size)
+        );
+    }
+    let update_offset = info.update_offset as u64;
+    let update_size = info.update_size as u64;
+
+    let update_signature = if update_size < 4 { Vec::new() } else { read_bytes_at(fp, update_offset, 4)? };
+    if update_signature == RKFW_SIGNATURE {
+        if depth + 1 >= MAX_RKFW_NESTING {
+            return Err(ApfError::Invalid(format!(
+                "embedded-update.img at offset {:#x} is the RKFW image at nesting level {}, at most {} levels are supported",
+                update_offset,
+                depth + 2,
+                MAX_RKFW_NESTING
+            )).into());
+        }
+        warn_or_err(options, format!("embedded-update.img at offset {:#x} is another RKFW image instead of RKAF", update_offset))?;
+        // Boxed so every level below this one reads through the same type
+        let mut embedded: Box<dyn ImageSource + '_> = Box::new(StreamSource(RegionReader::new(fp, update_offset, update_size)));
+        let nested = read_nested_rkfw(&mut embedded, options, depth + 1)?;
+        drop(embedded);
+        info.nested = Some(Box::new(nested));
+        check_md5(fp, &mut info, filesize, options)?;
+        return Ok(info);
+    }
+    if !UpdateHeader::has_rkaf_magic(&update_signature) {
+        return Err(anyhow!(
+            "cannot find embedded RKAF update.img at offset {:#x} or in the {} bytes after it (found {:?} instead of the {} magic)",
+            update_offset,
+            EMBEDDED_MAGIC_WINDOW,
+            String::from_utf8_lossy(&update_signature),
+            RKAFP_MAGIC
+        ));
+    }
+
+    // The embedded header's length is the offset of its trailing RKCRC, so it should cover update_size exactly
+    if update_size >= 8 {
+        let length = get_u32_le(&read_bytes_at(fp, update_offset + 4, 4)?) as u64;
+        if length + 4 != update_size {
+            warn_or_err(options, format!(
+                "embedded-update.img header length ({:#x}) + 4 does not match the RKFW update size ({:#x})",
+                length, update_size
+            ))?;
+        }
+    }
+
+    // The RKFW file itself ends with an MD5 digest; the RKCRC trails the embedded update image
+    if update_size >= 8 {
+        let computed = crc_range(fp, update_offset, update_size - 4)?;
+        let stored = get_u32_le(&read_bytes_at(fp, update_offset + update_size - 4, 4)?);
+        check_crc(stored, computed, options)?;
+    }
+    // Checked last, a corrupt embedded image is reported as the more specific CRC mismatch
+    check_md5(fp, &mut info, filesize, options)?;
+    Ok(info)
 }
+
+fn unpack_rkfw<R: ImageSource>(fp: &mut R, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<RkfwInfo> {
+    let mut info = read_rkfw(fp, options)?;
+    let boot_offset = info.boot_offset as u64;
+    let boot_size = info.boot_size as u64;
+    let update_offset = info.update_offset as u64;
+    let update_size = info.update_size as u64;
+
+    std::fs::create_dir_all(dst_path)?;
+    if options.save_header {
+        std::fs::write(format!("{}/{}", dst_path, HEADER_FILE), read_bytes_at(fp, 0, RKFW_HEADER_SIZE)?)?;
+    }
+    for (region, file_name) in [(info.pre_boot, PRE_BOOT_FILE), (info.boot_gap, BOOT_GAP_FILE), (info.signature, SIGNATURE_FILE)] {
+        let path = format!("{}/{}", dst_path, file_name);
+        match region {
+            Some(region) if !region.zeroed => {
+                info!("Reserved region {:#x}-{:#x} is not zero-filled, saving it to {}", region.offset, region_end(region.offset, region.size), path);
+                copy_range(fp, region.offset as u64, region.size as u64, &path, options.buffer_size(), Progress::default())?;
+            }
+            _ => {
+                if std::path::Path::new(&path).exists() {
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+    }
+    let mut errors = Vec::new();
+    let boot_entry = container_entry("BOOT", info.boot_offset, info.boot_size);
+    let boot_path = format!("{}/BOOT", dst_path);
+    if info.has_boot && options.no_extract_boot {
+        info!("Skipping BOOT (not extracted)");
+        progress.finished(&boot_entry, &boot_path, PartitionStatus::NotSelected, 0);
+    } else if info.has_boot {
+        let result = (|| -> Result<()> {
+            copy_range(fp, boot_offset, boot_size, &boot_path, options.buffer_size(), progress)?;
+            info.stats.add_file(boot_size);
+            if options.split_boot {
+                let boot_data = read_bytes_at(fp, boot_offset, boot_size as usize)?;
+                split_boot(&boot_data, dst_path, options)?;
+            }
+            Ok(())
+        })();
+        match &result {
+            Ok(()) => progress.finished(&boot_entry, &boot_path, PartitionStatus::Extracted, boot_size),
+            Err(err) => progress.failed(&boot_entry, &boot_path, err),
+        }
+        record_failure(options, &mut errors, "BOOT", result)?;
+    }
+    if options.boot_only {
+        info!("Skipping embedded-update.img (boot only)");
+    } else if options.recursive {
+        let embedded_dir = format!("{}/{}", dst_path, EMBEDDED_DIR);
+        let mut embedded_image = RegionReader::new(fp, update_offset, update_size);
+        let (embedded_stats, embedded_errors) = if info.nested.is_some() {
+            // read_rkfw has already checked the whole chain of nested images
+            let mut embedded_image: Box<dyn ImageSource + '_> = Box::new(embedded_image);
+            let mut nested = unpack_rkfw(&mut embedded_image, &embedded_dir, options, progress)?;
+            let embedded = (nested.stats, std::mem::take(&mut nested.errors));
+            info.nested = Some(Box::new(nested));
+            embedded
+        } else {
+            let mut embedded = unpack_rkafp(&mut embedded_image, &embedded_dir, options, progress)?;
+            let stats = (embedded.stats, std::mem::take(&mut embedded.errors));
+            info.embedded = Some(Box::new(embedded));
+            stats
+        };
+        info.stats.merge(&embedded_stats);
+        errors.extend(embedded_errors.into_iter().map(|(path, err)| (format!("{}/{}", EMBEDDED_DIR, path), err)));
+    } else {
+        let update_entry = container_entry("embedded-update.img", info.update_offset, info.update_size);
+        let update_path = format!("{}/embedded-update.img", dst_path);
+        let result = copy_range(fp, update_offset, update_size, &update_path, options.buffer_size(), progress);
+        match &result {
+            Ok(()) => {
+                info.stats.add_file(update_size);
+                progress.finished(&update_entry, &update_path, PartitionStatus::Extracted, update_size);
+            }
+            Err(err) => progress.failed(&update_entry, &update_path, err),
+        }
+        record_failure(options, &mut errors, "embedded-update.img", result)?;
+    }
+    info.errors = errors;
+
+    // Lets repack_dir re-wrap the embedded image with the original RKFW header fields
+    #[cfg(feature = "serde")]
+    std::fs::write(format!("{}/{}", dst_path, RKFW_INFO_FILE), serde_json::to_string_pretty(&info)?)?;
+
+    Ok(info)
+}
+
+// Inclusive byte range for logs, e.g. `00000800-00001fff`; placeholder partitions have no range
+pub(crate) fn format_range(offset: u64, len: u64) -> String {
+    if len == 0 {
+        return "(empty)".to_string();
+    }
+    format!("{:08x}-{:08x}", offset, offset + len - 1)
+}
+
+// e.g. `78.2 MB/s`, for the per-partition timing logs
+pub(crate) fn format_throughput(bytes: u64, elapsed: Duration) -> String {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => format!("{:.1} MB/s", bytes as f64 / 1e6 / secs),
+        _ => "-".to_string(),
+    }
+}
+
+fn extract_file<R: ImageSource>(fp: &mut R, offset: u64, len: u64, full_path: &str, buffer_size: usize, progress: Progress<'_>) -> Result<()> {
+    info!("{:17} {:26} (size: {})", format_range(offset, len), full_path, human_size(len));
+    let started = Instant::now();
+    copy_range(fp, offset, len, full_path, buffer_size, progress)?;
+    let elapsed = started.elapsed();
+    debug!("{} written in {:.1?} ({})", full_path, elapsed, format_throughput(len, elapsed));
+    Ok(())
+}
+
+// Returns the size of the expanded image
+fn extract_sparse_file<R: Read + Seek>(fp: &mut R, offset: u64, len: u64, full_path: &str, progress: Progress<'_>) -> Result<u64> {
+    let started = Instant::now();
+    fp.seek(std::io::SeekFrom::Start(offset))?;
+    let mut fp_out = std::io::BufWriter::new(File::create(full_path)?);
+    let input = ProgressReader { inner: fp.take(len), done: 0, total: len, progress };
+    // The cancel flag reaches `unsparse` as an I/O error
+    let expanded = unsparse(input, &mut fp_out).map_err(|err| progress.check_cancel().err().unwrap_or(err));
+    let expanded = remove_if_cancelled(expanded.map_err(anyhow::Error::from), full_path)?;
+    progress.report(len, len);
+    fp_out.flush()?;
+    info!("{:17} {:26} (size: {}, expanded to {})", format_range(offset, len), full_path, human_size(len), human_size(expanded));
+    let elapsed = started.elapsed();
+    debug!("{} written in {:.1?} ({})", full_path, elapsed, format_throughput(expanded, elapsed));
+    Ok(expanded)
+}
+
+pub(crate) fn copy_range<R: ImageSource>(fp: &mut R, offset: u64, len: u64, full_path: &str, buffer_size: usize, progress: Progress<'_>) -> Result<()> {
+    let mut fp_out = File::create(full_path)?;
+    // The file may continue past the end of `fp`, e.g. behind an embedded image
+    let stream_end = stream_len(fp)?;
+    let in_bounds = offset.checked_add(len).is_some_and(|end| end <= stream_end);
+    let result = match fp.as_file().filter(|_| in_bounds) {
+        Some((file, base)) => copy_between_files(file, base + offset, len, &mut fp_out, buffer_size, progress),
+        None => copy_to_writer(fp, offset, len, &mut fp_out, buffer_size, progress),
+    };
+    remove_if_cancelled(result, full_path)
+}
+
+// A cancelled extraction leaves no truncated file behind
+pub(crate) fn remove_if_cancelled<T>(result: Result<T>, full_path: &str) -> Result<T> {
+    if result.as_ref().is_err_and(is_cancelled) {
+        std::fs::remove_file(full_path).ok();
+    }
+    result
+}
+
+// std::io::copy from one file to another uses copy_file_range on Linux, which reflinks on btrfs
+// and xfs and keeps the data in the kernel elsewhere, and falls back to a buffered copy where
+// the kernel or filesystem cannot. Copied in `buffer_size` chunks to keep progress reports.
+fn copy_between_files(mut file: &File, offset: u64, len: u64, out: &mut File, buffer_size: usize, progress: Progress<'_>) -> Result<()> {
+    file.seek(std::io::SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        progress.check_cancel()?;
+        let chunk = std::cmp::min(remaining, buffer_size as u64);
+        if std::io::copy(&mut file.take(chunk), out)? < chunk {
+            return Err(ApfError::Truncated("Insufficient length in container image file".to_string()).into());
+        }
+        trace!("copied {} bytes from {:#x}", chunk, offset + len - remaining);
+        remaining -= chunk;
+        if remaining > 0 {
+            progress.report(len - remaining, len);
+        }
+    }
+    progress.report(len, len);
+    Ok(())
+}
+
+/// A stream extraction can copy from: plain local files are copied file to file by
+/// `copy_range`, everything else through a userspace buffer
+pub(crate) trait ImageSource: Read + Seek {
+    /// The file this stream reads and the offset of the stream in it
+    fn as_file(&self) -> Option<(&File, u64)> {
+        None
+    }
+}
+
+impl ImageSource for File {
+    fn as_file(&self) -> Option<(&File, u64)> {
+        Some((self, 0))
+    }
+}
+
+impl ImageSource for MultiFileReader {}
+
+impl<R: ImageSource + ?Sized> ImageSource for Box<R> {
+    fn as_file(&self) -> Option<(&File, u64)> {
+        (**self).as_file()
+    }
+}
+
+impl<R: ImageSource> ImageSource for RegionReader<'_, R> {
+    fn as_file(&self) -> Option<(&File, u64)> {
+        self.inner.as_file().map(|(file, base)| (file, base + self.base))
+    }
+}
+
+// Readers passed to `unpack_reader`, which may not be files
+struct StreamSource<R>(R);
+
+impl<R: Read> Read for StreamSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for StreamSource<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<R: Read + Seek> ImageSource for StreamSource<R> {}
+
+// Presents `len` bytes at `base` of the inner stream as a stream of their own
+pub(crate) struct RegionReader<'a, R> {
+    inner: &'a mut R,
+    base: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek> RegionReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, base: u64, len: u64) -> Self {
+        Self { inner, base, len, pos: 0 }
+    }
+}
+
+impl<R: Read + Seek> Read for RegionReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let max = std::cmp::min(buf.len() as u64, self.len - self.pos) as usize;
+        self.inner.seek(std::io::SeekFrom::Start(self.base + self.pos))?;
+        let read_bytes = self.inner.read(&mut buf[..max])?;
+        self.pos += read_bytes as u64;
+        Ok(read_bytes)
+    }
+}
+
+impl<R: Read + Seek> Seek for RegionReader<'_, R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            std::io::SeekFrom::Start(offset) => Some(offset),
+            std::io::SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            std::io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the region")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+// Reports sparse input consumption, the expanded size is not known up front
+struct ProgressReader<'a, R> {
+    inner: R,
+    done: u64,
+    total: u64,
+    progress: Progress<'a>,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.progress.is_cancelled() {
+            return Err(std::io::Error::other(ApfError::Cancelled));
+        }
+        let read_bytes = self.inner.read(buf)?;
+        self.done += read_bytes as u64;
+        if read_bytes > 0 && self.done < self.total {
+            self.progress.report(self.done, self.total);
+        }
+        Ok(read_bytes)
+    }
+}
+
+/// The caller's progress callbacks and cancel flag, handed down to every copy loop
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Progress<'a> {
+    /// Called with (bytes done, total) for every chunk of each extracted file
+    pub(crate) report: Option<&'a dyn Fn(u64, u64)>,
+    pub(crate) cancel: Option<&'a AtomicBool>,
+    /// Called once per partition when it is done
+    pub(crate) finished: Option<&'a dyn Fn(&PartitionEvent)>,
+}
+
+impl Progress<'_> {
+    pub(crate) fn report(&self, done: u64, total: u64) {
+        if let Some(report) = self.report {
+            report(done, total);
+        }
+    }
+
+    pub(crate) fn finished(&self, part: &PartitionInfo, output: &str, status: PartitionStatus, written: u64) {
+        if let Some(finished) = self.finished {
+            finished(&PartitionEvent { partition: part.clone(), output: output.to_string(), status, written, error: None });
+        }
+    }
+
+    /// Reports the error of a partition that failed, a cancelled unpack reports nothing more
+    pub(crate) fn failed(&self, part: &PartitionInfo, output: &str, err: &anyhow::Error) {
+        if let (Some(finished), false) = (self.finished, is_cancelled(err)) {
+            let error = Some(format!("{:#}", err));
+            finished(&PartitionEvent { partition: part.clone(), output: output.to_string(), status: PartitionStatus::Failed, written: 0, error });
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// `ApfError::Cancelled` once the cancel flag is set
+    pub(crate) fn check_cancel(&self) -> Result<(), ApfError> {
+        if self.is_cancelled() {
+            return Err(ApfError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_cancelled(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ApfError>(), Some(ApfError::Cancelled))
+}
+
+pub(crate) fn copy_to_writer<R: Read + Seek, W: Write>(fp: &mut R, offset: u64, len: u64, out: &mut W, buffer_size: usize, progress: Progress<'_>) -> Result<()> {
+    // Never allocate more than the range needs
+    let mut buffer = vec![0u8; std::cmp::min(buffer_size as u64, len.max(1)) as usize];
+
+    fp.seek(std::io::SeekFrom::Start(offset))?;
+
+    let mut remaining = len;
+
+    while remaining > 0 {
+        progress.check_cancel()?;
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        // A single read may legitimately return less (pipes, network readers), only EOF is an error
+        let mut filled = 0;
+        while filled < read_len {
+            match fp.read(&mut buffer[filled..read_len]) {
+                Ok(0) => {
+                    return Err(ApfError::Truncated("Insufficient length in container image file".to_string()).into());
+                }
+                Ok(read_bytes) => filled += read_bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        trace!("copied {} bytes from {:#x}", read_len, offset + len - remaining);
+        out.write_all(&buffer[..read_len])?;
+
+        remaining -= read_len as u64;
+        if remaining > 0 {
+            progress.report(len - remaining, len);
+        }
+    }
+
+    progress.report(len, len);
+    Ok(())
+}
+
+pub fn extract_partition<R: Read + Seek, W: Write>(reader: &mut R, part: &PartitionInfo, out: &mut W) -> Result<u64, ApfError> {
+    let filesize = stream_len(reader)?;
+    check_region(part.part_offset, part.part_byte_count, filesize, &part.name)?;
+    copy_to_writer(reader, part.part_offset as u64, part.part_byte_count as u64, out, DEFAULT_BUFFER_SIZE, Progress::default())?;
+    Ok(part.part_byte_count as u64)
+}
+
+impl PartitionInfo {
+    /// `SELF` and `RESERVED` entries describe the image itself and are never extracted
+    pub fn is_special(&self) -> bool {
+        self.path == "SELF" || self.path == "RESERVED"
+    }
+
+    pub fn extract_to_writer<R: Read + Seek, W: Write>(&self, reader: &mut R, out: &mut W) -> Result<u64, ApfError> {
+        extract_partition(reader, self, out)
+    }
+
+    /// `flash_size` is the `FLASH_SIZE_GROW` marker: the partition takes all remaining flash
+    pub fn is_grow(&self) -> bool {
+        self.flash_size == FLASH_SIZE_GROW
+    }
+
+    /// `flash_offset` in bytes, the flash address the partition is written to
+    pub fn flash_offset_bytes(&self) -> u64 {
+        self.flash_offset as u64 * SECTOR_SIZE
+    }
+
+    /// `flash_size` in bytes, `None` for the grow-to-end marker (0xFFFFFFFF sectors)
+    pub fn flash_size_bytes(&self) -> Option<u64> {
+        if self.is_grow() {
+            None
+        } else {
+            Some(self.flash_size as u64 * SECTOR_SIZE)
+        }
+    }
+
+    /// The same as `flash_size_bytes`
+    pub fn allocated_bytes(&self) -> Option<u64> {
+        self.flash_size_bytes()
+    }
+
+    /// Fraction of the flash allocation used by the partition data, `None` when the partition
+    /// grows to the end of the flash or has no allocation
+    pub fn utilization(&self) -> Option<f64> {
+        match self.allocated_bytes() {
+            Some(allocated) if allocated > 0 => Some(self.part_byte_count as f64 / allocated as f64),
+            _ => None,
+        }
+    }
+
+    /// e.g. `system: 1.8 GiB / 2.0 GiB (90%)`
+    pub fn usage_summary(&self) -> String {
+        let used = format_size(self.part_byte_count as u64);
+        match (self.allocated_bytes(), self.utilization()) {
+            (None, _) => format!("{}: {} / grow", self.name, used),
+            (Some(allocated), Some(utilization)) => {
+                format!("{}: {} / {} ({:.0}%)", self.name, used, format_size(allocated), utilization * 100.0)
+            }
+            (Some(_), None) => format!("{}: {} (no flash allocation)", self.name, used),
+        }
+    }
+}
+
+const SECTOR_SIZE: u64 = 512;
+/// `flash_size` of a partition that grows to the end of the flash (`-` in the parameter file)
+pub const FLASH_SIZE_GROW: u32 = 0xffff_ffff;
+
+/// Size for the console, e.g. `117.7 MiB (123456789 bytes)`, or `100 bytes` below 1 KiB. JSON
+/// and the other serialized output keep plain byte counts
+pub fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{} ({} bytes)", format_size(bytes), bytes)
+    }
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn read_rkafp_header<R: Read + Seek>(fp: &mut R) -> Result<UpdateHeader> {
+    let mut buf = vec![0u8; std::mem::size_of::<UpdateHeader>()];
+    fp.read_exact(&mut buf)?;
+    if !UpdateHeader::has_rkaf_magic(&buf) {
+        return Err(ApfError::BadMagic.into());
+    }
+    let header = *UpdateHeader::from_bytes(&buf);
+    check_num_parts(&header)?;
+    Ok(header)
+}
+
+// `parts` has room for MAX_PARTS entries, anything beyond that is a corrupt header
+pub(crate) fn check_num_parts(header: &UpdateHeader) -> Result<(), ApfError> {
+    let num_parts = header.num_parts;
+    if num_parts as usize > MAX_PARTS {
+        return Err(ApfError::Invalid(format!(
+            "num_parts {} exceeds max {}",
+            num_parts, MAX_PARTS
+        )));
+    }
+    Ok(())
+}
+
+// Some vendor packers store `part_offset` as a 512-byte sector index. As byte offsets their
+// partitions then start inside the header or run past the end of the image of `image_len`
+// bytes, while as sector offsets all of them start after the header and fit
+pub(crate) fn offsets_in_sectors(header: &UpdateHeader, image_len: u64) -> bool {
+    let header_size = std::mem::size_of::<UpdateHeader>() as u64;
+    let parts: Vec<(u64, u64)> = header.parts.iter()
+        .take(header.num_parts as usize)
+        .filter(|part| part.part_offset > 0 && part.part_byte_count > 0)
+        .map(|part| (part.part_offset as u64, part.part_byte_count as u64))
+        .collect();
+    let plausible = |scale: u64| parts.iter().all(|&(offset, len)| {
+        let start = offset * scale;
+        start >= header_size && start <= u32::MAX as u64 && start + len <= image_len
+    });
+    !parts.is_empty() && !plausible(1) && plausible(SECTOR_SIZE)
+}
+
+// Every decodable partition table entry, including SELF and RESERVED, with `part_offset` in
+// bytes
+fn read_part_table(header: &UpdateHeader, encoding: TextEncoding, image_len: u64) -> Vec<PartitionInfo> {
+    let mut partitions = Vec::new();
+    let scale = if offsets_in_sectors(header, image_len) { SECTOR_SIZE as u32 } else { 1 };
+
+    for part in header.parts.iter().take(header.num_parts as usize) {
+        let part_name = decode_header_string(&part.name, encoding).unwrap_or_default();
+        // 安全地提取路径字符串; `part_table_problems` reports entries left out here
+        let Ok(cstr_path) = std::ffi::CStr::from_bytes_until_nul(&part.full_path) else {
+            continue;
+        };
+
+        partitions.push(PartitionInfo {
+            path: cstr_path.to_string_lossy().to_string(),
+            flash_size: part.flash_size,
+            flash_offset: part.flash_offset,
+            part_offset: part.part_offset * scale,
+            padded_size: part.padded_size,
+            part_byte_count: part.part_byte_count,
+            sparse: false,
+            kind: PartitionKind::from_name(&part_name),
+            content_type: None,
+            name: part_name,
+        });
+    }
+
+    partitions
+}
+
+// Entries of the partition table whose name or path has no NUL terminator within its field:
+// `read_part_table` leaves out those without a path and gives the others an empty name
+fn part_table_problems(header: &UpdateHeader, encoding: TextEncoding) -> Vec<String> {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+    let mut problems = Vec::new();
+    for (index, part) in header.parts.iter().take(header.num_parts as usize).enumerate() {
+        if std::ffi::CStr::from_bytes_until_nul(&part.full_path).is_err() {
+            problems.push(format!(
+                "Partition table entry #{} has no NUL-terminated path and is left out (name {}, path {})",
+                index, hex(&part.name), hex(&part.full_path)
+            ));
+        } else if decode_header_string(&part.name, encoding).is_none() {
+            problems.push(format!("Partition table entry #{} has no NUL-terminated name and gets an empty one (name {})", index, hex(&part.name)));
+        }
+    }
+    problems
+}
+
+pub(crate) fn parse_rkafp(header: &UpdateHeader, filesize: u64, encoding: TextEncoding) -> RkafInfo {
+    info!("Filesize: {}", human_size(filesize));
+    // 安全地从null-terminated字符串中提取文本
+    let manufacturer = decode_header_string(&header.manufacturer, encoding).unwrap_or_else(|| "unknown".to_string());
+    let model = decode_header_string(&header.model, encoding).unwrap_or_else(|| "unknown".to_string());
+
+    let version = FirmwareCode(header.version);
+    info!("manufacturer: {}", manufacturer);
+    info!("model: {}", model);
+    info!("version: {} ({:#010x})", version, version.raw());
+
+    let table = read_part_table(header, encoding, filesize);
+    if offsets_in_sectors(header, filesize) {
+        info!("Partition offsets are stored in 512-byte sectors, converted to bytes");
+    }
+    for (index, part) in table.iter().enumerate() {
+        debug!(
+            "partition {}: {} {} at {:#010x}, {:#x} bytes padded to {:#x}, flash {:#010x}+{:#x}",
+            index, part.name, part.path, part.part_offset, part.part_byte_count, part.padded_size, part.flash_offset, part.flash_size
+        );
+    }
+    let (reserved_parts, partitions) = table.into_iter().partition(|part| part.is_special());
+
+    let mut info = RkafInfo {
+        manufacturer,
+        model,
+        manufacturer_raw: header.manufacturer.to_vec(),
+        model_raw: header.model.to_vec(),
+        version,
+        filesize,
+        declared_length: header.length as u64 + 4,
+        actual_length: filesize,
+        length_matches: header.length as u64 + 4 == filesize,
+        partitions,
+        reserved_parts,
+        alignment: None,
+        trailing_bytes: 0,
+        sector_offsets: offsets_in_sectors(header, filesize),
+        stats: UnpackStats::default(),
+        errors: Vec::new(),
+    };
+    info.alignment = detect_alignment(&info);
+    info
+}
+
+// An RKAF image that passed every check, nothing has been written yet
+pub(crate) struct RkafImage {
+    pub(crate) header: UpdateHeader,
+    pub(crate) info: RkafInfo,
+    /// Output path of every partition relative to the destination with `/` separators, see
+    /// `output_names` and `normalize_partition_path`
+    pub(crate) relative_paths: Vec<String>,
+    /// Non-zero padding (offset, length) after a partition, keyed by partition index
+    pub(crate) padding: HashMap<usize, (u64, u64)>,
+}
+
+impl RkafImage {
+    // `generate_parameter` for images that have partitions on flash but no parameter partition
+    pub(crate) fn generated_parameter(&self) -> Option<String> {
+        let partitions = &self.info.partitions;
+        let has_parameter = partitions.iter().any(|part| part.name.eq_ignore_ascii_case("parameter"));
+        (!has_parameter && partitions.iter().any(|part| part.flash_size != 0)).then(|| generate_parameter(&self.info))
+    }
+
+    fn id(&self) -> String {
+        std::ffi::CStr::from_bytes_until_nul(&self.header.id)
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    // The header's `reserved` bytes as hex, empty when they are all zero as usual
+    fn reserved_hex(&self) -> String {
+        if self.header.reserved.iter().all(|&byte| byte == 0) {
+            return String::new();
+        }
+        self.header.reserved.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// `header-metadata.txt`: header fields that are not part of the partition table, with a
+    /// `reserved:` line only when the reserved bytes are not all zero
+    pub(crate) fn header_metadata(&self) -> String {
+        let version = self.header.version;
+        let unknown1 = self.header.unknown1;
+        let length = self.header.length;
+        let mut metadata = format!(
+            "manufacturer:{}\nmodel:{}\nid:{}\nversion:{:#010x}\nunknown1:{:#010x}\nlength:{:#010x}\n",
+            self.info.manufacturer,
+            self.info.model,
+            self.id(),
+            version,
+            unknown1,
+            length
+        );
+        let reserved = self.reserved_hex();
+        if !reserved.is_empty() {
+            metadata.push_str(&format!("reserved:{}\n", reserved));
+        }
+        metadata
+    }
+
+    /// The whole partition table, SELF and RESERVED entries included, in `options.metadata_format`
+    /// and `options.sort` order
+    pub(crate) fn partition_metadata(&self, options: &UnpackOptions) -> Result<String> {
+        let mut parts = read_part_table(&self.header, options.text_encoding, self.info.filesize);
+        options.sort.apply(&mut parts);
+        let table: Vec<PartitionMetadata> = parts.iter().map(PartitionMetadata::from).collect();
+        format_partition_metadata(&table, options.metadata_format)
+    }
+
+    #[cfg(feature = "serde")]
+    pub(crate) fn manifest(&self, options: &UnpackOptions) -> Manifest {
+        Manifest {
+            schema_version: MANIFEST_SCHEMA_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            manufacturer: self.info.manufacturer.clone(),
+            model: self.info.model.clone(),
+            id: self.id(),
+            version: self.header.version,
+            unknown1: self.header.unknown1,
+            length: self.header.length,
+            reserved: self.reserved_hex(),
+            filesize: self.info.filesize,
+            partitions: read_part_table(&self.header, options.text_encoding, self.info.filesize)
+                .into_iter()
+                .map(|mut part| {
+                    // The raw table has no sparse flag or content type, take them from the detected partitions
+                    if let Some(detected) = self.info.partitions.iter().find(|p| p.part_offset == part.part_offset && p.path == part.path) {
+                        part.sparse = detected.sparse;
+                        part.content_type = detected.content_type.clone();
+                    }
+                    part
+                })
+                .collect(),
+        }
+    }
+}
+
+// Parses and checks an RKAF image before anything is written
+pub(crate) fn read_rkafp<R: Read + Seek>(fp: &mut R, options: &UnpackOptions) -> Result<RkafImage> {
+    let filesize = stream_len(fp)?;
+    options.check_size("Input image", filesize)?;
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    let header = read_rkafp_header(fp)?;
+    let mut info = parse_rkafp(&header, filesize, options.text_encoding);
+    for problem in part_table_problems(&header, options.text_encoding) {
+        warn_or_err(options, problem)?;
+    }
+    for part in info.partitions.iter_mut() {
+        if let Some(&kind) = options.kind_overrides.get(&part.name) {
+            part.kind = kind;
+        }
+    }
+    for warning in validate_layout(&info, filesize) {
+        warn_or_err(options, warning.to_string())?;
+    }
+    check_rkaf_version(info.version, options)?;
+    detect_partition_contents(fp, &mut info.partitions, filesize)?;
+    check_parameter(fp, &info, filesize, options)?;
+    check_backup(fp, &info, filesize)?;
+    check_declared_sizes(fp, &header, &info, options)?;
+
+    let (stored, computed) = rkaf_crc(fp, header.length, filesize, None, None)?;
+    check_length(info.declared_length, info.actual_length, options)?;
+    check_crc(stored, computed, options)?;
+    // Validate every output path before anything is written
+    let relative_paths = output_names(&info.partitions, options.naming)?
+        .iter()
+        .zip(&info.partitions)
+        .map(|(path, part)| match options.path_map.get(&part.name) {
+            Some(mapped) => normalize_partition_path(&mapped.to_string_lossy()),
+            None => normalize_partition_path(path),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for name in options.path_map.keys() {
+        if !info.partitions.iter().any(|part| part.name == *name) {
+            warn!("The image has no partition named {} to map", name);
+        }
+    }
+    let mut targets: Vec<(&str, usize)> = Vec::new();
+    for (index, (part, path)) in info.partitions.iter().zip(&relative_paths).enumerate() {
+        let target = path.as_str();
+        if !is_selected(part, options) {
+            continue;
+        }
+        // A later partition with the same path would silently replace the earlier one
+        if let Some((_, earlier)) = targets.iter().find(|(other, _)| *other == target) {
+            let message = format!("Partitions #{} ({}) and #{} ({}) would both be extracted to {}", earlier, info.partitions[*earlier].name, index, part.name, path);
+            if !options.force {
+                return Err(ApfError::Invalid(format!("{}, pass --force to keep the last one", message)).into());
+            }
+            warn!("{}, keeping #{}", message, index);
+        }
+        targets.push((target, index));
+    }
+    let padding = find_nonzero_padding(fp, &info.partitions, std::cmp::min(header.length as u64, filesize))?;
+    for (index, &(offset, len)) in &padding {
+        let message = format!(
+            "Padding after {} contains non-zero bytes ({:#x}-{:#x}), a zero-padded repack will not match",
+            info.partitions[*index].path,
+            offset,
+            offset + len
+        );
+        if options.strict_padding {
+            return Err(ApfError::Invalid(message).into());
+        }
+        warn_or_err(options, message)?;
+    }
+    let crc_offset = std::cmp::min(header.length as u64, filesize);
+    let partitions_end = info
+        .partitions
+        .iter()
+        .map(|part| part.part_offset as u64 + std::cmp::max(part.part_byte_count, part.padded_size) as u64)
+        .max()
+        .unwrap_or(std::mem::size_of::<UpdateHeader>() as u64);
+    info.trailing_bytes = crc_offset.saturating_sub(partitions_end);
+    if info.trailing_bytes > 0 && !range_is_zero(fp, partitions_end, info.trailing_bytes)? {
+        let message = format!(
+            "{} bytes after the last partition ({:#x}-{:#x}) contain non-zero data",
+            info.trailing_bytes,
+            partitions_end,
+            crc_offset
+        );
+        if options.strict_padding {
+            return Err(ApfError::Invalid(message).into());
+        }
+        warn_or_err(options, message)?;
+    }
+    Ok(RkafImage { header, info, relative_paths, padding })
+}
+
+fn unpack_rkafp<R: ImageSource>(fp: &mut R, dst_path: &str, options: &UnpackOptions, progress: Progress<'_>) -> Result<RkafInfo> {
+    let image = read_rkafp(fp, options)?;
+    let output_paths = image
+        .relative_paths
+        .iter()
+        .map(|path| partition_output_path(dst_path, path))
+        .collect::<Result<Vec<_>>>()?;
+    std::fs::create_dir_all(dst_path)?;
+
+    // Save header fields that are not part of the partition table for repacking
+    std::fs::write(format!("{}/header-metadata.txt", dst_path), image.header_metadata())?;
+    if options.save_header {
+        std::fs::write(format!("{}/{}", dst_path, HEADER_FILE), read_bytes_at(fp, 0, std::mem::size_of::<UpdateHeader>())?)?;
+    }
+    if let Some(parameter) = image.generated_parameter() {
+        info!("The image has no parameter partition, writing the layout to {}", GENERATED_PARAMETER_FILE);
+        std::fs::write(format!("{}/{}", dst_path, GENERATED_PARAMETER_FILE), parameter)?;
+    }
+
+    // Save partition metadata for repacking
+    let metadata_path = format!("{}/{}", dst_path, options.metadata_format.file_name());
+    std::fs::write(&metadata_path, image.partition_metadata(options)?)?;
+    // pack_rkafp reads the first metadata file it finds, drop ones left by an earlier unpack
+    for format in MetadataFormat::ALL.iter().filter(|&&format| format != options.metadata_format) {
+        let stale_path = format!("{}/{}", dst_path, format.file_name());
+        if std::path::Path::new(&stale_path).exists() {
+            std::fs::remove_file(&stale_path)?;
+        }
+    }
+    let mut checksums = Vec::new();
+    let mut flashed = Vec::new();
+    let RkafImage { info, relative_paths, padding, .. } = &image;
+    let mut stats = UnpackStats { skipped_special: info.reserved_parts.len() as u32, ..Default::default() };
+    // Plain copies are collected and run together afterwards when extracting in parallel
+    let parallel = options.parallelism > 1 && CAN_EXTRACT_IN_PARALLEL && fp.as_file().is_some();
+    let image_len = stream_len(fp)?;
+    let mut jobs = Vec::new();
+    let mut chunked = Vec::new();
+    let mut errors = Vec::new();
+    let mut order: Vec<usize> = (0..info.partitions.len()).collect();
+    if options.preserve_order {
+        order.sort_by_key(|&index| info.partitions[index].part_offset);
+    }
+
+    for index in order {
+        progress.check_cancel()?;
+        let (part, output_path) = (&info.partitions[index], &output_paths[index]);
+        if !is_selected(part, options) {
+            info!("Skipping {} (not selected)", part.path);
+            stats.skipped_filtered += 1;
+            progress.finished(part, &output_path.to_string_lossy(), PartitionStatus::NotSelected, 0);
+            continue;
+        }
+        // Parallel copies are reported by extract_parallel once they are done
+        let mut done = None;
+        let result = (|| -> Result<()> {
+            let part_full_path = output_path.to_string_lossy().to_string();
+            // Paths may be nested or lack the usual Image/ prefix
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let expanded = part.sparse && options.expand_sparse;
+            if options.resume && !options.force && is_already_extracted(fp, part, output_path, expanded, options.pad_to_flash_size, options.resume_verify)? {
+                info!("Skipping {} (already extracted)", part.path);
+                progress.report(part.part_byte_count as u64, part.part_byte_count as u64);
+                stats.skipped_existing += 1;
+                done = Some((PartitionStatus::AlreadyExtracted, 0));
+            } else if expanded {
+                let written = extract_sparse_file(fp, part.part_offset as u64, part.part_byte_count as u64, &part_full_path, progress)?;
+                stats.add_file(written);
+                done = Some((PartitionStatus::Extracted, written));
+                if options.pad_to_flash_size {
+                    pad_file(part, &part_full_path, written)?;
+                }
+            } else if let Some(chunk_size) = options.split_size.filter(|&size| size > 0 && part.part_byte_count as u64 > size) {
+                chunked.push((index, extract_chunks(fp, part, dst_path, &relative_paths[index], chunk_size, options.buffer_size(), progress)?));
+                stats.add_file(part.part_byte_count as u64);
+                done = Some((PartitionStatus::Extracted, part.part_byte_count as u64));
+            } else if parallel && part.part_offset as u64 + part.part_byte_count as u64 <= image_len {
+                jobs.push(CopyJob { index, part: part.clone(), path: part_full_path.clone() });
+                stats.add_file(part.part_byte_count as u64);
+            } else {
+                extract_file(
+                    fp,
+                    part.part_offset as u64,
+                    part.part_byte_count as u64,
+                    &part_full_path,
+                    options.buffer_size(),
+                    progress,
+                )?;
+                stats.add_file(part.part_byte_count as u64);
+                done = Some((PartitionStatus::Extracted, part.part_byte_count as u64));
+                if options.pad_to_flash_size {
+                    pad_file(part, &part_full_path, part.part_byte_count as u64)?;
+                }
+            }
+
+            if options.split_resource && part.name.eq_ignore_ascii_case("resource") {
+                let resource_data = read_bytes_at(fp, part.part_offset as u64, part.part_byte_count as usize)?;
+                split_resource(&resource_data, dst_path, options)?;
+            }
+            if options.split_boot_image && part.content_type.as_deref() == Some("android-boot") {
+                let image_data = read_bytes_at(fp, part.part_offset as u64, part.part_byte_count as usize)?;
+                split_boot_image(&image_data, &part.name, dst_path, options)?;
+            }
+            if options.gunzip_partitions {
+                gunzip_partition(fp, part, output_path, options)?;
+            }
+
+            flashed.push((index, (part, relative_paths[index].as_str())));
+
+            if options.capture_padding {
+                if let Some(&(offset, len)) = padding.get(&index) {
+                    copy_range(fp, offset, len, &format!("{}.padding", part_full_path), options.buffer_size(), Progress::default())?;
+                }
+            }
+
+            if let Some(algorithm) = options.checksums {
+                // Expanded sparse images no longer match the stored bytes, hash what was written
+                let padded_len = flash_padded_len(part, part.part_byte_count as u64).filter(|_| options.pad_to_flash_size);
+                let digest = if expanded {
+                    hash_reader(&mut File::open(&part_full_path)?, algorithm)?.0
+                } else if let Some(padded_len) = padded_len {
+                    // The partition followed by the zeros `pad_file` adds, also for files that
+                    // are still being copied in parallel
+                    fp.seek(std::io::SeekFrom::Start(part.part_offset as u64))?;
+                    let zeros = std::io::repeat(0).take(padded_len - part.part_byte_count as u64);
+                    hash_reader(&mut (&mut *fp).take(part.part_byte_count as u64).chain(zeros), algorithm)?.0
+                } else {
+                    hash_partition(fp, part, algorithm)?
+                };
+                checksums.push((index, format!("{}  {}", digest, relative_paths[index])));
+            }
+            Ok(())
+        })();
+        match (&result, done) {
+            (Ok(()), Some((status, written))) => progress.finished(part, &output_path.to_string_lossy(), status, written),
+            (Ok(()), None) => {}
+            (Err(err), _) => progress.failed(part, &output_path.to_string_lossy(), err),
+        }
+        record_failure(options, &mut errors, &part.path, result)?;
+    }
+
+    if let (false, Some((file, base))) = (jobs.is_empty(), fp.as_file()) {
+        let failures = extract_parallel(file, base, &jobs, options.parallelism, options.buffer_size(), options.continue_on_error, progress)?;
+        if options.pad_to_flash_size {
+            for (_, job) in jobs.iter().enumerate().filter(|(index, _)| !failures.iter().any(|(failed, _)| failed == index)) {
+                pad_file(&job.part, &job.path, job.part.part_byte_count as u64)?;
+            }
+        }
+        for (job, err) in failures {
+            let index = jobs[job].index;
+            stats.files_written -= 1;
+            stats.bytes_written -= jobs[job].part.part_byte_count as u64;
+            flashed.retain(|(flashed, _)| *flashed != index);
+            checksums.retain(|(hashed, _)| *hashed != index);
+            record_failure(options, &mut errors, &info.partitions[index].path, Err(err))?;
+        }
+    }
+    // Back in partition table order after `preserve_order`
+    flashed.sort_by_key(|(index, _)| *index);
+    checksums.sort_by_key(|(index, _)| *index);
+    let flashed: Vec<_> = flashed.into_iter().map(|(_, entry)| entry).collect();
+
+    check_partition_count(&stats, errors.len(), image.header.num_parts, options)?;
+
+    log_flash_usage(info);
+    info!("\nPartition metadata saved to: {}", metadata_path);
+
+    #[cfg(feature = "serde")]
+    {
+        let manifest_path = format!("{}/manifest.json", dst_path);
+        std::fs::write(&manifest_path, serde_json::to_string_pretty(&image.manifest(options))?)?;
+        info!("Manifest saved to: {}", manifest_path);
+    }
+
+    let flash_script_path = format!("{}/{}", dst_path, FLASH_SCRIPT_FILE);
+    std::fs::write(&flash_script_path, flash_script(&flashed, options.expand_sparse))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&flash_script_path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    info!("Flash commands saved to: {}", flash_script_path);
+
+    if !checksums.is_empty() {
+        let checksums_path = format!("{}/checksums.txt", dst_path);
+        let mut checksums_file = File::create(&checksums_path)?;
+        for (_, line) in &checksums {
+            writeln!(checksums_file, "{}", line)?;
+        }
+        info!("Partition checksums saved to: {}", checksums_path);
+    }
+
+    #[cfg(feature = "serde")]
+    if let Some(chunk_size) = options.split_size.filter(|_| !chunked.is_empty()) {
+        chunked.sort_by_key(|(index, _)| *index);
+        let index = ChunkIndex { chunk_size, partitions: chunked.into_iter().map(|(_, partition)| partition).collect() };
+        let chunks_path = format!("{}/{}", dst_path, CHUNKS_FILE);
+        std::fs::write(&chunks_path, serde_json::to_string_pretty(&index)?)?;
+        info!("Chunk index saved to: {}", chunks_path);
+    }
+
+    let mut info = image.info;
+    info.stats = stats;
+    info.errors = errors;
+    Ok(info)
+}
+
+// Every partition table entry has to be written, skipped or failed; any other entry, such as one
+// `read_part_table` could not decode, went missing without a trace
+pub(crate) fn check_partition_count(stats: &UnpackStats, failed: usize, num_parts: u32, options: &UnpackOptions) -> Result<()> {
+    let accounted = stats.files_written + stats.skipped_special + stats.skipped_filtered + stats.skipped_existing + failed as u32;
+    if accounted == num_parts {
+        return Ok(());
+    }
+    warn_or_err(options, format!(
+        "The header lists {} partition table entries but {} are accounted for ({} written, {} SELF/RESERVED, {} not selected, {} kept, {} failed)",
+        num_parts, accounted, stats.files_written, stats.skipped_special, stats.skipped_filtered, stats.skipped_existing, failed
+    ))
+}
+
+// Under `UnpackOptions::continue_on_error` a file that failed to extract is logged and added to
+// `errors` instead of ending the unpack
+fn record_failure(options: &UnpackOptions, errors: &mut Vec<(String, ApfError)>, path: &str, result: Result<()>) -> Result<()> {
+    match result {
+        Err(err) if options.continue_on_error && !is_cancelled(&err) => {
+            error!("Cannot extract {}: {:#}", path, err);
+            errors.push((path.to_string(), err.into()));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+pub(crate) fn log_flash_usage(info: &RkafInfo) {
+    info!("\nFlash usage:");
+    for part in &info.partitions {
+        info!("  {}", part.usage_summary());
+    }
+    if let Some(alignment) = info.alignment {
+        info!("Partition offsets aligned to {:#x} bytes", alignment);
+    }
+}
+
+// Output path of every partition relative to the destination, in partition table order
+fn output_names(partitions: &[PartitionInfo], naming: OutputNaming) -> Result<Vec<String>> {
+    let file_stem = |part: &PartitionInfo| -> String {
+        let stem: String = part.name
+            .chars()
+            .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+            .collect();
+        if stem.is_empty() { "partition".to_string() } else { stem }
+    };
+
+    let names = match naming {
+        OutputNaming::ByPath => partitions.iter().map(|part| part.path.clone()).collect(),
+        OutputNaming::ByName => {
+            let mut names: Vec<String> = Vec::new();
+            for part in partitions {
+                let mut name = format!("{}.img", file_stem(part));
+                if names.contains(&name) {
+                    name = format!("{}.{}.img", file_stem(part), names.len());
+                }
+                names.push(name);
+            }
+            names
+        }
+        OutputNaming::ByFlashOffset => {
+            let mut order: Vec<usize> = (0..partitions.len()).collect();
+            order.sort_by_key(|&index| partitions[index].flash_offset);
+            let mut names = vec![String::new(); partitions.len()];
+            for (position, &index) in order.iter().enumerate() {
+                let part = &partitions[index];
+                names[index] = format!("{:02}_{}_{:#010x}.img", position, file_stem(part), part.flash_offset);
+            }
+            names
+        }
+        OutputNaming::Flat => {
+            let mut names: Vec<String> = Vec::new();
+            for part in partitions {
+                let name = part.path.rsplit(['/', '\\']).next().unwrap_or_default().to_string();
+                if let Some(index) = names.iter().position(|other| *other == name) {
+                    return Err(ApfError::Invalid(format!(
+                        "{} and {} both flatten to {}",
+                        partitions[index].path, part.path, name
+                    )).into());
+                }
+                names.push(name);
+            }
+            names
+        }
+    };
+    Ok(names)
+}
+
+pub(crate) fn is_selected(part: &PartitionInfo, options: &UnpackOptions) -> bool {
+    if options.mapped_only && !options.path_map.contains_key(&part.name) {
+        return false;
+    }
+    options.only.is_empty()
+        || options.only
+            .iter()
+            .any(|pattern| glob_match(pattern, &part.name) || glob_match(pattern, &part.path))
+}
+
+// Case-insensitive match supporting `*` (any run of characters) and `?` (one character)
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it is currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Not allowed in Windows file names; rejected everywhere so an image extracts to the same
+// paths on every platform
+const INVALID_PATH_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+const RESERVED_FILE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// `part_path` relative to the destination with `/` separators, e.g. `Image\boot.img` becomes
+/// `Image/boot.img`; fails for paths that leave the destination or are not valid on Windows
+pub(crate) fn normalize_partition_path(part_path: &str) -> Result<String> {
+    use std::path::Component;
+
+    // Images built on Windows may use backslashes as separators
+    let normalized = part_path.replace('\\', "/");
+    let mut segments = Vec::new();
+    for component in std::path::Path::new(&normalized).components() {
+        match component {
+            Component::Normal(segment) => {
+                let segment = segment.to_string_lossy();
+                // A drive letter such as `C:` is an absolute path on Windows
+                if segments.is_empty() && segment.len() == 2 && segment.ends_with(':') {
+                    return Err(ApfError::Invalid(format!("Partition path escapes destination directory: {}", part_path)).into());
+                }
+                if let Some(c) = segment.chars().find(|c| INVALID_PATH_CHARS.contains(c) || c.is_control()) {
+                    return Err(ApfError::Invalid(format!("Partition path {} contains {:?}, which is not valid in a file name", part_path, c)).into());
+                }
+                let stem = segment.split('.').next().unwrap_or_default();
+                if RESERVED_FILE_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) || segment.ends_with(['.', ' ']) {
+                    return Err(ApfError::Invalid(format!("Partition path {} contains {:?}, which is not a valid Windows file name", part_path, segment)).into());
+                }
+                segments.push(segment.to_string());
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ApfError::Invalid(format!("Partition path escapes destination directory: {}", part_path)).into());
+            }
+        }
+    }
+    if segments.is_empty() {
+        return Err(ApfError::Invalid(format!("Empty partition path: {:?}", part_path)).into());
+    }
+    Ok(segments.join("/"))
+}
+
+// Joins a partition path from the image onto `dst_path` with the platform's separators
+pub(crate) fn partition_output_path(dst_path: &str, part_path: &str) -> Result<std::path::PathBuf> {
+    let mut output_path = std::path::PathBuf::from(dst_path);
+    output_path.extend(normalize_partition_path(part_path)?.split('/'));
+    Ok(output_path)
+}
+
+// Padding regions (offset, length) that are not all zero, keyed by partition index. The padding
+// of a partition runs from the end of its data to the end of `padded_size`, stopping at the next
+// partition or `limit`.
+fn find_nonzero_padding<R: Read + Seek>(fp: &mut R, partitions: &[PartitionInfo], limit: u64) -> Result<HashMap<usize, (u64, u64)>> {
+    let mut padding = HashMap::new();
+    for (index, part) in partitions.iter().enumerate() {
+        let data_end = part.part_offset as u64 + part.part_byte_count as u64;
+        let next_offset = partitions
+            .iter()
+            .enumerate()
+            .filter(|&(other_index, _)| other_index != index)
+            .map(|(_, other)| other.part_offset as u64)
+            .filter(|&offset| offset >= data_end)
+            .min()
+            .unwrap_or(u64::MAX);
+        let pad_end = (part.part_offset as u64 + part.padded_size as u64).min(next_offset).min(limit);
+        if pad_end > data_end && !range_is_zero(fp, data_end, pad_end - data_end)? {
+            padding.insert(index, (data_end, pad_end - data_end));
+        }
+    }
+    Ok(padding)
+}
+
+fn range_is_zero<R: Read + Seek>(fp: &mut R, offset: u64, len: u64) -> Result<bool> {
+    let mut buffer = vec![0u8; 16 * 1024];
+    let mut remaining = len;
+    fp.seek(std::io::SeekFrom::Start(offset))?;
+    while remaining > 0 {
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        fp.read_exact(&mut buffer[..read_len])?;
+        if buffer[..read_len].iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+        remaining -= read_len as u64;
+    }
+    Ok(true)
+}
+
+// The parameter partition declares the flash layout, it should agree with the partition table
+fn check_parameter<R: Read + Seek>(fp: &mut R, info: &RkafInfo, filesize: u64, options: &UnpackOptions) -> Result<()> {
+    let Some(part) = info.partitions.iter().find(|part| part.name.eq_ignore_ascii_case("parameter")) else {
+        return Ok(());
+    };
+    if part.part_offset as u64 + part.part_byte_count as u64 > filesize {
+        return Ok(());
+    }
+    let data = read_bytes_at(fp, part.part_offset as u64, part.part_byte_count as usize)?;
+    let declared = match parse_parameter(&data) {
+        Ok(declared) => declared,
+        Err(e) => {
+            return warn_or_err(options, format!("cannot parse {}: {}", part.path, e));
+        }
+    };
+
+    info!("{} declares:", part.path);
+    for partition in &declared {
+        match partition.sector_count {
+            Some(count) => info!("  {:<16} {:#010x}@{:#010x}", partition.name, count, partition.start_sector),
+            None => info!("  {:<16} grow@{:#010x}", partition.name, partition.start_sector),
+        }
+    }
+    for warning in validate_parameter_layout(&declared, info) {
+        warn_or_err(options, warning.to_string())?;
+    }
+    Ok(())
+}
+
+// Firmware versions start at 1.0.0; anything below, including a byte-swapped 1.0.0, suggests a
+// header written by another tool whose part entries may be laid out differently
+fn check_rkaf_version(version: FirmwareCode, options: &UnpackOptions) -> Result<()> {
+    if version.major() == 0 {
+        return warn_or_err(options, format!("unexpected RKAF header version {} ({:#010x}), the partition table may not be read correctly", version, version.raw()));
+    }
+    Ok(())
+}
+
+// A backup partition may hold a second copy of the parameter, list what it declares
+fn check_backup<R: Read + Seek>(fp: &mut R, info: &RkafInfo, filesize: u64) -> Result<()> {
+    for part in info.partitions.iter().filter(|part| part.kind == PartitionKind::Backup) {
+        if part.part_offset as u64 + part.part_byte_count as u64 > filesize {
+            continue;
+        }
+        let data = read_bytes_at(fp, part.part_offset as u64, part.part_byte_count as usize)?;
+        if let Ok(declared) = parse_parameter(&data) {
+            let names: Vec<&str> = declared.iter().map(|partition| partition.name.as_str()).collect();
+            info!("{} contains a parameter copy declaring {}", part.path, names.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn check_declared_sizes<R: Read + Seek>(fp: &mut R, header: &UpdateHeader, info: &RkafInfo, options: &UnpackOptions) -> Result<()> {
+    if options.max_size.is_none() {
+        return Ok(());
+    }
+    let length = header.length;
+    options.check_size("Declared image length", length as u64)?;
+    for part in &info.partitions {
+        options.check_size(&part.path, part.part_byte_count as u64)?;
+        if part.sparse && options.expand_sparse {
+            let header_len = std::cmp::min(part.part_byte_count as usize, SPARSE_HEADER_SIZE);
+            if let Some(expanded) = sparse_expanded_size(&read_bytes_at(fp, part.part_offset as u64, header_len)?) {
+                options.check_size(&format!("Expanded {}", part.path), expanded)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Size of a partition file of `len` bytes zero-filled to the flash allocation, `None` when there
+// is nothing to pad: grow-to-end and unallocated partitions, and data that already fills it
+fn flash_padded_len(part: &PartitionInfo, len: u64) -> Option<u64> {
+    part.flash_size_bytes().filter(|&allocated| allocated > len)
+}
+
+// Zero-fills a partition file of `len` bytes up to its flash allocation for `pad_to_flash_size`
+fn pad_file(part: &PartitionInfo, path: &str, len: u64) -> Result<()> {
+    match flash_padded_len(part, len) {
+        Some(padded_len) => {
+            File::options().write(true).open(path)?.set_len(padded_len)?;
+            debug!("{} padded to {} ({} sectors)", path, human_size(padded_len), part.flash_size);
+        }
+        None if !part.is_grow() && part.flash_size > 0 && part.flash_size_bytes() < Some(len) => {
+            warn!("{} ({}) does not fit its flash allocation of {} sectors, not padded", path, human_size(len), part.flash_size);
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// A crash leaves a short file behind, which fails the size check and is rewritten by File::create
+fn is_already_extracted<R: Read + Seek>(fp: &mut R, part: &PartitionInfo, output_path: &std::path::Path, expanded: bool, padded: bool, verify: bool) -> Result<bool> {
+    let Ok(metadata) = std::fs::metadata(output_path) else {
+        return Ok(false);
+    };
+    let expected = if expanded {
+        let header_len = std::cmp::min(part.part_byte_count as usize, SPARSE_HEADER_SIZE);
+        sparse_expanded_size(&read_bytes_at(fp, part.part_offset as u64, header_len)?)
+    } else {
+        Some(part.part_byte_count as u64)
+    };
+    let expected = expected.map(|len| if padded { flash_padded_len(part, len).unwrap_or(len) } else { len });
+    if !metadata.is_file() || Some(metadata.len()) != expected {
+        return Ok(false);
+    }
+    if verify && !expanded {
+        // Only the partition data, not the zeros `pad_to_flash_size` added
+        let existing = hash_reader(&mut File::open(output_path)?.take(part.part_byte_count as u64), HashAlgorithm::Sha256)?.0;
+        if existing != hash_partition(fp, part, HashAlgorithm::Sha256)? {
+            warn!("{} has the expected size but different contents, extracting again", output_path.display());
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Sets `sparse` and `content_type` from the first `FS_PROBE_LEN` bytes of every partition
+fn detect_partition_contents<R: Read + Seek>(fp: &mut R, partitions: &mut [PartitionInfo], filesize: u64) -> Result<()> {
+    for part in partitions.iter_mut() {
+        let offset = part.part_offset as u64;
+        let probe_len = (part.part_byte_count as u64).min(FS_PROBE_LEN as u64).min(filesize.saturating_sub(offset));
+        if probe_len == 0 {
+            continue;
+        }
+        let header = read_bytes_at(fp, offset, probe_len as usize)?;
+        if header.len() >= SPARSE_HEADER_SIZE && is_sparse(&header) {
+            part.sparse = true;
+            if let Some(expanded) = sparse_expanded_size(&header) {
+                info!("{} is a sparse image ({} when expanded)", part.path, human_size(expanded));
+            }
+        }
+        if let Some(content_type) = detect_fs(&header) {
+            if !part.sparse {
+                info!("{} contains {}", part.path, content_type);
+            }
+            if content_type == "fit" {
+                log_fit_components(fp, part, &header, filesize)?;
+            }
+            part.content_type = Some(content_type.to_string());
+        }
+    }
+    Ok(())
+}
+
+// FIT trees only describe their images, anything larger is not one
+const MAX_FIT_TREE_SIZE: u64 = 1024 * 1024;
+
+// Lists the images of a FIT partition (U-Boot, ATF, OP-TEE, ...) from its tree, the data they
+// point at is not read
+fn log_fit_components<R: Read + Seek>(fp: &mut R, part: &PartitionInfo, header: &[u8], filesize: u64) -> Result<()> {
+    let offset = part.part_offset as u64;
+    let Some(total_size) = header.get(4..8).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64) else {
+        return Ok(());
+    };
+    let tree_len = total_size.min(part.part_byte_count as u64).min(MAX_FIT_TREE_SIZE).min(filesize - offset);
+    let tree = read_bytes_at(fp, offset, tree_len as usize)?;
+    match parse_fit(&tree) {
+        Ok(components) => {
+            for component in components {
+                info!(
+                    "{}: FIT image {} ({}{}{}{})",
+                    part.path,
+                    component.name,
+                    if component.kind.is_empty() { "unknown type" } else { &component.kind },
+                    if component.arch.is_empty() { String::new() } else { format!(", {}", component.arch) },
+                    component.load.map(|load| format!(", load {:#x}", load)).unwrap_or_default(),
+                    component.size.map(|size| format!(", size: {}", human_size(size))).unwrap_or_default(),
+                );
+            }
+        }
+        Err(e) => warn!("{} looks like a FIT image but its tree cannot be read: {}", part.path, e),
+    }
+    Ok(())
+}
+
+pub fn inspect_file(file_path: &str) -> Result<UnpackResult, ApfError> {
+    let mut file = File::open(file_path)?;
+    let (kind, signature) = read_signature(&mut file)?;
+
+    match kind {
+        ImageKind::Rkaf => {
+            let header = read_rkafp_header(&mut file)?;
+            let filesize = file.metadata()?.len();
+            let mut info = parse_rkafp(&header, filesize, TextEncoding::Auto);
+            detect_partition_contents(&mut file, &mut info.partitions, filesize)?;
+            Ok(UnpackResult::Rkaf(info))
+        }
+        ImageKind::Rkfw => {
+            let mut buf = vec![0u8; RKFW_HEADER_SIZE];
+            file.read_exact(&mut buf)?;
+            let mut info = parse_rkfw(&buf, &UnpackOptions::default())?;
+            let filesize = file.metadata()?.len();
+            check_boot_signature(&mut file, &mut info, filesize, &UnpackOptions::default())?;
+            infer_chip_from_boot(&mut file, &mut info, &UnpackOptions::default())?;
+            locate_embedded_image(&mut file, &mut info, filesize)?;
+            find_reserved_regions(&mut file, &mut info, filesize)?;
+
+            let mut update_signature = [0u8; 4];
+            file.seek(std::io::SeekFrom::Start(info.update_offset as u64))?;
+            file.read_exact(&mut update_signature)?;
+            if update_signature == RKFW_SIGNATURE {
+                return Err(nested_rkfw_error(&info));
+            }
+            if !UpdateHeader::has_rkaf_magic(&update_signature) {
+                return Err(ApfError::BadMagic);
+            }
+            Ok(UnpackResult::Rkfw(info))
+        }
+        _ => {
+            Err(ApfError::UnknownSignature(signature))
+        }
+    }
+}
+
+/// Lists what `unpack_file` would extract without touching the file system: the RKFW BOOT and
+/// update ranges, or the RKAF header and partition table, parsed the same way as `inspect_file`
+pub fn probe_file(file_path: &str) -> Result<UnpackResult, ApfError> {
+    inspect_file(file_path)
+}
+
+/// Writes only the embedded update image of an RKFW image to `out`, after the same checks as
+/// `unpack_file`; BOOT is not extracted. `inspect_file` gives the byte range
+/// (`update_offset`/`update_size`) without writing anything.
+pub fn carve_embedded_rkaf(file_path: &str, out: &str) -> Result<(), ApfError> {
+    let mut file = File::open(file_path)?;
+    let (kind, signature) = read_signature(&mut file)?;
+    match kind {
+        ImageKind::Rkfw => {}
+        ImageKind::Rkaf => return Err(ApfError::Invalid(format!("{} is an RKAF image already, there is no RKFW wrapper to remove", file_path))),
+        _ => return Err(ApfError::UnknownSignature(signature)),
+    }
+    let options = UnpackOptions::default();
+    let info = read_rkfw(&mut file, &options)?;
+    copy_range(&mut file, info.update_offset as u64, info.update_size as u64, out, options.buffer_size(), Progress::default())?;
+    Ok(())
+}
+
+/// Reads every partition into memory, keyed by the path `unpack_file` would write it to
+/// (`BOOT` and `embedded-update.img` for RKFW images). Holds the whole image in memory, so
+/// it is meant for small and test images.
+pub fn unpack_to_map(file_path: &str) -> Result<(UnpackResult, HashMap<String, Vec<u8>>), ApfError> {
+    let result = inspect_file(file_path)?;
+    let entries = match &result {
+        UnpackResult::Rkaf(info) => info.partitions.iter().filter(|part| !part.is_special()).cloned().collect(),
+        UnpackResult::Rkfw(info) => {
+            let mut entries = Vec::new();
+            if info.has_boot {
+                entries.push(container_entry("BOOT", info.boot_offset, info.boot_size));
+            }
+            entries.push(container_entry("embedded-update.img", info.update_offset, info.update_size));
+            entries
+        }
+    };
+
+    let mut file = File::open(file_path)?;
+    let mut partitions = HashMap::new();
+    for part in entries {
+        let mut data = Vec::with_capacity(part.part_byte_count as usize);
+        extract_partition(&mut file, &part, &mut data)?;
+        partitions.insert(part.path, data);
+    }
+    Ok((result, partitions))
+}
+
+pub fn list_partitions(file_path: &str) -> Result<Vec<PartitionInfo>, ApfError> {
+    let mut file = File::open(file_path)?;
+    let filesize = file.metadata()?.len();
+    let (kind, signature) = read_signature(&mut file)?;
+
+    let mut partitions = Vec::new();
+    let mut base_offset = 0u32;
+    match kind {
+        ImageKind::Rkaf => {}
+        ImageKind::Rkfw => {
+            let mut buf = vec![0u8; RKFW_HEADER_SIZE];
+            file.read_exact(&mut buf)?;
+            let mut info = parse_rkfw(&buf, &UnpackOptions::default())?;
+            check_boot_signature(&mut file, &mut info, filesize, &UnpackOptions::default())?;
+            locate_embedded_image(&mut file, &mut info, filesize)?;
+            if info.has_boot {
+                partitions.push(container_entry("BOOT", info.boot_offset, info.boot_size));
+            }
+            partitions.push(container_entry("embedded-update.img", info.update_offset, info.update_size));
+            base_offset = info.update_offset;
+            if read_bytes_at(&mut file, base_offset as u64, 4).ok().as_deref() == Some(RKFW_SIGNATURE) {
+                return Err(nested_rkfw_error(&info));
+            }
+            file.seek(std::io::SeekFrom::Start(base_offset as u64))?;
+        }
+        _ => {
+            return Err(ApfError::UnknownSignature(signature));
+        }
+    }
+
+    // Offsets of embedded partitions are reported relative to the start of the outer file
+    let header = read_rkafp_header(&mut file)?;
+    let mut embedded = read_part_table(&header, TextEncoding::Auto, filesize.saturating_sub(base_offset as u64));
+    for part in embedded.iter_mut() {
+        part.part_offset = part.part_offset.saturating_add(base_offset);
+    }
+    partitions.extend(embedded);
+    detect_partition_contents(&mut file, &mut partitions, filesize)?;
+
+    Ok(partitions)
+}
+
+pub(crate) fn container_entry(name: &str, offset: u32, size: u32) -> PartitionInfo {
+    PartitionInfo {
+        name: name.to_string(),
+        path: name.to_string(),
+        flash_size: 0,
+        flash_offset: 0,
+        part_offset: offset,
+        padded_size: size,
+        part_byte_count: size,
+        sparse: false,
+        kind: if name == "BOOT" { PartitionKind::Loader } else { PartitionKind::Other },
+        content_type: None,
+    }
+}
+
+pub fn read_rkaf_header(file_path: &str) -> Result<UpdateHeader, ApfError> {
+    let mut file = File::open(file_path)?;
+    Ok(read_rkafp_header(&mut file)?)
+}
+
+pub fn verify_rkaf_crc(file_path: &str) -> Result<bool, ApfError> {
+    verify_rkaf_crc_with_progress(file_path, None, None)
+}
+
+/// Like `verify_rkaf_crc`, calling `progress` with (bytes checked, total) after every chunk;
+/// `cancel` is checked before each chunk and stops the check with `ApfError::Cancelled` once set
+pub fn verify_rkaf_crc_with_progress(file_path: &str, progress: Option<&dyn Fn(u64, u64)>, cancel: Option<&AtomicBool>) -> Result<bool, ApfError> {
+    let mut fp = File::open(file_path)?;
+    let mut buf = vec![0u8; std::mem::size_of::<UpdateHeader>()];
+    fp.read_exact(&mut buf)
+        .map_err(|_| ApfError::Truncated("File too small to contain an RKAF header".to_string()))?;
+    if !UpdateHeader::has_rkaf_magic(&buf) {
+        return Err(ApfError::BadMagic);
+    }
+    let header = UpdateHeader::from_bytes(&buf);
+
+    let filesize = fp.metadata()?.len();
+    let (stored, computed) = rkaf_crc(&mut fp, header.length, filesize, progress, cancel)?;
+    check_length(header.length as u64 + 4, filesize, &UnpackOptions::default())?;
+    Ok(stored == computed)
+}
+
+/// Checks the trailing RKCRC of an RKAF image held in memory: the RockChip CRC-32 over the first
+/// `header.length` bytes of `buf` against its last 4 bytes, failing with `ApfError::CrcMismatch`
+pub fn verify_rkafp_crc(buf: &[u8], header: &UpdateHeader) -> Result<(), ApfError> {
+    let length = header.length as usize;
+    if buf.len() < 4 || length > buf.len() - 4 {
+        return Err(ApfError::Truncated(format!(
+            "update_header.length ({:#x}) points past the end of the image ({} bytes)",
+            length,
+            buf.len()
+        )));
+    }
+    let computed = rkcrc32(0, &buf[..length]);
+    let stored = get_u32_le(&buf[buf.len() - 4..]);
+    if stored != computed {
+        return Err(ApfError::CrcMismatch { expected: stored, actual: computed });
+    }
+    Ok(())
+}
+
+/// Checks the MD5 digest at the end of an RKFW image held in memory against the MD5 of
+/// everything before it, failing with `ApfError::Md5Mismatch`; an image too small for a header
+/// and digest is `Truncated`, one that does not end in 32 hex digits `Invalid`
+pub fn verify_rkfw_md5(buf: &[u8]) -> Result<(), ApfError> {
+    verify_rkfw_md5_reader(&mut std::io::Cursor::new(buf))
+}
+
+/// Like `verify_rkfw_md5`, streaming the image from `reader`
+pub fn verify_rkfw_md5_reader<R: Read + Seek>(reader: &mut R) -> Result<(), ApfError> {
+    let filesize = stream_len(reader)?;
+    if filesize < RKFW_HEADER_SIZE as u64 + RKFW_MD5_LEN {
+        return Err(ApfError::Truncated(format!("Image too small to contain an RKFW header and MD5 digest ({} bytes)", filesize)));
+    }
+    match rkfw_md5(reader, filesize)? {
+        Some((stored, computed)) if stored == computed => Ok(()),
+        Some((expected, actual)) => Err(ApfError::Md5Mismatch { expected, actual }),
+        None => Err(ApfError::Invalid("The image does not end with an MD5 digest".to_string())),
+    }
+}
+
+// `declared_length` counts the trailing RKCRC, see `RkafInfo::declared_length`
+fn check_length(declared_length: u64, actual_length: u64, options: &UnpackOptions) -> Result<()> {
+    if declared_length != actual_length {
+        warn_or_err(options, format!(
+            "update_header.length ({:#x}) does not match file size ({} bytes)",
+            declared_length - 4,
+            actual_length
+        ))?;
+    }
+    Ok(())
+}
+
+// Returns the stored (last 4 bytes) and computed (over the first `length` bytes) CRC
+fn rkaf_crc<R: Read + Seek>(fp: &mut R, length: u32, filesize: u64, progress: Option<&dyn Fn(u64, u64)>, cancel: Option<&AtomicBool>) -> Result<(u32, u32)> {
+    let length = length as u64;
+    if length + 4 > filesize {
+        return Err(ApfError::Truncated(format!(
+            "update_header.length ({:#x}) points past the end of the file ({} bytes)",
+            length,
+            filesize
+        )).into());
+    }
+    let crc = crc_range_with_progress(fp, 0, length, progress, cancel)?;
+    let stored = read_bytes_at(fp, filesize - 4, 4)?;
+    debug!("RKCRC over {:#x} bytes: computed {:#010x}, stored at {:#x}", length, crc, filesize - 4);
+
+    Ok((get_u32_le(&stored), crc))
+}
+
+pub(crate) fn crc_range<R: Read + Seek>(fp: &mut R, offset: u64, len: u64) -> Result<u32> {
+    crc_range_with_progress(fp, offset, len, None, None)
+}
+
+fn crc_range_with_progress<R: Read + Seek>(fp: &mut R, offset: u64, len: u64, progress: Option<&dyn Fn(u64, u64)>, cancel: Option<&AtomicBool>) -> Result<u32> {
+    let mut buffer = vec![0u8; 16 * 1024];
+    let mut crc = 0;
+    let mut remaining = len;
+    fp.seek(std::io::SeekFrom::Start(offset))?;
+    while remaining > 0 {
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return Err(ApfError::Cancelled.into());
+        }
+        let read_len = std::cmp::min(remaining as usize, buffer.len());
+        fp.read_exact(&mut buffer[..read_len])?;
+        crc = rkcrc32(crc, &buffer[..read_len]);
+        remaining -= read_len as u64;
+        if let Some(progress) = progress {
+            progress(len - remaining, len);
+        }
+    }
+    Ok(crc)
+}
+
+pub(crate) fn stream_len<R: Seek>(fp: &mut R) -> Result<u64> {
+    Ok(fp.seek(std::io::SeekFrom::End(0))?)
+}
+
+pub(crate) fn read_bytes_at<R: Read + Seek>(fp: &mut R, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; len];
+    fp.seek(std::io::SeekFrom::Start(offset))?;
+    fp.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+// Logs `message` as a warning, or fails with it under `UnpackOptions::strict`
+fn warn_or_err(options: &UnpackOptions, message: String) -> Result<()> {
+    if options.strict {
+        return Err(ApfError::Invalid(message).into());
+    }
+    warn!("{}", message);
+    Ok(())
+}
+
+fn check_crc(stored: u32, computed: u32, options: &UnpackOptions) -> Result<()> {
+    if stored == computed {
+        info!("CRC: {:#010x} (ok)", stored);
+        return Ok(());
+    }
+
+    checksum_mismatch(ApfError::CrcMismatch { expected: stored, actual: computed }, options)
+}
+
+// CRC and MD5 mismatches fail unless `allow_crc_mismatch` is set without `strict_crc` or `strict`
+fn checksum_mismatch(mismatch: ApfError, options: &UnpackOptions) -> Result<()> {
+    if !options.allow_crc_mismatch || options.strict_crc || options.strict {
+        return Err(mismatch.into());
+    }
+    warn!("{}", mismatch);
+    Ok(())
+}
+
+// The RKFW file ends with the MD5 of everything before it, as 32 hex characters; returns the
+// stored and the computed digest, `None` when the file is too small or does not end in hex digits
+fn rkfw_md5<R: Read + Seek>(fp: &mut R, filesize: u64) -> Result<Option<(String, String)>> {
+    if filesize < RKFW_HEADER_SIZE as u64 + RKFW_MD5_LEN {
+        return Ok(None);
+    }
+    let stored = read_bytes_at(fp, filesize - RKFW_MD5_LEN, RKFW_MD5_LEN as usize)?;
+    if !stored.iter().all(u8::is_ascii_hexdigit) {
+        return Ok(None);
+    }
+    let stored = String::from_utf8_lossy(&stored).to_lowercase();
+    fp.seek(std::io::SeekFrom::Start(0))?;
+    let (computed, _) = hash_reader(&mut (&mut *fp).take(filesize - RKFW_MD5_LEN), HashAlgorithm::Md5)?;
+    Ok(Some((stored, computed)))
+}
+
+fn check_md5<R: Read + Seek>(fp: &mut R, info: &mut RkfwInfo, filesize: u64, options: &UnpackOptions) -> Result<()> {
+    let Some((stored, computed)) = rkfw_md5(fp, filesize)? else {
+        debug!("No MD5 digest at the end of the image, not checking it");
+        return Ok(());
+    };
+    info.md5_expected = Some(stored.clone());
+    info.md5_computed = Some(computed.clone());
+    if stored == computed {
+        info!("MD5: {} (ok)", stored);
+        return Ok(());
+    }
+
+    checksum_mismatch(ApfError::Md5Mismatch { expected: stored, actual: computed }, options)
+}
+
+fn check_region(offset: u32, size: u32, filesize: u64, what: &str) -> Result<()> {
+    if offset as u64 + size as u64 > filesize {
+        return Err(ApfError::Truncated(format!(
+            "{} at offset {:#x} (size {}) is out of bounds (file size {})",
+            what,
+            offset,
+            size,
+            filesize
+        )).into());
+    }
+    Ok(())
+}
+
+fn region_end(offset: u32, size: u32) -> u64 {
+    (offset as u64 + size as u64).saturating_sub(1)
+}
+
+pub(crate) fn get_u32_le(slice: &[u8]) -> u32 {
+    u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]])
+}
+
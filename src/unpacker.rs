@@ -0,0 +1,306 @@
+use std::io::{Read, Seek};
+use std::sync::atomic::AtomicBool;
+use crate::checksum::HashAlgorithm;
+use crate::encoding::TextEncoding;
+use crate::error::ApfError;
+use crate::iter::PartitionIter;
+use crate::kind::PartitionKind;
+use crate::metadata::{MetadataFormat, PartitionSort};
+use crate::unpack::{unpack_path, unpack_stream, OutputNaming, PartitionEvent, Progress, UnpackOptions, UnpackResult};
+
+type PartitionCallback<'a> = Box<dyn Fn(&PartitionEvent) + 'a>;
+
+/// Builder over `UnpackOptions`, optional progress callbacks and a cancel flag, e.g.
+/// `Unpacker::new().chunk_size(1 << 20).verify_crc(true).only(&["boot"]).unpack(file, dst)`
+#[derive(Default)]
+pub struct Unpacker<'a> {
+    options: UnpackOptions,
+    progress: Option<Box<dyn Fn(u64, u64) + 'a>>,
+    on_partition: Option<PartitionCallback<'a>>,
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> Unpacker<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from existing options instead of the defaults
+    pub fn with_options(options: UnpackOptions) -> Self {
+        Self { options, progress: None, on_partition: None, cancel: None }
+    }
+
+    pub fn options(&self) -> &UnpackOptions {
+        &self.options
+    }
+
+    /// Size of the copy buffer, see `UnpackOptions::buffer_size`
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.options.buffer_size = Some(size);
+        self
+    }
+
+    /// Fail on a CRC or RKFW MD5 mismatch (the default), or only warn with `false`
+    pub fn verify_crc(mut self, strict: bool) -> Self {
+        self.options.strict_crc = strict;
+        self.options.allow_crc_mismatch = !strict;
+        self
+    }
+
+    /// Fail on every warning about the image, see `UnpackOptions::strict`
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    pub fn strict_padding(mut self, strict: bool) -> Self {
+        self.options.strict_padding = strict;
+        self
+    }
+
+    pub fn capture_padding(mut self, capture: bool) -> Self {
+        self.options.capture_padding = capture;
+        self
+    }
+
+    /// Only extract partitions matching one of these patterns, see `UnpackOptions::only`
+    pub fn only<S: AsRef<str>>(mut self, patterns: &[S]) -> Self {
+        self.options.only = patterns.iter().map(|pattern| pattern.as_ref().to_string()).collect();
+        self
+    }
+
+    pub fn naming(mut self, naming: OutputNaming) -> Self {
+        self.options.naming = naming;
+        self
+    }
+
+    pub fn metadata_format(mut self, format: MetadataFormat) -> Self {
+        self.options.metadata_format = format;
+        self
+    }
+
+    /// Order of the partition metadata rows, see `UnpackOptions::sort`
+    pub fn sort(mut self, sort: PartitionSort) -> Self {
+        self.options.sort = sort;
+        self
+    }
+
+    pub fn expand_sparse(mut self, expand: bool) -> Self {
+        self.options.expand_sparse = expand;
+        self
+    }
+
+    pub fn checksums(mut self, algorithm: Option<HashAlgorithm>) -> Self {
+        self.options.checksums = algorithm;
+        self
+    }
+
+    pub fn split_boot(mut self, split: bool) -> Self {
+        self.options.split_boot = split;
+        self
+    }
+
+    pub fn decrypt_loader(mut self, decrypt: bool) -> Self {
+        self.options.decrypt_loader = decrypt;
+        self
+    }
+
+    /// Leave `BOOT` out of an RKFW unpack, see `UnpackOptions::no_extract_boot`
+    pub fn extract_boot(mut self, extract: bool) -> Self {
+        self.options.no_extract_boot = !extract;
+        self
+    }
+
+    /// Only extract `BOOT` from an RKFW image, see `UnpackOptions::boot_only`
+    pub fn boot_only(mut self, boot_only: bool) -> Self {
+        self.options.boot_only = boot_only;
+        self
+    }
+
+    pub fn split_resource(mut self, split: bool) -> Self {
+        self.options.split_resource = split;
+        self
+    }
+
+    /// Also split Android boot images into their sections, see `UnpackOptions::split_boot_image`
+    pub fn split_boot_image(mut self, split: bool) -> Self {
+        self.options.split_boot_image = split;
+        self
+    }
+
+    /// Also write gzip-compressed partitions decompressed, see `UnpackOptions::gunzip_partitions`
+    pub fn gunzip_partitions(mut self, gunzip: bool) -> Self {
+        self.options.gunzip_partitions = gunzip;
+        self
+    }
+
+    /// Extract partitions in the order they are stored in, see `UnpackOptions::preserve_order`
+    pub fn preserve_order(mut self, preserve: bool) -> Self {
+        self.options.preserve_order = preserve;
+        self
+    }
+
+    /// Zero-fill extracted partitions to their flash allocation, see `UnpackOptions::pad_to_flash_size`
+    pub fn pad_to_flash_size(mut self, pad: bool) -> Self {
+        self.options.pad_to_flash_size = pad;
+        self
+    }
+
+    /// Maps an RKFW chip code to a family name, checked before the built-in table
+    pub fn chip_override(mut self, code: u8, name: &str) -> Self {
+        self.options.chip_overrides.insert(code, name.to_string());
+        self
+    }
+
+    /// Treats partition `name` as `kind` regardless of what its name suggests
+    pub fn kind_override(mut self, name: &str, kind: PartitionKind) -> Self {
+        self.options.kind_overrides.insert(name.to_string(), kind);
+        self
+    }
+
+    /// Extracts partition `name` to `path` in the destination, see `UnpackOptions::path_map`
+    pub fn map_path<P: Into<std::path::PathBuf>>(mut self, name: &str, path: P) -> Self {
+        self.options.path_map.insert(name.to_string(), path.into());
+        self
+    }
+
+    /// Skip partitions without a `map_path` entry
+    pub fn mapped_only(mut self, mapped_only: bool) -> Self {
+        self.options.mapped_only = mapped_only;
+        self
+    }
+
+    /// Write partitions larger than `bytes` in chunks, see `UnpackOptions::split_size`
+    pub fn split_size(mut self, bytes: u64) -> Self {
+        self.options.split_size = Some(bytes);
+        self
+    }
+
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.options.no_decompress = !decompress;
+        self
+    }
+
+    /// Look for a signature after a vendor prefix, see `UnpackOptions::scan`
+    pub fn scan(mut self, scan: bool) -> Self {
+        self.options.scan = scan;
+        self
+    }
+
+    pub fn text_encoding(mut self, encoding: TextEncoding) -> Self {
+        self.options.text_encoding = encoding;
+        self
+    }
+
+    /// Also write the raw image header to `header.bin`
+    pub fn save_header(mut self, save: bool) -> Self {
+        self.options.save_header = save;
+        self
+    }
+
+    /// Record partitions that fail to extract in the result's `errors` and go on with the rest,
+    /// see `UnpackOptions::continue_on_error`
+    pub fn continue_on_error(mut self, keep_going: bool) -> Self {
+        self.options.continue_on_error = keep_going;
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    pub fn resume_verify(mut self, verify: bool) -> Self {
+        self.options.resume_verify = verify;
+        self
+    }
+
+    /// Check the extracted files against a `sha256sum` style list, see `UnpackOptions::verify_manifest`
+    pub fn verify_manifest(mut self, manifest_path: Option<&str>) -> Self {
+        self.options.verify_manifest = manifest_path.map(str::to_string);
+        self
+    }
+
+    /// Fail when the destination directory is not empty, see `UnpackOptions::require_empty_dir`
+    pub fn require_empty_dir(mut self, require: bool) -> Self {
+        self.options.require_empty_dir = require;
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.options.force = force;
+        self
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.options.recursive = recursive;
+        self
+    }
+
+    pub fn max_size(mut self, max_size: Option<u64>) -> Self {
+        self.options.max_size = max_size;
+        self
+    }
+
+    /// Number of partitions extracted at once, see `UnpackOptions::parallelism`
+    pub fn parallelism(mut self, threads: usize) -> Self {
+        self.options.parallelism = threads;
+        self
+    }
+
+    pub fn preserve_mtime(mut self, preserve: bool) -> Self {
+        self.options.preserve_mtime = preserve;
+        self
+    }
+
+    /// Modification time for images without a build date, see `UnpackOptions::epoch`
+    pub fn epoch(mut self, epoch: Option<i64>) -> Self {
+        self.options.epoch = epoch;
+        self
+    }
+
+    /// Unix permission bits for the extracted files, e.g. `0o644`
+    pub fn file_mode(mut self, mode: Option<u32>) -> Self {
+        self.options.file_mode = mode;
+        self
+    }
+
+    /// Called with (bytes done, total) while each file is extracted
+    pub fn progress<F: Fn(u64, u64) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Called from `unpack` once per partition as soon as it is extracted, skipped or failed,
+    /// e.g. to stream the results, see `PartitionEvent`
+    pub fn on_partition<F: Fn(&PartitionEvent) + 'a>(mut self, on_partition: F) -> Self {
+        self.on_partition = Some(Box::new(on_partition));
+        self
+    }
+
+    /// Stops `unpack` with `ApfError::Cancelled` once the flag is set, e.g. from a GUI thread;
+    /// the partially written file is deleted
+    pub fn cancel(mut self, cancel: &'a AtomicBool) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn unpack(&self, file_path: &str, dst_path: &str) -> Result<UnpackResult, ApfError> {
+        unpack_path(file_path, dst_path, &self.options, self.callbacks())
+    }
+
+    /// Checks the image like `unpack` does, then yields each selected partition with a reader
+    /// over its bytes instead of writing anything; RKFW images yield `BOOT` and
+    /// `embedded-update.img`, or `BOOT` and the embedded partitions with `recursive`
+    pub fn into_iter_partitions(self, file_path: &str) -> Result<PartitionIter, ApfError> {
+        PartitionIter::open(file_path, &self.options)
+    }
+
+    pub fn unpack_reader<R: Read + Seek>(&self, reader: R, dst_path: &str) -> Result<UnpackResult, ApfError> {
+        unpack_stream(reader, dst_path, &self.options, self.callbacks())
+    }
+
+    fn callbacks(&self) -> Progress<'_> {
+        Progress { report: self.progress.as_deref(), cancel: self.cancel, finished: self.on_partition.as_deref() }
+    }
+}
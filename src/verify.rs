@@ -0,0 +1,275 @@
+#[cfg(feature = "serde")]
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+#[cfg(feature = "serde")]
+use std::path::Path;
+#[cfg(feature = "serde")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "serde")]
+use log::{debug, info, warn};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::checksum::{hash_reader, HashAlgorithm};
+use crate::encoding::TextEncoding;
+use crate::error::ApfError;
+use crate::unpack::{check_num_parts, crc_range, get_u32_le, locate_embedded_image, parse_rkafp, parse_rkfw, read_bytes_at, stream_len, UnpackOptions, RKFW_HEADER_SIZE};
+use crate::signature::{read_signature, ImageKind};
+use crate::UpdateHeader;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VerifyCheck {
+    pub name: String,
+    pub passed: bool,
+    pub details: String,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VerifyReport {
+    pub checks: Vec<VerifyCheck>,
+}
+
+impl VerifyReport {
+    /// `true` when every check passed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    fn check(&mut self, name: &str, passed: bool, details: String) -> bool {
+        self.checks.push(VerifyCheck { name: name.to_string(), passed, details });
+        passed
+    }
+}
+
+// Checks an RKAF image stored at `base..base + size`, as a whole file or embedded in RKFW
+fn verify_rkaf<R: Read + Seek>(fp: &mut R, base: u64, size: u64, prefix: &str, report: &mut VerifyReport) -> Result<(), ApfError> {
+    let header_size = std::mem::size_of::<UpdateHeader>();
+    if !report.check(
+        &format!("{}header size", prefix),
+        size >= header_size as u64,
+        format!("{} bytes, header needs {}", size, header_size),
+    ) {
+        return Ok(());
+    }
+    let buf = read_bytes_at(fp, base, header_size)?;
+    let header = *UpdateHeader::from_bytes(&buf);
+    if !report.check(
+        &format!("{}header magic", prefix),
+        UpdateHeader::has_rkaf_magic(&buf),
+        format!("{:?}", String::from_utf8_lossy(&header.magic)),
+    ) {
+        return Ok(());
+    }
+
+    if let Err(err) = check_num_parts(&header) {
+        report.check(&format!("{}partition count", prefix), false, err.to_string());
+        return Ok(());
+    }
+
+    let length = header.length as u64;
+    if length + 4 > size {
+        report.check(
+            &format!("{}CRC", prefix),
+            false,
+            format!("length ({:#x}) points past the end of the image ({} bytes)", length, size),
+        );
+    } else {
+        let computed = crc_range(fp, base, length)?;
+        let stored = get_u32_le(&read_bytes_at(fp, base + size - 4, 4)?);
+        report.check(
+            &format!("{}CRC", prefix),
+            stored == computed,
+            format!("stored {:#010x}, computed {:#010x}", stored, computed),
+        );
+    }
+
+    for part in parse_rkafp(&header, size, TextEncoding::Auto).partitions {
+        let end = part.part_offset as u64 + part.part_byte_count as u64;
+        report.check(
+            &format!("{}partition {}", prefix, part.name),
+            end <= size,
+            format!("{:#010x}-{:#010x} in {} bytes", part.part_offset, end, size),
+        );
+    }
+    Ok(())
+}
+
+fn verify_rkfw<R: Read + Seek>(fp: &mut R, filesize: u64, report: &mut VerifyReport) -> Result<(), ApfError> {
+    let buf = read_bytes_at(fp, 0, std::cmp::min(filesize, RKFW_HEADER_SIZE as u64) as usize)?;
+    let mut info = match parse_rkfw(&buf, &UnpackOptions::default()) {
+        Ok(info) => {
+            report.check("RKFW header", true, format!("{} {}", info.chip_family, info.version));
+            info
+        }
+        Err(err) => {
+            report.check("RKFW header", false, format!("{:#}", err));
+            return Ok(());
+        }
+    };
+
+    let boot_end = info.boot_offset as u64 + info.boot_size as u64;
+    report.check(
+        "BOOT region",
+        boot_end <= filesize,
+        format!("{:#010x}-{:#010x} in {} bytes", info.boot_offset, boot_end, filesize),
+    );
+
+    // The RKFW file ends with the MD5 of everything before it, as 32 hex characters
+    if filesize >= 32 {
+        fp.seek(std::io::SeekFrom::Start(0))?;
+        let (computed, _) = hash_reader(&mut (&mut *fp).take(filesize - 32), HashAlgorithm::Md5)?;
+        let stored = String::from_utf8_lossy(&read_bytes_at(fp, filesize - 32, 32)?).to_lowercase();
+        report.check("MD5", stored == computed, format!("stored {}, computed {}", stored, computed));
+    } else {
+        report.check("MD5", false, "File too small to contain an MD5 digest".to_string());
+    }
+
+    let update_end = info.update_offset as u64 + info.update_size as u64;
+    if !report.check(
+        "embedded update region",
+        update_end <= filesize,
+        format!("{:#010x}-{:#010x} in {} bytes", info.update_offset, update_end, filesize),
+    ) {
+        return Ok(());
+    }
+    let skipped = locate_embedded_image(fp, &mut info, filesize).map_err(ApfError::from)?;
+    let update_offset = info.update_offset as u64;
+    let update_size = info.update_size as u64;
+    let magic_ok = update_size >= 4 && UpdateHeader::has_rkaf_magic(&read_bytes_at(fp, update_offset, 4)?);
+    let detail = match skipped {
+        0 => format!("at offset {:#x}", update_offset),
+        _ => format!("at offset {:#x}, {} bytes after the update offset in the header", update_offset, skipped),
+    };
+    if !report.check("embedded RKAF signature", magic_ok, detail) {
+        return Ok(());
+    }
+    verify_rkaf(fp, update_offset, update_size, "embedded ", report)
+}
+
+pub fn verify_reader<R: Read + Seek>(mut reader: R) -> Result<VerifyReport, ApfError> {
+    let filesize = stream_len(&mut reader)?;
+    let mut report = VerifyReport::default();
+    if !report.check("signature size", filesize >= 4, format!("{} bytes", filesize)) {
+        return Ok(report);
+    }
+    let (kind, signature) = read_signature(&mut reader)?;
+    match kind {
+        ImageKind::Rkfw => {
+            report.check("signature", true, "RKFW".to_string());
+            verify_rkfw(&mut reader, filesize, &mut report)?;
+        }
+        ImageKind::Rkaf => {
+            report.check("signature", true, "RKAF".to_string());
+            verify_rkaf(&mut reader, 0, filesize, "", &mut report)?;
+        }
+        _ => {
+            report.check("signature", false, format!("Unknown signature: {:?} ({:?})", signature, kind));
+        }
+    }
+    Ok(report)
+}
+
+pub fn verify_file(file_path: &str) -> Result<VerifyReport, ApfError> {
+    verify_reader(File::open(file_path)?)
+}
+
+// File systems update mtime at a coarse granularity, so a file written again within this long
+// of the previous write can keep its mtime; such reports are not cached
+#[cfg(feature = "serde")]
+const RACY_MTIME_WINDOW: Duration = Duration::from_secs(2);
+
+// A report is reused only for the same file, modification time and size, checked by the same
+// version of the crate
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    tool_version: String,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    report: VerifyReport,
+}
+
+/// `$XDG_CACHE_HOME/afptool-rs/verify-cache.json`, falling back to `~/.cache` and on Windows to
+/// `%LOCALAPPDATA%`; `None` when none of them is set
+pub fn default_verify_cache_path() -> Option<PathBuf> {
+    let non_empty = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let base = non_empty("XDG_CACHE_HOME")
+        .or_else(|| non_empty("HOME").map(|home| home.join(".cache")))
+        .or_else(|| non_empty("LOCALAPPDATA"))?;
+    Some(base.join("afptool-rs").join("verify-cache.json"))
+}
+
+#[cfg(feature = "serde")]
+fn cache_key(file_path: &str) -> Result<(String, CacheEntry), ApfError> {
+    let metadata = std::fs::metadata(file_path)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let path = std::fs::canonicalize(file_path)?.to_string_lossy().to_string();
+    let entry = CacheEntry {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        size: metadata.len(),
+        mtime_secs: mtime.as_secs(),
+        mtime_nanos: mtime.subsec_nanos(),
+        report: VerifyReport::default(),
+    };
+    Ok((path, entry))
+}
+
+// A missing or unreadable cache is an empty one
+#[cfg(feature = "serde")]
+fn read_cache(cache_path: &Path) -> HashMap<String, CacheEntry> {
+    match std::fs::read(cache_path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable verify cache {}: {}", cache_path.display(), e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+// Written next to the cache and renamed over it, so a concurrent verify never reads half a file
+#[cfg(feature = "serde")]
+fn write_cache(cache_path: &Path, entries: &HashMap<String, CacheEntry>) -> Result<(), ApfError> {
+    let dir = cache_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let temp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&temp, entries).map_err(|e| ApfError::Io(e.into()))?;
+    temp.persist(cache_path).map_err(|e| ApfError::Io(e.error))?;
+    Ok(())
+}
+
+/// `verify_file` that keeps its reports in the JSON file at `cache_path`: verifying a file again
+/// with the same path, modification time and size returns the stored report without reading the
+/// image. Any change to one of them, or another version of the crate, verifies from scratch and
+/// replaces the entry; entries for files that no longer exist are dropped. Files modified in the
+/// last two seconds are not cached, as a rewrite in the same mtime tick would go unnoticed. A
+/// cache that cannot be read or written only costs the speedup
+#[cfg(feature = "serde")]
+pub fn verify_file_cached(file_path: &str, cache_path: &Path) -> Result<VerifyReport, ApfError> {
+    let (key, mut entry) = cache_key(file_path)?;
+    let mut entries = read_cache(cache_path);
+    if let Some(cached) = entries.get(&key) {
+        if (&cached.tool_version, cached.size, cached.mtime_secs, cached.mtime_nanos) == (&entry.tool_version, entry.size, entry.mtime_secs, entry.mtime_nanos) {
+            info!("{} is unchanged since it was last verified, using the cached report", file_path);
+            return Ok(cached.report.clone());
+        }
+        debug!("{} changed since it was last verified", file_path);
+    }
+
+    entry.report = verify_file(file_path)?;
+    let report = entry.report.clone();
+    let mtime = UNIX_EPOCH + Duration::new(entry.mtime_secs, entry.mtime_nanos);
+    if SystemTime::now().duration_since(mtime).map_or(true, |age| age < RACY_MTIME_WINDOW) {
+        debug!("{} was modified too recently to cache its report", file_path);
+        return Ok(report);
+    }
+    entries.retain(|path, _| Path::new(path).exists());
+    entries.insert(key, entry);
+    if let Err(e) = write_cache(cache_path, &entries) {
+        warn!("Could not update the verify cache {}: {}", cache_path.display(), e);
+    }
+    Ok(report)
+}
@@ -94,6 +94,533 @@ mod integration_tests {
             .stdout(predicate::str::contains("A Rust tool for packing and unpacking RockChip firmware images"));
     }
     
+    #[test]
+    fn test_cli_unpack_keeps_stdout_clean() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 诊断信息通过日志输出到 stderr，stdout 保持为空
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg(&image).arg(&output_dir);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::contains("manufacturer:  RK3326"));
+    }
+
+    #[test]
+    fn test_cli_unpack_logs_byte_ranges() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "misc Image/misc.img\nboot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/misc.img"), b"").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "misc,Image/misc.img,0x00008000,0x00002000,0x00000000,0x00000000,0x00000000\n\
+             boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let boot = afptool_rs::list_partitions(image.to_str().unwrap()).unwrap()
+            .into_iter()
+            .find(|part| part.name == "boot")
+            .unwrap();
+
+        // 日志打印包含首尾字节的区间和大小，空分区显示为 (empty)
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg(&image).arg(&output_dir);
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains(format!(
+                "{:08x}-{:08x}",
+                boot.part_offset,
+                boot.part_offset + 99
+            )))
+            .stderr(predicate::str::contains("(size: 100 bytes)"))
+            .stderr(predicate::str::contains("(empty)"))
+            .stderr(predicate::str::contains("Done in "));
+        assert_eq!(fs::read(output_dir.join("Image/misc.img")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_cli_list() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("list").arg(&image);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("NAME"))
+            .stdout(predicate::str::contains("Image/boot.img"));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_cli_strings() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\nkernel Image/kernel.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), b"\x00\x00U-Boot 2017.09\x00ab\x00").unwrap();
+        fs::write(input_dir.join("Image/kernel.img"), b"Linux version 4.19\x00").unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n\
+             kernel,Image/kernel.img,0x00010000,0x00012000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 只扫描 boot 分区，带偏移输出，短于 min-len 的字符串被忽略
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("strings").arg(&image).arg("--partition").arg("boot").arg("-t").arg("-n").arg("6");
+        cmd.assert()
+            .success()
+            .stdout("boot: 0x00000002 U-Boot 2017.09\n");
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("strings").arg(&image);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("kernel: Linux version 4.19"))
+            .stdout(predicate::str::contains("RK3326").not());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_cli_dump_header() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(input_dir.join("partition-metadata.txt"), "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n").unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 逐字段显示偏移、原始字节和解析值，最后是前 256 字节的十六进制
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("dump-header").arg(&image);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("parts[0].part_byte_count"))
+            .stdout(predicate::str::contains("64 00 00 00"))
+            .stdout(predicate::str::contains("00000000  52 4b 41 46"));
+
+        // 校验失败的镜像也能显示
+        let mut data = fs::read(&image).unwrap();
+        data[0x88] = 0x40;
+        fs::write(&image, &data).unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("dump-header").arg(&image).arg("--json");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("\"value\": \"64 (more than 16)\""));
+    }
+
+    #[test]
+    fn test_cli_unpack_json_stream() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img
+kernel Image/kernel.img
+").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(input_dir.join("Image/kernel.img"), vec![0x5au8; 300]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000
+\
+             kernel,Image/kernel.img,0x00010000,0x00012000,0x00000000,0x00000800,0x00000000
+",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 每个分区一行 JSON，按完成顺序输出
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg(&image).arg(temp_dir.path().join("out")).arg("--json-stream").arg("--only").arg("kernel");
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let events: Vec<serde_json::Value> = String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!((&events[0]["name"], &events[0]["status"]), (&serde_json::json!("boot"), &serde_json::json!("not-selected")));
+        assert_eq!((&events[1]["name"], &events[1]["status"], &events[1]["written"]), (&serde_json::json!("kernel"), &serde_json::json!("extracted"), &serde_json::json!(300)));
+
+        // 与 --json 互斥
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg(&image).arg(temp_dir.path().join("out")).arg("--json-stream").arg("--json");
+        cmd.assert().failure();
+    }
+
+    #[test]
+    fn test_cli_verify() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("verify").arg(&image).env("XDG_CACHE_HOME", temp_dir.path());
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("CRC"))
+            .stdout(predicate::str::contains("FAIL").not());
+
+        // 破坏分区数据后 CRC 校验失败，命令应返回非零
+        let mut data = fs::read(&image).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("verify").arg(&image).env("XDG_CACHE_HOME", temp_dir.path());
+        cmd.assert()
+            .failure()
+            .stdout(predicate::str::contains("[FAIL] CRC"));
+
+        // 文件足够旧时结果写入缓存，再次校验直接使用缓存；--no-cache 则重新计算
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        fs::File::options().write(true).open(&image).unwrap().set_modified(old).unwrap();
+        let cache = temp_dir.path().join("verify-cache.json");
+        for _ in 0..2 {
+            let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+            cmd.arg("verify").arg(&image).arg("--cache-file").arg(&cache);
+            cmd.assert().failure().stdout(predicate::str::contains("[FAIL] CRC"));
+        }
+        assert!(cache.exists());
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-v").arg("verify").arg(&image).arg("--cache-file").arg(&cache);
+        cmd.assert().failure().stderr(predicate::str::contains("using the cached report"));
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-v").arg("verify").arg(&image).arg("--no-cache");
+        cmd.assert().failure().stderr(predicate::str::contains("cached").not());
+    }
+
+    #[test]
+    fn test_cli_check_metadata() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\nkernel Image/kernel.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(input_dir.join("Image/kernel.img"), vec![0x5au8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n\
+             kernel,Image/kernel.img,0x00010000,0x00012000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        afptool_rs::unpack_file(image.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("check-metadata").arg(&output_dir);
+        cmd.assert().success().stdout(predicate::str::contains("is consistent"));
+
+        // repack --align 重新排布分区，非 2 的幂的对齐在解析参数时就被拒绝
+        let repacked = temp_dir.path().join("repacked.img");
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("repack").arg(&output_dir).arg(&repacked).arg("--align").arg("0x4000");
+        cmd.assert().success().stdout(predicate::str::contains("checks passed"));
+        let offsets: Vec<u32> = afptool_rs::list_partitions(repacked.to_str().unwrap()).unwrap().iter().map(|p| p.part_offset).collect();
+        assert_eq!(offsets, [0x4000, 0x8000]);
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("repack").arg(&output_dir).arg(&repacked).arg("--align").arg("3000");
+        cmd.assert().failure().stderr(predicate::str::contains("not a power of two"));
+
+        // 把 kernel 挪到 boot 的位置上：报告重叠并以错误退出
+        let metadata_path = output_dir.join("partition-metadata.txt");
+        let metadata = fs::read_to_string(&metadata_path).unwrap();
+        let boot_offset = metadata.lines().find(|line| line.starts_with("boot,")).unwrap().split(',').nth(4).unwrap().to_string();
+        let edited: Vec<String> = metadata.lines()
+            .map(|line| {
+                let mut fields: Vec<&str> = line.split(',').collect();
+                if fields[0] == "kernel" {
+                    fields[4] = &boot_offset;
+                }
+                fields.join(",")
+            })
+            .collect();
+        fs::write(&metadata_path, edited.join("\n") + "\n").unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("check-metadata").arg(&output_dir);
+        cmd.assert()
+            .failure()
+            .stdout(predicate::str::contains("overlaps"))
+            .stderr(predicate::str::contains("layout problems"));
+    }
+
+    #[test]
+    fn test_cli_update_crc() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("update-crc").arg(&image);
+        cmd.assert().success().stdout(predicate::str::contains("already correct"));
+
+        let mut data = fs::read(&image).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("update-crc").arg(&image);
+        cmd.assert().success().stdout(predicate::str::contains("->"));
+        assert!(afptool_rs::verify_rkaf_crc(image.to_str().unwrap()).unwrap());
+
+        // replace 原地替换分区并更新 CRC，放不下时提示完整重新打包
+        let new_boot = temp_dir.path().join("boot-new.img");
+        fs::write(&new_boot, vec![0x11u8; 2048]).unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("replace").arg(&image).arg("boot").arg(&new_boot);
+        cmd.assert().success().stdout(predicate::str::contains("Replaced boot: 100 -> 2048 bytes"));
+        assert!(afptool_rs::verify_rkaf_crc(image.to_str().unwrap()).unwrap());
+        fs::write(&new_boot, vec![0x11u8; 2049]).unwrap();
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("replace").arg(&image).arg("boot").arg(&new_boot);
+        cmd.assert().failure().stderr(predicate::str::contains("repack"));
+    }
+
+    #[test]
+    fn test_cli_unsparse_alias() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        // 一个 4096 字节块的 sparse 镜像，唯一的块是 DONT_CARE
+        let mut sparse = Vec::new();
+        for field in [0xed26ff3au32, 0x0000_0001, 0x000c_001c, 4096, 1, 1, 0] {
+            sparse.extend_from_slice(&field.to_le_bytes());
+        }
+        sparse.extend_from_slice(&0xcac3u16.to_le_bytes());
+        sparse.extend_from_slice(&0u16.to_le_bytes());
+        sparse.extend_from_slice(&1u32.to_le_bytes());
+        sparse.extend_from_slice(&12u32.to_le_bytes());
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), &sparse).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // --unsparse 是 --expand-sparse 的别名
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg(&image).arg(&out_dir).arg("--unsparse");
+        cmd.assert().success();
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), vec![0u8; 4096]);
+    }
+
+    #[test]
+    fn test_cli_exit_codes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let unpack = |input: &Path, extra: &[&str]| {
+            let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+            cmd.arg("unpack").arg(input).arg(&output_dir).args(extra);
+            cmd.assert()
+        };
+
+        unpack(&image, &[]).success();
+        // 帮助中列出退出码
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("--help").assert().success().stdout(predicate::str::contains("3  CRC or MD5 mismatch"));
+        // 参数错误不占用 2
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg("--bogus").assert().code(1);
+
+        // 未知签名
+        let junk = temp_dir.path().join("junk.img");
+        fs::write(&junk, b"XXXXXXXXXXXXXXXX").unwrap();
+        unpack(&junk, &[]).code(2);
+
+        // CRC 不匹配
+        let mut data = fs::read(&image).unwrap();
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        let corrupt = temp_dir.path().join("corrupt.img");
+        fs::write(&corrupt, &data).unwrap();
+        unpack(&corrupt, &[]).code(3).stderr(predicate::str::contains("CRC mismatch"));
+        unpack(&corrupt, &["--strict-crc"]).code(3);
+        // --ignore-crc 只给出警告
+        unpack(&corrupt, &["--ignore-crc"]).success().stderr(predicate::str::contains("CRC mismatch"));
+
+        // 映像被截断
+        let truncated = temp_dir.path().join("truncated.img");
+        fs::write(&truncated, &data[..200]).unwrap();
+        unpack(&truncated, &[]).code(4);
+
+        // 输入文件不存在
+        unpack(&temp_dir.path().join("missing.img"), &[]).code(5);
+    }
+
+    #[test]
+    fn test_cli_crc() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        fs::write(&path, b"xxRKAFyy").unwrap();
+
+        let crc = afptool_rs::rkcrc(b"xxRKAFyy");
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("crc").arg(&path);
+        cmd.assert().success().stdout(format!("{:#010x} {}\n", crc, crc));
+
+        // 十六进制起始偏移和长度
+        let crc = afptool_rs::rkcrc(b"RKAF");
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("crc").arg(&path).arg("--start").arg("0x2").arg("--len").arg("4");
+        cmd.assert().success().stdout(format!("{:#010x} {}\n", crc, crc));
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("crc").arg(&path).arg("--start").arg("6").arg("--len").arg("4");
+        cmd.assert().failure();
+    }
+
+    #[test]
+    fn test_cli_chips() {
+        // 列出所有已知的芯片代码，每行一个
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        let output = cmd.arg("chips").assert().success().get_output().stdout.clone();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), afptool_rs::CHIP_FAMILIES.len() + 1);
+        assert!(output.contains("0x36   RK3326\n"));
+        assert!(output.contains("0x91   RK3399\n"));
+    }
+
+    #[test]
+    fn test_cli_version_info() {
+        // 打印版本号和本次构建支持的功能
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("version-info").assert()
+            .success()
+            .stdout(predicate::str::contains(format!("afptool-rs {}", env!("CARGO_PKG_VERSION"))))
+            .stdout(predicate::str::contains("signatures: RKFW, RKAF"))
+            .stdout(predicate::str::contains("cli                  yes"));
+
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        let output = cmd.arg("version-info").arg("--json").assert().success().get_output().stdout.clone();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["chips"], afptool_rs::CHIP_FAMILIES.len());
+        assert_eq!(json["features"][0], serde_json::json!(["cli", true]));
+    }
+
+    #[test]
+    fn test_cli_unpack_stdin() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // `-` 从 stdin 读取镜像
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("unpack").arg("-").arg(&output_dir).write_stdin(fs::read(&image).unwrap());
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("from stdin"));
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 100]);
+    }
+
+    #[test]
+    fn test_cli_verbosity() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00000800,0x00000000\n",
+        ).unwrap();
+        afptool_rs::pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // -q 只输出错误
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-q").arg("unpack").arg(&image).arg(temp_dir.path().join("quiet"));
+        cmd.assert().success().stderr(predicate::str::is_empty());
+
+        // -v 输出分区表的偏移，-vv 追加每个数据块
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-v").arg("unpack").arg(&image).arg(temp_dir.path().join("debug"));
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("debug: partition 0: boot"))
+            .stderr(predicate::str::contains("copied").not());
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-vv").arg("unpack").arg(&image).arg(temp_dir.path().join("trace"));
+        cmd.assert().success().stderr(predicate::str::contains("trace: copied 100 bytes from"));
+
+        // JSON 输出不受 -q 影响
+        let mut cmd = Command::cargo_bin("afptool-rs").unwrap();
+        cmd.arg("-q").arg("unpack").arg(&image).arg(temp_dir.path().join("json")).arg("--json");
+        cmd.assert().success().stdout(predicate::str::contains("\"partitions\""));
+    }
+
     #[test]
     #[ignore] // 默认忽略此测试，因为它需要构建可执行文件
     fn test_cli_unpack_rkfw() -> Result<(), Box<dyn std::error::Error>> {
@@ -107,9 +634,9 @@ mod integration_tests {
         // 执行命令并检查输出
         cmd.assert()
             .success()
-            .stdout(predicate::str::contains("RKFW signature detected"))
-            .stdout(predicate::str::contains("version: 8.1.0"))
-            .stdout(predicate::str::contains("family: PX30"));
+            .stderr(predicate::str::contains("RKFW signature detected"))
+            .stderr(predicate::str::contains("version: 8.1.0"))
+            .stderr(predicate::str::contains("family: PX30"));
         
         // 检查文件是否被正确提取
         let boot_file = Path::new(&output_dir).join("BOOT");
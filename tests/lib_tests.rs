@@ -1,9 +1,10 @@
 #[cfg(test)]
 mod tests {
     use std::fs::{self, File};
-    use std::io::Write;
-    use std::path::Path;
-    use afptool_rs::{RKAF_SIGNATURE, RKFW_SIGNATURE, UpdateHeader};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use afptool_rs::{all_chips, ChunkIndex, CHUNKS_FILE, generate_parameter, make_firmware, UpdatePart, check_metadata, dump_header, find_strings, planned_size, EMBEDDED_MAGIC_WINDOW, RkfwInfo, replace_partition, verify_file_cached, parse_fit, parse_boot_image, BootImageSection, detect_fs, FS_PROBE_LEN, human_size, capabilities, carve_embedded_rkaf, verify_against_manifest, chip_family, chip_name_to_code, CHIP_FAMILIES, FirmwareCode, decode_header_string, find_signature, unpack_to_tar, unpack_to_tar_with_options, FLASH_SIZE_GROW, UnpackStats, parse_resource, ResourceEntry, PartitionKind, Unpacker, split_parts, MultiFileReader, detect_alignment, diff_images, extract_partition, peek_signature, ImageKind, parse_boot, rc4_rk, ApfError, BootEntryKind, hash_partition, inspect_file, probe_file, is_sparse, list_partitions, read_manifest, read_rkaf_header, repack_dir, repack_dir_with_alignment, repack_dir_with_options, pack_rkaf, pack_rkafp, pack_rkafp_with_alignment, pack_rkafp_with_options, PackOptions, pack_rkfw, parse_parameter, repack_rkfw, rkcrc, rkcrc_file, update_crc, unpack_file, unpack_file_json, unpack_file_with_options, unpack_file_with_progress, unpack_reader, unpack_reader_with_options, unpack_to_map, unsparse, validate_layout, validate_parameter_layout, verify_file, verify_rkaf_crc, verify_rkaf_crc_with_progress, verify_rkafp_crc, verify_rkfw_md5, verify_rkfw_md5_reader, HashAlgorithm, LayoutWarning, MetadataFormat, OutputNaming, PartitionSort, PartitionDiff, PartitionInfo, PartitionEvent, PartitionStatus, RkafInfo, ParameterPartition, ReservedRegion, TextEncoding, UnpackOptions, UnpackResult, RKAF_SIGNATURE, RKFW_SIGNATURE, MANIFEST_SCHEMA_VERSION, UpdateHeader};
+    use tempfile::TempDir;
 
     // 创建模拟的 RKFW 文件用于测试
     fn create_mock_rkfw() -> Vec<u8> {
@@ -91,6 +92,4275 @@ mod tests {
         data
     }
 
+    // 创建用于 pack_rkaf 的输入目录
+    fn create_pack_input(dir: &Path) {
+        fs::create_dir_all(dir.join("Image")).unwrap();
+        fs::write(
+            dir.join("package-file"),
+            "# 注释行\nparameter Image/parameter.txt\nboot Image/boot.img\n",
+        ).unwrap();
+        fs::write(dir.join("Image/parameter.txt"), b"FIRMWARE_VER: 1.0\nMACHINE_ID: 007\n").unwrap();
+        fs::copy(dir.join("Image/parameter.txt"), dir.join("parameter.txt")).unwrap();
+        fs::write(dir.join("Image/boot.img"), vec![0xa5u8; 5000]).unwrap();
+        fs::write(
+            dir.join("partition-metadata.txt"),
+            "parameter,Image/parameter.txt,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00001800,0x00000000\n",
+        ).unwrap();
+    }
+
+    // 构造 Android sparse 镜像：每个 chunk 为 (类型, 块数, 数据)
+    fn build_sparse_image(block_size: u32, total_blocks: u32, chunks: &[(u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xed26ff3au32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&28u16.to_le_bytes());
+        data.extend_from_slice(&12u16.to_le_bytes());
+        data.extend_from_slice(&block_size.to_le_bytes());
+        data.extend_from_slice(&total_blocks.to_le_bytes());
+        data.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for (chunk_type, blocks, body) in chunks {
+            data.extend_from_slice(&chunk_type.to_le_bytes());
+            data.extend_from_slice(&0u16.to_le_bytes());
+            data.extend_from_slice(&blocks.to_le_bytes());
+            data.extend_from_slice(&(12 + body.len() as u32).to_le_bytes());
+            data.extend_from_slice(body);
+        }
+        data
+    }
+
+    // 构造 Rockchip loader：每个 (表序号, 名称, 数据)，表序号 0/1/2 对应 471/472/loader
+    fn build_boot_blob(entries: &[(usize, &str, Vec<u8>)]) -> Vec<u8> {
+        let mut header = vec![0u8; 0x66];
+        header[0..4].copy_from_slice(b"BOOT");
+        header[4] = 0x66;
+        let mut tables = Vec::new();
+        let mut data = Vec::new();
+        let data_start = 0x66 + entries.len() * 0x39;
+        for table in 0..3 {
+            let table_offset = 0x66 + tables.len();
+            let field = [0x19, 0x1f, 0x25][table];
+            let mut count = 0u8;
+            for (_, name, body) in entries.iter().filter(|(t, _, _)| *t == table) {
+                let mut entry = vec![0u8; 0x39];
+                entry[0] = 0x39;
+                entry[1..5].copy_from_slice(&[1u32, 2, 4][table].to_le_bytes());
+                for (i, unit) in name.encode_utf16().enumerate() {
+                    entry[5 + i * 2..7 + i * 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                entry[0x2d..0x31].copy_from_slice(&((data_start + data.len()) as u32).to_le_bytes());
+                entry[0x31..0x35].copy_from_slice(&(body.len() as u32).to_le_bytes());
+                entry[0x35..0x39].copy_from_slice(&(table as u32).to_le_bytes());
+                tables.extend_from_slice(&entry);
+                data.extend_from_slice(body);
+                count += 1;
+            }
+            header[field] = count;
+            header[field + 1..field + 5].copy_from_slice(&(table_offset as u32).to_le_bytes());
+            header[field + 5] = 0x39;
+        }
+        [header, tables, data].concat()
+    }
+
+    #[test]
+    fn test_pack_rkafp_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+
+        pack_rkaf(input_dir.to_str().unwrap(), original.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap();
+        let result = pack_rkafp(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+
+        assert_eq!(result.num_parts, 2);
+        assert_eq!(result.model, " RK3326");
+        assert_eq!(result.machine_id.as_deref(), Some(" 007"));
+        // 重新打包的镜像应与原始镜像逐字节一致
+        assert_eq!(fs::read(&original).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_pack_rkafp_preserves_reserved_parts() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+
+        fs::create_dir_all(src_dir.join("Image")).unwrap();
+        fs::write(src_dir.join("Image/boot.img"), vec![0xa5u8; 5000]).unwrap();
+        fs::write(
+            src_dir.join("header-metadata.txt"),
+            "manufacturer: RK3326\nmodel: RK3326\nid:\nversion:0x01000000\nunknown1:0x00000000\nlength:0x00000000\n",
+        ).unwrap();
+        fs::write(
+            src_dir.join("partition-metadata.txt"),
+            "package-file,SELF,0x00000000,0x00000000,0x00000000,0x00000000,0x00000000,#skip-extract\n\
+             boot,Image/boot.img,0x00010000,0x00002000,0x00000800,0x00001800,0x00001388\n\
+             backup,RESERVED,0x00000000,0x00000000,0x00002000,0x00000000,0x00000000,#skip-extract\n",
+        ).unwrap();
+        pack_rkafp(src_dir.to_str().unwrap(), original.to_str().unwrap()).unwrap();
+
+        let info = match unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => info,
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        };
+        let length = read_rkaf_header(original.to_str().unwrap()).unwrap().length;
+        let names: Vec<&str> = info.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["boot"]);
+        assert_eq!(info.reserved_parts.len(), 2);
+        assert_eq!(info.reserved_parts[0].path, "SELF");
+        assert_eq!(info.reserved_parts[0].part_byte_count, length);
+        assert_eq!(info.reserved_parts[1].path, "RESERVED");
+        assert_eq!(info.reserved_parts[1].part_offset, 0x2000);
+        assert!(!unpack_dir.join("SELF").exists());
+
+        let metadata = fs::read_to_string(unpack_dir.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.starts_with("package-file,SELF,"));
+        assert!(metadata.lines().next().unwrap().ends_with(",#skip-extract"));
+
+        // 重新打包后 SELF/RESERVED 条目保持不变
+        pack_rkafp(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&original).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_unpack_creates_partition_dirs_on_demand() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+
+        fs::create_dir_all(src_dir.join("firmware/kernel")).unwrap();
+        fs::write(src_dir.join("boot.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(src_dir.join("firmware/kernel/kernel.img"), vec![0x5au8; 200]).unwrap();
+        fs::write(
+            src_dir.join("header-metadata.txt"),
+            "manufacturer: RK3326\nmodel: RK3326\nid:\nversion:0x01000000\nunknown1:0x00000000\nlength:0x00000000\n",
+        ).unwrap();
+        fs::write(
+            src_dir.join("partition-metadata.txt"),
+            "boot,boot.img,0x00010000,0x00002000,0x00000800,0x00000800,0x00000064\n\
+             kernel,firmware/kernel/kernel.img,0x00010000,0x00012000,0x00001000,0x00000800,0x000000c8\n",
+        ).unwrap();
+        pack_rkafp(src_dir.to_str().unwrap(), image.to_str().unwrap()).unwrap();
+
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(out_dir.join("boot.img")).unwrap(), vec![0xa5u8; 100]);
+        assert_eq!(fs::read(out_dir.join("firmware/kernel/kernel.img")).unwrap(), vec![0x5au8; 200]);
+        // 没有分区使用 Image/ 前缀时不应创建空目录
+        assert!(!out_dir.join("Image").exists());
+    }
+
+    #[test]
+    fn test_unpack_rejects_path_traversal() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("a/src");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("x/y/out");
+
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(temp_dir.path().join("evil.img"), vec![0xa5u8; 100]).unwrap();
+        fs::write(
+            src_dir.join("header-metadata.txt"),
+            "manufacturer: RK3326\nmodel: RK3326\nid:\nversion:0x01000000\nunknown1:0x00000000\nlength:0x00000000\n",
+        ).unwrap();
+        // 构造一个分区路径指向目标目录之外的镜像
+        fs::write(
+            src_dir.join("partition-metadata.txt"),
+            "evil,../../evil.img,0x00010000,0x00002000,0x00000800,0x00000800,0x00000064\n",
+        ).unwrap();
+        pack_rkafp(src_dir.to_str().unwrap(), image.to_str().unwrap()).unwrap();
+
+        let err = unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(_)), "unexpected error: {:?}", err);
+        assert!(!temp_dir.path().join("x/evil.img").exists());
+        assert!(!out_dir.exists());
+
+        // 绝对路径同样应被拒绝
+        fs::write(src_dir.join("abs.img"), vec![0x5au8; 100]).unwrap();
+        fs::write(
+            src_dir.join("partition-metadata.txt"),
+            "abs,/abs.img,0x00010000,0x00002000,0x00000800,0x00000800,0x00000064\n",
+        ).unwrap();
+        pack_rkafp(src_dir.to_str().unwrap(), image.to_str().unwrap()).unwrap();
+        let err = unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(_)), "unexpected error: {:?}", err);
+        assert!(!out_dir.exists());
+    }
+
+    #[test]
+    fn test_pack_rkafp_uses_actual_file_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+
+        pack_rkaf(input_dir.to_str().unwrap(), original.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap();
+
+        // 缩小 boot 分区，part_byte_count 应从实际文件大小重新计算
+        fs::write(unpack_dir.join("Image/boot.img"), vec![0x5au8; 100]).unwrap();
+        let options = PackOptions { allow_resized: true, ..Default::default() };
+        pack_rkafp_with_options(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap(), &options).unwrap();
+
+        let repacked_dir = temp_dir.path().join("repacked");
+        unpack_file(repacked.to_str().unwrap(), repacked_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(repacked_dir.join("Image/boot.img")).unwrap(), vec![0x5au8; 100]);
+    }
+
+    #[test]
+    fn test_pack_rkafp_refuses_resized_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+
+        pack_rkaf(input_dir.to_str().unwrap(), original.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap();
+
+        // 默认拒绝与元数据大小不符的文件
+        fs::write(unpack_dir.join("Image/boot.img"), vec![0x5au8; 100]).unwrap();
+        let err = pack_rkafp(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("100 bytes") && message.contains("5000")), "{:?}", err);
+        assert!(!repacked.exists());
+    }
+
+    #[test]
+    fn test_pack_rkafp_reserved_header_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), original.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 在头部末尾的 reserved 区域写入厂商数据并更新 CRC
+        let reserved_offset = std::mem::size_of::<UpdateHeader>() - 116;
+        let mut data = fs::read(&original).unwrap();
+        data[reserved_offset..reserved_offset + 8].copy_from_slice(b"BUILD-42");
+        data[reserved_offset + 115] = 0xff;
+        fs::write(&original, &data).unwrap();
+        update_crc(original.to_str().unwrap()).unwrap();
+
+        unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap();
+        let expected_hex: String = fs::read(&original).unwrap()[reserved_offset..reserved_offset + 116].iter().map(|b| format!("{:02x}", b)).collect();
+        let header_metadata = fs::read_to_string(unpack_dir.join("header-metadata.txt")).unwrap();
+        assert!(header_metadata.contains(&format!("reserved:{}\n", expected_hex)));
+        let manifest = read_manifest(&fs::read_to_string(unpack_dir.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest.reserved, expected_hex);
+
+        // 重新打包恢复 reserved 字节，镜像逐字节一致
+        pack_rkafp(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&original).unwrap(), fs::read(&repacked).unwrap());
+
+        // 长度不对的 reserved 行被拒绝
+        fs::write(unpack_dir.join("header-metadata.txt"), header_metadata.replace(&expected_hex, "00ff")).unwrap();
+        assert!(pack_rkafp(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_pack_rkafp_alignment() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let original = temp_dir.path().join("original.img");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), original.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap();
+        let layout = |path: &Path| -> Vec<(u32, u32)> {
+            list_partitions(path.to_str().unwrap()).unwrap().iter().map(|p| (p.part_offset, p.padded_size)).collect()
+        };
+        assert_eq!(layout(&original), [(0x800, 0x800), (0x1000, 0x1800)]);
+
+        // 指定 4 KiB 对齐：分区按原顺序重新排布，padded_size 为数据长度向上取整
+        let result = repack_dir_with_alignment(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap(), Some(0x1000)).unwrap();
+        assert!(result.report.passed());
+        assert_eq!(layout(&repacked), [(0x1000, 0x1000), (0x2000, 0x2000)]);
+        let length = read_rkaf_header(repacked.to_str().unwrap()).unwrap().length;
+        assert_eq!(length, 0x4000);
+
+        // 非 2 的幂的对齐被拒绝
+        let err = pack_rkafp_with_alignment(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap(), Some(3000)).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(_)));
+
+        // 不指定对齐且文件变大：按原镜像的对齐重新排布，而不是报重叠错误
+        fs::write(unpack_dir.join("Image/parameter.txt"), vec![b'#'; 3000]).unwrap();
+        let options = PackOptions { allow_resized: true, ..Default::default() };
+        repack_dir_with_options(unpack_dir.to_str().unwrap(), repacked.to_str().unwrap(), &options).unwrap();
+        assert_eq!(layout(&repacked), [(0x800, 0x1000), (0x1800, 0x1800)]);
+        let repacked_dir = temp_dir.path().join("repacked");
+        unpack_file(repacked.to_str().unwrap(), repacked_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(repacked_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+    }
+
+    #[test]
+    fn test_find_strings() {
+        // UTF-8 字符被拆在两次读取之间，控制字符和不完整的字符会截断字符串
+        let first = b"\x00\x01version 1.2\x00ab\x00\xe7\x89".to_vec();
+        let mut second = b"\x88".to_vec();
+        second.extend_from_slice("\u{4ef6}\u{8bf4}\u{660e}\tok\x07".as_bytes());
+        second.extend_from_slice(b"\xc3(tail");
+        let mut found = Vec::new();
+        let read = find_strings(Cursor::new(first.clone()).chain(Cursor::new(second.clone())), 4, |offset, text| found.push((offset, text.to_string()))).unwrap();
+        assert_eq!(read, (first.len() + second.len()) as u64);
+        assert_eq!(found, [
+            (2, "version 1.2".to_string()),
+            (17, "\u{7248}\u{4ef6}\u{8bf4}\u{660e}\tok".to_string()),
+            (34, "(tail".to_string()),
+        ]);
+
+        let mut count = 0;
+        find_strings(Cursor::new(b"ab\x00cd".to_vec()), 2, |_, _| count += 1).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    // 使用 pack_rkaf 和 pack_rkfw 在 dir 下生成完整的 RKFW 镜像
+    fn build_rkfw_image(dir: &Path) -> PathBuf {
+        let input_dir = dir.join("input");
+        let rkfw_dir = dir.join("rkfw");
+        let image = dir.join("firmware.img");
+        create_pack_input(&input_dir);
+
+        fs::create_dir_all(&rkfw_dir).unwrap();
+        let mut boot = b"BOOT".to_vec();
+        boot.resize(300, 0x42);
+        fs::write(rkfw_dir.join("BOOT"), boot).unwrap();
+        pack_rkaf(
+            input_dir.to_str().unwrap(),
+            rkfw_dir.join("embedded-update.img").to_str().unwrap(),
+            "RK3562",
+            "RK3562",
+        ).unwrap();
+        pack_rkfw(rkfw_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3562", "1.0.0", 1762435994, "0x02000000").unwrap();
+        image
+    }
+
+    #[test]
+    fn test_rkcrc() {
+        assert_eq!(rkcrc(b""), 0);
+        assert_ne!(rkcrc(b"RKAF"), rkcrc(b"RKAG"));
+        // 与 rkflashtool 的 rkcrc（多项式 0x04c10db7，初值 0，不反转）结果一致
+        assert_eq!(rkcrc(b"123456789"), 0x889a9615);
+
+        // 文件中的任意区间
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        fs::write(&path, b"xxRKAFyy").unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(rkcrc_file(path, 0, None).unwrap(), rkcrc(b"xxRKAFyy"));
+        assert_eq!(rkcrc_file(path, 2, Some(4)).unwrap(), rkcrc(b"RKAF"));
+        assert_eq!(rkcrc_file(path, 6, None).unwrap(), rkcrc(b"yy"));
+        assert!(matches!(rkcrc_file(path, 6, Some(4)), Err(ApfError::Truncated(_))));
+        assert!(matches!(rkcrc_file(path, 9, None), Err(ApfError::Truncated(_))));
+
+        let check = temp_dir.path().join("check.bin");
+        fs::write(&check, b"123456789").unwrap();
+        assert_eq!(rkcrc_file(check.to_str().unwrap(), 0, None).unwrap(), 0x889a9615);
+    }
+
+    #[test]
+    fn test_update_crc() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 手工修改分区数据后 CRC 失效，update_crc 原地修复
+        let mut data = fs::read(&image).unwrap();
+        data[0x1000] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        assert!(!verify_rkaf_crc(image.to_str().unwrap()).unwrap());
+        let update = update_crc(image.to_str().unwrap()).unwrap();
+        assert_ne!(update.previous, update.crc);
+        assert_eq!(update.md5, None);
+        assert!(verify_rkaf_crc(image.to_str().unwrap()).unwrap());
+        assert_eq!(fs::read(&image).unwrap().len(), data.len());
+
+        // RKFW：修复内嵌镜像的 CRC 并重写末尾 MD5
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let mut data = fs::read(&rkfw).unwrap();
+        data[0x66 + 300 + 0x1000] ^= 0xff;
+        fs::write(&rkfw, &data).unwrap();
+        assert!(!verify_file(rkfw.to_str().unwrap()).unwrap().passed());
+        let update = update_crc(rkfw.to_str().unwrap()).unwrap();
+        assert!(update.md5.is_some());
+        assert!(verify_file(rkfw.to_str().unwrap()).unwrap().passed());
+
+        // 太短或未知格式的文件被拒绝
+        let short = temp_dir.path().join("short.img");
+        fs::write(&short, b"RKAF").unwrap();
+        assert!(matches!(update_crc(short.to_str().unwrap()), Err(ApfError::Truncated(_))));
+        fs::write(&short, b"ABCDxxxx").unwrap();
+        assert!(matches!(update_crc(short.to_str().unwrap()), Err(ApfError::UnknownSignature(_))));
+    }
+
+    #[test]
+    fn test_parse_parameter() {
+        let text = "FIRMWARE_VER: 1.0\nMACHINE_ID: 007\nCMDLINE: console=ttyFIQ0 mtdparts=rk29xxnand:0x00002000@0x00004000(uboot),0x00020000@0x00006000(boot:bootable),-@0x00026000(userdata:grow)\n";
+        let expected = vec![
+            ParameterPartition { name: "uboot".to_string(), start_sector: 0x4000, sector_count: Some(0x2000) },
+            ParameterPartition { name: "boot".to_string(), start_sector: 0x6000, sector_count: Some(0x20000) },
+            ParameterPartition { name: "userdata".to_string(), start_sector: 0x26000, sector_count: None },
+        ];
+        assert_eq!(parse_parameter(text.as_bytes()).unwrap(), expected);
+
+        // 镜像中的 parameter 带有 PARM 头和尾部 CRC
+        let mut wrapped = b"PARM".to_vec();
+        wrapped.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        wrapped.extend_from_slice(text.as_bytes());
+        wrapped.extend_from_slice(&rkcrc(text.as_bytes()).to_le_bytes());
+        assert_eq!(parse_parameter(&wrapped).unwrap(), expected);
+
+        assert!(parse_parameter(b"FIRMWARE_VER: 1.0\n").is_err());
+    }
+
+    #[test]
+    fn test_new_chip_codes() {
+        let temp_dir = TempDir::new().unwrap();
+        build_rkfw_image(temp_dir.path());
+        let rkfw_dir = temp_dir.path().join("rkfw");
+
+        for (chip, code) in [("RK3399", 0x90), ("RK3588", 0xa0), ("RK3588S", 0xa1), ("RK3576", 0xa2), ("RK3528", 0xa3)] {
+            let image = temp_dir.path().join(format!("{}.img", chip));
+            pack_rkfw(rkfw_dir.to_str().unwrap(), image.to_str().unwrap(), chip, "1.0.0", 1762435994, "0x02000000").unwrap();
+            match inspect_file(image.to_str().unwrap()).unwrap() {
+                UnpackResult::Rkfw(info) => {
+                    assert_eq!(info.chip_code, code);
+                    assert_eq!(info.chip_family, chip);
+                }
+                UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+            }
+        }
+
+        // 调用方可以注册自定义芯片代码
+        let mut data = fs::read(temp_dir.path().join("firmware.img")).unwrap();
+        data[0x15] = 0xee;
+        let mut options = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        options.chip_overrides.insert(0xee, "MyChip".to_string());
+        let out_dir = temp_dir.path().join("out");
+        match unpack_reader_with_options(Cursor::new(data), out_dir.to_str().unwrap(), &options).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!(info.chip_family, "MyChip"),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_rkfw_without_boot() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let data = fs::read(&image).unwrap();
+        // 改动头部后 MD5 不再匹配
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+
+        // BOOT 标记缺失时给出警告并跳过 BOOT
+        let mut bad_signature = data.clone();
+        bad_signature[0x66..0x6a].copy_from_slice(b"XXXX");
+        let out_dir = temp_dir.path().join("bad_signature");
+        match unpack_reader_with_options(Cursor::new(bad_signature), out_dir.to_str().unwrap(), &lenient).unwrap() {
+            UnpackResult::Rkfw(info) => assert!(!info.has_boot),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert!(!out_dir.join("BOOT").exists());
+        assert!(out_dir.join("embedded-update.img").exists());
+
+        // boot_size 为 0
+        let mut zero_size = data;
+        zero_size[0x1d..0x21].copy_from_slice(&[0; 4]);
+        let out_dir = temp_dir.path().join("zero_size");
+        match unpack_reader_with_options(Cursor::new(zero_size), out_dir.to_str().unwrap(), &lenient).unwrap() {
+            UnpackResult::Rkfw(info) => assert!(!info.has_boot),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert!(!out_dir.join("BOOT").exists());
+    }
+
+    #[test]
+    fn test_verify_rkafp_crc() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000])]);
+        let header = *UpdateHeader::from_bytes(&image[..std::mem::size_of::<UpdateHeader>()]);
+        verify_rkafp_crc(&image, &header).unwrap();
+
+        // 翻转一个字节后 CRC 不匹配
+        let mut corrupt = image.clone();
+        corrupt[0x1000] ^= 0xff;
+        let err = verify_rkafp_crc(&corrupt, &header).unwrap_err();
+        assert!(matches!(err, ApfError::CrcMismatch { actual, .. } if actual == rkcrc(&corrupt[..header.length as usize])));
+        assert!(matches!(verify_rkafp_crc(&image[..100], &header), Err(ApfError::Truncated(_))));
+
+        // 解包默认因 CRC 不匹配失败，allow_crc_mismatch 时只警告
+        let out_dir = temp_dir.path().join("output");
+        let err = unpack_reader(Cursor::new(corrupt.clone()), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::CrcMismatch { .. }));
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(corrupt), out_dir.to_str().unwrap(), &lenient).unwrap();
+        assert!(out_dir.join("Image/boot.img").exists());
+    }
+
+    #[test]
+    fn test_unpack_rkfw_crc_mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let strict = UnpackOptions { strict_crc: true, ..Default::default() };
+
+        let out_dir = temp_dir.path().join("good");
+        assert!(unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &strict).is_ok());
+
+        // 修改嵌入式更新映像中的一个字节
+        let mut data = fs::read(&image).unwrap();
+        data[0x66 + 300 + 0x1000] ^= 0xff;
+        let corrupted = temp_dir.path().join("corrupted.img");
+        fs::write(&corrupted, &data).unwrap();
+
+        let out_dir = temp_dir.path().join("bad");
+        let err = unpack_file_with_options(corrupted.to_str().unwrap(), out_dir.to_str().unwrap(), &strict).unwrap_err();
+        assert!(matches!(err, ApfError::CrcMismatch { .. }));
+        assert!(err.to_string().contains("CRC mismatch"));
+
+        // 默认同样报错，allow_crc_mismatch 时只输出警告
+        let err = unpack_file(corrupted.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::CrcMismatch { .. }));
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        assert!(unpack_file_with_options(corrupted.to_str().unwrap(), out_dir.to_str().unwrap(), &lenient).is_ok());
+    }
+
+    #[test]
+    fn test_unpack_rkfw_embedded_length_mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let embedded = fs::read(temp_dir.path().join("rkfw/embedded-update.img")).unwrap();
+        assert_eq!(u32::from_le_bytes(embedded[4..8].try_into().unwrap()) as usize + 4, embedded.len());
+
+        // 内嵌头部的 length 与 RKFW 的 update_size 不一致时只警告，仍按 update_size 提取
+        let mut data = fs::read(&image).unwrap();
+        let length_offset = 0x66 + 300 + 4;
+        data[length_offset..length_offset + 4].copy_from_slice(&0x100u32.to_le_bytes());
+        let mismatched = temp_dir.path().join("mismatched.img");
+        fs::write(&mismatched, &data).unwrap();
+        update_crc(mismatched.to_str().unwrap()).unwrap();
+        let out_dir = temp_dir.path().join("output");
+        assert!(unpack_file(mismatched.to_str().unwrap(), out_dir.to_str().unwrap()).is_ok());
+        assert_eq!(fs::read(out_dir.join("embedded-update.img")).unwrap().len(), embedded.len());
+    }
+
+    #[test]
+    fn test_unpack_rkfw_recursive() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let out_dir = temp_dir.path().join("output");
+        let repacked = temp_dir.path().join("repacked.img");
+
+        let options = UnpackOptions { recursive: true, ..Default::default() };
+        let result = unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert!(matches!(result, UnpackResult::Rkfw(_)));
+        // 内嵌 RKAF 的分区表随结果一并返回
+        let embedded = result.as_rkfw().unwrap().embedded.as_ref().unwrap();
+        assert!(embedded.partitions.iter().any(|part| part.name == "boot"));
+        assert!(out_dir.join("BOOT").exists());
+        assert!(!out_dir.join("embedded-update.img").exists());
+        let embedded_dir = out_dir.join("embedded-update");
+        assert_eq!(fs::read(embedded_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(embedded_dir.join("partition-metadata.txt").exists());
+        assert!(embedded_dir.join("manifest.json").exists());
+
+        // repack_dir 从子目录重建内嵌的 RKAF
+        let result = repack_dir(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert!(result.rkaf.is_some());
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+    }
+
+    #[test]
+    fn test_verify_rkfw_md5() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let data = fs::read(&image).unwrap();
+        verify_rkfw_md5(&data).unwrap();
+        verify_rkfw_md5_reader(&mut File::open(&image).unwrap()).unwrap();
+
+        // 翻转一个字节
+        let mut corrupt = data.clone();
+        corrupt[0x66 + 10] ^= 0xff;
+        let expected = String::from_utf8(data[data.len() - 32..].to_vec()).unwrap();
+        assert!(matches!(verify_rkfw_md5(&corrupt), Err(ApfError::Md5Mismatch { expected: stored, .. }) if stored == expected));
+
+        // 末尾没有 MD5，或文件被截断
+        let mut no_digest = data.clone();
+        let len = no_digest.len();
+        no_digest[len - 32..].fill(0);
+        assert!(matches!(verify_rkfw_md5(&no_digest), Err(ApfError::Invalid(_))));
+        assert!(matches!(verify_rkfw_md5(&data[..0x70]), Err(ApfError::Truncated(_))));
+    }
+
+    #[test]
+    fn test_unpack_rkfw_md5() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let out_dir = temp_dir.path().join("output");
+        let mut data = fs::read(&image).unwrap();
+        let digest = format!("{:x}", md5::compute(&data[..data.len() - 32]));
+
+        // pack_rkfw 写入的 MD5 与计算值一致
+        let info = RkfwInfo::try_from(unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(info.md5_expected.as_deref(), Some(digest.as_str()));
+        assert_eq!(info.md5_computed, info.md5_expected);
+
+        // 改动 BOOT 中的一个字节：默认报错，allow_crc_mismatch 时只警告
+        data[0x66 + 100] ^= 0xff;
+        let corrupt = temp_dir.path().join("corrupt.img");
+        fs::write(&corrupt, &data).unwrap();
+        let err = unpack_file(corrupt.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Md5Mismatch { expected, .. } if expected == digest));
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        let result = unpack_file_with_options(corrupt.to_str().unwrap(), out_dir.to_str().unwrap(), &lenient).unwrap();
+        let info = result.as_rkfw().unwrap();
+        assert_eq!(info.md5_expected.as_deref(), Some(digest.as_str()));
+        assert_ne!(info.md5_computed, info.md5_expected);
+    }
+
+    #[test]
+    fn test_unpack_rkfw_reserved_regions() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let data = fs::read(&image).unwrap();
+        let (header, rest) = data[..data.len() - 32].split_at(0x66);
+        let (boot, update) = rest.split_at(300);
+
+        // 在头部与 BOOT 之间插入 16 个非零字节，在 BOOT 与 update.img 之间插入 32 个零字节
+        let mut gapped = header.to_vec();
+        gapped[0x19..0x1d].copy_from_slice(&(0x66u32 + 16).to_le_bytes());
+        gapped[0x21..0x25].copy_from_slice(&(0x66u32 + 16 + 300 + 32).to_le_bytes());
+        gapped.extend_from_slice(&[0x11u8; 16]);
+        gapped.extend_from_slice(boot);
+        gapped.extend_from_slice(&[0u8; 32]);
+        gapped.extend_from_slice(update);
+        let digest = format!("{:x}", md5::compute(&gapped));
+        gapped.extend_from_slice(digest.as_bytes());
+        let gapped_path = temp_dir.path().join("gapped.img");
+        fs::write(&gapped_path, &gapped).unwrap();
+
+        let out_dir = temp_dir.path().join("output");
+        match unpack_file(gapped_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.pre_boot, Some(ReservedRegion { offset: 0x66, size: 16, zeroed: false }));
+                assert_eq!(info.boot_gap, Some(ReservedRegion { offset: 0x66 + 16 + 300, size: 32, zeroed: true }));
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert_eq!(fs::read(out_dir.join("reserved-pre-boot.bin")).unwrap(), vec![0x11u8; 16]);
+        assert!(!out_dir.join("reserved-gap.bin").exists());
+
+        // 重新打包时恢复两个保留区域
+        let repacked = temp_dir.path().join("repacked.img");
+        repack_dir(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), gapped);
+
+        // 没有间隙的镜像不记录保留区域
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!((info.pre_boot, info.boot_gap), (None, None)),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_rkfw_signature() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let data = fs::read(&image).unwrap();
+
+        // 在 update.img 与 MD5 之间插入 256 字节的签名
+        let mut signed = data[..data.len() - 32].to_vec();
+        let signature: Vec<u8> = (0..=255u8).collect();
+        signed.extend_from_slice(&signature);
+        let digest = format!("{:x}", md5::compute(&signed));
+        signed.extend_from_slice(digest.as_bytes());
+        let signed_path = temp_dir.path().join("signed.img");
+        fs::write(&signed_path, &signed).unwrap();
+
+        let out_dir = temp_dir.path().join("output");
+        match unpack_file(signed_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert!(info.signed);
+                assert_eq!(info.signature, Some(ReservedRegion { offset: (data.len() - 32) as u32, size: 256, zeroed: false }));
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert_eq!(fs::read(out_dir.join("signature.bin")).unwrap(), signature);
+        assert!(verify_file(signed_path.to_str().unwrap()).unwrap().passed());
+
+        // 重新打包时保留签名，结果逐字节一致
+        let repacked = temp_dir.path().join("repacked.img");
+        repack_dir(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), signed);
+
+        // 未签名的镜像
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!((info.signed, info.signature), (false, None)),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+    }
+
+    #[test]
+    fn test_dump_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let dump = dump_header(image.to_str().unwrap()).unwrap();
+        assert_eq!(dump.kind, ImageKind::Rkfw);
+        assert_eq!(dump.head.len(), 256);
+        let field = |dump: &afptool_rs::HeaderDump, name: &str| dump.fields.iter().find(|field| field.name == name).cloned().unwrap();
+
+        // RKFW 头部字段带偏移、原始字节和解析值，后面跟着嵌入的 RKAF 头部
+        let version = field(&dump, "version");
+        assert_eq!((version.offset, version.value.as_str()), (6, "1.0.0"));
+        assert_eq!(field(&dump, "layout").value, "0 (legacy layout)");
+        let update_offset = field(&dump, "update_offset");
+        assert_eq!(update_offset.raw, fs::read(&image).unwrap()[0x21..0x25].to_vec());
+        let offset = u32::from_le_bytes(update_offset.raw.clone().try_into().unwrap()) as u64;
+        let magic = field(&dump, "embedded.magic");
+        assert_eq!((magic.offset, magic.value.as_str()), (offset, "RKAF"));
+        assert!(dump.fields.iter().any(|field| field.name == "embedded.parts[0].part_offset"));
+        let json = serde_json::to_value(&dump).unwrap();
+        assert_eq!(json["fields"][0]["raw"], "524b4657");
+
+        // 截断的文件只报告缺失的字段，不做校验
+        let truncated = temp_dir.path().join("truncated.img");
+        fs::write(&truncated, &fs::read(&image).unwrap()[..0x1b]).unwrap();
+        let dump = dump_header(truncated.to_str().unwrap()).unwrap();
+        assert_eq!(field(&dump, "chip_code").value, format!("{:#04x} (RK3562)", chip_name_to_code("RK3562").unwrap()));
+        assert_eq!(field(&dump, "boot_offset").value, "(truncated)");
+        assert_eq!(field(&dump, "boot_offset").raw.len(), 2);
+
+        // 未知格式只有前 256 字节
+        let unknown = temp_dir.path().join("unknown.bin");
+        fs::write(&unknown, b"hello").unwrap();
+        let dump = dump_header(unknown.to_str().unwrap()).unwrap();
+        assert_eq!((dump.kind, dump.fields.len(), dump.head), (ImageKind::Unknown, 0, b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_unpack_rkfw_zeroed_date() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let mut data = fs::read(&image).unwrap();
+        let md5_offset = data.len() - 32;
+        data[0x0e..0x15].fill(0);
+        let digest = format!("{:x}", md5::compute(&data[..md5_offset]));
+        data[md5_offset..].copy_from_slice(digest.as_bytes());
+        let zeroed_path = temp_dir.path().join("zeroed.img");
+        fs::write(&zeroed_path, &data).unwrap();
+
+        // 全零日期只是警告：时间戳为 0，照常解包
+        let out_dir = temp_dir.path().join("output");
+        match unpack_file(zeroed_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!((info.timestamp, info.built_at.timestamp()), (0, 0)),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert!(out_dir.join("embedded-update.img").exists());
+
+        // 重新打包时写回全零日期
+        let repacked = temp_dir.path().join("repacked.img");
+        repack_dir(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), data);
+
+        // strict 下仍然返回 InvalidDate
+        let strict = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_file_with_options(zeroed_path.to_str().unwrap(), temp_dir.path().join("strict").to_str().unwrap(), &strict).unwrap_err();
+        assert!(matches!(err, ApfError::InvalidDate));
+    }
+
+    #[test]
+    fn test_verify_rkaf_crc() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 40000]).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert!(verify_rkaf_crc(image.to_str().unwrap()).unwrap());
+
+        let mut data = fs::read(&image).unwrap();
+        data[0x1000] ^= 0xff;
+        let corrupted = temp_dir.path().join("corrupted.img");
+        fs::write(&corrupted, &data).unwrap();
+        assert!(!verify_rkaf_crc(corrupted.to_str().unwrap()).unwrap());
+
+        // header.length 超出文件末尾时应返回错误而不是 panic
+        let truncated = temp_dir.path().join("truncated.img");
+        fs::write(&truncated, &data[..3000]).unwrap();
+        let err = verify_rkaf_crc(truncated.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("past the end"));
+
+        // 每个块之后报告进度，最后一次等于总长度
+        let calls = std::cell::RefCell::new(Vec::new());
+        let progress = |done: u64, total: u64| calls.borrow_mut().push((done, total));
+        let image_len = fs::metadata(&image).unwrap().len();
+        assert!(verify_rkaf_crc_with_progress(image.to_str().unwrap(), Some(&progress), None).unwrap());
+        let calls = calls.into_inner();
+        assert!(calls.len() > 1);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(*calls.last().unwrap(), (image_len - 4, image_len - 4));
+
+        // 设置取消标志后在下一个块之前停止
+        let cancel = std::sync::atomic::AtomicBool::new(false);
+        let chunks = std::cell::Cell::new(0);
+        let cancel_after_first = |_: u64, _: u64| {
+            chunks.set(chunks.get() + 1);
+            cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        };
+        let err = verify_rkaf_crc_with_progress(image.to_str().unwrap(), Some(&cancel_after_first), Some(&cancel)).unwrap_err();
+        assert!(matches!(err, ApfError::Cancelled));
+        assert_eq!(chunks.get(), 1);
+    }
+
+    #[test]
+    fn test_inspect_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.chip_family, "RK3562");
+                assert_eq!(info.boot_offset, 0x66);
+                assert_eq!(info.boot_size, 300);
+                assert_eq!(info.built_at.timestamp(), 1762435994);
+                // 0x02000000 按合并版本解析为 2.0.0
+                let code = info.firmware_code();
+                assert_eq!(code.raw(), 0x02000000);
+                assert_eq!((code.major(), code.minor(), code.build()), (2, 0, 0));
+                assert_eq!(code.to_string(), "2.0.0");
+                assert_eq!(info.built_at.to_rfc3339(), "2025-11-06T13:33:14+00:00");
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+
+        let update = temp_dir.path().join("rkfw/embedded-update.img");
+        match inspect_file(update.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => {
+                let names: Vec<&str> = info.partitions.iter().map(|p| p.name.as_str()).collect();
+                assert_eq!(names, ["parameter", "boot"]);
+                assert_eq!(info.partitions[1].part_byte_count, 5000);
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+
+        // 只解析元数据，不应写出任何文件
+        let entries = fs::read_dir(temp_dir.path()).unwrap().count();
+        assert_eq!(entries, 3);
+    }
+
+    #[test]
+    fn test_probe_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let update = temp_dir.path().join("rkfw/embedded-update.img");
+
+        // 把镜像单独放进空目录，探测后目录里仍然只有镜像本身
+        let probe_dir = TempDir::new().unwrap();
+        let rkfw = probe_dir.path().join("firmware.img");
+        let rkaf = probe_dir.path().join("update.img");
+        fs::copy(&image, &rkfw).unwrap();
+        fs::copy(&update, &rkaf).unwrap();
+
+        match probe_file(rkfw.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.chip_family, "RK3562");
+                assert_eq!((info.boot_offset, info.boot_size), (0x66, 300));
+                assert!(info.update_size > 0);
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        match probe_file(rkaf.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => {
+                let names: Vec<&str> = info.partitions.iter().map(|p| p.name.as_str()).collect();
+                assert_eq!(names, ["parameter", "boot"]);
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+
+        let mut entries: Vec<_> = fs::read_dir(probe_dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        entries.sort();
+        assert_eq!(entries, ["firmware.img", "update.img"]);
+    }
+
+    #[test]
+    fn test_rkfw_v6_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let mut data = fs::read(&image).unwrap();
+        data[0x05] = 6;
+        fs::write(&image, &data).unwrap();
+
+        assert_eq!(dump_header(image.to_str().unwrap()).unwrap().fields[2].value, "6 (v6 layout, not supported)");
+
+        // 尚不支持 v6 布局：默认只警告并按旧偏移读取，strict 下报错
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!((info.boot_offset, info.boot_size), (0x66, 300)),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        let out_dir = temp_dir.path().join("out");
+        match unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options) {
+            Err(ApfError::Invalid(message)) => assert!(message.contains("layout 6"), "{}", message),
+            other => panic!("expected the v6 layout to be refused, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_detect_sparse_partition() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        let sparse = build_sparse_image(4096, 4, &[(0xcac3, 4, Vec::new())]);
+        assert!(is_sparse(&sparse));
+        assert!(!is_sparse(&[0xa5u8; 28]));
+        fs::write(input_dir.join("Image/boot.img"), &sparse).unwrap();
+
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => {
+                assert!(!info.partitions[0].sparse);
+                assert!(info.partitions[1].sparse);
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+    }
+
+    #[test]
+    fn test_unsparse() {
+        let raw: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+        let sparse = build_sparse_image(4096, 4, &[
+            (0xcac1, 1, raw.clone()),
+            (0xcac2, 2, vec![0x11, 0x22, 0x33, 0x44]),
+            (0xcac3, 1, Vec::new()),
+            (0xcac4, 0, vec![0; 4]),
+        ]);
+
+        let mut out = Vec::new();
+        assert_eq!(unsparse(&sparse[..], &mut out).unwrap(), 4 * 4096);
+        assert_eq!(&out[..4096], &raw[..]);
+        assert!(out[4096..3 * 4096].chunks(4).all(|c| c == [0x11, 0x22, 0x33, 0x44]));
+        assert!(out[3 * 4096..].iter().all(|&b| b == 0));
+
+        // 解包时展开 sparse 分区
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/boot.img"), &sparse).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let options = UnpackOptions { expand_sparse: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), out);
+
+        assert!(unsparse(&sparse[..40], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_partition_checksums() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let options = UnpackOptions { checksums: Some(HashAlgorithm::Md5), ..Default::default() };
+        let info = match unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap() {
+            UnpackResult::Rkaf(info) => info,
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        };
+
+        let boot_md5 = format!("{:x}", md5::compute(vec![0xa5u8; 5000]));
+        let checksums = fs::read_to_string(out_dir.join("checksums.txt")).unwrap();
+        assert!(checksums.contains(&format!("{}  Image/boot.img\n", boot_md5)));
+
+        let mut file = File::open(&image).unwrap();
+        assert_eq!(hash_partition(&mut file, &info.partitions[1], HashAlgorithm::Md5).unwrap(), boot_md5);
+        assert_eq!(
+            hash_partition(&mut file, &info.partitions[1], "sha256".parse().unwrap()).unwrap().len(),
+            64
+        );
+    }
+
+    #[test]
+    fn test_unpack_only_selected_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 通配符匹配名称，且不区分大小写
+        let out_dir = temp_dir.path().join("by_name");
+        let options = UnpackOptions { only: vec!["*BOOT*".to_string()], ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert!(out_dir.join("Image/boot.img").exists());
+        assert!(!out_dir.join("Image/parameter.txt").exists());
+        // 元数据仍然包含全部分区
+        let metadata = fs::read_to_string(out_dir.join("partition-metadata.txt")).unwrap();
+        assert!(metadata.contains("parameter,Image/parameter.txt"));
+        assert!(metadata.contains("boot,Image/boot.img"));
+
+        // 也可以按完整路径匹配
+        let out_dir = temp_dir.path().join("by_path");
+        let options = UnpackOptions { only: vec!["image/param?ter.txt".to_string()], ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert!(out_dir.join("Image/parameter.txt").exists());
+        assert!(!out_dir.join("Image/boot.img").exists());
+    }
+
+    #[test]
+    fn test_unpack_path_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 映射的分区写到指定路径并自动创建父目录，其余分区保持默认路径
+        let out_dir = temp_dir.path().join("mapped");
+        let unpacker = Unpacker::new().map_path("boot", "out/boot/kernel.img");
+        unpacker.unpack(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(out_dir.join("out/boot/kernel.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(!out_dir.join("Image/boot.img").exists());
+        assert!(out_dir.join("Image/parameter.txt").exists());
+        // 元数据仍然记录原始路径
+        assert!(fs::read_to_string(out_dir.join("partition-metadata.txt")).unwrap().contains("boot,Image/boot.img"));
+
+        // --mapped-only 跳过未映射的分区
+        let out_dir = temp_dir.path().join("mapped_only");
+        let unpacker = Unpacker::new().map_path("boot", "kernel.img").mapped_only(true);
+        let UnpackResult::Rkaf(info) = unpacker.unpack(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap() else { panic!() };
+        assert!(out_dir.join("kernel.img").exists());
+        assert!(!out_dir.join("Image/parameter.txt").exists());
+        assert_eq!(info.stats.skipped_filtered as usize, info.partitions.len() - 1);
+
+        // 映射到另一个分区的路径时报错
+        let out_dir = temp_dir.path().join("collide");
+        let options = UnpackOptions { path_map: [("boot".to_string(), PathBuf::from("Image/parameter.txt"))].into(), ..Default::default() };
+        let err = unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("would both be extracted to Image/parameter.txt")));
+
+        // 不允许映射到输出目录之外
+        let options = UnpackOptions { path_map: [("boot".to_string(), PathBuf::from("../boot.img"))].into(), ..Default::default() };
+        let err = unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("escapes destination")));
+    }
+
+    #[test]
+    fn test_unpack_writes_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+
+        let manifest = read_manifest(&fs::read_to_string(out_dir.join("manifest.json")).unwrap()).unwrap();
+        let header = read_rkaf_header(image.to_str().unwrap()).unwrap();
+        assert_eq!(manifest.schema_version, MANIFEST_SCHEMA_VERSION);
+        assert_eq!(manifest.tool_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(manifest.model.trim(), "RK3326");
+        assert_eq!(manifest.length, { header.length });
+        assert_eq!(manifest.filesize, fs::metadata(&image).unwrap().len());
+        // 清单包含完整分区表（含 SELF/RESERVED 等特殊条目）
+        assert_eq!(manifest.partitions.len(), { header.num_parts } as usize);
+        let boot = manifest.partitions.iter().find(|p| p.path == "Image/boot.img").unwrap();
+        assert_eq!(boot.part_byte_count, 5000);
+
+        // 未知的 schema 版本应被拒绝
+        let mut value: serde_json::Value = serde_json::to_value(&manifest).unwrap();
+        value["schema_version"] = serde_json::json!(MANIFEST_SCHEMA_VERSION + 1);
+        assert!(matches!(read_manifest(&value.to_string()), Err(ApfError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_check_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert_eq!(check_metadata(out_dir.to_str().unwrap()).unwrap(), vec![]);
+
+        // 手工改大 boot 的数据长度：报告布局问题，不需要分区文件
+        fs::remove_file(out_dir.join("Image/boot.img")).unwrap();
+        let metadata_path = out_dir.join("partition-metadata.txt");
+        let edited: String = fs::read_to_string(&metadata_path).unwrap()
+            .lines()
+            .map(|line| {
+                let mut fields: Vec<String> = line.split(',').map(str::to_string).collect();
+                if fields[0] == "boot" {
+                    fields[6] = "0x00004000".to_string();
+                }
+                fields.join(",") + "\n"
+            })
+            .collect();
+        fs::write(&metadata_path, edited).unwrap();
+        let warnings = check_metadata(out_dir.to_str().unwrap()).unwrap();
+        assert!(warnings.iter().any(|warning| matches!(warning, LayoutWarning::PaddedSizeTooSmall { name, part_byte_count: 0x4000, .. } if name == "boot")), "{:?}", warnings);
+
+        // 缺少元数据是错误
+        fs::remove_file(&metadata_path).unwrap();
+        assert!(matches!(check_metadata(out_dir.to_str().unwrap()), Err(ApfError::Invalid(_))));
+        assert!(check_metadata(temp_dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_planned_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        let manifest = read_manifest(&fs::read_to_string(out_dir.join("manifest.json")).unwrap()).unwrap();
+
+        // 预计大小与重新打包后的文件大小一致
+        let planned = planned_size(&manifest);
+        pack_rkafp(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(planned, fs::metadata(&repacked).unwrap().len());
+        assert_eq!(planned, manifest.filesize);
+
+        // 最后一个分区的槽位超出记录的 length 时按槽位末尾计算
+        let mut grown = manifest.clone();
+        let last = grown.partitions.iter_mut().max_by_key(|part| part.part_offset).unwrap();
+        last.padded_size += 0x10000;
+        let end = last.part_offset as u64 + last.padded_size as u64;
+        assert_eq!(planned_size(&grown), end + 4);
+    }
+
+    #[test]
+    fn test_unpack_nonzero_padding() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 在 boot 分区数据之后的对齐填充中写入非零字节
+        let boot = list_partitions(image.to_str().unwrap()).unwrap()
+            .into_iter()
+            .find(|p| p.path == "Image/boot.img")
+            .unwrap();
+        assert!(boot.padded_size > boot.part_byte_count);
+        let pad_offset = (boot.part_offset + boot.part_byte_count) as usize;
+        let mut data = fs::read(&image).unwrap();
+        data[pad_offset..pad_offset + 4].copy_from_slice(b"PAD!");
+        fs::write(&image, &data).unwrap();
+        update_crc(image.to_str().unwrap()).unwrap();
+        let data = fs::read(&image).unwrap();
+
+        // 默认只警告
+        let out_dir = temp_dir.path().join("default");
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert!(!out_dir.join("Image/boot.img.padding").exists());
+
+        let out_dir = temp_dir.path().join("strict");
+        let options = UnpackOptions { strict_padding: true, ..Default::default() };
+        let err = unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(_)), "unexpected error: {:?}", err);
+
+        let out_dir = temp_dir.path().join("capture");
+        let options = UnpackOptions { capture_padding: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        let padding = fs::read(out_dir.join("Image/boot.img.padding")).unwrap();
+        assert_eq!(&padding[..4], b"PAD!");
+        assert_eq!(padding.len() as u32, boot.padded_size - boot.part_byte_count);
+        // 全零填充的分区不生成 .padding 文件
+        assert!(!out_dir.join("Image/parameter.txt.padding").exists());
+
+        // 重新打包时恢复填充内容（CRC 因篡改而不同，只比较 CRC 之前的部分）
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkafp(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        let repacked = fs::read(&repacked).unwrap();
+        assert_eq!(repacked.len(), data.len());
+        assert_eq!(&repacked[..data.len() - 4], &data[..data.len() - 4]);
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+
+        let report = verify_file(image.to_str().unwrap()).unwrap();
+        assert!(report.passed(), "{:?}", report);
+        let names: Vec<&str> = report.checks.iter().map(|check| check.name.as_str()).collect();
+        assert!(names.contains(&"MD5"));
+        assert!(names.contains(&"embedded RKAF signature"));
+        assert!(names.contains(&"embedded CRC"));
+        assert!(names.contains(&"embedded partition boot"));
+
+        // 篡改内嵌镜像中的分区数据：MD5 与内嵌 CRC 都应失败
+        let boot = list_partitions(image.to_str().unwrap()).unwrap()
+            .into_iter()
+            .find(|p| p.path == "Image/boot.img")
+            .unwrap();
+        let mut data = fs::read(&image).unwrap();
+        data[boot.part_offset as usize] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        let report = verify_file(image.to_str().unwrap()).unwrap();
+        assert!(!report.passed());
+        let failed: Vec<&str> = report.checks.iter().filter(|check| !check.passed).map(|check| check.name.as_str()).collect();
+        assert_eq!(failed, vec!["MD5", "embedded CRC"]);
+
+        // 未知签名只记录为失败检查，不返回错误
+        let bogus = temp_dir.path().join("bogus.img");
+        fs::write(&bogus, b"ABCD1234").unwrap();
+        let report = verify_file(bogus.to_str().unwrap()).unwrap();
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn test_replace_partition() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let image_path = image.to_str().unwrap();
+        let boot_part = |image_path: &str| list_partitions(image_path).unwrap().into_iter().find(|part| part.name == "boot").unwrap();
+        let before = boot_part(image_path);
+        assert_eq!((before.part_byte_count, before.padded_size), (5000, 6144));
+
+        // 新数据写入原位置，剩余的填充区清零，CRC 和 MD5 同时更新
+        let new_boot = temp_dir.path().join("new-boot.img");
+        fs::write(&new_boot, vec![0x5au8; 6000]).unwrap();
+        let result = replace_partition(image_path, "boot", new_boot.to_str().unwrap()).unwrap();
+        assert_eq!((result.name.as_str(), result.previous_size, result.size, result.padded_size), ("boot", 5000, 6000, 6144));
+        assert!(result.crc.md5.is_some());
+        assert!(verify_file(image_path).unwrap().passed());
+        let after = boot_part(image_path);
+        assert_eq!((after.part_offset, after.part_byte_count), (before.part_offset, 6000));
+        let mut data = Vec::new();
+        after.extract_to_writer(&mut fs::File::open(&image).unwrap(), &mut data).unwrap();
+        assert_eq!(data, vec![0x5au8; 6000]);
+
+        fs::write(&new_boot, b"tiny").unwrap();
+        replace_partition(image_path, "Image/boot.img", new_boot.to_str().unwrap()).unwrap();
+        let raw = fs::read(&image).unwrap();
+        let start = before.part_offset as usize;
+        assert_eq!(&raw[start..start + 4], b"tiny");
+        assert!(raw[start + 4..start + 6144].iter().all(|&b| b == 0));
+        assert!(verify_file(image_path).unwrap().passed());
+
+        // 超出 padded_size 时报错且不改动镜像
+        fs::write(&new_boot, vec![1u8; 6145]).unwrap();
+        let err = replace_partition(image_path, "boot", new_boot.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("unpack and repack")), "{}", err);
+        assert_eq!(fs::read(&image).unwrap(), raw);
+        assert!(matches!(replace_partition(image_path, "vendor", new_boot.to_str().unwrap()), Err(ApfError::Invalid(_))));
+
+        // 独立的 RKAF 镜像
+        let rkaf = temp_dir.path().join("update.img");
+        fs::write(&rkaf, build_synthetic_rkaf(&[("kernel", b"old-kernel"), ("misc", b"misc")])).unwrap();
+        fs::write(&new_boot, b"new-kernel-build").unwrap();
+        let result = replace_partition(rkaf.to_str().unwrap(), "kernel", new_boot.to_str().unwrap()).unwrap();
+        assert_eq!(result.crc.md5, None);
+        assert!(verify_rkaf_crc(rkaf.to_str().unwrap()).unwrap());
+        let UnpackResult::Rkaf(info) = unpack_file(rkaf.to_str().unwrap(), temp_dir.path().join("out").to_str().unwrap()).unwrap() else {
+            panic!("expected an RKAF result");
+        };
+        assert_eq!(info.partitions[0].part_byte_count, 16);
+        assert_eq!(fs::read(temp_dir.path().join("out/Image/kernel.img")).unwrap(), b"new-kernel-build");
+        assert_eq!(fs::read(temp_dir.path().join("out/Image/misc.img")).unwrap(), b"misc");
+    }
+
+    #[test]
+    fn test_embedded_rkaf_magic() {
+        assert!(UpdateHeader::has_rkaf_magic(b"RKAF\x00\x10"));
+        assert!(UpdateHeader::has_rkaf_magic(UpdateHeader::from_bytes(&build_synthetic_rkaf(&[("boot", b"boot")])).to_bytes()));
+        assert!(!UpdateHeader::has_rkaf_magic(b"RKA"));
+        assert!(!UpdateHeader::has_rkaf_magic(b"RKFW"));
+
+        // 内嵌镜像的 magic 不对时报告实际读到的字节
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let UnpackResult::Rkfw(info) = inspect_file(image.to_str().unwrap()).unwrap() else {
+            panic!("expected an RKFW result");
+        };
+        let mut data = fs::read(&image).unwrap();
+        data[info.update_offset as usize + 3] = b'X';
+        fs::write(&image, &data).unwrap();
+        assert!(matches!(inspect_file(image.to_str().unwrap()), Err(ApfError::BadMagic)));
+        let err = unpack_file(image.to_str().unwrap(), temp_dir.path().join("out").to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("found \"RKAX\" instead of the RKAF magic"), "{}", err);
+        let report = verify_file(image.to_str().unwrap()).unwrap();
+        assert!(report.checks.iter().any(|check| check.name == "embedded RKAF signature" && !check.passed));
+    }
+
+    #[test]
+    fn test_unpack_result_accessors() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let rkaf = temp_dir.path().join("rkfw/embedded-update.img");
+
+        let result = inspect_file(rkfw.to_str().unwrap()).unwrap();
+        assert_eq!(result.kind(), ImageKind::Rkfw);
+        assert_eq!(result.as_rkfw().unwrap().chip_family, "RK3562");
+        assert!(result.as_rkaf().is_none());
+        assert!(matches!(RkafInfo::try_from(result.clone()), Err(ApfError::Invalid(_))));
+        let info = RkfwInfo::try_from(result).unwrap();
+        assert_eq!(UnpackResult::from(info).kind(), ImageKind::Rkfw);
+
+        let result = inspect_file(rkaf.to_str().unwrap()).unwrap();
+        assert_eq!(result.kind(), ImageKind::Rkaf);
+        assert!(result.as_rkfw().is_none());
+        let partitions = result.as_rkaf().unwrap().partitions.len();
+        assert!(matches!(RkfwInfo::try_from(result.clone()), Err(ApfError::Invalid(_))));
+        let info: RkafInfo = result.try_into().unwrap();
+        assert_eq!(info.partitions.len(), partitions);
+        assert_eq!(UnpackResult::from(info).kind(), ImageKind::Rkaf);
+    }
+
+    #[test]
+    fn test_embedded_rkaf_leading_padding() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let path = image.to_str().unwrap();
+        let UnpackResult::Rkfw(original) = inspect_file(path).unwrap() else { panic!("expected an RKFW result") };
+        let embedded = fs::read(temp_dir.path().join("rkfw/embedded-update.img")).unwrap();
+        let data = fs::read(&image).unwrap();
+        let shift_update_offset = |skip: u32| {
+            let mut shifted = data.clone();
+            shifted[0x21..0x25].copy_from_slice(&(original.update_offset - skip).to_le_bytes());
+            shifted[0x25..0x29].copy_from_slice(&(original.update_size + skip).to_le_bytes());
+            fs::write(&image, &shifted).unwrap();
+        };
+
+        // 头部的 update_offset 比 RKAF magic 早几个字节：跳过这些字节并记录下来
+        shift_update_offset(8);
+        update_crc(path).unwrap();
+        let UnpackResult::Rkfw(info) = inspect_file(path).unwrap() else { panic!("expected an RKFW result") };
+        assert_eq!((info.update_offset, info.update_size, info.update_skipped), (original.update_offset, original.update_size, 8));
+        assert!(verify_file(path).unwrap().checks.iter().all(|check| check.passed));
+        let output_dir = temp_dir.path().join("out");
+        let result = unpack_file(path, output_dir.to_str().unwrap()).unwrap();
+        let UnpackResult::Rkfw(info) = result else { panic!("expected an RKFW result") };
+        assert_eq!(info.update_skipped, 8);
+        assert_eq!(fs::read(output_dir.join("embedded-update.img")).unwrap(), embedded);
+        let list = list_partitions(path).unwrap();
+        assert_eq!(list.iter().find(|part| part.path == "embedded-update.img").unwrap().part_offset, original.update_offset);
+
+        // --strict 下偏移不准确是错误
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_file_with_options(path, temp_dir.path().join("strict").to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("starts 8 bytes after the update offset"), "{}", err);
+
+        // 窗口内找不到 magic 时仍然报错
+        shift_update_offset(EMBEDDED_MAGIC_WINDOW + 4);
+        let err = unpack_file(path, temp_dir.path().join("far").to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("or in the 16 bytes after it"), "{}", err);
+    }
+
+    #[test]
+    fn test_verify_file_cached() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let path = image.to_str().unwrap();
+        let cache = temp_dir.path().join("cache/verify.json");
+        let set_mtime = |secs_ago: u64| {
+            let mtime = std::time::SystemTime::now() - std::time::Duration::from_secs(secs_ago);
+            fs::File::options().write(true).open(&image).unwrap().set_modified(mtime).unwrap();
+            mtime
+        };
+
+        // 刚修改过的文件不写缓存
+        assert!(verify_file_cached(path, &cache).unwrap().passed());
+        assert!(!cache.exists());
+        let mtime = set_mtime(60);
+        assert!(verify_file_cached(path, &cache).unwrap().passed());
+        assert!(cache.exists());
+
+        // 大小和修改时间不变时直接返回缓存结果，哪怕内容已被改动
+        let mut data = fs::read(&image).unwrap();
+        data[0x200] ^= 0xff;
+        fs::write(&image, &data).unwrap();
+        fs::File::options().write(true).open(&image).unwrap().set_modified(mtime).unwrap();
+        assert!(verify_file_cached(path, &cache).unwrap().passed());
+
+        // 修改时间变化后重新校验并更新缓存
+        set_mtime(30);
+        let report = verify_file_cached(path, &cache).unwrap();
+        assert!(!report.passed());
+        assert!(!verify_file_cached(path, &cache).unwrap().passed());
+        assert_eq!(verify_file_cached(path, &cache).unwrap().checks, report.checks);
+
+        // 损坏的缓存文件被忽略
+        fs::write(&cache, b"{not json").unwrap();
+        assert!(!verify_file_cached(path, &cache).unwrap().passed());
+        assert!(serde_json::from_slice::<serde_json::Value>(&fs::read(&cache).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_unpack_output_naming() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let parts = list_partitions(image.to_str().unwrap()).unwrap();
+        let boot = parts.iter().find(|p| p.name == "boot").unwrap();
+
+        let out_dir = temp_dir.path().join("by_name");
+        let options = UnpackOptions { naming: OutputNaming::ByName, checksums: Some(HashAlgorithm::Md5), ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(out_dir.join("parameter.img").exists());
+        assert!(!out_dir.join("Image").exists());
+        // checksums.txt 记录实际写出的文件名，元数据仍记录镜像中的路径
+        assert!(fs::read_to_string(out_dir.join("checksums.txt")).unwrap().contains("  boot.img\n"));
+        assert!(fs::read_to_string(out_dir.join("partition-metadata.txt")).unwrap().contains("boot,Image/boot.img"));
+
+        // 按 flash 偏移排序编号
+        let out_dir = temp_dir.path().join("by_offset");
+        let options = UnpackOptions { naming: "by-flash-offset".parse().unwrap(), ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert!(out_dir.join("00_parameter_0x00000000.img").exists());
+        assert_eq!(
+            fs::read(out_dir.join(format!("01_boot_{:#010x}.img", boot.flash_offset))).unwrap(),
+            vec![0xa5u8; 5000]
+        );
+        assert!("by-bogus".parse::<OutputNaming>().is_err());
+    }
+
+    #[test]
+    fn test_unpack_flat() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let flat_dir = temp_dir.path().join("flat");
+        let repacked = temp_dir.path().join("repacked.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let options = UnpackOptions { naming: OutputNaming::Flat, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), flat_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(flat_dir.join("boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(flat_dir.join("parameter.txt").exists());
+        assert!(!flat_dir.join("Image").exists());
+        assert!(fs::read_to_string(flat_dir.join("partition-metadata.txt")).unwrap().contains("boot,Image/boot.img"));
+        // 元数据保留原始路径，可以直接从平铺目录重新打包
+        pack_rkafp(flat_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), fs::read(&repacked).unwrap());
+
+        // 两个分区的文件名相同时报错
+        fs::create_dir_all(input_dir.join("Other")).unwrap();
+        fs::write(input_dir.join("Other/boot.img"), vec![0x11u8; 100]).unwrap();
+        fs::write(input_dir.join("package-file"), "boot Image/boot.img\nrecovery Other/boot.img\n").unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00001800,0x00000000\n\
+             recovery,Other/boot.img,0x00010000,0x00012000,0x00000000,0x00000200,0x00000000\n",
+        ).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let err = unpack_file_with_options(image.to_str().unwrap(), temp_dir.path().join("collide").to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("both flatten to boot.img")));
+        assert!(!temp_dir.path().join("collide").exists());
+    }
+
+    #[test]
+    fn test_unpack_resume() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        let parameter = fs::read(out_dir.join("Image/parameter.txt")).unwrap();
+        let damaged = vec![0xffu8; parameter.len()];
+
+        // 模拟中断：boot.img 只写了一部分，parameter.txt 大小正确但内容不同
+        fs::write(out_dir.join("Image/boot.img"), vec![0xa5u8; 1000]).unwrap();
+        fs::write(out_dir.join("Image/parameter.txt"), &damaged).unwrap();
+        let options = UnpackOptions { resume: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert_eq!(fs::read(out_dir.join("Image/parameter.txt")).unwrap(), damaged);
+
+        // 校验哈希后重新解包内容不同的文件
+        let options = UnpackOptions { resume: true, resume_verify: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/parameter.txt")).unwrap(), parameter);
+
+        // force 覆盖 resume
+        fs::write(out_dir.join("Image/parameter.txt"), &damaged).unwrap();
+        let options = UnpackOptions { resume: true, force: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/parameter.txt")).unwrap(), parameter);
+    }
+
+    #[test]
+    fn test_unpack_pad_to_flash_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut image = build_synthetic_rkaf(&[("boot", b"boot-data"), ("userdata", b"userdata"), ("misc", b"misc")]);
+        // userdata 扩展到 flash 末尾，misc 的数据比分配的空间还大
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image[..header_size]);
+        header.parts[1].flash_size = 0xffff_ffff;
+        header.parts[2].flash_size = 0;
+        image[..header_size].copy_from_slice(header.to_bytes());
+        let crc_at = image.len() - 4;
+        let crc = rkcrc(&image[..crc_at]);
+        image[crc_at..].copy_from_slice(&crc.to_le_bytes());
+        let image_path = temp_dir.path().join("update.img");
+        fs::write(&image_path, &image).unwrap();
+
+        let flash_bytes = 0x2000 * 512;
+        for jobs in [1, 2] {
+            let dst = temp_dir.path().join(format!("out-{}", jobs));
+            let options = UnpackOptions { pad_to_flash_size: true, parallelism: jobs, checksums: Some(HashAlgorithm::Sha256), ..Default::default() };
+            unpack_file_with_options(image_path.to_str().unwrap(), dst.to_str().unwrap(), &options).unwrap();
+            let boot = fs::read(dst.join("Image/boot.img")).unwrap();
+            assert_eq!(boot.len(), flash_bytes);
+            assert_eq!(&boot[..9], b"boot-data");
+            assert!(boot[9..].iter().all(|&b| b == 0));
+            assert_eq!(fs::read(dst.join("Image/userdata.img")).unwrap(), b"userdata");
+            assert_eq!(fs::read(dst.join("Image/misc.img")).unwrap(), b"misc");
+            // checksums.txt 对应填充后的文件
+            let results = verify_against_manifest(dst.to_str().unwrap(), dst.join("checksums.txt").to_str().unwrap()).unwrap();
+            assert!(results.iter().all(|(_, matched)| *matched), "{:?}", results);
+        }
+
+        // 续传时把填充后的大小当作已完成
+        let dst = temp_dir.path().join("out-1");
+        fs::write(dst.join("Image/boot.img"), b"boot-data").unwrap();
+        let options = UnpackOptions { pad_to_flash_size: true, resume: true, resume_verify: true, ..Default::default() };
+        unpack_file_with_options(image_path.to_str().unwrap(), dst.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::metadata(dst.join("Image/boot.img")).unwrap().len(), flash_bytes as u64);
+        let mut boot = fs::read(dst.join("Image/boot.img")).unwrap();
+        boot[100] = 1;
+        fs::write(dst.join("Image/boot.img"), &boot).unwrap();
+        unpack_file_with_options(image_path.to_str().unwrap(), dst.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(dst.join("Image/boot.img")).unwrap()[100], 1);
+    }
+
+    #[test]
+    fn test_num_parts_exceeds_capacity() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // num_parts 位于头部偏移 0x88
+        let mut data = fs::read(&image).unwrap();
+        data[0x88..0x8c].copy_from_slice(&0xffffu32.to_le_bytes());
+        fs::write(&image, &data).unwrap();
+
+        let out_dir = temp_dir.path().join("out");
+        match unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()) {
+            Err(ApfError::Invalid(msg)) => assert!(msg.contains("num_parts 65535 exceeds max 16"), "{}", msg),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!out_dir.exists());
+        assert!(matches!(inspect_file(image.to_str().unwrap()), Err(ApfError::Invalid(_))));
+        let report = verify_file(image.to_str().unwrap()).unwrap();
+        assert!(report.checks.iter().any(|check| check.name == "partition count" && !check.passed));
+    }
+
+    #[test]
+    fn test_repack_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let out_dir = temp_dir.path().join("out");
+
+        // 只解包 RKFW：直接重新封装 embedded-update.img，结果应与原文件一致
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert!(out_dir.join("rkfw-info.json").exists());
+        let repacked = temp_dir.path().join("repacked.img");
+        let result = repack_dir(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert!(result.rkfw.is_some() && result.rkaf.is_none());
+        assert!(result.report.passed());
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&image).unwrap());
+
+        // 将内嵌镜像解包到同一目录并修改分区后，重建 RKAF 并重新封装为 RKFW
+        unpack_file(out_dir.join("embedded-update.img").to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        fs::write(out_dir.join("Image/boot.img"), vec![0x5au8; 3000]).unwrap();
+        let options = PackOptions { allow_resized: true, ..Default::default() };
+        let result = repack_dir_with_options(out_dir.to_str().unwrap(), repacked.to_str().unwrap(), &options).unwrap();
+        assert!(result.rkfw.is_some() && result.rkaf.is_some());
+        assert!(!temp_dir.path().join("repacked.img.rkaf.tmp").exists());
+        match inspect_file(repacked.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => assert_eq!(info.chip_family, "RK3562"),
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        let boot = list_partitions(repacked.to_str().unwrap()).unwrap()
+            .into_iter()
+            .find(|p| p.path == "Image/boot.img")
+            .unwrap();
+        assert_eq!(boot.part_byte_count, 3000);
+
+        // 没有任何元数据的目录无法重新打包
+        let empty = temp_dir.path().join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        assert!(matches!(repack_dir(empty.to_str().unwrap(), repacked.to_str().unwrap()), Err(ApfError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_unpack_compressed_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let data = fs::read(&image).unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&data).unwrap();
+        let gzip_path = temp_dir.path().join("firmware.img.gz");
+        fs::write(&gzip_path, gzip.finish().unwrap()).unwrap();
+        let zstd_path = temp_dir.path().join("firmware.img.zst");
+        fs::write(&zstd_path, zstd::encode_all(&data[..], 3).unwrap()).unwrap();
+
+        // gzip 与 zstd 压缩的镜像都应自动解压
+        for (path, name) in [(&gzip_path, "gzip"), (&zstd_path, "zstd")] {
+            let out_dir = temp_dir.path().join(name);
+            let result = unpack_file(path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+            assert!(matches!(result, UnpackResult::Rkfw(_)));
+            assert!(out_dir.join("embedded-update.img").exists());
+        }
+
+        // 关闭自动解压后按未知签名处理
+        let options = UnpackOptions { no_decompress: true, ..Default::default() };
+        let out_dir = temp_dir.path().join("raw");
+        let err = unpack_file_with_options(gzip_path.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::UnknownSignature(_)));
+    }
+
+    #[test]
+    fn test_unpack_max_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let filesize = fs::metadata(&image).unwrap().len();
+
+        let out_dir = temp_dir.path().join("too_big");
+        let options = UnpackOptions { max_size: Some(filesize - 1), ..Default::default() };
+        let err = unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("maximum size")));
+        assert!(!out_dir.exists());
+
+        // header.length 声明的大小超过限制时，在提取前报错
+        let mut data = fs::read(&image).unwrap();
+        data[4..8].copy_from_slice(&0x7fff_0000u32.to_le_bytes());
+        let bad_length = temp_dir.path().join("bad_length.img");
+        fs::write(&bad_length, &data).unwrap();
+        let options = UnpackOptions { max_size: Some(filesize), ..Default::default() };
+        let err = unpack_file_with_options(bad_length.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("Declared image length"));
+        assert!(!out_dir.exists());
+
+        // 解压后超过限制的 gzip 输入
+        let compressed = temp_dir.path().join("update.img.gz");
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&compressed).unwrap(), flate2::Compression::default());
+        encoder.write_all(&fs::read(&image).unwrap()).unwrap();
+        encoder.finish().unwrap();
+        let options = UnpackOptions { max_size: Some(4096), ..Default::default() };
+        let err = unpack_file_with_options(compressed.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("Decompressed Gzip image exceeds"));
+
+        let options = UnpackOptions { max_size: Some(filesize), ..Default::default() };
+        assert!(unpack_file_with_options(compressed.to_str().unwrap(), out_dir.to_str().unwrap(), &options).is_ok());
+    }
+
+    #[test]
+    fn test_metadata_formats_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let legacy_dir = temp_dir.path().join("legacy");
+        let legacy_image = temp_dir.path().join("legacy.img");
+        unpack_file(image.to_str().unwrap(), legacy_dir.to_str().unwrap()).unwrap();
+        pack_rkafp(legacy_dir.to_str().unwrap(), legacy_image.to_str().unwrap()).unwrap();
+
+        for (format, file_name) in [
+            (MetadataFormat::Csv, "partition-metadata.csv"),
+            (MetadataFormat::Tsv, "partition-metadata.tsv"),
+            (MetadataFormat::Json, "partition-metadata.jsonl"),
+        ] {
+            let out_dir = temp_dir.path().join(file_name);
+            let options = UnpackOptions { metadata_format: format, ..Default::default() };
+            unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+            assert!(out_dir.join(file_name).exists());
+            assert!(!out_dir.join("partition-metadata.txt").exists());
+
+            // 任一格式重新打包的结果都应与传统格式一致
+            let repacked = temp_dir.path().join(format!("{}.img", file_name));
+            pack_rkafp(out_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+            assert_eq!(fs::read(&repacked).unwrap(), fs::read(&legacy_image).unwrap());
+        }
+
+        let csv = fs::read_to_string(temp_dir.path().join("partition-metadata.csv/partition-metadata.csv")).unwrap();
+        assert!(csv.starts_with("name,path,flash_size,flash_offset,part_offset,padded_size,part_byte_count,skip_extract\n"));
+        assert!(csv.contains("boot,Image/boot.img,"));
+        let json = fs::read_to_string(temp_dir.path().join("partition-metadata.jsonl/partition-metadata.jsonl")).unwrap();
+        let first: serde_json::Value = serde_json::from_str(json.lines().next().unwrap()).unwrap();
+        assert!(first["part_byte_count"].is_u64());
+
+        // 以另一种格式重新解包到同一目录时，旧的元数据文件会被移除
+        let out_dir = temp_dir.path().join("partition-metadata.csv");
+        unpack_file(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert!(out_dir.join("partition-metadata.txt").exists());
+        assert!(!out_dir.join("partition-metadata.csv").exists());
+        assert!("yaml".parse::<MetadataFormat>().is_err());
+    }
+
+    #[test]
+    fn test_decode_header_string() {
+        let gbk = b"\xc8\xf0\xd0\xbe\xce\xa2\0\0";
+        assert_eq!(decode_header_string(gbk, TextEncoding::Auto).unwrap(), "瑞芯微");
+        assert_eq!(decode_header_string(gbk, TextEncoding::Gbk).unwrap(), "瑞芯微");
+        assert!(decode_header_string(gbk, TextEncoding::Utf8).unwrap().contains('\u{fffd}'));
+        assert_eq!(decode_header_string("瑞芯微\0".as_bytes(), TextEncoding::Auto).unwrap(), "瑞芯微");
+        let utf16 = b"R\0o\0c\0k\0c\0h\0i\0p\0\0\0\0\0";
+        assert_eq!(decode_header_string(utf16, TextEncoding::Auto).unwrap(), "Rockchip");
+        assert_eq!(decode_header_string(utf16, TextEncoding::Utf8).unwrap(), "R");
+        // 没有 NUL 结尾的字段
+        assert_eq!(decode_header_string(b"RK3326", TextEncoding::Auto), None);
+        assert!("latin1".parse::<TextEncoding>().is_err());
+
+        // 镜像头中的 GBK 型号被正确解码，并保留原始字节
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let mut data = fs::read(&image).unwrap();
+        data[8..8 + 34].fill(0);
+        data[8..8 + 6].copy_from_slice(&gbk[..6]);
+        fs::write(&image, &data).unwrap();
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => {
+                assert_eq!(info.model, "瑞芯微");
+                assert_eq!(&info.model_raw[..6], &gbk[..6]);
+                assert_eq!(info.model_raw.len(), 34);
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+    }
+
+    #[test]
+    fn test_peek_signature() {
+        assert_eq!(peek_signature(b"RKFW\x66\0\0\0"), Some(ImageKind::Rkfw));
+        assert_eq!(peek_signature(b"RKAF"), Some(ImageKind::Rkaf));
+        assert_eq!(peek_signature(&[0x1f, 0x8b]), Some(ImageKind::Gzip));
+        assert_eq!(peek_signature(&[0x28, 0xb5, 0x2f, 0xfd, 0, 0, 0, 0]), Some(ImageKind::Zstd));
+        assert_eq!(peek_signature(&build_sparse_image(4096, 1, &[])[..8]), Some(ImageKind::Sparse));
+        assert_eq!(peek_signature(b"ABCDxxxx"), Some(ImageKind::Unknown));
+        assert_eq!(peek_signature(b"RK"), None);
+
+        // 不足 4 字节的文件返回 Truncated 而不是 panic
+        let temp_dir = TempDir::new().unwrap();
+        let tiny = temp_dir.path().join("tiny.img");
+        fs::write(&tiny, b"RK").unwrap();
+        let out_dir = temp_dir.path().join("output");
+        assert!(matches!(unpack_file(tiny.to_str().unwrap(), out_dir.to_str().unwrap()), Err(ApfError::Truncated(_))));
+        assert!(matches!(inspect_file(tiny.to_str().unwrap()), Err(ApfError::Truncated(_))));
+    }
+
+    #[test]
+    fn test_tiny_inputs() {
+        // 0、1、3 字节的文件（空文件或截断的下载）在所有入口都返回错误而不是 panic
+        let temp_dir = TempDir::new().unwrap();
+        let out_dir = temp_dir.path().join("output");
+        for data in [&b""[..], &b"R"[..], &b"RKF"[..], &[0x1f][..]] {
+            let tiny = temp_dir.path().join("tiny.img");
+            fs::write(&tiny, data).unwrap();
+            let path = tiny.to_str().unwrap();
+            assert!(matches!(unpack_file(path, out_dir.to_str().unwrap()), Err(ApfError::Truncated(_))));
+            assert!(matches!(unpack_reader(Cursor::new(data.to_vec()), out_dir.to_str().unwrap()), Err(ApfError::Truncated(_))));
+            assert!(matches!(inspect_file(path), Err(ApfError::Truncated(_))));
+            assert!(matches!(list_partitions(path), Err(ApfError::Truncated(_))));
+            assert!(read_rkaf_header(path).is_err());
+            assert!(verify_rkaf_crc(path).is_err());
+            assert!(!verify_file(path).unwrap().passed());
+            assert!(update_crc(path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_rkaf_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let header = read_rkaf_header(image.to_str().unwrap()).unwrap();
+        let length = header.length;
+        let num_parts = header.num_parts;
+        assert_eq!(length as u64 + 4, fs::metadata(&image).unwrap().len());
+        assert_eq!(num_parts, 2);
+        assert_eq!(&header.id[..4], b" 007");
+        let boot = header.parts[1];
+        let (part_offset, padded_size, part_byte_count) = (boot.part_offset, boot.padded_size, boot.part_byte_count);
+        assert!(part_byte_count <= padded_size);
+        assert_eq!(part_offset % 2048, 0);
+
+        assert!(read_rkaf_header(temp_dir.path().join("input/Image/boot.img").to_str().unwrap()).is_err());
+    }
+
+    fn partition(name: &str, part_offset: u32, padded_size: u32, part_byte_count: u32) -> PartitionInfo {
+        PartitionInfo {
+            name: name.to_string(),
+            path: format!("Image/{}.img", name),
+            flash_size: 0,
+            flash_offset: 0,
+            part_offset,
+            padded_size,
+            part_byte_count,
+            sparse: false,
+            kind: PartitionKind::from_name(name),
+            content_type: None,
+        }
+    }
+
+    // 构造 Android boot 镜像：版本 0-2 的 sections 为 kernel、ramdisk、second、recovery_dtbo、dtb，版本 3/4 为 kernel、ramdisk、signature
+    fn build_android_boot_image(version: u32, page_size: u32, sections: &[&[u8]], cmdline: &str) -> Vec<u8> {
+        let page_size = if version >= 3 { 4096 } else { page_size as usize };
+        let mut image = vec![0u8; page_size];
+        image[..8].copy_from_slice(b"ANDROID!");
+        image[0x28..0x2c].copy_from_slice(&version.to_le_bytes());
+        let size_fields: &[usize] = if version >= 3 { &[0x08, 0x0c, 1580] } else { &[0x08, 0x10, 0x18, 1632, 1648] };
+        for (section, &field) in sections.iter().zip(size_fields) {
+            image[field..field + 4].copy_from_slice(&(section.len() as u32).to_le_bytes());
+            image.extend_from_slice(section);
+            image.resize(image.len().div_ceil(page_size) * page_size, 0);
+        }
+        if version >= 3 {
+            image[0x2c..0x2c + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        } else {
+            image[0x24..0x28].copy_from_slice(&(page_size as u32).to_le_bytes());
+            image[0x30..0x34].copy_from_slice(b"rk35");
+            image[0x40..0x40 + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse_boot_image() {
+        let kernel = vec![0x4bu8; 3000];
+        let image = build_android_boot_image(2, 2048, &[&kernel, b"ramdisk", b"", b"", b"dtb"], "console=ttyFIQ0");
+        let boot = parse_boot_image(&image).unwrap();
+        assert_eq!(boot.header_version, 2);
+        assert_eq!(boot.page_size, 2048);
+        assert_eq!(boot.name, "rk35");
+        assert_eq!(boot.cmdline, "console=ttyFIQ0");
+        // 每个 section 都按页对齐
+        assert_eq!(boot.kernel, BootImageSection { offset: 2048, size: 3000 });
+        assert_eq!(boot.ramdisk, BootImageSection { offset: 6144, size: 7 });
+        assert_eq!(boot.second, Some(BootImageSection { offset: 8192, size: 0 }));
+        assert_eq!(boot.dtb, Some(BootImageSection { offset: 8192, size: 3 }));
+        let names: Vec<&str> = boot.sections().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["kernel", "ramdisk", "dtb"]);
+
+        let v0 = parse_boot_image(&build_android_boot_image(0, 4096, &[b"kernel", b"ramdisk", b"second"], "")).unwrap();
+        assert_eq!(v0.second, Some(BootImageSection { offset: 12288, size: 6 }));
+        assert_eq!((v0.recovery_dtbo, v0.dtb), (None, None));
+
+        let v4 = parse_boot_image(&build_android_boot_image(4, 0, &[b"kernel", b"ramdisk", b"sig"], "androidboot.hardware=rk30board")).unwrap();
+        assert_eq!(v4.page_size, 4096);
+        assert_eq!(v4.cmdline, "androidboot.hardware=rk30board");
+        assert_eq!(v4.ramdisk, BootImageSection { offset: 8192, size: 7 });
+        assert_eq!(v4.signature, Some(BootImageSection { offset: 12288, size: 3 }));
+        assert_eq!(v4.second, None);
+
+        // 截断、错误的 magic、页大小和版本
+        assert!(matches!(parse_boot_image(&image[..6000]), Err(ApfError::Truncated(_))));
+        assert!(matches!(parse_boot_image(&image[2048..]), Err(ApfError::BadMagic)));
+        let mut bad_page = image.clone();
+        bad_page[0x24..0x28].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(parse_boot_image(&bad_page), Err(ApfError::Invalid(_))));
+        let mut bad_version = image.clone();
+        bad_version[0x28] = 5;
+        assert!(matches!(parse_boot_image(&bad_version), Err(ApfError::Invalid(_))));
+
+        // 解包时按 content_type 拆分 boot 镜像
+        let temp_dir = TempDir::new().unwrap();
+        let rkaf = build_synthetic_rkaf(&[("boot", &image), ("misc", b"misc")]);
+        let dst = temp_dir.path().join("out");
+        Unpacker::new()
+            .split_boot_image(true)
+            .unpack_reader(Cursor::new(rkaf), dst.to_str().unwrap())
+            .unwrap();
+        assert_eq!(fs::read(dst.join("boot.d/kernel")).unwrap(), kernel);
+        assert_eq!(fs::read(dst.join("boot.d/ramdisk")).unwrap(), b"ramdisk");
+        assert_eq!(fs::read(dst.join("boot.d/dtb")).unwrap(), b"dtb");
+        assert_eq!(fs::read_to_string(dst.join("boot.d/cmdline")).unwrap(), "console=ttyFIQ0\n");
+        assert!(!dst.join("boot.d/second").exists());
+        assert!(!dst.join("misc.d").exists());
+    }
+
+    #[test]
+    fn test_detect_fs() {
+        let mut ext4 = vec![0u8; FS_PROBE_LEN];
+        ext4[0x438..0x43a].copy_from_slice(&0xef53u16.to_le_bytes());
+        assert_eq!(detect_fs(&ext4), Some("ext2"));
+        ext4[0x45c] = 0x4;
+        assert_eq!(detect_fs(&ext4), Some("ext3"));
+        ext4[0x460] = 0x40;
+        assert_eq!(detect_fs(&ext4), Some("ext4"));
+        // 超级块不完整时不识别
+        assert_eq!(detect_fs(&ext4[..0x439]), None);
+
+        let mut f2fs = vec![0u8; 0x800];
+        f2fs[0x400..0x404].copy_from_slice(&0xf2f52010u32.to_le_bytes());
+        assert_eq!(detect_fs(&f2fs), Some("f2fs"));
+        assert_eq!(detect_fs(b"hsqs\0\0\0\0"), Some("squashfs"));
+        assert_eq!(detect_fs(b"ANDROID!\0\0\0\0"), Some("android-boot"));
+        assert_eq!(detect_fs(&0x28cd3d45u32.to_le_bytes()), Some("cramfs"));
+        assert_eq!(detect_fs(&[0xd0, 0x0d, 0xfe, 0xed]), Some("fdt"));
+        assert_eq!(detect_fs(b"RSCE"), Some("rockchip-resource"));
+        assert_eq!(detect_fs(b""), None);
+        assert_eq!(detect_fs(&[0u8; 4096]), None);
+
+        // 解包结果和 list_partitions 都带上 content_type
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", b"ANDROID!boot"), ("rootfs", &ext4), ("misc", b"misc")]);
+        let image_path = temp_dir.path().join("update.img");
+        fs::write(&image_path, &image).unwrap();
+        let types = |parts: &[PartitionInfo]| parts.iter().map(|part| part.content_type.clone()).collect::<Vec<_>>();
+        let expected = [Some("android-boot".to_string()), Some("ext4".to_string()), None];
+        let UnpackResult::Rkaf(info) = unpack_reader(Cursor::new(image), temp_dir.path().join("out").to_str().unwrap()).unwrap() else {
+            panic!("expected an RKAF result");
+        };
+        assert_eq!(types(&info.partitions), expected);
+        assert_eq!(types(&list_partitions(image_path.to_str().unwrap()).unwrap()), expected);
+    }
+
+    // (节点名, 字符串属性, 加载地址, 数据)
+    type FitImage<'a> = (&'a str, &'a [(&'a str, &'a str)], u32, &'a [u8]);
+
+    // 生成 mkimage -E 风格的 FIT：/images 下每个节点带字符串属性和外置数据
+    fn build_fit(images: &[FitImage]) -> Vec<u8> {
+        let mut strings: Vec<u8> = Vec::new();
+        let mut string_offset = |name: &str| -> u32 {
+            let offset = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            offset
+        };
+        let mut structure: Vec<u8> = Vec::new();
+        let begin_node = |structure: &mut Vec<u8>, name: &str| {
+            structure.extend_from_slice(&1u32.to_be_bytes());
+            structure.extend_from_slice(name.as_bytes());
+            structure.push(0);
+            structure.resize(structure.len().next_multiple_of(4), 0);
+        };
+        let prop = |structure: &mut Vec<u8>, name_offset: u32, value: &[u8]| {
+            structure.extend_from_slice(&3u32.to_be_bytes());
+            structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            structure.extend_from_slice(&name_offset.to_be_bytes());
+            structure.extend_from_slice(value);
+            structure.resize(structure.len().next_multiple_of(4), 0);
+        };
+        let mut data: Vec<u8> = Vec::new();
+        begin_node(&mut structure, "");
+        prop(&mut structure, string_offset("description"), b"FIT Image\0");
+        begin_node(&mut structure, "images");
+        for (name, props, load, payload) in images {
+            begin_node(&mut structure, name);
+            for (key, value) in *props {
+                prop(&mut structure, string_offset(key), format!("{}\0", value).as_bytes());
+            }
+            prop(&mut structure, string_offset("load"), &load.to_be_bytes());
+            prop(&mut structure, string_offset("data-offset"), &(data.len() as u32).to_be_bytes());
+            prop(&mut structure, string_offset("data-size"), &(payload.len() as u32).to_be_bytes());
+            data.extend_from_slice(payload);
+            data.resize(data.len().next_multiple_of(4), 0);
+            structure.extend_from_slice(&2u32.to_be_bytes());
+        }
+        structure.extend_from_slice(&2u32.to_be_bytes());
+        structure.extend_from_slice(&2u32.to_be_bytes());
+        structure.extend_from_slice(&9u32.to_be_bytes());
+
+        // 头 40 字节 + 16 字节空的内存保留表
+        let struct_offset = 56u32;
+        let strings_offset = struct_offset + structure.len() as u32;
+        let total_size = strings_offset + strings.len() as u32;
+        let mut fit = Vec::new();
+        for value in [0xd00dfeed, total_size, struct_offset, strings_offset, 40, 17, 16, 0, strings.len() as u32, structure.len() as u32] {
+            fit.extend_from_slice(&u32::to_be_bytes(value));
+        }
+        fit.resize(struct_offset as usize, 0);
+        fit.extend_from_slice(&structure);
+        fit.extend_from_slice(&strings);
+        fit.resize(fit.len().next_multiple_of(4), 0);
+        fit.extend_from_slice(&data);
+        fit
+    }
+
+    #[test]
+    fn test_parse_fit() {
+        let fit = build_fit(&[
+            ("uboot", &[("description", "U-Boot"), ("type", "standalone"), ("arch", "arm64"), ("os", "U-Boot"), ("compression", "none")], 0x200000, b"uboot-data"),
+            ("atf-1", &[("type", "firmware"), ("arch", "arm64"), ("os", "arm-trusted-firmware")], 0x40000, b"bl31"),
+        ]);
+        let components = parse_fit(&fit).unwrap();
+        assert_eq!(components.len(), 2);
+        let uboot = &components[0];
+        assert_eq!(
+            (uboot.name.as_str(), uboot.description.as_str(), uboot.kind.as_str(), uboot.arch.as_str(), uboot.os.as_str(), uboot.compression.as_str()),
+            ("uboot", "U-Boot", "standalone", "arm64", "U-Boot", "none")
+        );
+        assert_eq!((uboot.load, uboot.entry, uboot.size), (Some(0x200000), None, Some(10)));
+        // data-offset 从树的末尾算起，转换后的偏移直接指向数据
+        let offset = uboot.offset.unwrap() as usize;
+        assert_eq!(&fit[offset..offset + 10], b"uboot-data");
+        let atf = &components[1];
+        assert_eq!((atf.name.as_str(), atf.kind.as_str(), atf.os.as_str()), ("atf-1", "firmware", "arm-trusted-firmware"));
+        let offset = atf.offset.unwrap() as usize;
+        assert_eq!(&fit[offset..offset + 4], b"bl31");
+
+        // 没有 /images 的普通设备树不是 FIT，截断的树报错而不是 panic
+        assert_eq!(detect_fs(&fit), Some("fit"));
+        let mut plain_dtb = fit.clone();
+        let images = plain_dtb.windows(8).position(|window| window == b"images\0\0").unwrap();
+        plain_dtb[images..images + 6].copy_from_slice(b"chosen");
+        assert_eq!(detect_fs(&plain_dtb), Some("fdt"));
+        assert!(matches!(parse_fit(&plain_dtb), Err(ApfError::Invalid(_))));
+        assert!(parse_fit(&fit[..100]).is_err());
+        assert!(matches!(parse_fit(b"not a tree"), Err(ApfError::BadMagic)));
+
+        // Rockchip 的 uboot/trust/idblock 容器
+        assert_eq!(detect_fs(b"LOADER  \0\0\0\0"), Some("rockchip-uboot"));
+        assert_eq!(detect_fs(b"BL3X\0\0\0\0"), Some("rockchip-trust"));
+        assert_eq!(detect_fs(b"TOS     \0\0\0\0"), Some("rockchip-trust"));
+        assert_eq!(detect_fs(b"RKNS\0\0\0\0"), Some("rockchip-idblock-v2"));
+        let mut idblock = 0x0ff0aa55u32.to_le_bytes().to_vec();
+        idblock.resize(512, 0);
+        rc4_rk(&mut idblock);
+        assert_eq!(detect_fs(&idblock), Some("rockchip-idblock"));
+
+        // 解包时 uboot 分区记为 fit
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("uboot", &fit), ("trust", b"BL3X\0\0\0\0trust")]);
+        let UnpackResult::Rkaf(info) = unpack_reader(Cursor::new(image), temp_dir.path().to_str().unwrap()).unwrap() else {
+            panic!("expected an RKAF result");
+        };
+        let types: Vec<_> = info.partitions.iter().map(|part| part.content_type.clone()).collect();
+        assert_eq!(types, [Some("fit".to_string()), Some("rockchip-trust".to_string())]);
+    }
+
+    #[test]
+    fn test_partition_sort() {
+        assert_eq!("by-offset".parse::<PartitionSort>().unwrap(), PartitionSort::ByOffset);
+        assert_eq!("SIZE".parse::<PartitionSort>().unwrap(), PartitionSort::BySize);
+        assert_eq!("header".parse::<PartitionSort>().unwrap(), PartitionSort::Header);
+        assert!("by-crc".parse::<PartitionSort>().is_err());
+
+        let mut parts = vec![partition("uboot", 0x800, 0x800, 0x400), partition("boot", 0x1000, 0x800, 0x10), partition("misc", 0x1800, 0x800, 0x400)];
+        parts[0].flash_offset = 0x4000;
+        parts[1].flash_offset = 0x8000;
+        parts[2].flash_offset = 0x2000;
+        let names = |parts: &[PartitionInfo]| parts.iter().map(|part| part.name.clone()).collect::<Vec<_>>();
+
+        PartitionSort::Header.apply(&mut parts);
+        assert_eq!(names(&parts), ["uboot", "boot", "misc"]);
+        PartitionSort::ByOffset.apply(&mut parts);
+        assert_eq!(names(&parts), ["misc", "uboot", "boot"]);
+        // 大小相同时保持原有顺序
+        PartitionSort::BySize.apply(&mut parts);
+        assert_eq!(names(&parts), ["boot", "misc", "uboot"]);
+        PartitionSort::ByName.apply(&mut parts);
+        assert_eq!(names(&parts), ["boot", "misc", "uboot"]);
+
+        // 元数据文件按 sort 排列，分区本身照常解出
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("uboot", b"uboot"), ("boot", b"boot"), ("misc", b"misc")]);
+        let dst = temp_dir.path().join("out");
+        Unpacker::new()
+            .sort(PartitionSort::ByName)
+            .unpack_reader(Cursor::new(image), dst.to_str().unwrap())
+            .unwrap();
+        let metadata = fs::read_to_string(dst.join("partition-metadata.txt")).unwrap();
+        let rows: Vec<&str> = metadata.lines().map(|line| line.split(',').next().unwrap()).collect();
+        assert_eq!(rows, ["boot", "misc", "uboot"]);
+        assert_eq!(fs::read(dst.join("Image/uboot.img")).unwrap(), b"uboot");
+    }
+
+    #[test]
+    fn test_partition_utilization() {
+        // flash_size 以 512 字节扇区为单位
+        let mut system = partition("system", 0, 0, 0x7000_0000);
+        system.flash_size = 0x40_0000;
+        assert_eq!(system.allocated_bytes(), Some(2 << 30));
+        assert!((system.utilization().unwrap() - 0.875).abs() < 1e-9);
+        assert_eq!(system.usage_summary(), "system: 1.8 GiB / 2.0 GiB (88%)");
+
+        // flash_offset 同样以扇区为单位，part_offset 是镜像内的字节偏移
+        let mut boot = partition("boot", 0x80_0000, 0x2000, 0x1000);
+        boot.flash_offset = 0x8000;
+        boot.flash_size = 0x2000;
+        assert_eq!(boot.flash_offset_bytes(), 0x100_0000);
+        assert_eq!(boot.flash_size_bytes(), Some(0x2000 * 512));
+        assert_eq!(boot.part_offset, 0x80_0000);
+
+        // 0xFFFFFFFF 表示扩展到 flash 末尾，不当作 2 TiB 处理
+        let mut userdata = partition("userdata", 0, 0, 1536);
+        userdata.flash_size = 0xffff_ffff;
+        assert_eq!(userdata.allocated_bytes(), None);
+        assert_eq!(userdata.utilization(), None);
+        assert!(userdata.is_grow());
+        assert_eq!(userdata.usage_summary(), "userdata: 1.5 KiB / grow");
+
+        let parameter = partition("parameter", 0, 0, 100);
+        assert_eq!(parameter.utilization(), None);
+        assert_eq!(parameter.usage_summary(), "parameter: 100 B (no flash allocation)");
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let filesize = fs::metadata(&image).unwrap().len();
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => assert!(validate_layout(&info, filesize).is_empty()),
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+
+        // 两个分区重叠 4KB，最后一个分区越界
+        let mut info = RkafInfo {
+            manufacturer: String::new(),
+            model: String::new(),
+            filesize: 0x10000,
+            declared_length: 0x10000,
+            actual_length: 0x10000,
+            length_matches: true,
+            manufacturer_raw: Vec::new(),
+            model_raw: Vec::new(),
+            version: FirmwareCode(0x01000000),
+            partitions: vec![
+                partition("misc", 0x1000, 0x2000, 0x2000),
+                partition("boot", 0x800, 0x2000, 0x1800),
+                partition("system", 0x8000, 0x1000, 0x1000),
+                partition("vendor", 0xf000, 0x2000, 0x2000),
+            ],
+            reserved_parts: Vec::new(),
+            alignment: None,
+            trailing_bytes: 0,
+            sector_offsets: false,
+            stats: Default::default(),
+            errors: Vec::new(),
+        };
+        assert_eq!(validate_layout(&info, 0x10000), vec![
+            // 偏移按 0x800 对齐，0x1800 字节的数据不该填充到 0x2000
+            LayoutWarning::UnroundedPaddedSize { name: "boot".to_string(), part_byte_count: 0x1800, padded_size: 0x2000, alignment: 0x800 },
+            LayoutWarning::OutOfBounds { name: "vendor".to_string(), end: 0x11000, filesize: 0x10000 },
+            LayoutWarning::Overlap { first: "boot".to_string(), second: "misc".to_string(), bytes: 0x1000 },
+            LayoutWarning::Gap { after: "misc".to_string(), before: "system".to_string(), bytes: 0x5000 },
+            LayoutWarning::Gap { after: "system".to_string(), before: "vendor".to_string(), bytes: 0x6000 },
+        ]);
+
+        // padded_size 小于数据长度；空分区可以保留一个对齐单位
+        info.partitions = vec![
+            partition("boot", 0x800, 0x800, 0x1000),
+            partition("misc", 0x1800, 0x800, 0),
+            partition("system", 0x2000, 0x1000, 0xf01),
+        ];
+        let warnings = validate_layout(&info, 0x10000);
+        assert_eq!(warnings[0], LayoutWarning::PaddedSizeTooSmall { name: "boot".to_string(), part_byte_count: 0x1000, padded_size: 0x800 });
+        assert_eq!(warnings[0].to_string(), "boot has 0x1000 bytes of data but a padded size of only 0x800");
+        assert!(!warnings.iter().any(|warning| matches!(warning, LayoutWarning::UnroundedPaddedSize { .. })), "{:?}", warnings);
+
+        // 默认只是警告，strict 模式下解包失败
+        let mut image = build_synthetic_rkaf(&[("boot", &[0x11u8; 0x900]), ("misc", b"misc")]);
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image[..header_size]);
+        header.parts[0].padded_size = 0x800;
+        image[..header_size].copy_from_slice(header.to_bytes());
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), temp_dir.path().join("lenient").to_str().unwrap(), &lenient).unwrap();
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_reader_with_options(Cursor::new(image), temp_dir.path().join("strict").to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("padded size of only 0x800"), "{}", err);
+    }
+
+    // 构造 RSCE 资源镜像：头部、索引表和数据均按 512 字节块对齐
+    fn build_resource_image(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0u8; 512 * (1 + files.len())];
+        data[0..4].copy_from_slice(b"RSCE");
+        data[8] = 1;
+        data[9] = 1;
+        data[10] = 1;
+        data[11..15].copy_from_slice(&(files.len() as u32).to_le_bytes());
+        for (i, (name, content)) in files.iter().enumerate() {
+            let block = data.len() / 512;
+            let entry = 512 * (1 + i);
+            data[entry..entry + 4].copy_from_slice(b"ENTR");
+            data[entry + 4..entry + 4 + name.len()].copy_from_slice(name.as_bytes());
+            data[entry + 260..entry + 264].copy_from_slice(&(block as u32).to_le_bytes());
+            data[entry + 264..entry + 268].copy_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(content);
+            data.resize(data.len().div_ceil(512) * 512, 0);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_resource() {
+        let dtb = [0xd0u8, 0x0d, 0xfe, 0xed, 1, 2, 3];
+        let resource = build_resource_image(&[("rk3326-evb.dtb", &dtb), ("logo.bmp", b"BM logo")]);
+        assert_eq!(parse_resource(&resource).unwrap(), vec![
+            ResourceEntry { name: "rk3326-evb.dtb".to_string(), offset: 0x600, size: 7 },
+            ResourceEntry { name: "logo.bmp".to_string(), offset: 0x800, size: 7 },
+        ]);
+
+        assert!(matches!(parse_resource(b"RSCE"), Err(ApfError::Truncated(_))));
+        assert!(matches!(parse_resource(&[0u8; 512]), Err(ApfError::BadMagic)));
+        let mut unsupported = resource.clone();
+        unsupported[6] = 1;
+        assert!(matches!(parse_resource(&unsupported), Err(ApfError::Invalid(_))));
+        assert!(matches!(parse_resource(&resource[..0x700]), Err(ApfError::Truncated(_))));
+
+        // split_resource 在解包时把每个资源写入 resource/
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/resource.img"), &resource).unwrap();
+        fs::write(input_dir.join("package-file"), "parameter Image/parameter.txt\nresource Image/resource.img\n").unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "parameter,Image/parameter.txt,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             resource,Image/resource.img,0x00008000,0x00004000,0x00000000,0x00001000,0x00000000\n",
+        ).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        Unpacker::new()
+            .split_resource(true)
+            .unpack(image.to_str().unwrap(), output_dir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(fs::read(output_dir.join("resource/rk3326-evb.dtb")).unwrap(), dtb);
+        assert_eq!(fs::read(output_dir.join("resource/logo.bmp")).unwrap(), b"BM logo");
+        assert_eq!(fs::read(output_dir.join("Image/resource.img")).unwrap(), resource);
+    }
+
+    // 在内存中直接构造最小的合法 RKAF：分区按 2048 字节对齐，末尾为 RKCRC
+    fn build_synthetic_rkaf(partitions: &[(&str, &[u8])]) -> Vec<u8> {
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = UpdateHeader::default();
+        header.magic.copy_from_slice(RKAF_SIGNATURE);
+        header.model[..6].copy_from_slice(b"RK3326");
+        header.manufacturer[..6].copy_from_slice(b"RK3326");
+        header.version = 0x01000000;
+        header.num_parts = partitions.len() as u32;
+
+        let mut data = Vec::new();
+        let mut offset = header_size;
+        for (i, (name, content)) in partitions.iter().enumerate() {
+            let path = format!("Image/{}.img", name);
+            let padded = std::cmp::max(content.len(), 1).div_ceil(2048) * 2048;
+            let part = &mut header.parts[i];
+            part.name[..name.len()].copy_from_slice(name.as_bytes());
+            part.full_path[..path.len()].copy_from_slice(path.as_bytes());
+            part.part_offset = offset as u32;
+            part.padded_size = padded as u32;
+            part.part_byte_count = content.len() as u32;
+            part.flash_offset = (0x2000 * (i + 1)) as u32;
+            part.flash_size = 0x2000;
+            data.resize(offset - header_size, 0);
+            data.extend_from_slice(content);
+            offset += padded;
+        }
+
+        header.length = (header_size + data.len()) as u32;
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&data);
+        let crc = rkcrc(&image);
+        image.extend_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_unpack_sector_offsets() {
+        let temp_dir = TempDir::new().unwrap();
+        let boot = vec![0xa5u8; 3000];
+        let kernel: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let mut image = build_synthetic_rkaf(&[("boot", &boot), ("kernel", &kernel)]);
+        // 把分区表中的字节偏移改写为 512 字节扇区号，并重新计算 RKCRC
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image[..header_size]);
+        for part in header.parts.iter_mut().take(2) {
+            part.part_offset /= 512;
+        }
+        image[..header_size].copy_from_slice(header.to_bytes());
+        let crc_offset = image.len() - 4;
+        let crc = rkcrc(&image[..crc_offset]);
+        image[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+        let image_path = temp_dir.path().join("sectors.img");
+        fs::write(&image_path, &image).unwrap();
+
+        // 按字节解释时分区落在头部内，按扇区解释则全部合法：换算成字节后提取
+        let out_dir = temp_dir.path().join("out");
+        let info = match unpack_file(image_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => info,
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        };
+        assert!(info.sector_offsets);
+        assert_eq!(info.partitions[0].part_offset as usize, header_size);
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), boot);
+        assert_eq!(fs::read(out_dir.join("Image/kernel.img")).unwrap(), kernel);
+        let listed = list_partitions(image_path.to_str().unwrap()).unwrap();
+        assert_eq!(listed[1].part_offset, info.partitions[1].part_offset);
+
+        // 正常的字节偏移镜像不受影响
+        let plain = temp_dir.path().join("plain.img");
+        fs::write(&plain, build_synthetic_rkaf(&[("boot", &boot)])).unwrap();
+        match inspect_file(plain.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => assert!(!info.sector_offsets),
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_gunzip_partitions() {
+        let kernel = vec![0x5au8; 10000];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&kernel).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let broken = [&gzipped[..10], &[0xffu8; 16][..]].concat();
+        let image = build_synthetic_rkaf(&[("kernel", &gzipped), ("boot", b"not compressed"), ("broken", &broken)]);
+        let temp_dir = TempDir::new().unwrap();
+
+        // 默认不解压，分区文件保持原样
+        let plain = temp_dir.path().join("plain");
+        unpack_reader(Cursor::new(image.clone()), plain.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(plain.join("Image/kernel.img")).unwrap(), gzipped);
+        assert!(!plain.join("Image/kernel.raw").exists());
+
+        // gzip 分区旁边多写一个 .raw，其余分区不受影响，解压失败只警告
+        let output = temp_dir.path().join("gunzip");
+        let options = UnpackOptions { gunzip_partitions: true, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(output.join("Image/kernel.img")).unwrap(), gzipped);
+        assert_eq!(fs::read(output.join("Image/kernel.raw")).unwrap(), kernel);
+        assert!(!output.join("Image/boot.raw").exists());
+        assert!(!output.join("Image/broken.raw").exists());
+
+        let options = UnpackOptions { gunzip_partitions: true, strict: true, ..Default::default() };
+        let err = unpack_reader_with_options(Cursor::new(image), temp_dir.path().join("strict").to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("cannot decompress Image/broken.img"), "{}", err);
+    }
+
+    // unpack 后再 pack 必须逐字节还原
+    fn assert_round_trip(image: &[u8]) {
+        let temp_dir = TempDir::new().unwrap();
+        let unpacked = temp_dir.path().join("unpacked");
+        let repacked = temp_dir.path().join("repacked.img");
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.to_vec()), unpacked.to_str().unwrap(), &options).unwrap();
+        pack_rkafp(unpacked.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), image);
+    }
+
+    #[test]
+    fn test_synthetic_round_trip() {
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc")]);
+        assert!(matches!(inspect_file_bytes(&image), UnpackResult::Rkaf(_)));
+        assert_round_trip(&image);
+
+        // 空分区和未对齐的大小
+        let odd: Vec<u8> = (0..2049u32).map(|i| (i * 7) as u8).collect();
+        assert_round_trip(&build_synthetic_rkaf(&[
+            ("empty", b""),
+            ("one", &[1]),
+            ("almost", &odd[..2047]),
+            ("over", &odd),
+            ("tail", &[0xff; 3]),
+        ]));
+
+        // 分区数达到上限 MAX_PARTS
+        let names: Vec<String> = (0..16).map(|i| format!("part{}", i)).collect();
+        let contents: Vec<Vec<u8>> = (0..16).map(|i| vec![i as u8 + 1; 100 * i + 1]).collect();
+        let partitions: Vec<(&str, &[u8])> = names.iter().map(String::as_str).zip(contents.iter().map(Vec::as_slice)).collect();
+        assert_round_trip(&build_synthetic_rkaf(&partitions));
+    }
+
+    #[test]
+    fn test_grow_partition() {
+        // 最后一个分区标记为 grow，重新计算 CRC
+        let mut image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("userdata", b"userdata")]);
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image);
+        header.parts[1].flash_size = FLASH_SIZE_GROW;
+        image[..header_size].copy_from_slice(header.to_bytes());
+        let crc_offset = image.len() - 4;
+        let crc = rkcrc(&image[..crc_offset]);
+        image[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+
+        let UnpackResult::Rkaf(info) = inspect_file_bytes(&image) else { panic!("expected RKAF") };
+        assert!(!info.partitions[0].is_grow());
+        assert!(info.partitions[1].is_grow());
+        assert_eq!(info.partitions[1].allocated_bytes(), None);
+        assert_eq!(info.partitions[1].usage_summary(), "userdata: 8 B / grow");
+
+        // 重新打包保留 0xFFFFFFFF，不替换为计算出的大小
+        assert_round_trip(&image);
+
+        // CSV 元数据写成 grow，读回来仍是标记值
+        let temp_dir = TempDir::new().unwrap();
+        let unpacked = temp_dir.path().join("unpacked");
+        let repacked = temp_dir.path().join("repacked.img");
+        let options = UnpackOptions { metadata_format: MetadataFormat::Csv, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), unpacked.to_str().unwrap(), &options).unwrap();
+        let metadata = fs::read_to_string(unpacked.join("partition-metadata.csv")).unwrap();
+        assert!(metadata.contains("userdata,Image/userdata.img,grow,"));
+        pack_rkafp(unpacked.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), image);
+    }
+
+    // 读取 ustar 归档中的 (名称, 权限, 内容)
+    fn read_tar(data: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let octal = |field: &[u8]| {
+            let text = String::from_utf8_lossy(field);
+            u64::from_str_radix(text.trim_matches(|c| c == '\0' || c == ' '), 8).unwrap()
+        };
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while data[offset..offset + 512].iter().any(|&b| b != 0) {
+            let header = &data[offset..offset + 512];
+            let checksum: u64 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 }).sum();
+            assert_eq!(octal(&header[148..156]), checksum);
+            let field = |range: std::ops::Range<usize>| {
+                let bytes = &header[range];
+                String::from_utf8(bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())].to_vec()).unwrap()
+            };
+            let prefix = field(345..500);
+            let name = if prefix.is_empty() { field(0..100) } else { format!("{}/{}", prefix, field(0..100)) };
+            let size = octal(&header[124..136]) as usize;
+            let start = offset + 512;
+            entries.push((name, octal(&header[100..108]) as u32, data[start..start + size].to_vec()));
+            offset = start + size.div_ceil(512) * 512;
+        }
+        // 结尾是两个全零块
+        assert_eq!(data.len(), offset + 1024);
+        entries
+    }
+
+    #[test]
+    fn test_unpack_split_size() {
+        let temp_dir = TempDir::new().unwrap();
+        let out_dir = temp_dir.path().join("out");
+        let odd: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let image = build_synthetic_rkaf(&[("boot", &odd), ("misc", b"misc")]);
+        let unpacker = Unpacker::new().split_size(2048);
+        let UnpackResult::Rkaf(info) = unpacker.unpack_reader(Cursor::new(image), out_dir.to_str().unwrap()).unwrap() else { panic!("expected RKAF") };
+        assert_eq!(info.stats.files_written, 2);
+
+        // 超过上限的分区按块写出，不再写完整文件；小分区不受影响
+        assert!(!out_dir.join("Image/boot.img").exists());
+        assert_eq!(fs::read(out_dir.join("Image/misc.img")).unwrap(), b"misc");
+        let index: ChunkIndex = serde_json::from_str(&fs::read_to_string(out_dir.join(CHUNKS_FILE)).unwrap()).unwrap();
+        assert_eq!(index.chunk_size, 2048);
+        assert_eq!(index.partitions.len(), 1);
+        let boot = &index.partitions[0];
+        assert_eq!((boot.name.as_str(), boot.path.as_str(), boot.size), ("boot", "Image/boot.img", 5000));
+        let sizes: Vec<u64> = boot.chunks.iter().map(|chunk| chunk.size).collect();
+        assert_eq!(sizes, [2048, 2048, 904]);
+        assert_eq!(boot.chunks[2].path, "Image/boot.img.part2");
+        assert_eq!(boot.chunks[2].offset, 4096);
+
+        // 按索引顺序拼接即可还原分区
+        let paths: Vec<PathBuf> = boot.chunks.iter().map(|chunk| out_dir.join(&chunk.path)).collect();
+        let mut joined = Vec::new();
+        MultiFileReader::open(&paths).unwrap().read_to_end(&mut joined).unwrap();
+        assert_eq!(joined, odd);
+    }
+
+    #[test]
+    fn test_generate_parameter() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", b"boot"), ("misc", b"misc")]);
+        let out_dir = temp_dir.path().join("out");
+        let UnpackResult::Rkaf(mut info) = unpack_reader(Cursor::new(image), out_dir.to_str().unwrap()).unwrap() else { panic!("expected RKAF") };
+
+        // 没有 parameter 分区时根据分区表生成，parse_parameter 能读回
+        let generated = fs::read_to_string(out_dir.join("parameter.generated")).unwrap();
+        assert_eq!(generated, generate_parameter(&info));
+        assert!(generated.contains("CMDLINE: mtdparts=rk29xxnand:0x00002000@0x00002000(boot),0x00002000@0x00004000(misc)\n"));
+        let declared = parse_parameter(generated.as_bytes()).unwrap();
+        assert_eq!(declared.len(), 2);
+        assert!(validate_parameter_layout(&declared, &info).is_empty());
+
+        // 自动扩展的分区使用 - 作为大小
+        info.partitions[1].flash_size = FLASH_SIZE_GROW;
+        let generated = generate_parameter(&info);
+        assert!(generated.contains("(boot),-@0x00004000(misc:grow)\n"));
+        assert_eq!(parse_parameter(generated.as_bytes()).unwrap()[1].sector_count, None);
+
+        // 自带 parameter 分区的映像不生成
+        let input_dir = temp_dir.path().join("input");
+        let image_path = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image_path.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let out_dir = temp_dir.path().join("with_parameter");
+        unpack_file(image_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        assert!(!out_dir.join("parameter.generated").exists());
+    }
+
+    #[test]
+    fn test_unpack_to_tar() {
+        let temp_dir = TempDir::new().unwrap();
+        let image_path = temp_dir.path().join("update.img");
+        let tar_path = temp_dir.path().join("update.tar");
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc")]);
+        fs::write(&image_path, &image).unwrap();
+
+        let result = unpack_to_tar(image_path.to_str().unwrap(), tar_path.to_str().unwrap()).unwrap();
+        assert_eq!(result.stats().files_written, 2);
+        let entries = read_tar(&fs::read(&tar_path).unwrap());
+        let names: Vec<&str> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+        // 映像没有 parameter 分区，归档中附带生成的 parameter.generated
+        assert_eq!(names, ["header-metadata.txt", "parameter.generated", "partition-metadata.txt", "manifest.json", "Image/boot.img", "Image/misc.img", "flash.sh"]);
+        assert_eq!(entries[4].2, vec![0xa5u8; 5000]);
+        assert_eq!(entries[4].1, 0o644);
+        assert_eq!(entries[6].1, 0o755);
+
+        // 解开归档后可以逐字节重新打包
+        let unpacked = temp_dir.path().join("unpacked");
+        for (name, _, data) in &entries {
+            let path = unpacked.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkafp(unpacked.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), image);
+
+        // 与目录解包写出的元数据一致
+        let dir = temp_dir.path().join("dir");
+        unpack_file(image_path.to_str().unwrap(), dir.to_str().unwrap()).unwrap();
+        for (name, _, data) in &entries {
+            assert_eq!(&fs::read(dir.join(name)).unwrap(), data, "{}", name);
+        }
+
+        // RKFW 递归解包：内嵌镜像的分区放在 embedded-update/ 下
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let options = UnpackOptions { recursive: true, only: vec!["boot".to_string()], ..Default::default() };
+        let result = unpack_to_tar_with_options(rkfw.to_str().unwrap(), tar_path.to_str().unwrap(), &options).unwrap();
+        assert!(matches!(result, UnpackResult::Rkfw(_)));
+        let entries = read_tar(&fs::read(&tar_path).unwrap());
+        let names: Vec<&str> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert!(names.contains(&"BOOT"));
+        assert!(names.contains(&"embedded-update/Image/boot.img"));
+        assert!(!names.contains(&"embedded-update/Image/parameter.txt"));
+        assert_eq!(names.last(), Some(&"rkfw-info.json"));
+
+        // 失败时不留下不完整的归档
+        fs::write(&image_path, b"not an image").unwrap();
+        assert!(unpack_to_tar(image_path.to_str().unwrap(), tar_path.to_str().unwrap()).is_err());
+        assert!(!tar_path.exists());
+    }
+
+    #[test]
+    fn test_find_signature() {
+        assert_eq!(find_signature(b"RKFW\x66\x00"), Some((ImageKind::Rkfw, 0)));
+        assert_eq!(find_signature(b"VENDOR\0\0RKAF"), Some((ImageKind::Rkaf, 8)));
+        // RKAFP 以 RKAF 开头；取最先出现的签名
+        assert_eq!(find_signature(b"xxRKAFPyyRKFW"), Some((ImageKind::Rkaf, 2)));
+        assert_eq!(find_signature(b"RKA"), None);
+        assert_eq!(find_signature(b""), None);
+
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc")]);
+        let mut prefixed = b"DLHDR\x01\x02\x03".repeat(64);
+        prefixed.extend_from_slice(&image);
+
+        // 默认要求签名位于偏移 0
+        let err = unpack_reader(Cursor::new(prefixed.clone()), output_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::UnknownSignature(sig) if &sig == b"DLHD"));
+
+        let options = UnpackOptions { scan: true, ..Default::default() };
+        let result = unpack_reader_with_options(Cursor::new(prefixed.clone()), output_dir.to_str().unwrap(), &options).unwrap();
+        let UnpackResult::Rkaf(info) = result else { panic!("expected RKAF") };
+        assert_eq!(info.partitions[0].part_offset, 2048);
+        assert_eq!(fs::read(output_dir.join("prefix.bin")).unwrap(), &prefixed[..512]);
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkafp(output_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), image);
+
+        // 没有前缀时删除上次留下的 prefix.bin
+        unpack_reader_with_options(Cursor::new(image), output_dir.to_str().unwrap(), &options).unwrap();
+        assert!(!output_dir.join("prefix.bin").exists());
+
+        // 扫描范围内找不到签名
+        let err = unpack_reader_with_options(Cursor::new(vec![0u8; 1024]), output_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(matches!(err, ApfError::UnknownSignature(_)));
+    }
+
+    #[test]
+    fn test_duplicate_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let image = build_synthetic_rkaf(&[("boot", b"first"), ("misc", b"misc"), ("boot", b"second")]);
+
+        let UnpackResult::Rkaf(info) = inspect_file_bytes(&image) else { panic!("expected RKAF") };
+        let warnings = validate_layout(&info, image.len() as u64);
+        assert!(warnings.contains(&LayoutWarning::DuplicateName { name: "boot".to_string(), count: 2 }));
+        assert!(warnings.contains(&LayoutWarning::DuplicatePath { path: "Image/boot.img".to_string(), count: 2 }));
+        assert_eq!(warnings[1].to_string(), "2 partitions are stored as Image/boot.img");
+
+        // 默认拒绝让后一个分区覆盖前一个
+        let err = unpack_reader(Cursor::new(image.clone()), output_dir.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("#0 (boot) and #2 (boot) would both be extracted to Image/boot.img"), "{}", err);
+        assert!(!output_dir.exists());
+
+        // 严格模式下重复名称本身就是错误
+        let options = UnpackOptions { strict: true, force: true, ..Default::default() };
+        let err = unpack_reader_with_options(Cursor::new(image.clone()), output_dir.to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("2 partitions are named boot"), "{}", err);
+
+        // --force 保留最后一个；按名称命名时分别写入 boot.img 和 boot.2.img
+        let options = UnpackOptions { force: true, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), output_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), b"second");
+        let options = UnpackOptions { naming: OutputNaming::ByName, ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image), output_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(output_dir.join("boot.img")).unwrap(), b"first");
+    }
+
+    // 把第一个分区的 full_path 换成 `path`（长度不变）并重新计算 CRC
+    fn with_first_path(image: &[u8], path: &str) -> Vec<u8> {
+        let mut image = image.to_vec();
+        let len = image.len() - 4;
+        let start = image.windows(path.len()).position(|window| window.starts_with(b"Image/")).unwrap();
+        image[start..start + path.len()].copy_from_slice(path.as_bytes());
+        let crc = rkcrc(&image[..len]);
+        image[len..].copy_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_unpack_backslash_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let image = with_first_path(&build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc")]), "Image\\boot.img");
+
+        // Windows 打包的镜像用反斜杠分隔，解包到 Image/ 子目录，flash.sh 也使用 `/`
+        let options = UnpackOptions { checksums: Some(HashAlgorithm::Sha256), ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), output_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(fs::read_to_string(output_dir.join("flash.sh")).unwrap().contains("'Image/boot.img'"));
+        assert!(fs::read_to_string(output_dir.join("checksums.txt")).unwrap().contains("  Image/boot.img\n"));
+
+        // 元数据保留原始路径，重新打包逐字节还原
+        let repacked = temp_dir.path().join("repacked.img");
+        pack_rkafp(output_dir.to_str().unwrap(), repacked.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&repacked).unwrap(), image);
+
+        // 盘符、Windows 不允许的字符和保留名称在所有平台上都被拒绝
+        let base = build_synthetic_rkaf(&[("boot", b"boot")]);
+        for (path, message) in [
+            ("C:\\boot.img", "escapes destination directory"),
+            ("Image/b?ot.img", "contains '?'"),
+            ("Image/con.img", "\"con.img\""),
+            ("Image/boot. ", "\"boot. \""),
+        ] {
+            let image = with_first_path(&base, &format!("{:\0<14}", path));
+            let err = unpack_reader(Cursor::new(image), output_dir.to_str().unwrap()).unwrap_err();
+            assert!(matches!(err, ApfError::Invalid(_)), "{}: {:?}", path, err);
+            assert!(err.to_string().contains(message), "{}: {}", path, err);
+        }
+    }
+
+    #[test]
+    fn test_rkaf_header_version() {
+        let image = build_synthetic_rkaf(&[("boot", b"boot")]);
+        let UnpackResult::Rkaf(info) = inspect_file_bytes(&image) else { panic!("expected RKAF") };
+        assert_eq!(info.version, FirmwareCode(0x01000000));
+        assert_eq!(info.version.to_string(), "1.0.0");
+
+        // 字节序颠倒的 1.0.0 默认只警告，严格模式下报错
+        let mut image = image;
+        let offset = std::mem::offset_of!(UpdateHeader, version);
+        image[offset..offset + 4].copy_from_slice(&1u32.to_le_bytes());
+        let len = image.len() - 4;
+        let crc = rkcrc(&image[..len]);
+        image[len..].copy_from_slice(&crc.to_le_bytes());
+        let UnpackResult::Rkaf(info) = inspect_file_bytes(&image) else { panic!("expected RKAF") };
+        assert_eq!(info.version.raw(), 1);
+        let temp_dir = TempDir::new().unwrap();
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_reader_with_options(Cursor::new(image), temp_dir.path().to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("unexpected RKAF header version 0.0.1 (0x00000001)"), "{}", err);
+    }
+
+    #[test]
+    fn test_unpack_undecodable_path() {
+        // 追加一个路径没有 NUL 结尾、无法解码的分区表项
+        let mut image = build_synthetic_rkaf(&[("boot", b"boot"), ("misc", b"misc")]);
+        let num_parts = std::mem::offset_of!(UpdateHeader, num_parts);
+        image[num_parts..num_parts + 4].copy_from_slice(&3u32.to_le_bytes());
+        let entry = std::mem::offset_of!(UpdateHeader, parts) + 2 * std::mem::size_of::<UpdatePart>();
+        image[entry..entry + 5].copy_from_slice(b"extra");
+        let path = entry + std::mem::offset_of!(UpdatePart, full_path);
+        image[path..path + std::mem::size_of_val(&UpdatePart::default().full_path)].fill(b'A');
+        let len = image.len() - 4;
+        let crc = rkcrc(&image[..len]);
+        image[len..].copy_from_slice(&crc.to_le_bytes());
+
+        // 默认只警告，其余分区照常解包
+        let temp_dir = TempDir::new().unwrap();
+        let UnpackResult::Rkaf(info) = unpack_reader(Cursor::new(image.clone()), temp_dir.path().join("out").to_str().unwrap()).unwrap() else { panic!("expected RKAF") };
+        assert_eq!(info.partitions.len(), 2);
+        assert_eq!(info.stats.files_written, 2);
+
+        // 严格模式下报错，并给出表项字节的十六进制
+        let options = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_reader_with_options(Cursor::new(image.clone()), temp_dir.path().join("strict").to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("Partition table entry #2 has no NUL-terminated path and is left out (name 65 78 74 72 61 00"), "{}", err);
+        assert!(err.to_string().contains("path 41 41 41"), "{}", err);
+
+        // 名称没有 NUL 结尾时分区名为空，严格模式下报错
+        let path = entry + std::mem::offset_of!(UpdatePart, full_path);
+        image[path..path + 6].copy_from_slice(b"extra\0");
+        image[entry..path].fill(b'B');
+        let len = image.len() - 4;
+        let crc = rkcrc(&image[..len]);
+        image[len..].copy_from_slice(&crc.to_le_bytes());
+        let UnpackResult::Rkaf(info) = inspect_file_bytes(&image) else { panic!("expected RKAF") };
+        assert_eq!(info.partitions.len() + info.reserved_parts.len(), 3);
+        assert!(info.partitions.iter().any(|part| part.name.is_empty()));
+        let err = unpack_reader_with_options(Cursor::new(image), temp_dir.path().join("strict_name").to_str().unwrap(), &options).unwrap_err();
+        assert!(err.to_string().contains("Partition table entry #2 has no NUL-terminated name and gets an empty one (name 42 42"), "{}", err);
+    }
+
+    #[test]
+    fn test_into_iter_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let image_path = temp_dir.path().join("update.img");
+        let odd: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        fs::write(&image_path, build_synthetic_rkaf(&[("boot", &odd), ("misc", b"misc")])).unwrap();
+
+        let partitions = Unpacker::new().into_iter_partitions(image_path.to_str().unwrap()).unwrap();
+        assert_eq!(partitions.len(), 2);
+        // 先全部取出再倒序读取，各个 reader 互不影响
+        let mut items: Vec<(PartitionInfo, _)> = partitions.map(Result::unwrap).collect();
+        items.reverse();
+        let mut contents = Vec::new();
+        for (part, mut reader) in items {
+            assert_eq!(reader.remaining(), part.part_byte_count as u64);
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(reader.remaining(), 0);
+            contents.push((part.name, data));
+        }
+        assert_eq!(contents, [("misc".to_string(), b"misc".to_vec()), ("boot".to_string(), odd)]);
+
+        let names: Vec<String> = Unpacker::new()
+            .only(&["misc"])
+            .into_iter_partitions(image_path.to_str().unwrap())
+            .unwrap()
+            .map(|item| item.unwrap().0.name)
+            .collect();
+        assert_eq!(names, ["misc"]);
+
+        // RKFW：默认给出 BOOT 和内嵌镜像，recursive 时给出内嵌分区，偏移相对于外层文件
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let names: Vec<String> = Unpacker::new()
+            .into_iter_partitions(rkfw.to_str().unwrap())
+            .unwrap()
+            .map(|item| item.unwrap().0.name)
+            .collect();
+        assert_eq!(names, ["BOOT", "embedded-update.img"]);
+        let listed = list_partitions(rkfw.to_str().unwrap()).unwrap();
+        for item in Unpacker::new().recursive(true).into_iter_partitions(rkfw.to_str().unwrap()).unwrap() {
+            let (part, mut reader) = item.unwrap();
+            let expected = listed.iter().find(|other| other.name == part.name).unwrap();
+            assert_eq!(part.part_offset, expected.part_offset);
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            let mut direct = Vec::new();
+            extract_partition(&mut File::open(&rkfw).unwrap(), expected, &mut direct).unwrap();
+            assert_eq!(data, direct);
+        }
+
+        // 与解包相同的检查
+        fs::write(&image_path, b"not an image").unwrap();
+        assert!(matches!(Unpacker::new().into_iter_partitions(image_path.to_str().unwrap()), Err(ApfError::UnknownSignature(_))));
+    }
+
+    #[test]
+    fn test_pack_three_field_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("old");
+        let image = temp_dir.path().join("update.img");
+        fs::create_dir_all(input_dir.join("Image")).unwrap();
+        fs::write(input_dir.join("header-metadata.txt"), "manufacturer:RK3326\nmodel:RK3326\nversion:0x01000000\n").unwrap();
+        fs::write(input_dir.join("Image/parameter.txt"), b"FIRMWARE_VER: 1.0\n").unwrap();
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 5000]).unwrap();
+        // 旧版本只记录名称、路径和大小
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "parameter,Image/parameter.txt,0x00000012\n\nboot,Image/boot.img,0x00001388\n",
+        ).unwrap();
+
+        pack_rkafp(input_dir.to_str().unwrap(), image.to_str().unwrap()).unwrap();
+        let UnpackResult::Rkaf(info) = inspect_file(image.to_str().unwrap()).unwrap() else { panic!("expected RKAF") };
+        let layout: Vec<(u32, u32, u32)> = info.partitions.iter().map(|p| (p.part_offset, p.padded_size, p.part_byte_count)).collect();
+        assert_eq!(layout, [(0x800, 0x800, 18), (0x1000, 0x1800, 5000)]);
+        let dir = temp_dir.path().join("unpacked");
+        unpack_file(image.to_str().unwrap(), dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+
+        // 列数不对的行报告行号
+        fs::write(input_dir.join("partition-metadata.txt"), "parameter,Image/parameter.txt,0x00000012\nboot,Image/boot.img\n").unwrap();
+        let err = pack_rkafp(input_dir.to_str().unwrap(), image.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Line 2 of partition metadata: expected 3 or 7 comma-separated fields, found 2"), "{}", err);
+    }
+
+    fn inspect_file_bytes(image: &[u8]) -> UnpackResult {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("update.img");
+        fs::write(&path, image).unwrap();
+        inspect_file(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_unpack_stats() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let parameter_len = fs::metadata(input_dir.join("Image/parameter.txt")).unwrap().len();
+
+        let result = unpack_file(image.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+        let duration_ms = result.stats().duration_ms;
+        assert_eq!(*result.stats(), UnpackStats { files_written: 2, bytes_written: 5000 + parameter_len, duration_ms, ..Default::default() });
+
+        // 过滤掉的分区和断点续传跳过的分区分别计数
+        let options = UnpackOptions { only: vec!["boot".to_string()], resume: true, ..Default::default() };
+        let result = unpack_file_with_options(image.to_str().unwrap(), output_dir.to_str().unwrap(), &options).unwrap();
+        let duration_ms = result.stats().duration_ms;
+        assert_eq!(*result.stats(), UnpackStats { skipped_filtered: 1, skipped_existing: 1, duration_ms, ..Default::default() });
+
+        // RKFW：BOOT 加上递归解出的内嵌分区
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let options = UnpackOptions { recursive: true, ..Default::default() };
+        let result = unpack_file_with_options(rkfw.to_str().unwrap(), temp_dir.path().join("rkfw").to_str().unwrap(), &options).unwrap();
+        let UnpackResult::Rkfw(info) = &result else { panic!("expected RKFW") };
+        assert_eq!(result.stats().files_written, 3);
+        assert!(result.stats().bytes_written > info.boot_size as u64);
+
+        // inspect_file 不写文件，计数为零
+        assert_eq!(*inspect_file(image.to_str().unwrap()).unwrap().stats(), UnpackStats::default());
+
+        // 吞吐量按 10^6 字节每秒计算，耗时为零时没有吞吐量
+        let stats = UnpackStats { bytes_written: 78_000_000, duration_ms: 2000, ..Default::default() };
+        assert_eq!(stats.throughput_mb_s(), Some(39.0));
+        assert_eq!(UnpackStats::default().throughput_mb_s(), None);
+        // 旧版本 JSON 没有 duration_ms 字段
+        let old: UnpackStats = serde_json::from_str(r#"{"files_written":1,"bytes_written":2,"skipped_special":0,"skipped_filtered":0,"skipped_existing":0}"#).unwrap();
+        assert_eq!(old.duration_ms, 0);
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/parameter.txt"), "CMDLINE: mtdparts=rk29xxnand:0x00010000@0x00002000(boot)\n").unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let clean = fs::read(&image).unwrap();
+        Unpacker::new().strict(true).unpack_reader(Cursor::new(clean.clone()), temp_dir.path().join("ok").to_str().unwrap()).unwrap();
+
+        // 末尾多出的字节使 header.length 与文件大小不符：默认只警告，strict 模式报错
+        let mut padded = clean.clone();
+        padded.extend_from_slice(&[0u8; 16]);
+        let out = temp_dir.path().join("out");
+        let lenient = UnpackOptions { allow_crc_mismatch: true, ..Default::default() };
+        assert!(unpack_reader_with_options(Cursor::new(padded.clone()), out.to_str().unwrap(), &lenient).is_ok());
+        let err = Unpacker::new().strict(true).unpack_reader(Cursor::new(padded), out.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("update_header.length")), "{:?}", err);
+
+        // CRC 错误在 strict 模式下同样报错
+        let mut corrupt = clean;
+        corrupt[0x1000] ^= 0xff;
+        let err = Unpacker::new().strict(true).unpack_reader(Cursor::new(corrupt), out.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::CrcMismatch { .. }));
+
+        // RKFW：未知芯片代码
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let mut data = fs::read(&rkfw).unwrap();
+        data[0x15] = 0xee;
+        assert!(unpack_reader_with_options(Cursor::new(data.clone()), out.to_str().unwrap(), &lenient).is_ok());
+        let err = Unpacker::new().strict(true).unpack_reader(Cursor::new(data), out.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("0xee")), "{:?}", err);
+    }
+
+    #[test]
+    fn test_preserve_mtime_and_file_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let output_dir = temp_dir.path().join("output");
+        let result = Unpacker::new()
+            .recursive(true)
+            .preserve_mtime(true)
+            .file_mode(Some(0o600))
+            .unpack(image.to_str().unwrap(), output_dir.to_str().unwrap())
+            .unwrap();
+        let UnpackResult::Rkfw(info) = result else { panic!("expected RKFW") };
+
+        // 所有输出文件（包括嵌套目录）的修改时间都等于 RKFW 的构建时间
+        let expected = std::time::UNIX_EPOCH + std::time::Duration::from_secs(info.timestamp as u64);
+        for path in [output_dir.join("BOOT"), output_dir.join("embedded-update/Image/boot.img")] {
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!(metadata.modified().unwrap(), expected, "{}", path.display());
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+            }
+        }
+
+        // RKAF 没有构建时间，使用调用方给出的时间，元数据文件也一样
+        let rkaf_dir = temp_dir.path().join("rkaf");
+        let unpacker = Unpacker::new().preserve_mtime(true).epoch(Some(1_600_000_000));
+        unpacker.unpack_reader(Cursor::new(build_synthetic_rkaf(&[("boot", b"boot")])), rkaf_dir.to_str().unwrap()).unwrap();
+        let expected = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        for name in ["Image/boot.img", "header-metadata.txt", "partition-metadata.txt", "manifest.json", "flash.sh"] {
+            assert_eq!(fs::metadata(rkaf_dir.join(name)).unwrap().modified().unwrap(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_flash_script() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/rootfs.img"), vec![0u8; 100]).unwrap();
+        fs::write(input_dir.join("package-file"), "parameter Image/parameter.txt\nboot Image/boot.img\nrootfs Image/rootfs.img\n").unwrap();
+        fs::write(
+            input_dir.join("partition-metadata.txt"),
+            "parameter,Image/parameter.txt,0x00000000,0x00000000,0x00000000,0x00000800,0x00000000\n\
+             rootfs,Image/rootfs.img,0xffffffff,0x00020000,0x00000000,0x00000800,0x00000000\n\
+             boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00001800,0x00000000\n",
+        ).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        unpack_file(image.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+
+        // 按 flash 偏移（扇区）排序，grow 分区注明写到 flash 末尾
+        let script = fs::read_to_string(output_dir.join("flash.sh")).unwrap();
+        let commands: Vec<&str> = script.lines().filter(|line| line.starts_with("rkdeveloptool")).collect();
+        assert_eq!(commands, [
+            "rkdeveloptool wl 0x00000000 'Image/parameter.txt' # parameter, 0x0 sectors",
+            "rkdeveloptool wl 0x00002000 'Image/boot.img' # boot, 0x10000 sectors",
+            "rkdeveloptool wl 0x00020000 'Image/rootfs.img' # rootfs, grows to the end of flash",
+        ]);
+
+        // 只列出实际提取的分区
+        let only_dir = temp_dir.path().join("only");
+        let options = UnpackOptions { only: vec!["boot".to_string()], naming: OutputNaming::ByName, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), only_dir.to_str().unwrap(), &options).unwrap();
+        let script = fs::read_to_string(only_dir.join("flash.sh")).unwrap();
+        assert_eq!(script.lines().filter(|line| line.starts_with("rkdeveloptool")).count(), 1);
+        assert!(script.contains("rkdeveloptool wl 0x00002000 'boot.img'"));
+    }
+
+    #[test]
+    fn test_partition_kind() {
+        assert_eq!(PartitionKind::from_name("parameter"), PartitionKind::Parameter);
+        assert_eq!(PartitionKind::from_name("uboot"), PartitionKind::Loader);
+        assert_eq!(PartitionKind::from_name("Boot_a"), PartitionKind::Boot);
+        assert_eq!(PartitionKind::from_name("resource"), PartitionKind::Kernel);
+        assert_eq!(PartitionKind::from_name("rootfs"), PartitionKind::System);
+        assert_eq!(PartitionKind::from_name("backup"), PartitionKind::Backup);
+        assert_eq!(PartitionKind::from_name("userdata"), PartitionKind::Other);
+        assert_eq!("Kernel".parse::<PartitionKind>().unwrap(), PartitionKind::Kernel);
+        assert!("firmware".parse::<PartitionKind>().is_err());
+
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let kinds: Vec<PartitionKind> = list_partitions(image.to_str().unwrap()).unwrap().iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, [PartitionKind::Loader, PartitionKind::Other, PartitionKind::Parameter, PartitionKind::Boot]);
+
+        // 可以按分区名覆盖推断结果
+        let input_dir = temp_dir.path().join("input");
+        let rkaf = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), rkaf.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let result = Unpacker::new()
+            .kind_override("boot", PartitionKind::Kernel)
+            .unpack(rkaf.to_str().unwrap(), temp_dir.path().join("output").to_str().unwrap())
+            .unwrap();
+        match result {
+            UnpackResult::Rkaf(info) => {
+                let boot = info.partitions.iter().find(|part| part.name == "boot").unwrap();
+                assert_eq!(boot.kind, PartitionKind::Kernel);
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+    }
+
+    #[test]
+    fn test_unpacker_builder() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let output_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let calls = std::cell::Cell::new(0);
+        let unpacker = Unpacker::new()
+            .chunk_size(1024)
+            .verify_crc(true)
+            .only(&["boot"])
+            .progress(|_, _| calls.set(calls.get() + 1));
+        assert_eq!(unpacker.options().buffer_size, Some(1024));
+        assert!(unpacker.options().strict_crc);
+        unpacker.unpack(image.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+        drop(unpacker);
+
+        // 只提取 boot，5000 字节按 1024 字节分块上报进度
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert!(!output_dir.join("Image/parameter.txt").exists());
+        assert!(calls.get() >= 5);
+
+        // CRC 损坏时 verify_crc(true) 报错
+        let mut data = fs::read(&image).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        let result = Unpacker::new()
+            .verify_crc(true)
+            .unpack_reader(Cursor::new(data), temp_dir.path().join("bad").to_str().unwrap());
+        assert!(matches!(result, Err(ApfError::CrcMismatch { .. })));
+    }
+
+    #[test]
+    fn test_unpack_split_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 按不等长切分为 .001/.002/.003，中间夹一个空分卷
+        let data = fs::read(&image).unwrap();
+        let chunks = [&data[..3000], &data[3000..3000], &data[3000..7000], &data[7000..]];
+        for (index, chunk) in chunks.iter().enumerate() {
+            fs::write(temp_dir.path().join(format!("update.img.{:03}", index + 1)), chunk).unwrap();
+        }
+        let first = temp_dir.path().join("update.img.001");
+        assert_eq!(split_parts(first.to_str().unwrap()).unwrap().len(), 4);
+        assert!(split_parts(image.to_str().unwrap()).is_none());
+        assert!(split_parts(temp_dir.path().join("update.img.002").to_str().unwrap()).is_none());
+
+        let mut reader = MultiFileReader::open_split(first.to_str().unwrap()).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut combined = Vec::new();
+        reader.read_to_end(&mut combined).unwrap();
+        assert_eq!(combined, data);
+        reader.seek(SeekFrom::Start(2990)).unwrap();
+        let mut across = [0u8; 20];
+        reader.read_exact(&mut across).unwrap();
+        assert_eq!(&across[..], &data[2990..3010]);
+
+        let whole_dir = temp_dir.path().join("whole");
+        let split_dir = temp_dir.path().join("split");
+        unpack_file(image.to_str().unwrap(), whole_dir.to_str().unwrap()).unwrap();
+        unpack_file(first.to_str().unwrap(), split_dir.to_str().unwrap()).unwrap();
+        assert_eq!(
+            fs::read(split_dir.join("Image/boot.img")).unwrap(),
+            fs::read(whole_dir.join("Image/boot.img")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_detect_alignment() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => {
+                let alignment = info.alignment.unwrap();
+                assert!(alignment.is_power_of_two() && alignment >= 512);
+                assert!(info.partitions.iter().all(|part| part.part_offset % alignment == 0));
+            }
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        }
+
+        let mut info = RkafInfo {
+            manufacturer: String::new(),
+            model: String::new(),
+            filesize: 0x10000,
+            declared_length: 0x10000,
+            actual_length: 0x10000,
+            length_matches: true,
+            manufacturer_raw: Vec::new(),
+            model_raw: Vec::new(),
+            version: FirmwareCode(0x01000000),
+            partitions: vec![
+                partition("misc", 0x0, 0x800, 0x800),
+                partition("boot", 0x800, 0x1800, 0x1800),
+                partition("system", 0x2000, 0x1000, 0x1000),
+            ],
+            reserved_parts: Vec::new(),
+            alignment: None,
+            trailing_bytes: 0,
+            sector_offsets: false,
+            stats: Default::default(),
+            errors: Vec::new(),
+        };
+        assert_eq!(detect_alignment(&info), Some(0x800));
+        assert!(validate_layout(&info, 0x10000).is_empty());
+
+        // 偏移量不按扇区对齐时给出警告
+        info.partitions[2].part_offset = 0x2010;
+        assert_eq!(detect_alignment(&info), Some(0x10));
+        assert!(validate_layout(&info, 0x10000).contains(&LayoutWarning::Misaligned { name: "system".to_string(), offset: 0x2010 }));
+
+        info.partitions.truncate(1);
+        assert_eq!(detect_alignment(&info), None);
+    }
+
+    #[test]
+    fn test_validate_parameter_layout() {
+        let mut boot = partition("boot", 0x800, 0x2000, 0x1800);
+        boot.flash_offset = 0x2000;
+        boot.flash_size = 0x4000;
+        let mut rootfs = partition("rootfs", 0x2800, 0x1000, 0x1000);
+        rootfs.flash_offset = 0x8000;
+        rootfs.flash_size = 0xffffffff;
+        let info = RkafInfo {
+            manufacturer: String::new(),
+            model: String::new(),
+            filesize: 0x4000,
+            declared_length: 0x4000,
+            actual_length: 0x4000,
+            length_matches: true,
+            manufacturer_raw: Vec::new(),
+            model_raw: Vec::new(),
+            version: FirmwareCode(0x01000000),
+            partitions: vec![boot, rootfs],
+            reserved_parts: Vec::new(),
+            alignment: None,
+            trailing_bytes: 0,
+            sector_offsets: false,
+            stats: Default::default(),
+            errors: Vec::new(),
+        };
+
+        // userdata 不在镜像中，忽略；rootfs 两边都是 grow 分区
+        let text = "CMDLINE: mtdparts=rk29xxnand:0x00004000@0x00002000(boot),-@0x00008000(rootfs:grow),-@0x00010000(userdata)\n";
+        let declared = parse_parameter(text.as_bytes()).unwrap();
+        assert!(validate_parameter_layout(&declared, &info).is_empty());
+
+        let text = "CMDLINE: mtdparts=rk29xxnand:0x00002000@0x00002000(boot),-@0x00009000(rootfs)\n";
+        let declared = parse_parameter(text.as_bytes()).unwrap();
+        assert_eq!(validate_parameter_layout(&declared, &info), vec![
+            LayoutWarning::ParameterMismatch { name: "boot".to_string(), field: "flash_size".to_string(), parameter: 0x2000, header: 0x4000 },
+            LayoutWarning::ParameterMismatch { name: "rootfs".to_string(), field: "flash_offset".to_string(), parameter: 0x9000, header: 0x8000 },
+        ]);
+
+        // parameter 中的 `-` 与分区表中的固定大小不一致
+        let text = "CMDLINE: mtdparts=rk29xxnand:-@0x00002000(boot),-@0x00008000(rootfs)\n";
+        let declared = parse_parameter(text.as_bytes()).unwrap();
+        assert_eq!(validate_parameter_layout(&declared, &info), vec![
+            LayoutWarning::ParameterMismatch { name: "boot".to_string(), field: "flash_size".to_string(), parameter: FLASH_SIZE_GROW, header: 0x4000 },
+        ]);
+    }
+
+    #[test]
+    fn test_list_partitions() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+
+        let partitions = list_partitions(image.to_str().unwrap()).unwrap();
+        let names: Vec<&str> = partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["BOOT", "embedded-update.img", "parameter", "boot"]);
+
+        // 嵌入分区的偏移量相对于外层文件
+        let mut file = File::open(&image).unwrap();
+        let mut boot = Vec::new();
+        extract_partition(&mut file, &partitions[3], &mut boot).unwrap();
+        assert_eq!(boot, vec![0xa5u8; 5000]);
+
+        // 只列出分区，不写任何文件
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+    }
+
+    // 每次 read 最多返回 7 字节，模拟管道或网络读取
+    struct ShortReader(Cursor<Vec<u8>>);
+
+    impl std::io::Read for ShortReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = std::cmp::min(buf.len(), 7);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl std::io::Seek for ShortReader {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    // 记录每次 SeekFrom::Start 的目标位置
+    struct SeekLog(Cursor<Vec<u8>>, std::rc::Rc<std::cell::RefCell<Vec<u64>>>);
+
+    impl std::io::Read for SeekLog {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl std::io::Seek for SeekLog {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            if let SeekFrom::Start(offset) = pos {
+                self.1.borrow_mut().push(offset);
+            }
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_unpack_preserve_order() {
+        // 分区表顺序与镜像中的存放顺序相反
+        let mut image = build_synthetic_rkaf(&[("uboot", &[1u8; 3000]), ("boot", &[2u8; 3000]), ("rootfs", &[3u8; 3000])]);
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image[..header_size]);
+        header.parts[..3].reverse();
+        image[..header_size].copy_from_slice(header.to_bytes());
+        let crc_offset = image.len() - 4;
+        let crc = rkcrc(&image[..crc_offset]);
+        image[crc_offset..].copy_from_slice(&crc.to_le_bytes());
+        let offsets: Vec<u64> = header.parts[..3].iter().map(|part| part.part_offset as u64).collect();
+        assert!(offsets[0] > offsets[2]);
+
+        let extraction_order = |preserve_order: bool, out: &Path| -> Vec<u64> {
+            let seeks = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            let options = UnpackOptions { preserve_order, checksums: Some(HashAlgorithm::Md5), ..Default::default() };
+            unpack_reader_with_options(SeekLog(Cursor::new(image.clone()), seeks.clone()), out.to_str().unwrap(), &options).unwrap();
+            // 每个分区先复制再计算校验和，取最后 6 次定位中的复制
+            let seeks: Vec<u64> = seeks.borrow().iter().copied().filter(|offset| offsets.contains(offset)).collect();
+            seeks[seeks.len() - 6..].iter().step_by(2).copied().collect()
+        };
+        let temp_dir = TempDir::new().unwrap();
+        let table_order = temp_dir.path().join("table");
+        let disk_order = temp_dir.path().join("disk");
+        assert_eq!(extraction_order(false, &table_order), offsets);
+        let mut ascending = offsets.clone();
+        ascending.sort();
+        assert_eq!(extraction_order(true, &disk_order), ascending);
+
+        // 元数据、flash.sh 和 checksums.txt 仍然按分区表顺序
+        for file in ["partition-metadata.txt", "manifest.json", "flash.sh", "checksums.txt", "Image/boot.img"] {
+            assert_eq!(fs::read(table_order.join(file)).unwrap(), fs::read(disk_order.join(file)).unwrap(), "{}", file);
+        }
+        let checksums = fs::read_to_string(disk_order.join("checksums.txt")).unwrap();
+        assert!(checksums.find("rootfs").unwrap() < checksums.find("uboot").unwrap());
+    }
+
+    #[test]
+    fn test_unpack_reader_short_reads() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let reader = ShortReader(Cursor::new(fs::read(&image).unwrap()));
+        unpack_reader(reader, out_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+
+        // 真正的 EOF 仍然报告为截断
+        let boot = list_partitions(image.to_str().unwrap()).unwrap()
+            .into_iter()
+            .find(|p| p.name == "boot")
+            .unwrap();
+        let data = fs::read(&image).unwrap();
+        let mut truncated = ShortReader(Cursor::new(data[..boot.part_offset as usize + 100].to_vec()));
+        let err = extract_partition(&mut truncated, &boot, &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ApfError::Truncated(_)), "unexpected error: {:?}", err);
+    }
+
+    #[test]
+    fn test_unpack_progress_callback() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("out");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 40000]).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let calls = std::cell::RefCell::new(Vec::new());
+        let progress = |done: u64, total: u64| calls.borrow_mut().push((done, total));
+        // 每读取一个缓冲区回调一次，4096 字节的缓冲区需要 10 次
+        let options = UnpackOptions { buffer_size: Some(4096), ..Default::default() };
+        unpack_file_with_progress(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options, Some(&progress), None).unwrap();
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 40000]);
+
+        let calls = calls.into_inner();
+        let boot: Vec<_> = calls.iter().filter(|(_, total)| *total == 40000).collect();
+        assert_eq!(boot.len(), 10);
+        assert!(boot.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(*boot.last().unwrap(), &(40000, 40000));
+        // 每个文件都以 (total, total) 结束
+        assert_eq!(calls.iter().filter(|(done, total)| done == total).count(), 2);
+    }
+
+    #[test]
+    fn test_unpack_cancel() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        fs::write(input_dir.join("Image/boot.img"), vec![0xa5u8; 40000]).unwrap();
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // boot 复制到一半时取消：返回 Cancelled，半截的 boot.img 被删除，continue_on_error 也不会继续
+        let cancel = std::sync::atomic::AtomicBool::new(false);
+        let cancel_midway = |done: u64, total: u64| {
+            if total == 40000 && done >= 20000 {
+                cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        };
+        for continue_on_error in [false, true] {
+            let out_dir = temp_dir.path().join(format!("out-{}", continue_on_error));
+            cancel.store(false, std::sync::atomic::Ordering::Relaxed);
+            let options = UnpackOptions { buffer_size: Some(4096), continue_on_error, ..Default::default() };
+            let err = unpack_file_with_progress(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options, Some(&cancel_midway), Some(&cancel)).unwrap_err();
+            assert!(matches!(err, ApfError::Cancelled), "unexpected error: {:?}", err);
+            assert!(out_dir.join("Image/parameter.txt").exists());
+            assert!(!out_dir.join("Image/boot.img").exists());
+        }
+
+        // 已设置的标志在第一个分区之前就停止，并行提取也一样
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        let out_dir = temp_dir.path().join("out-parallel");
+        let err = Unpacker::new().parallelism(2).cancel(&cancel).unpack(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Cancelled));
+        assert!(!out_dir.join("Image/parameter.txt").exists());
+    }
+
+    #[test]
+    fn test_unpack_partition_events() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let out_dir = temp_dir.path().join("out");
+        let statuses = |events: &[PartitionEvent]| events.iter().map(|event| (event.partition.name.clone(), event.status, event.written)).collect::<Vec<_>>();
+
+        // 未选中的分区标记为 not-selected，提取的分区带写入字节数和输出路径
+        let events = std::cell::RefCell::new(Vec::new());
+        Unpacker::new().only(&["boot"]).on_partition(|event| events.borrow_mut().push(event.clone())).unpack(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        let events = events.into_inner();
+        assert_eq!(statuses(&events), vec![("parameter".to_string(), PartitionStatus::NotSelected, 0), ("boot".to_string(), PartitionStatus::Extracted, 5000)]);
+        assert!(events[1].output.ends_with("Image/boot.img") && events[1].error.is_none());
+        let line = serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(line["name"], "boot");
+        assert_eq!(line["status"], "extracted");
+
+        // resume 时已有的文件为 already-extracted；并行提取的分区在复制完成后报告
+        let events = std::cell::RefCell::new(Vec::new());
+        Unpacker::new().resume(true).parallelism(2).on_partition(|event| events.borrow_mut().push(event.clone())).unpack(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        let mut events = statuses(&events.into_inner());
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(events, vec![("boot".to_string(), PartitionStatus::AlreadyExtracted, 0), ("parameter".to_string(), PartitionStatus::Extracted, 34)]);
+    }
+
+    #[test]
+    fn test_unpack_file_matches_reader() {
+        let temp_dir = TempDir::new().unwrap();
+        let odd: Vec<u8> = (0..70000u32).map(|i| (i * 13) as u8).collect();
+        let mut prefixed = b"DLHDR\x01\x02\x03".repeat(64);
+        prefixed.extend_from_slice(&build_synthetic_rkaf(&[("boot", &odd), ("misc", b"misc")]));
+        let image = temp_dir.path().join("update.img");
+        fs::write(&image, &prefixed).unwrap();
+
+        // 本地文件直接在文件之间复制，结果必须与通用 reader 相同，包括前缀之后的偏移
+        let options = UnpackOptions { scan: true, buffer_size: Some(4096), ..Default::default() };
+        let from_file = temp_dir.path().join("file");
+        let from_reader = temp_dir.path().join("reader");
+        unpack_file_with_options(image.to_str().unwrap(), from_file.to_str().unwrap(), &options).unwrap();
+        unpack_reader_with_options(Cursor::new(prefixed), from_reader.to_str().unwrap(), &options).unwrap();
+        for name in ["prefix.bin", "Image/boot.img", "Image/misc.img"] {
+            assert_eq!(fs::read(from_file.join(name)).unwrap(), fs::read(from_reader.join(name)).unwrap(), "{}", name);
+        }
+        assert_eq!(fs::read(from_file.join("Image/boot.img")).unwrap(), odd);
+    }
+
+    #[test]
+    fn test_unpack_parallel() {
+        let temp_dir = TempDir::new().unwrap();
+        let contents: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 3000 + i as usize * 5000]).collect();
+        let names = ["boot", "misc", "kernel", "system", "vendor", "oem"];
+        let partitions: Vec<(&str, &[u8])> = names.iter().zip(&contents).map(|(name, data)| (*name, data.as_slice())).collect();
+        let image = temp_dir.path().join("update.img");
+        fs::write(&image, build_synthetic_rkaf(&partitions)).unwrap();
+
+        let calls = std::sync::Mutex::new(Vec::new());
+        let progress = |done: u64, total: u64| calls.lock().unwrap().push((done, total));
+        let output_dir = temp_dir.path().join("output");
+        Unpacker::new()
+            .parallelism(4)
+            .checksums(Some(HashAlgorithm::Sha256))
+            .progress(progress)
+            .unpack(image.to_str().unwrap(), output_dir.to_str().unwrap())
+            .unwrap();
+        for (name, data) in names.iter().zip(&contents) {
+            assert_eq!(&fs::read(output_dir.join(format!("Image/{}.img", name))).unwrap(), data, "{}", name);
+        }
+        // 并行时每个分区完成后回调一次 (len, len)
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        let mut expected: Vec<_> = contents.iter().map(|data| (data.len() as u64, data.len() as u64)).collect();
+        expected.sort();
+        assert_eq!(calls, expected);
+        assert_eq!(fs::read_to_string(output_dir.join("checksums.txt")).unwrap().lines().count(), 6);
+
+        // 任一分区写入失败时返回错误
+        let blocked_dir = temp_dir.path().join("blocked");
+        fs::create_dir_all(blocked_dir.join("Image/system.img")).unwrap();
+        let err = Unpacker::new().parallelism(4).unpack(image.to_str().unwrap(), blocked_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Io(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(capabilities.signatures, ["RKFW", "RKAF"]);
+        assert_eq!(capabilities.chips, CHIP_FAMILIES.len());
+        // 列出的校验算法都能被解析
+        for algorithm in &capabilities.hash_algorithms {
+            algorithm.parse::<HashAlgorithm>().unwrap();
+        }
+        assert!(capabilities.features.contains(&("zstd", true)));
+    }
+
+    #[test]
+    fn test_chip_families() {
+        // 表中每个系列都能按名称打包，同一系列有多个代码时取第一个
+        for &(code, family) in CHIP_FAMILIES {
+            assert_eq!(chip_family(code), Some(family));
+            let packed = chip_name_to_code(family).unwrap();
+            assert_eq!(chip_family(packed), Some(family));
+        }
+        assert_eq!(chip_family(0x91), Some("RK3399"));
+        assert_eq!(chip_name_to_code("rk3399").unwrap(), 0x90);
+        assert_eq!(chip_name_to_code("RK29").unwrap(), 0x50);
+        assert_eq!(chip_name_to_code("rk30xx").unwrap(), 0x60);
+        assert_eq!(chip_family(0x00), None);
+        assert_eq!(all_chips(), CHIP_FAMILIES);
+        assert!(all_chips().contains(&(0xa3, "RK3528")));
+        assert!(matches!(chip_name_to_code("RK9999"), Err(ApfError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_save_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let rkfw_data = fs::read(&rkfw).unwrap();
+        let output_dir = temp_dir.path().join("output");
+        Unpacker::new().recursive(true).save_header(true).unpack(rkfw.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap();
+
+        // RKFW 头 0x66 字节，内嵌 RKAF 的 UpdateHeader 写入 embedded-update/header.bin
+        assert_eq!(fs::read(output_dir.join("header.bin")).unwrap(), &rkfw_data[..0x66]);
+        let UnpackResult::Rkfw(info) = inspect_file(rkfw.to_str().unwrap()).unwrap() else { panic!("expected RKFW") };
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let update_offset = info.update_offset as usize;
+        let embedded_header = fs::read(output_dir.join("embedded-update/header.bin")).unwrap();
+        assert_eq!(embedded_header.len(), header_size);
+        assert_eq!(embedded_header, &rkfw_data[update_offset..update_offset + header_size]);
+
+        // 默认不写，tar 输出同样支持
+        let plain_dir = temp_dir.path().join("plain");
+        unpack_file(rkfw.to_str().unwrap(), plain_dir.to_str().unwrap()).unwrap();
+        assert!(!plain_dir.join("header.bin").exists());
+        let tar_path = temp_dir.path().join("out.tar");
+        let options = UnpackOptions { save_header: true, ..Default::default() };
+        unpack_to_tar_with_options(rkfw.to_str().unwrap(), tar_path.to_str().unwrap(), &options).unwrap();
+        let entries = read_tar(&fs::read(&tar_path).unwrap());
+        assert_eq!(entries.iter().find(|(name, ..)| name == "header.bin").unwrap().2, &rkfw_data[..0x66]);
+    }
+
+    // 把 RKFW 镜像作为 embedded-update.img 再包一层 RKFW，沿用内层的头部与 BOOT
+    fn wrap_rkfw(dir: &Path, inner: &Path, name: &str) -> PathBuf {
+        let inner = fs::read(inner).unwrap();
+        let boot = &inner[0x66..0x66 + 300];
+        let mut data = inner[..0x66].to_vec();
+        data[0x19..0x1d].copy_from_slice(&0x66u32.to_le_bytes());
+        data[0x1d..0x21].copy_from_slice(&(boot.len() as u32).to_le_bytes());
+        data[0x21..0x25].copy_from_slice(&(0x66u32 + boot.len() as u32).to_le_bytes());
+        data[0x25..0x29].copy_from_slice(&(inner.len() as u32).to_le_bytes());
+        data.extend_from_slice(boot);
+        data.extend_from_slice(&inner);
+        let digest = format!("{:x}", md5::compute(&data));
+        data.extend_from_slice(digest.as_bytes());
+        let image = dir.join(format!("{}.img", name));
+        fs::write(&image, data).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_unpack_nested_rkfw() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner = build_rkfw_image(temp_dir.path());
+        let outer = wrap_rkfw(temp_dir.path(), &inner, "outer");
+        let outer_path = outer.to_str().unwrap();
+
+        // 递归解包到两层 embedded-update 目录
+        let output_dir = temp_dir.path().join("output");
+        match Unpacker::new().recursive(true).unpack(outer_path, output_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                let nested = info.nested.expect("nested RKFW");
+                assert!(nested.nested.is_none());
+                assert_eq!(nested.boot_size, 300);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(fs::read(output_dir.join("BOOT")).unwrap(), fs::read(output_dir.join("embedded-update/BOOT")).unwrap());
+        let nested_dir = output_dir.join("embedded-update/embedded-update");
+        assert_eq!(fs::read(nested_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+
+        // 不递归时照原样写出内层镜像
+        let flat_dir = temp_dir.path().join("flat");
+        unpack_file(outer_path, flat_dir.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(flat_dir.join("embedded-update.img")).unwrap(), fs::read(&inner).unwrap());
+
+        // 严格模式把嵌套当作错误；只认一层 RKFW 的接口给出明确的提示
+        let strict_dir = temp_dir.path().join("strict");
+        assert!(Unpacker::new().strict(true).unpack(outer_path, strict_dir.to_str().unwrap()).is_err());
+        match Unpacker::new().recursive(true).into_iter_partitions(outer_path) {
+            Err(ApfError::Invalid(message)) => assert!(message.contains("--recursive")),
+            _ => panic!("expected ApfError::Invalid"),
+        }
+        assert!(matches!(inspect_file(outer_path), Err(ApfError::Invalid(_))));
+
+        // 超过嵌套层数上限
+        let mut image = outer;
+        for level in 0..3 {
+            image = wrap_rkfw(temp_dir.path(), &image, &format!("level{}", level));
+        }
+        let deep_dir = temp_dir.path().join("deep");
+        match unpack_file(image.to_str().unwrap(), deep_dir.to_str().unwrap()) {
+            Err(ApfError::Invalid(message)) => assert!(message.contains("nesting level 5")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        let unpack_trailing = |image: &Path, options: &UnpackOptions| {
+            let output_dir = TempDir::new().unwrap();
+            match unpack_file_with_options(image.to_str().unwrap(), output_dir.path().to_str().unwrap(), options)? {
+                UnpackResult::Rkaf(info) => Ok(info.trailing_bytes),
+                UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+            }
+        };
+        let before = unpack_trailing(&image, &UnpackOptions::default()).unwrap();
+
+        // 在 RKCRC 之前插入 16 个零字节
+        let mut data = fs::read(&image).unwrap();
+        let crc_offset = data.len() - 4;
+        data.splice(crc_offset..crc_offset, [0u8; 16]);
+        data[4..8].copy_from_slice(&((crc_offset + 16) as u32).to_le_bytes());
+        fs::write(&image, &data).unwrap();
+        update_crc(image.to_str().unwrap()).unwrap();
+        let strict = UnpackOptions { strict_padding: true, ..Default::default() };
+        assert_eq!(unpack_trailing(&image, &strict).unwrap(), before + 16);
+
+        // 非零的尾部数据只警告，strict_padding 时报错
+        let mut data = fs::read(&image).unwrap();
+        data[crc_offset + 8] = 0x5a;
+        fs::write(&image, &data).unwrap();
+        update_crc(image.to_str().unwrap()).unwrap();
+        assert_eq!(unpack_trailing(&image, &UnpackOptions::default()).unwrap(), before + 16);
+        assert!(matches!(unpack_trailing(&image, &strict), Err(ApfError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_continue_on_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc"), ("system", &[0x5au8; 3000])]);
+
+        // 把 misc 的偏移改到文件之外
+        let header_size = std::mem::size_of::<UpdateHeader>();
+        let mut header = *UpdateHeader::from_bytes(&image[..header_size]);
+        header.parts[1].part_offset = 0x100000;
+        let mut image = [header.to_bytes(), &image[header_size..]].concat();
+        let len = image.len() - 4;
+        let crc = rkcrc(&image[..len]);
+        image[len..].copy_from_slice(&crc.to_le_bytes());
+
+        let failed_dir = temp_dir.path().join("failed");
+        assert!(unpack_reader(Cursor::new(image.clone()), failed_dir.to_str().unwrap()).is_err());
+
+        // 跳过失败的分区，其余分区照常解包
+        let output_dir = temp_dir.path().join("output");
+        let result = Unpacker::new().continue_on_error(true).unpack_reader(Cursor::new(image), output_dir.to_str().unwrap()).unwrap();
+        let paths: Vec<&str> = result.errors().iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["Image/misc.img"]);
+        assert_eq!(result.stats().files_written, 2);
+        assert_eq!(fs::read(output_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert_eq!(fs::read(output_dir.join("Image/system.img")).unwrap(), vec![0x5au8; 3000]);
+        assert!(!fs::read_to_string(output_dir.join("flash.sh")).unwrap().contains("misc"));
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["errors"][0][0], "Image/misc.img");
+    }
+
+    #[test]
+    fn test_declared_length() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000])]);
+        let unpack_info = |image: &[u8], options: &UnpackOptions| {
+            let output_dir = TempDir::new().unwrap();
+            match unpack_reader_with_options(Cursor::new(image.to_vec()), output_dir.path().to_str().unwrap(), options)? {
+                UnpackResult::Rkaf(info) => Ok::<_, ApfError>(info),
+                UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+            }
+        };
+        let info = unpack_info(&image, &UnpackOptions::default()).unwrap();
+        assert!(info.length_matches);
+        assert_eq!(info.declared_length, image.len() as u64);
+        assert_eq!(info.actual_length, image.len() as u64);
+
+        // 文件末尾多出 8 个字节：只警告，但字段给出两个长度（末尾不再是 CRC，放行 CRC 不符）
+        let mut longer = image.clone();
+        longer.extend_from_slice(&[0u8; 8]);
+        let info = unpack_info(&longer, &UnpackOptions { allow_crc_mismatch: true, ..Default::default() }).unwrap();
+        assert!(!info.length_matches);
+        assert_eq!(info.declared_length, image.len() as u64);
+        assert_eq!(info.actual_length, longer.len() as u64);
+        assert!(unpack_info(&longer, &UnpackOptions { strict: true, ..Default::default() }).is_err());
+
+        // verify_rkaf_crc 照旧只比较 CRC
+        let path = temp_dir.path().join("update.img");
+        fs::write(&path, &image).unwrap();
+        assert!(verify_rkaf_crc(path.to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_require_empty_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000])]);
+        let output_dir = temp_dir.path().join("output");
+        let output = output_dir.to_str().unwrap();
+
+        // 目录不存在或为空时照常解包
+        let unpacker = Unpacker::new().require_empty_dir(true);
+        unpacker.unpack_reader(Cursor::new(image.clone()), output).unwrap();
+
+        // 非空目录报错且不写入任何文件，--force 时照常解包
+        fs::write(output_dir.join("other.img"), b"other").unwrap();
+        fs::remove_file(output_dir.join("Image/boot.img")).unwrap();
+        match unpacker.unpack_reader(Cursor::new(image.clone()), output) {
+            Err(ApfError::Invalid(message)) => assert!(message.contains("not empty")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!output_dir.join("Image/boot.img").exists());
+        Unpacker::new().require_empty_dir(true).force(true).unpack_reader(Cursor::new(image.clone()), output).unwrap();
+        assert!(output_dir.join("Image/boot.img").exists());
+
+        // 默认不检查
+        unpack_reader(Cursor::new(image), output).unwrap();
+    }
+
+    #[test]
+    fn test_verify_against_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_synthetic_rkaf(&[("boot", &[0xa5u8; 5000]), ("misc", b"misc")]);
+        let output_dir = temp_dir.path().join("output");
+        let output = output_dir.to_str().unwrap();
+        let options = UnpackOptions { checksums: Some(HashAlgorithm::Sha256), ..Default::default() };
+        unpack_reader_with_options(Cursor::new(image.clone()), output, &options).unwrap();
+
+        // 解包生成的 checksums.txt 本身就是合法的清单
+        let checksums = output_dir.join("checksums.txt");
+        let results = verify_against_manifest(output, checksums.to_str().unwrap()).unwrap();
+        assert_eq!(results, [("Image/boot.img".to_string(), true), ("Image/misc.img".to_string(), true)]);
+
+        // md5sum 的二进制模式、被篡改的文件和缺失的文件
+        let manifest = temp_dir.path().join("md5sums");
+        fs::write(
+            &manifest,
+            format!("{:x} *Image/misc.img\n{:x}  ./Image/boot.img\n{:x}  Image/missing.img\n", md5::compute(b"misc"), md5::compute(b"BOOT"), md5::compute(b"")),
+        ).unwrap();
+        let results = verify_against_manifest(output, manifest.to_str().unwrap()).unwrap();
+        let matched: Vec<bool> = results.iter().map(|(_, matches)| *matches).collect();
+        assert_eq!(matched, [true, false, false]);
+
+        fs::write(&manifest, "not-a-digest  Image/boot.img\n").unwrap();
+        assert!(matches!(verify_against_manifest(output, manifest.to_str().unwrap()), Err(ApfError::Invalid(_))));
+
+        // 解包时校验，不匹配则失败
+        let expected = fs::read_to_string(&checksums).unwrap();
+        fs::write(&manifest, &expected).unwrap();
+        let checked_dir = temp_dir.path().join("checked");
+        Unpacker::new().verify_manifest(manifest.to_str()).unpack_reader(Cursor::new(image.clone()), checked_dir.to_str().unwrap()).unwrap();
+        fs::write(&manifest, expected.replacen("Image/misc.img", "Image/boot.img", 1)).unwrap();
+        assert!(Unpacker::new().verify_manifest(manifest.to_str()).unpack_reader(Cursor::new(image), checked_dir.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_carve_embedded_rkaf() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let carved = temp_dir.path().join("carved.img");
+
+        // 只写出内嵌的 RKAF，内容与 update_offset/update_size 的区间一致
+        carve_embedded_rkaf(image.to_str().unwrap(), carved.to_str().unwrap()).unwrap();
+        let info = match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => info,
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        };
+        let data = fs::read(&image).unwrap();
+        let range = info.update_offset as usize..(info.update_offset + info.update_size) as usize;
+        assert_eq!(fs::read(&carved).unwrap(), &data[range]);
+        assert!(verify_rkaf_crc(carved.to_str().unwrap()).unwrap());
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().filter(|entry| entry.as_ref().unwrap().file_name() == "BOOT").count(), 0);
+
+        // RKAF 输入没有可以去掉的外壳
+        let again = temp_dir.path().join("again.img");
+        assert!(matches!(carve_embedded_rkaf(carved.to_str().unwrap(), again.to_str().unwrap()), Err(ApfError::Invalid(_))));
+        assert!(!again.exists());
+    }
+
+    #[test]
+    fn test_infer_chip_family_from_boot() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+
+        // 未知的芯片代码，BOOT 中带有 SoC 名称
+        let mut data = fs::read(&image).unwrap();
+        let len = data.len() - 32;
+        data[0x15] = 0xee;
+        data[0x66 + 16..0x66 + 30].copy_from_slice(b"rk3588s_loader");
+        let digest = format!("{:x}", md5::compute(&data[..len]));
+        data[len..].copy_from_slice(digest.as_bytes());
+        fs::write(&image, &data).unwrap();
+
+        match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.chip_family, "RK3588S");
+                assert!(info.chip_family_inferred);
+                assert_eq!(info.chip_code, 0xee);
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+
+        // 已知代码或手动映射时不推断
+        let output_dir = temp_dir.path().join("output");
+        match Unpacker::new().chip_override(0xee, "Vendor").unpack(image.to_str().unwrap(), output_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.chip_family, "Vendor");
+                assert!(!info.chip_family_inferred);
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+    }
+
+    #[test]
+    fn test_unpack_rkfw_boot_selection() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = build_rkfw_image(temp_dir.path());
+        let image = image.to_str().unwrap();
+
+        // 不写 BOOT，但仍报告其位置
+        let no_boot_dir = temp_dir.path().join("no-boot");
+        match Unpacker::new().extract_boot(false).unpack(image, no_boot_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => {
+                assert_eq!(info.boot_size, 300);
+                assert_eq!(info.stats.files_written, 1);
+            }
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        }
+        assert!(!no_boot_dir.join("BOOT").exists());
+        assert!(no_boot_dir.join("embedded-update.img").exists());
+
+        // 只写 BOOT
+        let boot_dir = temp_dir.path().join("boot-only");
+        let result = Unpacker::new().boot_only(true).unpack(image, boot_dir.to_str().unwrap()).unwrap();
+        assert_eq!(result.stats().files_written, 1);
+        assert_eq!(fs::read(boot_dir.join("BOOT")).unwrap().len(), 300);
+        assert!(!boot_dir.join("embedded-update.img").exists());
+        let tar_path = temp_dir.path().join("boot.tar");
+        let options = UnpackOptions { boot_only: true, ..Default::default() };
+        unpack_to_tar_with_options(image, tar_path.to_str().unwrap(), &options).unwrap();
+        let names: Vec<String> = read_tar(&fs::read(&tar_path).unwrap()).into_iter().map(|(name, ..)| name).collect();
+        assert!(names.contains(&"BOOT".to_string()));
+        assert!(!names.contains(&"embedded-update.img".to_string()));
+
+        // RKAF 没有 BOOT；两个选项不能同时使用
+        let rkaf_dir = temp_dir.path().join("rkaf");
+        let rkaf = build_synthetic_rkaf(&[("boot", b"boot")]);
+        assert!(Unpacker::new().boot_only(true).unpack_reader(Cursor::new(rkaf), rkaf_dir.to_str().unwrap()).is_err());
+        assert!(Unpacker::new().boot_only(true).extract_boot(false).unpack(image, boot_dir.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_mutated_images_do_not_panic() {
+        // 与 fuzz/fuzz_targets/parse_image.rs 相同的入口，用固定种子的随机改写代替 libFuzzer
+        let temp_dir = TempDir::new().unwrap();
+        let seeds = [
+            fs::read(build_rkfw_image(temp_dir.path())).unwrap(),
+            build_synthetic_rkaf(&[("boot", &[0x42; 3000]), ("misc", b"misc")]),
+        ];
+        let case_path = temp_dir.path().join("case.img");
+        let dst = temp_dir.path().join("out");
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        for i in 0..400 {
+            let mut image = seeds[i % seeds.len()].clone();
+            for _ in 0..1 + next() % 6 {
+                // 一半的改写落在头部
+                let limit = if next() % 2 == 0 { image.len().min(2400) } else { image.len() };
+                let pos = next() % limit;
+                match next() % 3 {
+                    0 => image[pos] = next() as u8,
+                    1 => image[pos] = 0xff,
+                    _ => image.truncate(pos.max(4)),
+                }
+            }
+            fs::write(&case_path, &image).unwrap();
+            let _ = inspect_file(case_path.to_str().unwrap());
+            let _ = list_partitions(case_path.to_str().unwrap());
+            let _ = Unpacker::new()
+                .recursive(true)
+                .split_boot(true)
+                .split_resource(true)
+                .unpack_reader(Cursor::new(image), dst.to_str().unwrap());
+            let _ = fs::remove_dir_all(&dst);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "fuse")]
+    #[ignore] // 需要 /dev/fuse 以及挂载权限
+    fn test_mount_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let image = temp_dir.path().join("update.img");
+        let mountpoint = temp_dir.path().join("mnt");
+        fs::create_dir_all(&mountpoint).unwrap();
+        fs::write(&image, build_synthetic_rkaf(&[("boot", &[0x42; 3000]), ("misc", b"misc")])).unwrap();
+
+        let (image_path, mount_path) = (image.to_str().unwrap().to_string(), mountpoint.to_str().unwrap().to_string());
+        let session = std::thread::spawn(move || afptool_rs::mount_image(&image_path, &mount_path, &UnpackOptions::default()));
+        for _ in 0..50 {
+            if mountpoint.join("boot.img").exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let mut names: Vec<String> = fs::read_dir(&mountpoint).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["boot.img", "misc.img"]);
+        assert_eq!(fs::read(mountpoint.join("boot.img")).unwrap(), vec![0x42; 3000]);
+        // 读取不会越过分区末尾
+        let mut file = File::open(mountpoint.join("misc.img")).unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, b"sc");
+        assert!(fs::write(mountpoint.join("misc.img"), b"x").is_err());
+        drop(file);
+
+        assert!(std::process::Command::new("umount").arg(&mountpoint).status().unwrap().success());
+        session.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 bytes");
+        assert_eq!(human_size(1023), "1023 bytes");
+        assert_eq!(human_size(2048), "2.0 KiB (2048 bytes)");
+        assert_eq!(human_size(123456789), "117.7 MiB (123456789 bytes)");
+        assert_eq!(human_size(3 << 30), "3.0 GiB (3221225472 bytes)");
+    }
+
+    #[test]
+    fn test_parse_boot() {
+        let boot = build_boot_blob(&[
+            (0, "DDR", vec![0x11; 100]),
+            (1, "usbplug", vec![0x22; 200]),
+            (2, "FlashData", vec![0x33; 300]),
+            (2, "FlashBoot", vec![0x44; 400]),
+        ]);
+
+        let entries = parse_boot(&boot).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["DDR", "usbplug", "FlashData", "FlashBoot"]);
+        assert_eq!(entries[0].kind, BootEntryKind::Code471);
+        assert_eq!(entries[1].kind, BootEntryKind::Code472);
+        assert_eq!(entries[3].kind, BootEntryKind::Loader);
+        assert_eq!(entries[1].delay, 1);
+
+        let flash_data = &entries[2];
+        let start = flash_data.offset as usize;
+        assert_eq!(&boot[start..start + flash_data.size as usize], &[0x33; 300][..]);
+
+        assert!(matches!(parse_boot(&boot[..0x80]), Err(ApfError::Truncated(_))));
+        assert!(matches!(parse_boot(&[0u8; 0x66]), Err(ApfError::BadMagic)));
+    }
+
+    #[test]
+    fn test_unpack_rkfw_split_boot() {
+        let temp_dir = TempDir::new().unwrap();
+        build_rkfw_image(temp_dir.path());
+        let rkfw_dir = temp_dir.path().join("rkfw");
+        let boot = build_boot_blob(&[
+            (0, "DDR", vec![0x11; 100]),
+            (1, "usbplug", vec![0x22; 200]),
+            (2, "FlashData", vec![0x33; 300]),
+            (2, "FlashBoot", vec![0x44; 400]),
+        ]);
+        fs::write(rkfw_dir.join("BOOT"), &boot).unwrap();
+        let image = temp_dir.path().join("split.img");
+        pack_rkfw(rkfw_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3562", "1.0.0", 1762435994, "0x02000000").unwrap();
+
+        let out_dir = temp_dir.path().join("out");
+        let options = UnpackOptions { split_boot: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+
+        // 整个 BOOT 仍然写出，各个阶段写入 BOOT.d/
+        assert_eq!(fs::read(out_dir.join("BOOT")).unwrap(), boot);
+        assert_eq!(fs::read(out_dir.join("BOOT.d/DDR")).unwrap(), vec![0x11; 100]);
+        assert_eq!(fs::read(out_dir.join("BOOT.d/usbplug")).unwrap(), vec![0x22; 200]);
+        assert_eq!(fs::read(out_dir.join("BOOT.d/FlashData")).unwrap(), vec![0x33; 300]);
+        assert_eq!(fs::read(out_dir.join("BOOT.d/FlashBoot")).unwrap(), vec![0x44; 400]);
+
+        let plain_dir = temp_dir.path().join("plain");
+        unpack_file(image.to_str().unwrap(), plain_dir.to_str().unwrap()).unwrap();
+        assert!(!plain_dir.join("BOOT.d").exists());
+    }
+
+    #[test]
+    fn test_rc4_rk() {
+        // 已知密钥流（与标准 ARC4 实现对照）
+        let mut data = [0u8; 16];
+        rc4_rk(&mut data);
+        assert_eq!(data, [
+            0x6e, 0x26, 0x2c, 0xf3, 0xbe, 0x9f, 0x9d, 0x51, 0xea, 0x30, 0x34, 0xce, 0x20, 0x51, 0x1f, 0x98,
+        ]);
+
+        // RC4 是对称的，加密两次恢复原文
+        let plain: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut round_trip = plain.clone();
+        rc4_rk(&mut round_trip);
+        assert_ne!(round_trip, plain);
+        rc4_rk(&mut round_trip);
+        assert_eq!(round_trip, plain);
+
+        // 解包时解密 471/472，FlashData 保持原样
+        let mut ddr = vec![0x11u8; 100];
+        rc4_rk(&mut ddr);
+        let temp_dir = TempDir::new().unwrap();
+        build_rkfw_image(temp_dir.path());
+        let rkfw_dir = temp_dir.path().join("rkfw");
+        fs::write(rkfw_dir.join("BOOT"), build_boot_blob(&[(0, "DDR", ddr), (2, "FlashData", vec![0x33; 300])])).unwrap();
+        let image = temp_dir.path().join("encrypted.img");
+        pack_rkfw(rkfw_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3562", "1.0.0", 1762435994, "0x02000000").unwrap();
+
+        let out_dir = temp_dir.path().join("out");
+        let options = UnpackOptions { split_boot: true, decrypt_loader: true, ..Default::default() };
+        unpack_file_with_options(image.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap();
+        assert_eq!(fs::read(out_dir.join("BOOT.d/DDR")).unwrap(), vec![0x11; 100]);
+        assert_eq!(fs::read(out_dir.join("BOOT.d/FlashData")).unwrap(), vec![0x33; 300]);
+    }
+
+    #[test]
+    fn test_unpack_malformed_rkfw() {
+        let temp_dir = TempDir::new().unwrap();
+        let out_dir = temp_dir.path().join("output");
+
+        // 32 字节的垃圾数据，头部不完整
+        let mut garbage = vec![0xeeu8; 32];
+        garbage[0..4].copy_from_slice(RKFW_SIGNATURE);
+        let garbage_path = temp_dir.path().join("garbage.img");
+        fs::write(&garbage_path, &garbage).unwrap();
+        let err = unpack_file(garbage_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Truncated(_)));
+
+        // 嵌入式更新映像的偏移量超出文件范围
+        let mut data = create_mock_rkfw();
+        data[0x10] = 1;
+        data[0x11] = 1;
+        data[0x21..0x25].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        let bad_offset_path = temp_dir.path().join("bad_offset.img");
+        fs::write(&bad_offset_path, &data).unwrap();
+        let err = unpack_file(bad_offset_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Truncated(_)));
+        assert!(err.to_string().contains("out of bounds"));
+
+        // 未知签名与错误的头部魔数
+        let unknown_path = temp_dir.path().join("unknown.img");
+        fs::write(&unknown_path, b"ABCDxxxx").unwrap();
+        let err = unpack_file(unknown_path.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::UnknownSignature(sig) if &sig == b"ABCD"));
+        let zeros_path = temp_dir.path().join("zeros.img");
+        fs::write(&zeros_path, vec![0u8; 4096]).unwrap();
+        assert!(matches!(read_rkaf_header(zeros_path.to_str().unwrap()), Err(ApfError::BadMagic)));
+        assert!(matches!(unpack_file("/nonexistent/update.img", out_dir.to_str().unwrap()), Err(ApfError::Io(_))));
+
+        // 开发版镜像中日期字段常为全零，strict 时返回 InvalidDate
+        let zero_date_path = temp_dir.path().join("zero_date.img");
+        fs::write(&zero_date_path, create_mock_rkfw()).unwrap();
+        let strict = UnpackOptions { strict: true, ..Default::default() };
+        let err = unpack_file_with_options(zero_date_path.to_str().unwrap(), out_dir.to_str().unwrap(), &strict).unwrap_err();
+        assert!(matches!(err, ApfError::InvalidDate));
+    }
+
+    #[test]
+    fn test_unpack_reader_from_memory() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        // 从内存中的 Cursor 解包
+        let cursor = Cursor::new(fs::read(&image).unwrap());
+        let result = unpack_reader(cursor, out_dir.to_str().unwrap()).unwrap();
+        assert!(matches!(result, UnpackResult::Rkaf(ref info) if info.partitions.len() == 2));
+        assert_eq!(fs::read(out_dir.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+    }
+
+    #[test]
+    fn test_unpack_to_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let (result, partitions) = unpack_to_map(image.to_str().unwrap()).unwrap();
+        assert!(matches!(result, UnpackResult::Rkaf(_)));
+        let mut keys: Vec<_> = partitions.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["Image/boot.img", "Image/parameter.txt"]);
+        assert_eq!(partitions["Image/boot.img"], vec![0xa5u8; 5000]);
+
+        // RKFW 镜像只包含 BOOT 和内嵌的 update.img
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let (result, partitions) = unpack_to_map(rkfw.to_str().unwrap()).unwrap();
+        assert!(matches!(result, UnpackResult::Rkfw(_)));
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(&partitions["BOOT"][..4], b"BOOT");
+        assert_eq!(partitions["embedded-update.img"], fs::read(temp_dir.path().join("rkfw/embedded-update.img")).unwrap());
+    }
+
+    #[test]
+    fn test_diff_images() {
+        let temp_dir = TempDir::new().unwrap();
+        let a_dir = temp_dir.path().join("a");
+        let b_dir = temp_dir.path().join("b");
+        let a_image = temp_dir.path().join("a.img");
+        let b_image = temp_dir.path().join("b.img");
+        create_pack_input(&a_dir);
+        pack_rkaf(a_dir.to_str().unwrap(), a_image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+        assert_eq!(diff_images(a_image.to_str().unwrap(), a_image.to_str().unwrap()).unwrap(), vec![
+            PartitionDiff::Identical { name: "parameter".to_string(), size: 34 },
+            PartitionDiff::Identical { name: "boot".to_string(), size: 5000 },
+        ]);
+
+        // b 修改了 boot、去掉 parameter、新增 misc，并换了型号
+        create_pack_input(&b_dir);
+        fs::write(b_dir.join("package-file"), "boot Image/boot.img\nmisc Image/misc.img\n").unwrap();
+        fs::write(b_dir.join("Image/boot.img"), vec![0x5au8; 6000]).unwrap();
+        fs::write(b_dir.join("Image/misc.img"), vec![0u8; 100]).unwrap();
+        fs::write(
+            b_dir.join("partition-metadata.txt"),
+            "boot,Image/boot.img,0x00010000,0x00002000,0x00000000,0x00001800,0x00000000\n\
+             misc,Image/misc.img,0x00002000,0x00012000,0x00000000,0x00000200,0x00000000\n",
+        ).unwrap();
+        pack_rkaf(b_dir.to_str().unwrap(), b_image.to_str().unwrap(), "RK3326-B", "RK3326").unwrap();
+
+        let diffs = diff_images(a_image.to_str().unwrap(), b_image.to_str().unwrap()).unwrap();
+        assert_eq!(diffs.len(), 4);
+        assert!(matches!(&diffs[0], PartitionDiff::Header { field, b, .. } if field == "model" && b.trim() == "RK3326-B"));
+        assert_eq!(diffs[1], PartitionDiff::Removed { name: "parameter".to_string(), size: 34 });
+        assert!(matches!(&diffs[2], PartitionDiff::Changed { name, a_size: 5000, b_size: 6000, a_sha256, b_sha256 } if name == "boot" && a_sha256 != b_sha256));
+        assert_eq!(diffs[3], PartitionDiff::Added { name: "misc".to_string(), size: 100 });
+
+        // RKFW 与 RKAF 比较时报告格式差异
+        let rkfw = build_rkfw_image(temp_dir.path());
+        let diffs = diff_images(rkfw.to_str().unwrap(), a_image.to_str().unwrap()).unwrap();
+        assert!(diffs.contains(&PartitionDiff::Header { field: "format".to_string(), a: "rkfw".to_string(), b: "rkaf".to_string() }));
+        assert!(diffs.contains(&PartitionDiff::Removed { name: "BOOT".to_string(), size: 300 }));
+    }
+
+    #[test]
+    fn test_extract_partition() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let info = match inspect_file(image.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkaf(info) => info,
+            UnpackResult::Rkfw(_) => panic!("expected RKAF"),
+        };
+        let boot = info.partitions.iter().find(|p| p.name == "boot").unwrap();
+
+        let mut file = File::open(&image).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(extract_partition(&mut file, boot, &mut out).unwrap(), 5000);
+        assert_eq!(out, vec![0xa5u8; 5000]);
+
+        // part_byte_count 超出文件剩余长度时应返回错误
+        let mut oversized = boot.clone();
+        oversized.part_byte_count = 0x10_0000;
+        let mut out = Vec::new();
+        assert!(oversized.extract_to_writer(&mut file, &mut out).is_err());
+    }
+
+    #[test]
+    fn test_unpack_file_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let input_dir = temp_dir.path().join("input");
+        let image = temp_dir.path().join("update.img");
+        let out_dir = temp_dir.path().join("output");
+        create_pack_input(&input_dir);
+        pack_rkaf(input_dir.to_str().unwrap(), image.to_str().unwrap(), "RK3326", "RK3326").unwrap();
+
+        let json = unpack_file_json(image.to_str().unwrap(), out_dir.to_str().unwrap()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["format"], "rkaf");
+        assert_eq!(value["partitions"][1]["name"], "boot");
+        // 偏移量以十进制整数输出
+        assert_eq!(value["partitions"][1]["flash_offset"], 0x2000);
+
+        // to_json 与 unpack_file_json 输出一致
+        let result = inspect_file(image.to_str().unwrap()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&result.to_json().unwrap()).unwrap();
+        assert_eq!(value["partitions"][1]["part_offset"], result.as_rkaf().unwrap().partitions[1].part_offset);
+    }
+
+    #[test]
+    fn test_repack_rkfw_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let unpack_dir = temp_dir.path().join("unpacked");
+        let repacked = temp_dir.path().join("repacked.img");
+        let original = build_rkfw_image(temp_dir.path());
+
+        let info = match unpack_file(original.to_str().unwrap(), unpack_dir.to_str().unwrap()).unwrap() {
+            UnpackResult::Rkfw(info) => info,
+            UnpackResult::Rkaf(_) => panic!("expected RKFW"),
+        };
+        repack_rkfw(
+            unpack_dir.join("BOOT").to_str().unwrap(),
+            unpack_dir.join("embedded-update.img").to_str().unwrap(),
+            &info,
+            repacked.to_str().unwrap(),
+        ).unwrap();
+
+        assert_eq!(fs::read(&original).unwrap(), fs::read(&repacked).unwrap());
+
+        // 更新映像不是 RKAF 时应拒绝打包
+        let result = repack_rkfw(
+            unpack_dir.join("BOOT").to_str().unwrap(),
+            unpack_dir.join("BOOT").to_str().unwrap(),
+            &info,
+            repacked.to_str().unwrap(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_make_firmware() {
+        let temp_dir = TempDir::new().unwrap();
+        let loader = temp_dir.path().join("MiniLoaderAll.bin");
+        let parameter = temp_dir.path().join("parameter.txt");
+        let boot = temp_dir.path().join("boot.img");
+        let rootfs = temp_dir.path().join("rootfs.img");
+        let output = temp_dir.path().join("firmware.img");
+        fs::write(&loader, [b"BOOT".as_slice(), &[0x4cu8; 3000]].concat()).unwrap();
+        fs::write(&parameter, "FIRMWARE_VER: 2.1
+MACHINE_MODEL: Snapmaker
+MANUFACTURER: RK
+MACHINE_ID: 007
+CMDLINE: mtdparts=rk29xxnand:0x00002000@0x00004000(boot),-@0x00006000(rootfs:grow)
+").unwrap();
+        fs::write(&boot, vec![0xa5u8; 5000]).unwrap();
+        fs::write(&rootfs, vec![0x5au8; 100]).unwrap();
+        let partitions = vec![
+            ("boot".to_string(), boot.to_str().unwrap().to_string()),
+            ("rootfs".to_string(), rootfs.to_str().unwrap().to_string()),
+        ];
+        let result = make_firmware(loader.to_str().unwrap(), parameter.to_str().unwrap(), &partitions, "RK3326", output.to_str().unwrap()).unwrap();
+        assert_eq!(result.version, "2.1.0");
+        assert_eq!(result.chip_code, chip_name_to_code("RK3326").unwrap());
+        assert!(result.timestamp > 0);
+
+        // 生成的固件能在严格模式下解包，闪存布局来自 parameter
+        let out_dir = temp_dir.path().join("out");
+        let options = UnpackOptions { strict: true, recursive: true, ..Default::default() };
+        let UnpackResult::Rkfw(info) = unpack_file_with_options(output.to_str().unwrap(), out_dir.to_str().unwrap(), &options).unwrap() else { panic!("expected RKFW") };
+        assert_eq!(info.chip_family, "RK3326");
+        assert_eq!(fs::read(out_dir.join("BOOT")).unwrap(), fs::read(&loader).unwrap());
+        let partitions = list_partitions(output.to_str().unwrap()).unwrap();
+        let names: Vec<&str> = partitions.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["BOOT", "embedded-update.img", "parameter", "bootloader", "boot", "rootfs"]);
+        assert_eq!((partitions[4].flash_offset, partitions[4].flash_size), (0x4000, 0x2000));
+        assert_eq!(partitions[5].flash_size, FLASH_SIZE_GROW);
+        let embedded = out_dir.join("embedded-update");
+        assert_eq!(fs::read(embedded.join("Image/boot.img")).unwrap(), vec![0xa5u8; 5000]);
+        assert_eq!(fs::read(embedded.join("MiniLoaderAll.bin")).unwrap(), fs::read(&loader).unwrap());
+        // parameter 带 PARM 头保存
+        let stored = fs::read(embedded.join("parameter.txt")).unwrap();
+        assert!(stored.starts_with(b"PARM"));
+        assert_eq!(parse_parameter(&stored).unwrap().len(), 2);
+        let header = fs::read_to_string(embedded.join("header-metadata.txt")).unwrap();
+        assert!(header.contains("Snapmaker"));
+
+        // parameter 中未声明的分区报错
+        let partitions = vec![("userdata".to_string(), boot.to_str().unwrap().to_string())];
+        let err = make_firmware(loader.to_str().unwrap(), parameter.to_str().unwrap(), &partitions, "RK3326", output.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ApfError::Invalid(ref message) if message.contains("does not declare a partition named userdata")));
+    }
+
     #[test]
     fn test_update_header_from_bytes() {
         let mock_rkaf = create_mock_rkaf();